@@ -152,6 +152,11 @@ impl BroadcastChangeTracker {
         tracker
     }
 
+    /// The current change_count, incremented by every Broadcast change
+    pub fn change_count(&self) -> u32 {
+        self.change_count
+    }
+
     /// Add a `Vec` of `Broadcast`s via `self.add_broadcast`
     ///
     /// Returning the latest change_count (or `None` for an empty `Vec`)
@@ -0,0 +1,102 @@
+//! Tracks (and optionally caps) the number of connected WebSocket clients
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use cadence::{Gauged, StatsdClient};
+
+/// The number of connected clients, across all workers
+pub struct Connections {
+    count: AtomicUsize,
+    /// The maximum number of connected clients, `None` for unlimited
+    max: Option<usize>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl Connections {
+    pub fn new(max: Option<usize>, metrics: Arc<StatsdClient>) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            max,
+            metrics,
+        }
+    }
+
+    /// Track a new connection until the returned guard is dropped
+    ///
+    /// Returns `None` when already at the maximum. The count is
+    /// checked and incremented atomically, so simultaneous connections can't
+    /// exceed it.
+    pub fn try_connect(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        let max = self.max.unwrap_or(usize::MAX);
+        let previous = self
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+        self.report(previous + 1);
+        Some(ConnectionGuard {
+            connections: Arc::clone(self),
+        })
+    }
+
+    /// The current number of connected clients
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    fn report(&self, count: usize) {
+        let _ = self.metrics.gauge("ws.connections", count as u64);
+    }
+}
+
+/// A connected client, no longer counted once dropped
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let previous = self.connections.count.fetch_sub(1, Ordering::AcqRel);
+        self.connections.report(previous - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::Connections;
+
+    fn connections(max: Option<usize>) -> Arc<Connections> {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        Arc::new(Connections::new(max, metrics))
+    }
+
+    #[test]
+    fn capped() {
+        let connections = connections(Some(2));
+        let first = connections.try_connect().unwrap();
+        let _second = connections.try_connect().unwrap();
+        assert!(connections.try_connect().is_none());
+        assert_eq!(connections.count(), 2);
+
+        drop(first);
+        assert_eq!(connections.count(), 1);
+        assert!(connections.try_connect().is_some());
+    }
+
+    #[test]
+    fn unlimited() {
+        let connections = connections(None);
+        let guards: Vec<_> = (0..100).map(|_| connections.try_connect()).collect();
+        assert!(guards.iter().all(Option::is_some));
+        assert_eq!(connections.count(), 100);
+        drop(guards);
+        assert_eq!(connections.count(), 0);
+    }
+}
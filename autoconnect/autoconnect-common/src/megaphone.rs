@@ -5,7 +5,10 @@ use cadence::{CountedExt, StatsdClient};
 use serde_derive::Deserialize;
 use tokio::sync::RwLock;
 
-use crate::broadcast::{Broadcast, BroadcastChangeTracker};
+use crate::{
+    broadcast::{Broadcast, BroadcastChangeTracker},
+    registry::ClientRegistry,
+};
 
 /// The payload provided by the Megaphone service
 #[derive(Deserialize)]
@@ -17,9 +20,11 @@ pub struct MegaphoneResponse {
 ///
 /// Immediately populates it with the current Broadcasts polled from the
 /// Megaphone service, then spawns a background task to periodically refresh
-/// it
+/// it. Connected `clients` are notified of changes as they're polled, rather
+/// than awaiting their next Ping.
 pub async fn init_and_spawn_megaphone_updater(
     broadcaster: &Arc<RwLock<BroadcastChangeTracker>>,
+    clients: &Arc<ClientRegistry>,
     http: &reqwest::Client,
     metrics: &Arc<StatsdClient>,
     url: &str,
@@ -29,6 +34,7 @@ pub async fn init_and_spawn_megaphone_updater(
     updater(broadcaster, http, url, token).await?;
 
     let broadcaster = Arc::clone(broadcaster);
+    let clients = Arc::clone(clients);
     let http = http.clone();
     let metrics = Arc::clone(metrics);
     let url = url.to_owned();
@@ -36,10 +42,14 @@ pub async fn init_and_spawn_megaphone_updater(
     rt::spawn(async move {
         loop {
             rt::time::sleep(poll_interval).await;
-            if let Err(e) = updater(&broadcaster, &http, &url, &token).await {
-                report_updater_error(&metrics, e);
-            } else {
-                metrics.incr_with_tags("megaphone.updater.ok").send();
+            match updater(&broadcaster, &http, &url, &token).await {
+                Ok(changed) => {
+                    metrics.incr_with_tags("megaphone.updater.ok").send();
+                    if changed {
+                        clients.broadcast_changed().await;
+                    }
+                }
+                Err(e) => report_updater_error(&metrics, e),
             }
         }
     });
@@ -71,12 +81,14 @@ fn report_updater_error(metrics: &Arc<StatsdClient>, err: reqwest::Error) {
 }
 
 /// Refresh the `BroadcastChangeTracker`'s Broadcasts from the Megaphone service
+///
+/// Returning whether any Broadcasts changed
 async fn updater(
     broadcaster: &Arc<RwLock<BroadcastChangeTracker>>,
     http: &reqwest::Client,
     url: &str,
     token: &str,
-) -> reqwest::Result<()> {
+) -> reqwest::Result<bool> {
     trace!("📢megaphone::updater");
     let MegaphoneResponse { broadcasts } = http
         .get(url)
@@ -87,11 +99,14 @@ async fn updater(
         .json()
         .await?;
     let broadcasts = Broadcast::from_hashmap(broadcasts);
-    if !broadcasts.is_empty() {
-        let change_count = broadcaster.write().await.add_broadcasts(broadcasts);
-        trace!("📢 add_broadcast change_count: {:?}", change_count);
+    if broadcasts.is_empty() {
+        return Ok(false);
     }
-    Ok(())
+    let mut broadcaster = broadcaster.write().await;
+    let prior_change_count = broadcaster.change_count();
+    let change_count = broadcaster.add_broadcasts(broadcasts);
+    trace!("📢 add_broadcast change_count: {:?}", change_count);
+    Ok(change_count.map_or(false, |count| count != prior_change_count))
 }
 
 /// Determine if a source of [reqwest::Error] was a [hyper::Error] Io Error
@@ -119,3 +134,75 @@ fn is_hyper_io(err: &hyper::Error) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use cadence::{NopMetricSink, StatsdClient};
+    use serde_json::json;
+    use tokio::sync::RwLock;
+    use uuid::Uuid;
+
+    use super::init_and_spawn_megaphone_updater;
+    use crate::{
+        broadcast::{Broadcast, BroadcastChangeTracker},
+        protocol::{BroadcastValue, ServerNotification},
+        registry::ClientRegistry,
+    };
+
+    fn megaphone_mock(version: &str) -> mockito::Mock {
+        mockito::mock("GET", "/v1/broadcasts")
+            .match_header("Authorization", "token")
+            .with_header("Content-Type", "application/json")
+            .with_body(json!({"broadcasts": {"foo/bar": version}}).to_string())
+            .create()
+    }
+
+    /// The version of "foo/bar" currently tracked
+    async fn version(broadcaster: &RwLock<BroadcastChangeTracker>) -> String {
+        let stale: Broadcast = ("foo/bar".to_owned(), "".to_owned()).into();
+        let delta = broadcaster.read().await.broadcast_delta(&[stale]).1;
+        match Broadcast::vec_into_hashmap(delta).remove("foo/bar") {
+            Some(BroadcastValue::Value(version)) => version,
+            other => panic!("Unexpected delta: {other:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn notifies_polled_changes() {
+        let broadcaster = Arc::new(RwLock::new(BroadcastChangeTracker::new(Vec::new())));
+        let clients = Arc::new(ClientRegistry::default());
+        let mut snotifs = clients.connect(Uuid::new_v4(), Uuid::new_v4()).await;
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+
+        let mock = megaphone_mock("v1");
+        init_and_spawn_megaphone_updater(
+            &broadcaster,
+            &clients,
+            &reqwest::Client::new(),
+            &metrics,
+            &format!("{}/v1/broadcasts", mockito::server_url()),
+            "token",
+            Duration::from_secs_f32(0.1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(version(&broadcaster).await, "v1");
+
+        // Polling an unchanged source doesn't notify the clients
+        actix_web::rt::time::sleep(Duration::from_secs_f32(0.25)).await;
+        assert!(snotifs.try_next().is_err());
+
+        drop(mock);
+        let _mock = megaphone_mock("v2");
+        actix_web::rt::time::sleep(Duration::from_secs_f32(0.25)).await;
+        assert_eq!(version(&broadcaster).await, "v2");
+        // Notified once of the change
+        assert!(matches!(
+            snotifs.try_next(),
+            Ok(Some(ServerNotification::BroadcastChanged))
+        ));
+        assert!(snotifs.try_next().is_err());
+    }
+}
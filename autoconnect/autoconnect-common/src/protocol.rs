@@ -26,8 +26,12 @@ pub enum BroadcastValue {
 pub enum ServerNotification {
     CheckStorage,
     Notification(Notification),
+    /// The Megaphone service's Broadcasts changed
+    BroadcastChanged,
     #[default]
     Disconnect,
+    /// This node's draining: the client should reconnect (to another node)
+    Drain,
 }
 
 #[derive(Debug, Deserialize)]
@@ -98,6 +102,11 @@ pub enum ServerMessage {
         #[serde(skip_serializing_if = "Option::is_none")]
         use_webpush: Option<bool>,
         broadcasts: HashMap<String, BroadcastValue>,
+        /// Whether the UAID the client presented couldn't be resumed (it's
+        /// unknown or expired), so it should drop its channel registrations
+        /// and register them again under the new one
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        uaid_reset_requested: bool,
     },
 
     Register {
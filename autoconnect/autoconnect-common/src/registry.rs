@@ -1,5 +1,8 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use actix_web::rt;
 use futures::channel::mpsc;
 use futures_locks::RwLock;
 use uuid::Uuid;
@@ -24,13 +27,31 @@ struct RegisteredClient {
 #[derive(Default)]
 pub struct ClientRegistry {
     clients: RwLock<HashMap<Uuid, RegisteredClient>>,
+    /// How long a connection superseded by a newer one for its UAID is kept
+    /// open before it's disconnected
+    duplicate_grace: Duration,
+    /// Whether this node's draining (see [ClientRegistry::drain])
+    draining: AtomicBool,
 }
 
 impl ClientRegistry {
+    pub fn new(duplicate_grace: Duration) -> Self {
+        Self {
+            clients: Default::default(),
+            duplicate_grace,
+            draining: AtomicBool::new(false),
+        }
+    }
+
     /// Informs this server that a new `client` has connected
     ///
     /// For now just registers internal state by keeping track of the `client`,
     /// namely its channel to send notifications back.
+    ///
+    /// An existing connection for the same UAID (e.g. the client reconnected
+    /// over a flapping network before the old one was reaped) is "Ghosted":
+    /// notifications are only routed to the new connection from now on and
+    /// the existing one's disconnected after the `duplicate_grace` window.
     pub async fn connect(
         &self,
         uaid: Uuid,
@@ -40,16 +61,32 @@ impl ClientRegistry {
         let (tx, snotif_stream) = mpsc::unbounded();
         let client = RegisteredClient { uaid, uid, tx };
         let mut clients = self.clients.write().await;
-        if let Some(client) = clients.insert(client.uaid, client) {
-            // Drop existing connection
-            let result = client.tx.unbounded_send(ServerNotification::Disconnect);
-            if result.is_ok() {
-                debug!("ClientRegistry::connect Ghosting client, new one wants to connect");
+        if self.is_draining() {
+            // Connected mid-drain (its Hello was accepted just before)
+            let _ = client.tx.unbounded_send(ServerNotification::Drain);
+        }
+        if let Some(ghost) = clients.insert(client.uaid, client) {
+            if self.duplicate_grace.is_zero() {
+                Self::ghost(ghost);
+            } else {
+                let grace = self.duplicate_grace;
+                rt::spawn(async move {
+                    rt::time::sleep(grace).await;
+                    Self::ghost(ghost);
+                });
             }
         }
         snotif_stream
     }
 
+    /// Drop an existing connection
+    fn ghost(client: RegisteredClient) {
+        let result = client.tx.unbounded_send(ServerNotification::Disconnect);
+        if result.is_ok() {
+            debug!("ClientRegistry::connect Ghosting client, new one wants to connect");
+        }
+    }
+
     /// A notification has come for the uaid
     pub async fn notify(&self, uaid: Uuid, notif: Notification) -> Result<()> {
         trace!("ClientRegistry::notify");
@@ -81,6 +118,42 @@ impl ClientRegistry {
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 
+    /// The Broadcasts changed: notify every connected client so they're sent
+    /// any changes they're subscribed to
+    pub async fn broadcast_changed(&self) {
+        trace!("ClientRegistry::broadcast_changed");
+        let clients = self.clients.read().await;
+        for client in clients.values() {
+            // Ignore clients in the midst of disconnecting
+            let _ = client
+                .tx
+                .unbounded_send(ServerNotification::BroadcastChanged);
+        }
+    }
+
+    /// Drain this node: signal every connected client to reconnect (to
+    /// another node), returning how many were signaled. New clients should
+    /// be refused from now on (see [ClientRegistry::is_draining]).
+    ///
+    /// Only the first call signals the clients, later ones return 0.
+    pub async fn drain(&self) -> usize {
+        trace!("ClientRegistry::drain");
+        if self.draining.swap(true, Ordering::AcqRel) {
+            return 0;
+        }
+        let clients = self.clients.read().await;
+        clients
+            .values()
+            // Ignore clients in the midst of disconnecting
+            .filter(|client| client.tx.unbounded_send(ServerNotification::Drain).is_ok())
+            .count()
+    }
+
+    /// Whether this node's draining
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
     /// The client specified by `uaid` has disconnected.
     pub async fn disconnect(&self, uaid: &Uuid, uid: &Uuid) -> Result<()> {
         trace!("ClientRegistry::disconnect");
@@ -93,3 +166,67 @@ impl ClientRegistry {
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use uuid::Uuid;
+
+    use autopush_common::notification::Notification;
+
+    use super::ClientRegistry;
+    use crate::protocol::ServerNotification;
+
+    /// A client reconnecting while still connected: only the new connection's
+    /// routed to, the old one's disconnected after the grace window
+    #[actix_web::test]
+    async fn duplicate_connection() {
+        let registry = ClientRegistry::new(Duration::from_millis(100));
+        let uaid = Uuid::new_v4();
+        let mut old = registry.connect(uaid, Uuid::new_v4()).await;
+        let mut new = registry.connect(uaid, Uuid::new_v4()).await;
+
+        registry
+            .notify(uaid, Notification::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            new.next().await,
+            Some(ServerNotification::Notification(_))
+        ));
+        // Still connected during the grace window
+        assert!(old.try_next().is_err());
+
+        actix_web::rt::time::sleep(Duration::from_millis(150)).await;
+        assert!(matches!(
+            old.next().await,
+            Some(ServerNotification::Disconnect)
+        ));
+        assert!(new.try_next().is_err());
+    }
+
+    /// Every connected client's signaled once, even when drained again
+    #[actix_web::test]
+    async fn drain() {
+        let registry = ClientRegistry::new(Duration::ZERO);
+        let mut conns = Vec::new();
+        for _ in 0..3 {
+            conns.push(registry.connect(Uuid::new_v4(), Uuid::new_v4()).await);
+        }
+        assert!(!registry.is_draining());
+
+        assert_eq!(registry.drain().await, 3);
+        assert!(registry.is_draining());
+        assert_eq!(registry.drain().await, 0);
+        for conn in &mut conns {
+            assert!(matches!(conn.next().await, Some(ServerNotification::Drain)));
+            assert!(conn.try_next().is_err());
+        }
+
+        // Connecting mid-drain
+        let mut late = registry.connect(Uuid::new_v4(), Uuid::new_v4()).await;
+        assert!(matches!(late.next().await, Some(ServerNotification::Drain)));
+    }
+}
@@ -7,8 +7,8 @@ use fernet::{Fernet, MultiFernet};
 use tokio::sync::RwLock;
 
 use autoconnect_common::{
-    broadcast::BroadcastChangeTracker, megaphone::init_and_spawn_megaphone_updater,
-    registry::ClientRegistry,
+    broadcast::BroadcastChangeTracker, connections::Connections,
+    megaphone::init_and_spawn_megaphone_updater, registry::ClientRegistry,
 };
 use autopush_common::db::{client::DbClient, dynamodb::DdbClientImpl, DbSettings, StorageType};
 use autopush_common::errors::{ApcErrorKind, Result};
@@ -26,6 +26,8 @@ pub struct AppState {
     pub fernet: MultiFernet,
     /// The connected WebSocket clients
     pub clients: Arc<ClientRegistry>,
+    /// The number of connected WebSocket clients
+    pub connections: Arc<Connections>,
     /// The Megaphone Broadcast change tracker
     pub broadcaster: Arc<RwLock<BroadcastChangeTracker>>,
 
@@ -69,6 +71,7 @@ impl AppState {
         let db_settings = DbSettings {
             dsn: settings.db_dsn.clone(),
             db_settings: settings.db_settings.clone(),
+            table_prefix: settings.database_table_prefix.clone(),
         };
         let storage_type = StorageType::from_dsn(&db_settings.dsn);
         let db: Box<dyn DbClient> = match storage_type {
@@ -89,6 +92,8 @@ impl AppState {
             .unwrap_or_else(|e| panic!("Error while building reqwest::Client: {}", e));
         let broadcaster = Arc::new(RwLock::new(BroadcastChangeTracker::new(Vec::new())));
 
+        let connections = Arc::new(Connections::new(settings.max_connections, metrics.clone()));
+
         let router_url = settings.router_url();
         let endpoint_url = settings.endpoint_url();
 
@@ -97,7 +102,10 @@ impl AppState {
             metrics,
             http,
             fernet,
-            clients: Arc::new(ClientRegistry::default()),
+            clients: Arc::new(ClientRegistry::new(Duration::from_millis(
+                settings.duplicate_connection_grace_ms,
+            ))),
+            connections,
             broadcaster,
             settings,
             router_url,
@@ -122,6 +130,7 @@ impl AppState {
         };
         init_and_spawn_megaphone_updater(
             &self.broadcaster,
+            &self.clients,
             &self.http,
             &self.metrics,
             url,
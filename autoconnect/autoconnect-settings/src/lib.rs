@@ -13,7 +13,7 @@ use lazy_static::lazy_static;
 use serde::{Deserialize, Deserializer};
 use serde_json::json;
 
-use autopush_common::util::deserialize_u32_to_duration;
+use autopush_common::{db::DbSettings, util::deserialize_u32_to_duration};
 
 pub use app_state::AppState;
 
@@ -63,6 +63,11 @@ pub struct Settings {
     /// How long to wait for a response Pong before being timed out and connection drop
     #[serde(deserialize_with = "deserialize_f64_to_duration")]
     pub auto_ping_timeout: Duration,
+    /// How long a connection may go without the Client sending a WebPush
+    /// message or being sent a notification (WebSocket Pings and Pongs don't
+    /// count) before it's closed. 0 never closes idle connections.
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub idle_timeout: Duration,
     /// How long to wait for the initial connection handshake.
     #[serde(deserialize_with = "deserialize_u32_to_duration")]
     pub open_handshake_timeout: Duration,
@@ -75,9 +80,13 @@ pub struct Settings {
     pub endpoint_hostname: String,
     /// The optional port override for the endpoint URL
     pub endpoint_port: u16,
+    /// The path prefix autoendpoint serves its routes under (its
+    /// `url_path_prefix`), e.g. `/push`. Empty for the root.
+    pub endpoint_url_path_prefix: String,
     /// The seed key to use for endpoint encryption
     pub crypto_key: String,
-    /// The host name to send recorded metrics
+    /// The host name to send recorded metrics, or a comma separated list of
+    /// `host[:port]`s to send them all to
     pub statsd_host: Option<String>,
     /// The port number to send recorded metrics
     pub statsd_port: u16,
@@ -87,6 +96,9 @@ pub struct Settings {
     pub db_dsn: Option<String>,
     /// JSON set of specific database settings (See data storage engines)
     pub db_settings: String,
+    /// Prefixed to the storage's table names, so separate deployments can
+    /// share a database. Letters, digits and `_` only.
+    pub database_table_prefix: String,
     /// Server endpoint to pull Broadcast ID change values (Sent in Pings)
     pub megaphone_api_url: Option<String>,
     /// Broadcast token for authentication
@@ -108,6 +120,36 @@ pub struct Settings {
     ///
     /// By default, the number of available physical CPUs is used as the worker count.
     pub actix_workers: Option<usize>,
+    /// The maximum number of connected WebSocket clients (across all
+    /// workers). Further connections are refused with a 503.
+    ///
+    /// By default the number of connections is unlimited.
+    pub max_connections: Option<usize>,
+    /// The maximum number of channels a client may register. Registering
+    /// more fails until others are unregistered.
+    pub max_channels_per_client: u32,
+    /// The number of days after which the UAID of a client that hasn't
+    /// connected is expired. Its client's then issued a new UAID (and
+    /// asked to reset its registrations) on its next Hello.
+    ///
+    /// By default UAIDs don't expire from idleness.
+    pub uaid_ttl_days: u64,
+    /// The number of times a notification the client Nacks (failing to
+    /// process it) is redelivered before it's dropped
+    pub max_nack_retries: u32,
+    /// How long (in milliseconds) a client's existing connection is kept
+    /// open after it connects again (e.g. over a flapping network) before
+    /// it's closed. Notifications are only delivered to the newest connection
+    /// meanwhile.
+    pub duplicate_connection_grace_ms: u64,
+    /// How far (in seconds) the clock that stored a notification may have
+    /// been behind ours, extending its TTL so it's not expired early
+    pub clock_skew_tolerance_secs: u64,
+    /// The bearer token authorizing the admin routes (e.g. draining the
+    /// node) on the router port.
+    ///
+    /// By default the admin routes are disabled.
+    pub admin_token: Option<String>,
 }
 
 impl Default for Settings {
@@ -120,11 +162,13 @@ impl Default for Settings {
             router_hostname: None,
             auto_ping_interval: Duration::from_secs(300),
             auto_ping_timeout: Duration::from_secs(4),
+            idle_timeout: Duration::ZERO,
             open_handshake_timeout: Duration::from_secs(5),
             close_handshake_timeout: Duration::from_secs(0),
             endpoint_scheme: "http".to_owned(),
             endpoint_hostname: "localhost".to_owned(),
             endpoint_port: 8082,
+            endpoint_url_path_prefix: "".to_owned(),
             crypto_key: format!("[{}]", Fernet::generate_key()),
             statsd_host: Some("localhost".to_owned()),
             // Matches the legacy value
@@ -132,6 +176,7 @@ impl Default for Settings {
             statsd_port: 8125,
             db_dsn: None,
             db_settings: "".to_owned(),
+            database_table_prefix: "".to_owned(),
             megaphone_api_url: None,
             megaphone_api_token: None,
             megaphone_poll_interval: Duration::from_secs(30),
@@ -139,6 +184,13 @@ impl Default for Settings {
             msg_limit: 100,
             actix_max_connections: None,
             actix_workers: None,
+            max_connections: None,
+            max_channels_per_client: 1000,
+            uaid_ttl_days: 0,
+            max_nack_retries: 3,
+            duplicate_connection_grace_ms: 1000,
+            clock_skew_tolerance_secs: 30,
+            admin_token: None,
         }
     }
 }
@@ -180,10 +232,16 @@ impl Settings {
 
     pub fn endpoint_url(&self) -> String {
         let url = format!("{}://{}", self.endpoint_scheme, self.endpoint_hostname,);
-        if include_port(&self.endpoint_scheme, self.endpoint_port) {
+        let url = if include_port(&self.endpoint_scheme, self.endpoint_port) {
             format!("{}:{}", url, self.endpoint_port)
         } else {
             url
+        };
+        if self.endpoint_url_path_prefix.is_empty() {
+            url
+        } else {
+            // The endpoints are joined onto it, which requires the trailing /
+            format!("{}{}/", url, self.endpoint_url_path_prefix)
         }
     }
 
@@ -215,6 +273,46 @@ impl Settings {
         non_zero(self.megaphone_poll_interval, "MEGAPHONE_POLL_INTERVAL")?;
         non_zero(self.auto_ping_interval, "AUTO_PING_INTERVAL")?;
         non_zero(self.auto_ping_timeout, "AUTO_PING_TIMEOUT")?;
+        if self.max_connections == Some(0) {
+            return Err(ConfigError::Message(format!(
+                "Invalid {}_MAX_CONNECTIONS: cannot be 0",
+                ENV_PREFIX
+            )));
+        }
+        if self.max_channels_per_client == 0 {
+            return Err(ConfigError::Message(format!(
+                "Invalid {}_MAX_CHANNELS_PER_CLIENT: cannot be 0",
+                ENV_PREFIX
+            )));
+        }
+        if !self.endpoint_url_path_prefix.is_empty() {
+            let prefix = &self.endpoint_url_path_prefix;
+            let valid = prefix.starts_with('/')
+                && !prefix.ends_with('/')
+                && !prefix.contains("//")
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c));
+            if !valid {
+                return Err(ConfigError::Message(format!(
+                    "Invalid {}_ENDPOINT_URL_PATH_PREFIX {:?}: expected a path starting with / \
+                     and without a trailing /, e.g. \"/push\"",
+                    ENV_PREFIX, prefix
+                )));
+            }
+        }
+        if !DbSettings::valid_table_prefix(&self.database_table_prefix) {
+            return Err(ConfigError::Message(format!(
+                "Invalid {}_DATABASE_TABLE_PREFIX {:?}: only letters, digits and _ are allowed",
+                ENV_PREFIX, self.database_table_prefix
+            )));
+        }
+        if self.admin_token.as_deref() == Some("") {
+            return Err(ConfigError::Message(format!(
+                "Invalid {}_ADMIN_TOKEN: cannot be empty",
+                ENV_PREFIX
+            )));
+        }
         Ok(())
     }
 
@@ -285,6 +383,42 @@ mod tests {
         settings.endpoint_port = 8080;
         let url = settings.endpoint_url();
         assert_eq!("https://testname:8080", url);
+
+        settings.endpoint_url_path_prefix = "/push".to_string();
+        let url = settings.endpoint_url();
+        assert_eq!("https://testname:8080/push/", url);
+    }
+
+    #[test]
+    fn test_endpoint_url_path_prefix() {
+        let endpoint = |prefix: &str| {
+            let settings = Settings {
+                endpoint_hostname: "testname".to_string(),
+                endpoint_url_path_prefix: prefix.to_string(),
+                ..Default::default()
+            };
+            settings.validate()?;
+            Ok::<_, ConfigError>(settings.endpoint_url())
+        };
+        assert_eq!(endpoint("").unwrap(), "http://testname:8082");
+        assert_eq!(endpoint("/a/push").unwrap(), "http://testname:8082/a/push/");
+        for prefix in ["push", "/push/", "//push", "/pu sh"] {
+            assert!(endpoint(prefix).is_err(), "{prefix}");
+        }
+    }
+
+    #[test]
+    fn test_database_table_prefix() {
+        let settings = Settings {
+            database_table_prefix: "tenantA_".to_owned(),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+        let settings = Settings {
+            database_table_prefix: "tenant-a".to_owned(),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
     }
 
     #[test]
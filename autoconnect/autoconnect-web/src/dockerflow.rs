@@ -57,8 +57,11 @@ pub async fn status_route(state: Data<AppState>) -> Json<serde_json::Value> {
 }
 
 /// Handle the `/__lbheartbeat__` route
-pub async fn lb_heartbeat_route() -> HttpResponse {
-    // Used by the load balancers, just return OK.
+pub async fn lb_heartbeat_route(state: Data<AppState>) -> HttpResponse {
+    // Used by the load balancers: take a draining node out of rotation
+    if state.clients.is_draining() {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
     HttpResponse::Ok().finish()
 }
 
@@ -1,8 +1,11 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{http::header::AUTHORIZATION, web, HttpRequest, HttpResponse};
+use cadence::Counted;
+use serde_json::json;
 use uuid::Uuid;
 
 use autoconnect_settings::AppState;
 use autopush_common::notification::Notification;
+use autopush_common::util::is_admin_token;
 
 use crate::error::ApiError;
 
@@ -50,3 +53,28 @@ pub async fn check_storage_route(
         HttpResponse::NotFound().body("Client not available")
     }
 }
+
+/// Drain this node: close every connected client so it reconnects to another
+/// node, refuse new connections and fail the `/__lbheartbeat__` so the load
+/// balancer stops sending clients here. Requires the `admin_token`.
+///
+/// Responds with the number of clients signaled, 0 when already drained.
+pub async fn drain_route(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    trace!("⏩ drain_route");
+    let auth_header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+    if !is_admin_token(auth_header, app_state.settings.admin_token.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let clients_notified = app_state.clients.drain().await;
+    info!(
+        "Draining, signaled {} clients to reconnect",
+        clients_notified
+    );
+    let _ = app_state
+        .metrics
+        .count("drain.clients_notified", clients_notified as i64);
+    HttpResponse::Ok().json(json!({ "clients_notified": clients_notified }))
+}
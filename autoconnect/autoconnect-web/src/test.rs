@@ -6,11 +6,13 @@ use futures_util::{SinkExt, StreamExt};
 use serde_json::json;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use autoconnect_common::test_support::{hello_again_db, hello_db, DUMMY_UAID, HELLO, HELLO_AGAIN};
+use autoconnect_common::test_support::{
+    hello_again_db, hello_db, CURRENT_MONTH, DUMMY_UAID, HELLO, HELLO_AGAIN,
+};
 use autoconnect_settings::{AppState, Settings};
-use autopush_common::notification::Notification;
+use autopush_common::{db::mock::MockDbClient, notification::Notification};
 
-use crate::{build_app, config};
+use crate::{build_app, config, config_router};
 
 #[ctor::ctor]
 fn init_test_logging() {
@@ -21,6 +23,10 @@ fn test_server(app_state: AppState) -> TestServer {
     actix_test::start(move || build_app!(app_state, config))
 }
 
+fn test_router_server(app_state: AppState) -> TestServer {
+    actix_test::start(move || build_app!(app_state, config_router))
+}
+
 /// Extract the next message from the pending message queue and attempt to
 /// convert it into a parsed JSON Value
 async fn json_msg(
@@ -88,6 +94,31 @@ pub async fn unsupported_websocket_message() {
     assert!(framed.next().await.is_none());
 }
 
+#[actix_rt::test]
+pub async fn max_connections() {
+    let settings = Settings {
+        max_connections: Some(1),
+        ..Settings::test_settings()
+    };
+    let mut srv = test_server(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+
+    let mut framed = srv.ws().await.unwrap();
+    framed.send(ws::Message::Text(HELLO.into())).await.unwrap();
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["status"], 200);
+
+    // At the cap: further connections are refused
+    assert!(srv.ws().await.is_err());
+    let response = srv.get("/").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
 #[actix_rt::test]
 pub async fn invalid_webpush_message() {
     let mut srv = test_server(AppState {
@@ -227,3 +258,158 @@ pub async fn broadcast_after_ping() {
         .expect("!broadcasts.is_object()");
     assert_eq!(broadcasts["foo/bar"].as_str(), Some("v2"));
 }
+
+#[actix_rt::test]
+pub async fn broadcast_behind_on_hello() {
+    let app_state = AppState {
+        db: hello_db().into_boxed_arc(),
+        ..Default::default()
+    };
+    app_state
+        .broadcaster
+        .write()
+        .await
+        .add_broadcast(("foo/bar".to_owned(), "v2".to_owned()).into());
+    let mut srv = test_server(app_state);
+
+    let hello = json!({"messageType": "hello", "use_webpush": true,
+                       "broadcasts": {"foo/bar": "v1"}});
+    let mut framed = srv.ws().await.unwrap();
+    framed
+        .send(ws::Message::Text(hello.to_string().into()))
+        .await
+        .unwrap();
+
+    // The Hello includes the Broadcasts changed since the Client's versions
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "hello");
+    let broadcasts = msg["broadcasts"]
+        .as_object()
+        .expect("!broadcasts.is_object()");
+    assert_eq!(broadcasts.len(), 1);
+    assert_eq!(broadcasts["foo/bar"].as_str(), Some("v2"));
+}
+
+#[actix_rt::test]
+pub async fn broadcast_changed() {
+    let app_state = AppState {
+        db: hello_db().into_boxed_arc(),
+        ..Default::default()
+    };
+    let broadcaster = &app_state.broadcaster;
+    broadcaster
+        .write()
+        .await
+        .add_broadcast(("foo/bar".to_owned(), "v1".to_owned()).into());
+    let mut srv = test_server(app_state.clone());
+
+    let hello = json!({"messageType": "hello", "use_webpush": true,
+                       "broadcasts": {"foo/bar": "v1"}});
+    let mut framed = srv.ws().await.unwrap();
+    framed
+        .send(ws::Message::Text(hello.to_string().into()))
+        .await
+        .unwrap();
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "hello");
+
+    // Sent as soon as the change's polled, without awaiting a Ping
+    broadcaster
+        .write()
+        .await
+        .add_broadcast(("foo/bar".to_owned(), "v2".to_owned()).into());
+    app_state.clients.broadcast_changed().await;
+
+    let msg = json_msg(&mut framed).await;
+    assert_eq!(msg["messageType"], "broadcast");
+    let broadcasts = msg["broadcasts"]
+        .as_object()
+        .expect("!broadcasts.is_object()");
+    assert_eq!(broadcasts["foo/bar"].as_str(), Some("v2"));
+}
+
+/// Draining closes the connected clients with the reconnect close code and
+/// refuses new connections
+#[actix_rt::test]
+pub async fn drain() {
+    let mut db = MockDbClient::new();
+    db.expect_rotating_message_table()
+        .times(2)
+        .return_const(Some(CURRENT_MONTH));
+    let settings = Settings {
+        admin_token: Some("s3cret".to_owned()),
+        ..Settings::test_settings()
+    };
+    let app_state = AppState {
+        db: db.into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    };
+    let mut srv = test_server(app_state.clone());
+    let router = test_router_server(app_state);
+    let response = srv.get("/__lbheartbeat__").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+
+    let mut conns = Vec::new();
+    for _ in 0..2 {
+        let mut framed = srv.ws().await.unwrap();
+        framed.send(ws::Message::Text(HELLO.into())).await.unwrap();
+        let msg = json_msg(&mut framed).await;
+        assert_eq!(msg["status"], 200);
+        conns.push(framed);
+    }
+
+    let drain = |token: &'static str| {
+        router
+            .post("/v1/admin/drain")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .send()
+    };
+    let response = drain("wrong").await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+
+    let mut response = drain("s3cret").await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, json!({"clients_notified": 2}));
+
+    for framed in &mut conns {
+        let item = framed.next().await.unwrap().unwrap();
+        let ws::Frame::Close(Some(close_reason)) = item else {
+            panic!("Expected Close(Some(..)) not {:#?}", item);
+        };
+        assert_eq!(close_reason.code, ws::CloseCode::Other(4001));
+    }
+
+    // Idempotent
+    let mut response = drain("s3cret").await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, json!({"clients_notified": 0}));
+
+    assert!(srv.ws().await.is_err());
+    let response = srv.get("/").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::SERVICE_UNAVAILABLE
+    );
+    // Out of the load balancer's rotation
+    let response = srv.get("/__lbheartbeat__").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        actix_http::StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+/// The admin routes are disabled without an `admin_token`
+#[actix_rt::test]
+pub async fn drain_disabled() {
+    let app_state = AppState::default();
+    let router = test_router_server(app_state.clone());
+    let response = router
+        .post("/v1/admin/drain")
+        .insert_header(("Authorization", "Bearer "))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+    assert!(!app_state.clients.is_draining());
+}
@@ -37,12 +37,22 @@ where
     }
 }
 
+/// The close code of a connection superseded by a newer one for the same UAID
+/// (from the range reserved for applications)
+pub const DUPLICATE_CONNECTION_CLOSE_CODE: u16 = 4000;
+
+/// The close code of a connection to a draining node: the client should
+/// reconnect (to another node)
+pub const RECONNECT_CLOSE_CODE: u16 = 4001;
+
 impl SMError {
     pub fn close_code(&self) -> actix_ws::CloseCode {
         match self.kind {
             // TODO: applicable here?
             //SMErrorKind::InvalidMessage(_) => CloseCode::Invalid,
             SMErrorKind::UaidReset => CloseCode::Normal,
+            SMErrorKind::Ghost => CloseCode::Other(DUPLICATE_CONNECTION_CLOSE_CODE),
+            SMErrorKind::Draining => CloseCode::Other(RECONNECT_CLOSE_CODE),
             _ => CloseCode::Error,
         }
     }
@@ -97,11 +107,18 @@ pub enum SMErrorKind {
     #[error("New Client with the same UAID has connected to this node")]
     Ghost,
 
+    #[error("This node is draining, reconnect to another")]
+    Draining,
+
     #[error("Failed to generate endpoint: {0}")]
     MakeEndpoint(#[source] ApcError),
 
     #[error("Client sent too many pings too often")]
     ExcessivePing,
+
+    /// The client already registered `max_channels_per_client` channels
+    #[error("Client registered too many channels (limit {0})")]
+    ChannelLimitExceeded(u32),
 }
 
 impl SMErrorKind {
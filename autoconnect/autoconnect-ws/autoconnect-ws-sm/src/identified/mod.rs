@@ -1,7 +1,7 @@
-use std::{fmt, mem, sync::Arc};
+use std::{collections::HashSet, fmt, mem, sync::Arc};
 
 use actix_web::rt;
-use cadence::{CountedExt, Timed};
+use cadence::{CountedExt, StatsdClient, Timed};
 use futures::channel::mpsc;
 use uuid::Uuid;
 
@@ -22,6 +22,8 @@ use crate::error::{SMError, SMErrorKind};
 mod on_client_msg;
 mod on_server_notif;
 
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
 /// A WebPush Client that's successfully identified itself to the server via a
 /// Hello message.
 ///
@@ -54,6 +56,10 @@ pub struct WebPushClient {
     /// Hello, instead they're lazily added to the db on their first Register
     /// message
     deferred_add_user: Option<User>,
+    /// The user's channels (for enforcing `settings.max_channels_per_client`),
+    /// read from the db on the first Register then kept up to date by this
+    /// session's Register/Unregister messages
+    channels: Option<HashSet<Uuid>>,
 
     /// WebPush Session Statistics
     stats: SessionStatistics,
@@ -114,6 +120,8 @@ impl WebPushClient {
             sent_from_storage: Default::default(),
             connected_at,
             current_timestamp,
+            // New users (not yet added) have no channels
+            channels: deferred_add_user.is_some().then(HashSet::new),
             deferred_add_user,
             last_ping: Default::default(),
             stats,
@@ -138,6 +146,11 @@ impl WebPushClient {
         &self.app_state.settings
     }
 
+    /// Return a reference to `AppState`'s metrics client
+    pub fn app_metrics(&self) -> &StatsdClient {
+        &self.app_state.metrics
+    }
+
     /// Connect this `WebPushClient` to the `ClientRegistry`
     ///
     /// Returning a `Stream` of `ServerNotification`s from the `ClientRegistry`
@@ -319,6 +332,19 @@ pub async fn process_existing_user(
         }
     }
 
+    if is_idle(app_state, user) {
+        debug!("User is idle, dropping user";
+               "user.connected_at" => user.connected_at,
+               "user" => ?user);
+        app_state
+            .metrics
+            .incr_with_tags("ua.expiration")
+            .with_tag("reason", "idle")
+            .send();
+        app_state.db.remove_user(&user.uaid).await?;
+        return Ok(None);
+    }
+
     let flags = ClientFlags {
         check_storage: true,
         old_record_version: user
@@ -329,6 +355,13 @@ pub async fn process_existing_user(
     Ok(Some(flags))
 }
 
+/// Whether the user hasn't connected in `uaid_ttl_days`, expiring its UAID
+pub fn is_idle(app_state: &AppState, user: &User) -> bool {
+    let ttl_days = app_state.settings.uaid_ttl_days;
+    let idle_ms = ms_since_epoch().saturating_sub(user.connected_at);
+    ttl_days > 0 && idle_ms > ttl_days.saturating_mul(MS_PER_DAY)
+}
+
 #[derive(Debug)]
 pub struct ClientFlags {
     /// Whether check_storage queries for topic (not "timestamped") messages
@@ -414,7 +447,8 @@ impl AckState {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
 
     use uuid::Uuid;
 
@@ -422,7 +456,7 @@ mod tests {
         protocol::{ClientMessage, ServerMessage, ServerNotification},
         test_support::{DUMMY_CHID, DUMMY_UAID, UA},
     };
-    use autoconnect_settings::AppState;
+    use autoconnect_settings::{AppState, Settings};
     use autopush_common::{
         db::{client::FetchMessageResponse, mock::MockDbClient},
         notification::Notification,
@@ -529,4 +563,207 @@ mod tests {
             .expect("CheckStorage failed");
         assert!(smsgs.is_empty())
     }
+
+    /// Register a channel, returning the response's status
+    async fn register(client: &mut WebPushClient, channel_id: Uuid) -> u32 {
+        let smsgs = client
+            .on_client_msg(ClientMessage::Register {
+                channel_id: channel_id.as_hyphenated().to_string(),
+                key: None,
+            })
+            .await
+            .unwrap();
+        match smsgs.as_slice() {
+            [ServerMessage::Register { status, .. }] => *status,
+            _ => panic!("Expected a Register response"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn channel_limit() {
+        let channels = Arc::new(Mutex::new(HashSet::new()));
+        let mut db = MockDbClient::new();
+        db.expect_get_channels().times(1).returning({
+            let channels = channels.clone();
+            move |_| Ok(channels.lock().unwrap().clone())
+        });
+        db.expect_add_channel().returning({
+            let channels = channels.clone();
+            move |_, chid| {
+                channels.lock().unwrap().insert(*chid);
+                Ok(())
+            }
+        });
+        db.expect_remove_channel().returning({
+            let channels = channels.clone();
+            move |_, chid| Ok(channels.lock().unwrap().remove(chid))
+        });
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    max_channels_per_client: 2,
+                    ..Settings::test_settings()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(register(&mut client, first).await, 200);
+        assert_eq!(register(&mut client, second).await, 200);
+        assert_eq!(register(&mut client, third).await, 413);
+        // Already registered channels don't take another slot
+        assert_eq!(register(&mut client, second).await, 200);
+        assert_eq!(channels.lock().unwrap().len(), 2);
+
+        // Unregistering frees a slot
+        client
+            .on_client_msg(ClientMessage::Unregister {
+                channel_id: first,
+                code: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(register(&mut client, third).await, 200);
+    }
+
+    /// A client whose timestamp messages are `stored`, allowing
+    /// `max_nack_retries`
+    async fn nack_client(
+        stored: Arc<Mutex<Vec<Notification>>>,
+        max_nack_retries: u32,
+        metrics: cadence::StatsdClient,
+    ) -> WebPushClient {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .returning(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages().returning({
+            let stored = stored.clone();
+            move |_, timestamp, _| {
+                let messages: Vec<_> = stored
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|n| n.sortkey_timestamp > timestamp)
+                    .cloned()
+                    .collect();
+                Ok(FetchMessageResponse {
+                    timestamp: messages.iter().filter_map(|n| n.sortkey_timestamp).max(),
+                    messages,
+                })
+            }
+        });
+        db.expect_save_message().returning({
+            let stored = stored.clone();
+            move |_, notif| {
+                stored.lock().unwrap().push(notif);
+                Ok(())
+            }
+        });
+        db.expect_remove_message().returning({
+            let stored = stored.clone();
+            move |_, sort_key| {
+                stored
+                    .lock()
+                    .unwrap()
+                    .retain(|n| n.chidmessageid() != sort_key);
+                Ok(())
+            }
+        });
+        db.expect_increment_storage().returning(|_, _| Ok(()));
+        let (client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                metrics: Arc::new(metrics),
+                settings: Settings {
+                    max_nack_retries,
+                    ..Settings::test_settings()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        client
+    }
+
+    fn nacked_notif(nack_count: u32) -> Notification {
+        Notification {
+            version: "nacked".to_owned(),
+            sortkey_timestamp: Some(ms_since_epoch() - 1000),
+            nack_count,
+            ..new_timestamp_notif(&DUMMY_CHID, 60)
+        }
+    }
+
+    fn nack() -> ClientMessage {
+        ClientMessage::Nack {
+            code: Some(301),
+            version: "nacked".to_owned(),
+        }
+    }
+
+    /// A Nack'd notification is redelivered from storage until it's been
+    /// Nack'd more than max_nack_retries times
+    #[actix_rt::test]
+    async fn nack_redelivers_until_retry_cap() {
+        let stored = Arc::new(Mutex::new(vec![nacked_notif(0)]));
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = cadence::StatsdClient::from_sink("autoconnect", sink);
+        let mut client = nack_client(stored.clone(), 2, metrics).await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .unwrap();
+        assert!(matches!(
+            smsgs.as_slice(),
+            [ServerMessage::Notification(n)] if n.version == "nacked"
+        ));
+        for nack_count in 1..=2 {
+            let smsgs = client.on_client_msg(nack()).await.unwrap();
+            assert!(matches!(
+                smsgs.as_slice(),
+                [ServerMessage::Notification(n)] if n.version == "nacked"
+            ));
+            // Moved, not copied, with its Nacks
+            let stored = stored.lock().unwrap();
+            assert_eq!(stored.len(), 1);
+            assert_eq!(stored[0].nack_count, nack_count);
+        }
+        // Retries exhausted: dropped
+        let smsgs = client.on_client_msg(nack()).await.unwrap();
+        assert!(smsgs.is_empty());
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        let count = |prefix: &str| metrics.iter().filter(|m| m.starts_with(prefix)).count();
+        assert_eq!(count("autoconnect.notification.nack:1|c|#reason:301"), 3);
+        assert_eq!(count("autoconnect.notification.nack_exhausted:1|c"), 1);
+    }
+
+    /// Nacks from previous sessions count towards max_nack_retries
+    #[actix_rt::test]
+    async fn nack_retries_span_sessions() {
+        let stored = Arc::new(Mutex::new(vec![nacked_notif(2)]));
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = cadence::StatsdClient::from_sink("autoconnect", sink);
+        let mut client = nack_client(stored, 2, metrics).await;
+
+        let smsgs = client
+            .on_server_notif(ServerNotification::CheckStorage)
+            .await
+            .unwrap();
+        assert_eq!(smsgs.len(), 1);
+        let smsgs = client.on_client_msg(nack()).await.unwrap();
+        assert!(smsgs.is_empty());
+        assert!(rx
+            .try_iter()
+            .any(|m| m.starts_with(b"autoconnect.notification.nack_exhausted")));
+    }
 }
@@ -7,7 +7,10 @@ use autoconnect_common::{
     broadcast::Broadcast,
     protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage},
 };
-use autopush_common::{endpoint::make_endpoint, util::sec_since_epoch};
+use autopush_common::{
+    endpoint::make_endpoint,
+    util::{ms_since_epoch, sec_since_epoch},
+};
 
 use super::WebPushClient;
 use crate::error::{SMError, SMErrorKind};
@@ -34,10 +37,7 @@ impl WebPushClient {
                 .await?
                 .map_or_else(Vec::new, |smsg| vec![smsg])),
             ClientMessage::Ack { updates } => self.ack(&updates).await,
-            ClientMessage::Nack { code, .. } => {
-                self.nack(code);
-                Ok(vec![])
-            }
+            ClientMessage::Nack { code, version } => self.nack(&version, code).await,
             ClientMessage::Ping => Ok(vec![self.ping()?]),
         }
     }
@@ -72,6 +72,11 @@ impl WebPushClient {
                 error!("WebPushClient::register make_endpoint failed: {}", msg);
                 (400, "Failed to generate endpoint".to_owned())
             }
+            Err(SMErrorKind::ChannelLimitExceeded(limit)) => {
+                debug!("WebPushClient::register channel limit ({}) exceeded", limit);
+                let _ = self.app_state.metrics.incr("channel.limit_exceeded");
+                (413, "".to_owned())
+            }
             Err(e) => {
                 error!("WebPushClient::register failed: {}", e);
                 (500, "".to_owned())
@@ -89,6 +94,16 @@ impl WebPushClient {
         channel_id: &Uuid,
         key: Option<String>,
     ) -> Result<String, SMErrorKind> {
+        let channels = match self.channels.take() {
+            Some(channels) => channels,
+            None => self.app_state.db.get_channels(&self.uaid).await?,
+        };
+        let channels = self.channels.insert(channels);
+        let limit = self.app_state.settings.max_channels_per_client;
+        // Re-registering a channel doesn't take another slot
+        if !channels.contains(channel_id) && channels.len() >= limit as usize {
+            return Err(SMErrorKind::ChannelLimitExceeded(limit));
+        }
         if let Some(user) = &self.deferred_add_user {
             debug!(
                 "💬WebPushClient::register: User not yet registered: {}",
@@ -110,6 +125,9 @@ impl WebPushClient {
             .db
             .add_channel(&self.uaid, channel_id)
             .await?;
+        if let Some(channels) = &mut self.channels {
+            channels.insert(*channel_id);
+        }
         Ok(endpoint)
     }
 
@@ -134,6 +152,9 @@ impl WebPushClient {
             .await;
         let status = match result {
             Ok(_) => {
+                if let Some(channels) = &mut self.channels {
+                    channels.remove(&channel_id);
+                }
                 self.app_state
                     .metrics
                     .incr_with_tags("ua.command.unregister")
@@ -181,6 +202,8 @@ impl WebPushClient {
         trace!("✅ WebPushClient:ack");
         let _ = self.app_state.metrics.incr("ua.command.ack");
 
+        // Acked Topic messages, deleted in a single batch
+        let mut acked_topic_sort_keys = vec![];
         for notif in updates {
             // Check the list of unacked "direct" (unstored) notifications. We only want to
             // ack messages we've not yet seen and we have the right version, otherwise we could
@@ -227,16 +250,19 @@ impl WebPushClient {
                         "✅ WebPushClient:ack removing Stored, sort_key: {}",
                         &n.chidmessageid()
                     );
-                    self.app_state
-                        .db
-                        .remove_message(&self.uaid, &n.chidmessageid())
-                        .await?;
+                    acked_topic_sort_keys.push(n.chidmessageid());
                 }
                 self.ack_state.unacked_stored_notifs.remove(pos);
                 self.stats.stored_acked += 1;
                 continue;
             };
         }
+        if !acked_topic_sort_keys.is_empty() {
+            self.app_state
+                .db
+                .remove_messages(&self.uaid, &acked_topic_sort_keys)
+                .await?;
+        }
 
         if self.ack_state.unacked_notifs() {
             // Wait for the Client to Ack all notifications before further
@@ -247,9 +273,18 @@ impl WebPushClient {
         }
     }
 
-    /// Negative Acknowledgement (a Client error occurred) of one or more Push
-    /// Notifications
-    fn nack(&mut self, code: Option<i32>) {
+    /// Negative Acknowledgement (a Client error occurred) of a Push
+    /// Notification
+    ///
+    /// The Notification's left in (or, when sent directly, moved to) storage
+    /// to be redelivered, until it's been Nack'd more than
+    /// `Settings::max_nack_retries` times (counted across sessions by its
+    /// stored `nack_count`)
+    async fn nack(
+        &mut self,
+        version: &str,
+        code: Option<i32>,
+    ) -> Result<Vec<ServerMessage>, SMError> {
         trace!("WebPushClient:nack");
         // only metric codes expected from the client (or 0)
         let code = code
@@ -261,6 +296,71 @@ impl WebPushClient {
             .with_tag("code", &code.to_string())
             .send();
         self.stats.nacks += 1;
+
+        let ack_state = &mut self.ack_state;
+        let (mut notif, stored) = if let Some(pos) = ack_state
+            .unacked_direct_notifs
+            .iter()
+            .position(|n| n.version == version)
+        {
+            (ack_state.unacked_direct_notifs.remove(pos), false)
+        } else if let Some(pos) = ack_state
+            .unacked_stored_notifs
+            .iter()
+            .position(|n| n.version == version)
+        {
+            (ack_state.unacked_stored_notifs.remove(pos), true)
+        } else {
+            // Already Ack'd (or Nack'd)
+            return Ok(vec![]);
+        };
+        self.app_state
+            .metrics
+            .incr_with_tags("notification.nack")
+            .with_tag("reason", &code.to_string())
+            .send();
+
+        notif.nack_count += 1;
+        if notif.nack_count > self.app_state.settings.max_nack_retries {
+            debug!("WebPushClient:nack retries exhausted, dropping";
+                   "version" => &notif.version);
+            let _ = self.app_state.metrics.incr("notification.nack_exhausted");
+            // Stored timestamp messages are passed over by increment_storage,
+            // while Topic messages require deletion
+            if notif.sortkey_timestamp.is_none() {
+                self.app_state
+                    .db
+                    .remove_message(&self.uaid, &notif.chidmessageid())
+                    .await?;
+            }
+        } else {
+            // Topic messages are updated in place, to simply be read again.
+            // Timestamp messages are read in sort key order, so they're moved
+            // after those read so far, replacing the (stored) original.
+            let mut replaced = None;
+            if notif.topic.is_none() {
+                if stored {
+                    replaced = Some(notif.chidmessageid());
+                }
+                let after = ack_state.unacked_stored_highest.map_or(0, |ts| ts + 1);
+                notif.sortkey_timestamp = Some(ms_since_epoch().max(after));
+            }
+            self.app_state.db.save_message(&self.uaid, notif).await?;
+            if let Some(sort_key) = replaced {
+                self.app_state
+                    .db
+                    .remove_message(&self.uaid, &sort_key)
+                    .await?;
+            }
+            self.flags.check_storage = true;
+            self.flags.include_topic = true;
+        }
+
+        if self.ack_state.unacked_notifs() {
+            Ok(vec![])
+        } else {
+            self.post_process_all_acked().await
+        }
     }
 
     /// Handle a WebPush Ping
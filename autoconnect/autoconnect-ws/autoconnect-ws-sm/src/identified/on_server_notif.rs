@@ -1,6 +1,9 @@
 use cadence::{Counted, CountedExt};
 
-use autoconnect_common::protocol::{ServerMessage, ServerNotification};
+use autoconnect_common::{
+    broadcast::Broadcast,
+    protocol::{ServerMessage, ServerNotification},
+};
 use autopush_common::{
     db::CheckStorageResponse, notification::Notification, util::sec_since_epoch,
 };
@@ -14,6 +17,7 @@ impl WebPushClient {
     /// `ServerNotification::Disconnect` is emitted by the same autoconnect
     /// node recieving it when a User has logged into that same node twice to
     /// "Ghost" (disconnect) the first user's session for its second session.
+    /// `ServerNotification::Drain` too, when the node's drained.
     ///
     /// Other variants are emitted by autoendpoint
     pub async fn on_server_notif(
@@ -23,7 +27,15 @@ impl WebPushClient {
         match snotif {
             ServerNotification::Notification(notif) => Ok(vec![self.notif(notif)?]),
             ServerNotification::CheckStorage => self.check_storage().await,
-            ServerNotification::Disconnect => Err(SMErrorKind::Ghost.into()),
+            ServerNotification::BroadcastChanged => Ok(self.broadcast_changed().await),
+            ServerNotification::Disconnect => {
+                let _ = self.app_state.metrics.incr("connection.duplicate_closed");
+                Err(SMErrorKind::Ghost.into())
+            }
+            ServerNotification::Drain => {
+                let _ = self.app_state.metrics.incr("connection.drained");
+                Err(SMErrorKind::Draining.into())
+            }
         }
     }
 
@@ -37,6 +49,18 @@ impl WebPushClient {
         }
     }
 
+    /// Send this user any Broadcast changes they're subscribed to
+    async fn broadcast_changed(&mut self) -> Vec<ServerMessage> {
+        trace!("📢WebPushClient::broadcast_changed");
+        self.broadcast_delta()
+            .await
+            .map(|broadcasts| ServerMessage::Broadcast {
+                broadcasts: Broadcast::vec_into_hashmap(broadcasts),
+            })
+            .into_iter()
+            .collect()
+    }
+
     /// Send a Direct Push Notification to this user
     fn notif(&mut self, notif: Notification) -> Result<ServerMessage, SMError> {
         trace!("WebPushClient::notif Sending a direct notif");
@@ -115,23 +139,29 @@ impl WebPushClient {
 
         // Filter out TTL expired messages
         let now_sec = sec_since_epoch();
+        let tolerance = self.app_state.settings.clock_skew_tolerance_secs;
         // Topic messages require immediate deletion from the db
         let mut expired_topic_sort_keys = vec![];
         messages.retain(|msg| {
-            if !msg.expired(now_sec) {
+            if !msg.expired_with_tolerance(now_sec, tolerance) {
                 return true;
             }
+            self.app_state
+                .metrics
+                .incr_with_tags("notification.message.expired")
+                .with_tag("topic", &msg.topic.is_some().to_string())
+                .with_tag("source", "Stored")
+                .send();
             if msg.sortkey_timestamp.is_none() {
                 expired_topic_sort_keys.push(msg.chidmessageid());
             }
             false
         });
-        // TODO: A batch remove_messages would be nicer
-        for sort_key in expired_topic_sort_keys {
-            trace!("🉑 removing expired topic sort key: {sort_key}");
+        if !expired_topic_sort_keys.is_empty() {
+            trace!("🉑 removing expired topic sort keys: {expired_topic_sort_keys:?}");
             self.app_state
                 .db
-                .remove_message(&self.uaid, &sort_key)
+                .remove_messages(&self.uaid, &expired_topic_sort_keys)
                 .await?;
         }
 
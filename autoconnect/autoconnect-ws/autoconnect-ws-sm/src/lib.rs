@@ -5,7 +5,7 @@ mod error;
 mod identified;
 mod unidentified;
 
-pub use error::SMError;
+pub use error::{SMError, DUPLICATE_CONNECTION_CLOSE_CODE, RECONNECT_CLOSE_CODE};
 pub use identified::WebPushClient;
 pub use unidentified::UnidentifiedClient;
 
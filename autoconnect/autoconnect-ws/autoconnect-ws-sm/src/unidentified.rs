@@ -12,7 +12,7 @@ use autopush_common::{db::User, util::ms_since_epoch};
 
 use crate::{
     error::{SMError, SMErrorKind},
-    identified::{process_existing_user, ClientFlags, WebPushClient},
+    identified::{is_idle, process_existing_user, ClientFlags, WebPushClient},
 };
 
 /// Represents a Client waiting for (or yet to process) a Hello message
@@ -65,6 +65,9 @@ impl UnidentifiedClient {
             "👋UnidentifiedClient::on_client_msg Hello from uaid?: {:?}",
             uaid
         );
+        if self.app_state.clients.is_draining() {
+            return Err(SMErrorKind::Draining.into());
+        }
 
         // Ignore invalid uaids (treat as None) so they'll be issued a new one
         let original_uaid = uaid.as_deref().and_then(|uaid| Uuid::try_parse(uaid).ok());
@@ -72,6 +75,7 @@ impl UnidentifiedClient {
         let GetOrCreateUser {
             user,
             existing_user,
+            expired_user,
             flags,
         } = self.get_or_create_user(original_uaid).await?;
         let uaid = user.uaid;
@@ -92,6 +96,16 @@ impl UnidentifiedClient {
                 }
             })
             .send();
+        // The presented UAID couldn't be resumed, so its registrations are
+        // lost: the client must register again under the new one
+        let uaid_reset_requested = original_uaid.is_some() && !existing_user;
+        if uaid_reset_requested {
+            self.app_state
+                .metrics
+                .incr_with_tags("uaid.reset")
+                .with_tag("reason", if expired_user { "expired" } else { "unknown" })
+                .send();
+        }
 
         let (broadcast_subs, broadcasts) = self
             .broadcast_init(&Broadcast::from_hashmap(broadcasts.unwrap_or_default()))
@@ -113,6 +127,7 @@ impl UnidentifiedClient {
             status: 200,
             use_webpush: Some(true),
             broadcasts,
+            uaid_reset_requested,
         };
         let smsgs = std::iter::once(smsg).chain(check_storage_smsgs);
         Ok((wpclient, smsgs))
@@ -122,6 +137,7 @@ impl UnidentifiedClient {
     async fn get_or_create_user(&self, uaid: Option<Uuid>) -> Result<GetOrCreateUser, SMError> {
         trace!("❓UnidentifiedClient::get_or_create_user");
         let connected_at = ms_since_epoch();
+        let mut expired_user = false;
 
         if let Some(uaid) = uaid {
             // NOTE: previously a user would be dropped when
@@ -131,6 +147,7 @@ impl UnidentifiedClient {
                 if let Some(flags) = process_existing_user(&self.app_state, &user).await? {
                     user.node_id = Some(self.app_state.router_url.to_owned());
                     user.connected_at = connected_at;
+                    user.last_connected_at = Some(connected_at);
                     user.set_last_connect();
                     if !self.app_state.db.update_user(&user).await? {
                         let _ = self.app_state.metrics.incr("ua.already_connected");
@@ -139,9 +156,12 @@ impl UnidentifiedClient {
                     return Ok(GetOrCreateUser {
                         user,
                         existing_user: true,
+                        expired_user,
                         flags,
                     });
                 }
+                // Otherwise it was dropped as invalid
+                expired_user = is_idle(&self.app_state, &user);
             }
             // NOTE: when the client's specified a uaid but get_user returns
             // None (or process_existing_user dropped the user record due to it
@@ -159,11 +179,13 @@ impl UnidentifiedClient {
                 .map(str::to_owned),
             node_id: Some(self.app_state.router_url.to_owned()),
             connected_at,
+            last_connected_at: Some(connected_at),
             ..Default::default()
         };
         Ok(GetOrCreateUser {
             user,
             existing_user: false,
+            expired_user,
             flags: Default::default(),
         })
     }
@@ -192,6 +214,9 @@ impl UnidentifiedClient {
 struct GetOrCreateUser {
     user: User,
     existing_user: bool,
+    /// Whether the requested User was found but dropped as idle (see
+    /// `uaid_ttl_days`)
+    expired_user: bool,
     flags: ClientFlags,
 }
 
@@ -199,11 +224,17 @@ struct GetOrCreateUser {
 mod tests {
     use std::sync::Arc;
 
+    use cadence::{SpyMetricSink, StatsdClient};
+
     use autoconnect_common::{
-        protocol::ClientMessage,
-        test_support::{hello_again_db, hello_db, DUMMY_CHID, DUMMY_UAID, UA},
+        protocol::{ClientMessage, ServerMessage},
+        test_support::{hello_again_db, hello_db, CURRENT_MONTH, DUMMY_CHID, DUMMY_UAID, UA},
+    };
+    use autoconnect_settings::{AppState, Settings};
+    use autopush_common::{
+        db::{mock::MockDbClient, User},
+        util::ms_since_epoch,
     };
-    use autoconnect_settings::AppState;
 
     use crate::error::SMErrorKind;
 
@@ -295,6 +326,175 @@ mod tests {
         client.on_client_msg(msg).await.expect("Hello failed");
     }
 
+    #[tokio::test]
+    async fn hello_updates_last_connected_at() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(|_| {
+            Ok(Some(User {
+                uaid: DUMMY_UAID,
+                current_month: Some(CURRENT_MONTH.to_owned()),
+                last_connected_at: Some(1),
+                ..Default::default()
+            }))
+        });
+        db.expect_rotating_message_table()
+            .times(1)
+            .return_const(Some(CURRENT_MONTH));
+        db.expect_update_user()
+            .withf(|user| {
+                user.last_connected_at > Some(1)
+                    && user.last_connected_at == Some(user.connected_at)
+            })
+            .times(1)
+            .return_once(|_| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| Ok(Default::default()));
+        let client = uclient(AppState {
+            db: db.into_boxed_arc(),
+            ..Default::default()
+        });
+        let msg = ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.to_string()),
+            channel_ids: None,
+            use_webpush: Some(true),
+            broadcasts: None,
+        };
+        client.on_client_msg(msg).await.expect("Hello failed");
+    }
+
     #[tokio::test]
     async fn hello_bad_user() {}
+
+    /// Send a Hello resuming `DUMMY_UAID`, returning the response's uaid and
+    /// uaid_reset_requested
+    async fn hello_resume(client: UnidentifiedClient) -> (String, bool) {
+        let msg = ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.to_string()),
+            channel_ids: None,
+            use_webpush: Some(true),
+            broadcasts: None,
+        };
+        let (_, smsgs) = client.on_client_msg(msg).await.expect("Hello failed");
+        match smsgs.into_iter().next() {
+            Some(ServerMessage::Hello {
+                uaid,
+                uaid_reset_requested,
+                ..
+            }) => (uaid, uaid_reset_requested),
+            smsg => panic!("Expected a Hello, got {:?}", smsg),
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_resumes_uaid() {
+        let client = uclient(AppState {
+            db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+            settings: Settings {
+                uaid_ttl_days: 30,
+                ..Settings::test_settings()
+            },
+            ..Default::default()
+        });
+        let (uaid, reset) = hello_resume(client).await;
+        assert_eq!(uaid, DUMMY_UAID.as_simple().to_string());
+        assert!(!reset);
+    }
+
+    #[tokio::test]
+    async fn hello_expired_uaid() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(|_| {
+            Ok(Some(User {
+                uaid: DUMMY_UAID,
+                current_month: Some(CURRENT_MONTH.to_owned()),
+                connected_at: ms_since_epoch() - 31 * 24 * 60 * 60 * 1000,
+                ..Default::default()
+            }))
+        });
+        db.expect_rotating_message_table()
+            .times(2)
+            .return_const(Some(CURRENT_MONTH));
+        db.expect_remove_user()
+            .withf(|uaid| uaid == &DUMMY_UAID)
+            .times(1)
+            .return_once(|_| Ok(()));
+        let (rx, sink) = SpyMetricSink::new();
+        let client = uclient(AppState {
+            db: db.into_boxed_arc(),
+            metrics: Arc::new(StatsdClient::builder("", sink).build()),
+            settings: Settings {
+                uaid_ttl_days: 30,
+                ..Settings::test_settings()
+            },
+            ..Default::default()
+        });
+        let (uaid, reset) = hello_resume(client).await;
+        assert_ne!(uaid, DUMMY_UAID.as_simple().to_string());
+        assert!(reset);
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics
+            .iter()
+            .any(|m| m.contains("uaid.reset:1|c|#reason:expired")));
+    }
+
+    /// A user dropped as invalid (rather than idle) isn't reported expired
+    #[tokio::test]
+    async fn hello_invalid_user() {
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(|_| {
+            Ok(Some(User {
+                uaid: DUMMY_UAID,
+                current_month: Some("message_2018_05".to_owned()),
+                connected_at: ms_since_epoch(),
+                ..Default::default()
+            }))
+        });
+        db.expect_rotating_message_table()
+            .times(2)
+            .return_const(Some(CURRENT_MONTH));
+        db.expect_remove_user()
+            .withf(|uaid| uaid == &DUMMY_UAID)
+            .times(1)
+            .return_once(|_| Ok(()));
+        let (rx, sink) = SpyMetricSink::new();
+        let client = uclient(AppState {
+            db: db.into_boxed_arc(),
+            metrics: Arc::new(StatsdClient::builder("", sink).build()),
+            settings: Settings {
+                uaid_ttl_days: 30,
+                ..Settings::test_settings()
+            },
+            ..Default::default()
+        });
+        let (uaid, reset) = hello_resume(client).await;
+        assert_ne!(uaid, DUMMY_UAID.as_simple().to_string());
+        assert!(reset);
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics
+            .iter()
+            .any(|m| m.contains("uaid.reset:1|c|#reason:unknown")));
+    }
+
+    #[tokio::test]
+    async fn hello_unknown_uaid() {
+        let mut db = hello_db();
+        db.expect_get_user().times(1).return_once(|_| Ok(None));
+        let client = uclient(AppState {
+            db: db.into_boxed_arc(),
+            ..Default::default()
+        });
+        let (uaid, reset) = hello_resume(client).await;
+        assert_ne!(uaid, DUMMY_UAID.as_simple().to_string());
+        assert!(reset);
+    }
 }
@@ -1,10 +1,17 @@
 use std::sync::Arc;
 
-use actix_ws::{CloseReason, Message};
+use actix_ws::{CloseCode, CloseReason, Message};
+use cadence::CountedExt;
 use futures::{channel::mpsc, Stream, StreamExt};
-use tokio::{select, time::timeout};
+use tokio::{
+    select,
+    time::{sleep, timeout, Instant},
+};
 
-use autoconnect_common::protocol::{ServerMessage, ServerNotification};
+use autoconnect_common::{
+    connections::ConnectionGuard,
+    protocol::{ServerMessage, ServerNotification},
+};
 use autoconnect_settings::AppState;
 use autoconnect_ws_sm::{UnidentifiedClient, WebPushClient};
 
@@ -22,8 +29,11 @@ pub fn spawn_webpush_ws(
     msg_stream: actix_ws::MessageStream,
     app_state: Arc<AppState>,
     ua: String,
+    connection: ConnectionGuard,
 ) {
     actix_rt::spawn(async move {
+        // Counted as connected until the session's closed
+        let _connection = connection;
         let client = UnidentifiedClient::new(ua, app_state);
         let mut session = SessionImpl::new(session);
         let close_reason = webpush_ws(client, &mut session, msg_stream)
@@ -133,6 +143,10 @@ async fn unidentified_ws(
 /// out the Client for not responding to a previous Ping in time. The Ping
 /// encourages the connection to keep alive (it's more likely to be dropped if
 /// completely idle) and aids in detecting Clients that are no longer connected
+///
+/// - idle: A timer closing the connection when neither the Client sent a
+/// WebPush message nor a `ServerNotification` arrived within `idle_timeout`
+/// (when enabled). WebSocket Pings and Pongs don't reset it.
 async fn identified_ws(
     client: &mut WebPushClient,
     smsgs: impl IntoIterator<Item = ServerMessage>,
@@ -150,6 +164,9 @@ async fn identified_ws(
     }
 
     let mut ping_manager = PingManager::new(client.app_settings()).await;
+    let idle_timeout = client.app_settings().idle_timeout;
+    let idle = sleep(idle_timeout);
+    tokio::pin!(idle);
     let close_reason = loop {
         select! {
             maybe_result = msg_stream.next() => {
@@ -174,6 +191,7 @@ async fn identified_ws(
                     },
                     _ => return Err(WSErrorKind::UnsupportedMessage("Expected Text, etc.".to_owned()).into())
                 };
+                idle.as_mut().reset(Instant::now() + idle_timeout);
                 for smsg in client.on_client_msg(client_msg).await? {
                     trace!("identified_ws: msg_stream, ServerMessage -> session {:#?}", smsg);
                     session.text(smsg).await?;
@@ -185,6 +203,7 @@ async fn identified_ws(
                     trace!("identified_ws: snotif_stream EOF");
                     return Err(WSErrorKind::RegistryDisconnected.into());
                 };
+                idle.as_mut().reset(Instant::now() + idle_timeout);
                 for smsg in client.on_server_notif(snotif).await? {
                     trace!("identified_ws: snotif_stream, ServerMessage -> session {:#?}", smsg);
                     session.text(smsg).await?;
@@ -193,10 +212,22 @@ async fn identified_ws(
 
             result = ping_manager.tick() => {
                 trace!("identified_ws: ping_manager tick is_ok: {}", result.is_ok());
-                // Propagate PongTimeout
-                result?;
+                if let Err(e) = result {
+                    // Propagate PongTimeout
+                    let _ = client.app_metrics().incr("ws.pong_timeout");
+                    return Err(e);
+                }
                 ping_manager.ws_ping_or_broadcast(client, session).await?;
             }
+
+            () = &mut idle, if !idle_timeout.is_zero() => {
+                trace!("identified_ws: idle timeout");
+                let _ = client.app_metrics().incr("ws.idle_closed");
+                break Some(CloseReason {
+                    code: CloseCode::Normal,
+                    description: Some("IdleTimeout".to_owned()),
+                });
+            }
         }
     };
 
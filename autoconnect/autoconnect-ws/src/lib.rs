@@ -5,6 +5,7 @@ use actix_web::{
     http::header::{HeaderValue, USER_AGENT},
     web, Error, HttpRequest, HttpResponse,
 };
+use cadence::CountedExt;
 
 use autoconnect_settings::AppState;
 
@@ -22,6 +23,16 @@ pub async fn ws_handler(
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     debug!("🔌 Got connection");
+    if app_state.clients.is_draining() {
+        debug!("🔌 Refusing connection: draining");
+        let _ = app_state.metrics.incr("ws.connection_rejected");
+        return Ok(HttpResponse::ServiceUnavailable().body("Draining"));
+    }
+    let Some(connection) = app_state.connections.try_connect() else {
+        debug!("🔌 Refusing connection: max_connections reached");
+        let _ = app_state.metrics.incr("ws.connection_rejected");
+        return Ok(HttpResponse::ServiceUnavailable().body("Too many connections"));
+    };
     let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
     let ua = req
         .headers()
@@ -30,6 +41,6 @@ pub async fn ws_handler(
         .to_str()
         .unwrap_or_default()
         .to_owned();
-    handler::spawn_webpush_ws(session, msg_stream, app_state.into_inner(), ua);
+    handler::spawn_webpush_ws(session, msg_stream, app_state.into_inner(), ua, connection);
     Ok(response)
 }
@@ -1,3 +1,4 @@
+use cadence::CountedExt;
 use tokio::time::{interval, Interval};
 
 use autoconnect_common::{broadcast::Broadcast, protocol::ServerMessage};
@@ -80,6 +81,7 @@ impl PingManager {
         } else {
             trace!("🏓PingManager::ws_ping_or_broadcast ping");
             session.ping(&[]).await?;
+            let _ = client.app_metrics().incr("ws.ping_sent");
             self.set_waiting(Waiting::ForPong, client.app_settings())
                 .await;
         }
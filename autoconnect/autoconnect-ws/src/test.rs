@@ -1,14 +1,20 @@
 use std::{sync::Arc, time::Duration};
 
+use actix_ws::CloseCode;
 use async_stream::stream;
-use futures::pin_mut;
+use cadence::{SpyMetricSink, StatsdClient};
+use futures::{pin_mut, StreamExt};
+use uuid::Uuid;
 
 use autoconnect_common::{
-    protocol::ServerMessage,
-    test_support::{hello_db, HELLO, UA},
+    protocol::{ServerMessage, ServerNotification},
+    test_support::{hello_again_db, hello_db, DUMMY_UAID, HELLO, HELLO_AGAIN, UA},
 };
 use autoconnect_settings::{AppState, Settings};
-use autoconnect_ws_sm::UnidentifiedClient;
+use autoconnect_ws_sm::{
+    UnidentifiedClient, DUPLICATE_CONNECTION_CLOSE_CODE, RECONNECT_CLOSE_CODE,
+};
+use autopush_common::notification::Notification;
 
 use crate::{error::WSErrorKind, handler::webpush_ws, session::MockSession};
 
@@ -94,8 +100,10 @@ async fn auto_ping_timeout() {
         auto_ping_timeout: Duration::from_secs_f32(0.15),
         ..Settings::test_settings()
     };
+    let (rx, sink) = SpyMetricSink::new();
     let client = uclient(AppState {
         db: hello_db().into_boxed_arc(),
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
         ..AppState::from_settings(settings).unwrap()
     });
     let mut session = MockSession::new();
@@ -109,6 +117,13 @@ async fn auto_ping_timeout() {
     pin_mut!(s);
     let err = webpush_ws(client, &mut session, s).await.unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::PongTimeout));
+
+    let metrics: Vec<String> = rx
+        .try_iter()
+        .map(|metric| String::from_utf8(metric).unwrap())
+        .collect();
+    assert!(metrics.iter().any(|m| m.contains("ws.ping_sent:1|c")));
+    assert!(metrics.iter().any(|m| m.contains("ws.pong_timeout:1|c")));
 }
 
 #[actix_web::test]
@@ -136,3 +151,163 @@ async fn auto_ping_timeout_after_pong() {
     let err = webpush_ws(client, &mut session, s).await.unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::PongTimeout));
 }
+
+#[actix_web::test]
+async fn idle_timeout() {
+    let settings = Settings {
+        idle_timeout: Duration::from_secs_f32(0.15),
+        ..Settings::test_settings()
+    };
+    let (rx, sink) = SpyMetricSink::new();
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
+        ..AppState::from_settings(settings).unwrap()
+    });
+    let mut session = MockSession::new();
+    session.expect_text().times(1).return_once(|_| Ok(()));
+    session.expect_ping().never();
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO.into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.3)).await;
+    };
+    pin_mut!(s);
+    let close_reason = webpush_ws(client, &mut session, s)
+        .await
+        .expect("Handler failed")
+        .expect("No CloseReason");
+    assert_eq!(close_reason.code, CloseCode::Normal);
+    assert_eq!(close_reason.description.as_deref(), Some("IdleTimeout"));
+    assert!(rx.try_iter().any(|metric| String::from_utf8(metric)
+        .unwrap()
+        .contains("ws.idle_closed:1|c")));
+}
+
+#[actix_web::test]
+async fn idle_timeout_reset_by_activity() {
+    let settings = Settings {
+        idle_timeout: Duration::from_secs_f32(0.15),
+        ..Settings::test_settings()
+    };
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Ping))
+        .return_once(|_| Ok(()));
+
+    // Ends (without being idle for 0.15s) after 0.2s
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO.into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        yield Ok(actix_ws::Message::Text("{}".into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+    };
+    pin_mut!(s);
+    let close_reason = webpush_ws(client, &mut session, s)
+        .await
+        .expect("Handler failed");
+    assert!(close_reason.is_none());
+}
+
+/// The client reconnecting while still connected: notifications go to the
+/// new connection and the old one's closed after the grace window
+#[actix_web::test]
+async fn duplicate_connection() {
+    let settings = Settings {
+        duplicate_connection_grace_ms: 150,
+        ..Settings::test_settings()
+    };
+    let (rx, sink) = SpyMetricSink::new();
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
+        ..AppState::from_settings(settings).unwrap()
+    };
+    let clients = app_state.clients.clone();
+    let client = uclient(app_state);
+    let mut session = MockSession::new();
+    // Only the Hello: the notification's not sent to the old connection
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO_AGAIN.into()));
+        let mut new_conn = clients.connect(DUMMY_UAID, Uuid::new_v4()).await;
+        clients
+            .notify(DUMMY_UAID, Notification::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            new_conn.next().await,
+            Some(ServerNotification::Notification(_))
+        ));
+        tokio::time::sleep(Duration::from_secs_f32(0.3)).await;
+    };
+    pin_mut!(s);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert_eq!(
+        err.close_code(),
+        CloseCode::Other(DUPLICATE_CONNECTION_CLOSE_CODE)
+    );
+    assert!(rx.try_iter().any(|metric| String::from_utf8(metric)
+        .unwrap()
+        .contains("connection.duplicate_closed:1|c")));
+}
+
+/// Draining closes the connected client with the reconnect close code, after
+/// which Hellos are refused
+#[actix_web::test]
+async fn drain() {
+    let (rx, sink) = SpyMetricSink::new();
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        metrics: Arc::new(StatsdClient::builder("", sink).build()),
+        ..AppState::from_settings(Settings::test_settings()).unwrap()
+    };
+    let clients = app_state.clients.clone();
+    let client = uclient(app_state);
+    let mut session = MockSession::new();
+    session
+        .expect_text()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+
+    let drainer = clients.clone();
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO_AGAIN.into()));
+        assert_eq!(drainer.drain().await, 1);
+        tokio::time::sleep(Duration::from_secs_f32(0.2)).await;
+    };
+    pin_mut!(s);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert_eq!(err.close_code(), CloseCode::Other(RECONNECT_CLOSE_CODE));
+    assert!(rx.try_iter().any(|metric| String::from_utf8(metric)
+        .unwrap()
+        .contains("connection.drained:1|c")));
+
+    // No db calls: the Hello's refused outright
+    let client = uclient(AppState {
+        clients,
+        ..Default::default()
+    });
+    let mut session = MockSession::new();
+    session.expect_text().never();
+    let s = futures::stream::iter(vec![Ok(actix_ws::Message::Text(HELLO.into()))]);
+    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    assert_eq!(err.close_code(), CloseCode::Other(RECONNECT_CLOSE_CODE));
+}
@@ -54,6 +54,7 @@ async fn main() -> Result<()> {
         !settings.human_logs,
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION"),
+        None,
     )
     .expect("Logging failed to initialize");
     debug!("Starting up autoconnect...");
@@ -88,6 +89,7 @@ async fn main() -> Result<()> {
     );
 
     let router_app_state = app_state.clone();
+    let db = app_state.db.clone();
     let mut builder = Server::build()
         .bind("autoconnect", ("0.0.0.0", port), move || {
             let app = build_app!(app_state, config);
@@ -113,5 +115,6 @@ async fn main() -> Result<()> {
     builder.run().await?;
 
     info!("Shutting down autoconnect");
+    db.shutdown();
     Ok(())
 }
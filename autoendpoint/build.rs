@@ -0,0 +1,30 @@
+//! Capture build information for the `/__version__` route
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+        })
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=AUTOEND_GIT_COMMIT={commit}");
+    println!(
+        "cargo:rustc-env=AUTOEND_BUILD_TIMESTAMP={}",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    );
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    // Rebuild when checking out another commit (or committing, which moves
+    // the branch's ref)
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-changed=../.git/packed-refs");
+}
@@ -0,0 +1,141 @@
+//! Mirroring accepted notifications (including those sent via `/bulk`, but
+//! not dry runs) to a debugging webhook. Only compiled with the `debug`
+//! feature and only enabled when `debug_mirror_url` is set.
+//!
+//! Only a summary of the notification's metadata is mirrored: never its
+//! (encrypted) body, its crypto headers or its VAPID credentials.
+use actix_rt::task::JoinHandle;
+use serde::Serialize;
+
+use crate::extractors::{notification::Notification, routers::RouterType};
+use crate::receipts::token_hash;
+
+/// The metadata of an accepted notification
+#[derive(Debug, Serialize)]
+pub struct NotificationSummary {
+    /// The base64url SHA-256 hash of the endpoint's token: identifying the
+    /// endpoint without allowing the webhook to send to it
+    token_hash: String,
+    bridge: String,
+    /// The size of the (encrypted) body in bytes
+    size: usize,
+    topic: Option<String>,
+    ttl: i64,
+    /// UNIX timestamp in seconds
+    timestamp: u64,
+}
+
+impl NotificationSummary {
+    pub fn new(token: &str, notification: &Notification, router_type: RouterType) -> Self {
+        Self {
+            token_hash: token_hash(token),
+            bridge: router_type.to_string(),
+            // The body is stored unpadded base64url encoded
+            size: notification
+                .data
+                .as_ref()
+                .map_or(0, |data| data.len() * 3 / 4),
+            topic: notification.headers.topic.clone(),
+            ttl: notification.headers.ttl,
+            timestamp: notification.timestamp,
+        }
+    }
+}
+
+/// POST the `summary` to the webhook at `url`, in the background: this is
+/// best effort and never delays (or fails) the delivery
+pub fn mirror(http: &reqwest::Client, url: &str, summary: NotificationSummary) -> JoinHandle<()> {
+    let request = http.post(url).json(&summary);
+    actix_rt::spawn(async move {
+        let result = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = result {
+            warn!("Could not mirror the notification: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use autopush_common::db::User;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{mirror, NotificationSummary};
+    use crate::extractors::{
+        notification::Notification, notification_headers::NotificationHeaders, routers::RouterType,
+        subscription::Subscription,
+    };
+
+    fn notification() -> Notification {
+        Notification {
+            message_id: "message-id".to_owned(),
+            subscription: Subscription {
+                user: User::default(),
+                channel_id: Uuid::new_v4(),
+                vapid: None,
+            },
+            headers: NotificationHeaders {
+                ttl: 60,
+                topic: Some("topic".to_owned()),
+                urgency: "normal".to_owned(),
+                encoding: Some("aes128gcm".to_owned()),
+                encryption: None,
+                encryption_key: None,
+                crypto_key: Some("dh=secret".to_owned()),
+            },
+            timestamp: 1234,
+            sort_key_timestamp: 1234000,
+            // 6 bytes, base64url encoded
+            data: Some("Y2lwaGVy".to_owned()),
+            dry_run: false,
+            respond_async: false,
+            idempotency_key: None,
+        }
+    }
+
+    /// The webhook receives the notification's metadata, never its body
+    #[actix_rt::test]
+    async fn mirrors_summary() {
+        let summary = NotificationSummary::new("token", &notification(), RouterType::FCM);
+        let expected = json!({
+            // base64url(sha256("token"))
+            "token_hash": "PEaenWxYddN6Q_NT1PiOYfz4EsZu7jRXRlpAsNpBU-A",
+            "bridge": "fcm",
+            "size": 6,
+            "topic": "topic",
+            "ttl": 60,
+            "timestamp": 1234,
+        });
+        assert_eq!(serde_json::to_value(&summary).unwrap(), expected);
+
+        let mock = mockito::mock("POST", "/debug-mirror")
+            .match_header("content-type", "application/json")
+            .match_body(mockito::Matcher::Json(expected))
+            .create();
+        let url = mockito::server_url() + "/debug-mirror";
+        mirror(&reqwest::Client::new(), &url, summary)
+            .await
+            .unwrap();
+        mock.assert();
+    }
+
+    /// A failing (or unreachable) webhook is only logged
+    #[actix_rt::test]
+    async fn mirror_failure_is_contained() {
+        let mock = mockito::mock("POST", "/failing-debug-mirror")
+            .with_status(500)
+            .create();
+        let http = reqwest::Client::new();
+        for url in [
+            mockito::server_url() + "/failing-debug-mirror",
+            "http://127.0.0.1:1/debug-mirror".to_owned(),
+        ] {
+            let summary = NotificationSummary::new("token", &notification(), RouterType::FCM);
+            assert!(mirror(&http, &url, summary).await.is_ok());
+        }
+        mock.assert();
+    }
+}
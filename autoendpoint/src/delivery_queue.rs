@@ -0,0 +1,227 @@
+//! The queue of deliveries of notifications accepted with `Prefer:
+//! respond-async`
+//!
+//! Each server thread runs at most `async_delivery_workers` deliveries at a
+//! time, queueing the others. So that time sensitive notifications stay fast
+//! while catching up on a backlog, the queued deliveries run by urgency
+//! (high, normal, low then very-low) and in the order they were accepted
+//! within an urgency. At most `max_queued` deliveries are queued per
+//! thread. The depth of each urgency's queues, across the threads, is
+//! reported as the `notification.async.queued` gauge.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use cadence::{Gauged, StatsdClient};
+use futures::future::LocalBoxFuture;
+use tokio::sync::Notify;
+
+use crate::extractors::notification_headers::DEFAULT_URGENCY;
+
+/// The urgencies, most urgent first
+const URGENCIES: [&str; 4] = ["high", "normal", "low", "very-low"];
+
+type Delivery = LocalBoxFuture<'static, ()>;
+
+/// The depth of each urgency's queues, shared by the server threads' queues
+pub struct QueueDepths {
+    /// Indexed like [URGENCIES]
+    depths: [AtomicUsize; URGENCIES.len()],
+    metrics: Arc<StatsdClient>,
+}
+
+impl QueueDepths {
+    pub fn new(metrics: Arc<StatsdClient>) -> Arc<Self> {
+        Arc::new(Self {
+            depths: Default::default(),
+            metrics,
+        })
+    }
+
+    fn increment(&self, priority: usize) {
+        let depth = self.depths[priority].fetch_add(1, Ordering::Relaxed) + 1;
+        self.report(priority, depth);
+    }
+
+    fn decrement(&self, priority: usize) {
+        let depth = self.depths[priority].fetch_sub(1, Ordering::Relaxed) - 1;
+        self.report(priority, depth);
+    }
+
+    fn report(&self, priority: usize, depth: usize) {
+        self.metrics
+            .gauge_with_tags("notification.async.queued", depth as u64)
+            .with_tag("urgency", URGENCIES[priority])
+            .send();
+    }
+}
+
+/// The deliveries queued on a server thread
+pub struct DeliveryQueue {
+    workers: usize,
+    max_queued: usize,
+    /// The workers are started by the first delivery
+    started: Cell<bool>,
+    /// Indexed like [URGENCIES]
+    queues: RefCell<[VecDeque<Delivery>; URGENCIES.len()]>,
+    queued: Notify,
+    depths: Arc<QueueDepths>,
+}
+
+impl DeliveryQueue {
+    pub fn new(workers: usize, max_queued: usize, depths: Arc<QueueDepths>) -> Rc<Self> {
+        Rc::new(Self {
+            workers,
+            max_queued,
+            started: Cell::new(false),
+            queues: RefCell::default(),
+            queued: Notify::new(),
+            depths,
+        })
+    }
+
+    /// Whether another delivery can be queued
+    pub fn has_room(&self) -> bool {
+        let queued: usize = self.queues.borrow().iter().map(VecDeque::len).sum();
+        queued < self.max_queued
+    }
+
+    /// Queue the `delivery` of a notification of the given `urgency`. The
+    /// caller checks [DeliveryQueue::has_room] first.
+    pub fn push(self: &Rc<Self>, urgency: &str, delivery: Delivery) {
+        if !self.started.replace(true) {
+            for _ in 0..self.workers {
+                actix_rt::spawn(self.clone().work());
+            }
+        }
+        let priority = Self::priority(urgency);
+        self.queues.borrow_mut()[priority].push_back(delivery);
+        self.depths.increment(priority);
+        self.queued.notify_one();
+    }
+
+    /// Take the most urgent delivery, the longest queued of its urgency
+    fn pop(&self) -> Option<Delivery> {
+        let (priority, delivery) = {
+            let mut queues = self.queues.borrow_mut();
+            let priority = queues.iter().position(|queue| !queue.is_empty())?;
+            (priority, queues[priority].pop_front()?)
+        };
+        self.depths.decrement(priority);
+        Some(delivery)
+    }
+
+    /// Run the queued deliveries, one at a time
+    async fn work(self: Rc<Self>) {
+        loop {
+            match self.pop() {
+                Some(delivery) => delivery.await,
+                None => self.queued.notified().await,
+            }
+        }
+    }
+
+    /// The index in [URGENCIES] of the `urgency`, unknown ones being treated
+    /// as the default
+    fn priority(urgency: &str) -> usize {
+        URGENCIES
+            .iter()
+            .position(|known| *known == urgency)
+            .or_else(|| URGENCIES.iter().position(|known| *known == DEFAULT_URGENCY))
+            .expect("DEFAULT_URGENCY is not a known urgency")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+    use futures::FutureExt;
+
+    use super::{DeliveryQueue, QueueDepths};
+
+    /// Queued deliveries run by urgency, then in the order they were queued
+    #[actix_rt::test]
+    async fn runs_by_urgency() {
+        let (rx, sink) = SpyMetricSink::new();
+        let depths = QueueDepths::new(Arc::new(StatsdClient::from_sink("autoendpoint", sink)));
+        let queue = DeliveryQueue::new(1, 100, depths);
+        // Keeps the only worker busy while the others are queued
+        let (tx, paused) = futures::channel::oneshot::channel::<()>();
+        queue.push(
+            "normal",
+            async move {
+                paused.await.ok();
+            }
+            .boxed_local(),
+        );
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        for (urgency, id) in [
+            ("low", 1),
+            ("normal", 2),
+            ("very-low", 3),
+            ("high", 4),
+            ("normal", 5),
+            ("bogus", 6),
+            ("high", 7),
+        ] {
+            let order = order.clone();
+            queue.push(
+                urgency,
+                async move { order.borrow_mut().push(id) }.boxed_local(),
+            );
+        }
+        assert!(order.borrow().is_empty());
+
+        tx.send(()).unwrap();
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(*order.borrow(), [4, 7, 2, 5, 6, 1, 3]);
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics
+            .contains(&"autoendpoint.notification.async.queued:2|g|#urgency:high".to_owned()));
+        assert!(metrics
+            .contains(&"autoendpoint.notification.async.queued:0|g|#urgency:very-low".to_owned()));
+    }
+
+    /// The depths are those of all the threads' queues, each of which is
+    /// capped
+    #[actix_rt::test]
+    async fn shared_depths() {
+        let (rx, sink) = SpyMetricSink::new();
+        let depths = QueueDepths::new(Arc::new(StatsdClient::from_sink("autoendpoint", sink)));
+        let queues = [
+            DeliveryQueue::new(0, 2, depths.clone()),
+            DeliveryQueue::new(0, 2, depths),
+        ];
+        for queue in &queues {
+            assert!(queue.has_room());
+            queue.push("normal", async {}.boxed_local());
+        }
+        queues[0].push("normal", async {}.boxed_local());
+        assert!(!queues[0].has_room());
+        assert!(queues[1].has_room());
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(
+            metrics.last().unwrap(),
+            "autoendpoint.notification.async.queued:3|g|#urgency:normal"
+        );
+    }
+}
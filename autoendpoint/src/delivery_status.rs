@@ -0,0 +1,150 @@
+//! Status of notifications accepted with `Prefer: respond-async`
+//!
+//! Such notifications are acknowledged with a 202 before being routed, their
+//! outcome is recorded here for polling via `GET /v1/delivery/{id}`. Statuses
+//! are only kept in memory, for `ttl` after the notification was accepted
+//! and at most `max_size` of them (the oldest being dropped first).
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+/// The outcome of routing an asynchronously accepted notification
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Still being routed
+    Pending,
+    /// Routed, `code` being the status code of the synchronous response
+    Delivered { code: u16 },
+    /// Routing failed with the given status code (and errno)
+    Failed { code: u16, errno: Option<usize> },
+}
+
+/// In-memory store of `DeliveryStatus`es
+pub struct DeliveryStatuses {
+    ttl: Duration,
+    max_size: usize,
+    statuses: Mutex<Statuses>,
+}
+
+#[derive(Default)]
+struct Statuses {
+    by_id: HashMap<Uuid, (Instant, DeliveryStatus)>,
+    /// The ids in the order they were accepted, to drop the oldest first
+    accepted: VecDeque<(Instant, Uuid)>,
+}
+
+impl DeliveryStatuses {
+    pub fn new(ttl: Duration, max_size: usize) -> Self {
+        Self {
+            ttl,
+            max_size,
+            statuses: Mutex::default(),
+        }
+    }
+
+    /// Track a newly accepted notification, returning its status id
+    pub fn insert_pending(&self) -> Uuid {
+        self.insert_pending_at(Instant::now())
+    }
+
+    fn insert_pending_at(&self, now: Instant) -> Uuid {
+        let id = Uuid::new_v4();
+        let mut statuses = self
+            .statuses
+            .lock()
+            .expect("DeliveryStatuses lock poisoned");
+        // Expired statuses (and the oldest, when full) are dropped as new
+        // ones arrive
+        while let Some(&(accepted, oldest)) = statuses.accepted.front() {
+            if statuses.accepted.len() < self.max_size
+                && now.saturating_duration_since(accepted) < self.ttl
+            {
+                break;
+            }
+            statuses.accepted.pop_front();
+            statuses.by_id.remove(&oldest);
+        }
+        statuses.by_id.insert(id, (now, DeliveryStatus::Pending));
+        statuses.accepted.push_back((now, id));
+        id
+    }
+
+    /// Record the outcome of a notification (unless its status has expired)
+    pub fn complete(&self, id: &Uuid, status: DeliveryStatus) {
+        let mut statuses = self
+            .statuses
+            .lock()
+            .expect("DeliveryStatuses lock poisoned");
+        if let Some((_, current)) = statuses.by_id.get_mut(id) {
+            *current = status;
+        }
+    }
+
+    /// Get a notification's status, `None` when unknown or expired
+    pub fn get(&self, id: &Uuid) -> Option<DeliveryStatus> {
+        self.get_at(id, Instant::now())
+    }
+
+    fn get_at(&self, id: &Uuid, now: Instant) -> Option<DeliveryStatus> {
+        let statuses = self
+            .statuses
+            .lock()
+            .expect("DeliveryStatuses lock poisoned");
+        statuses
+            .by_id
+            .get(id)
+            .filter(|(accepted, _)| now.saturating_duration_since(*accepted) < self.ttl)
+            .map(|(_, status)| status.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use super::{DeliveryStatus, DeliveryStatuses};
+
+    #[test]
+    fn tracks_until_expired() {
+        let statuses = DeliveryStatuses::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+        let id = statuses.insert_pending_at(now);
+        assert_eq!(statuses.get_at(&id, now), Some(DeliveryStatus::Pending));
+
+        statuses.complete(&id, DeliveryStatus::Delivered { code: 201 });
+        assert_eq!(
+            statuses.get_at(&id, now),
+            Some(DeliveryStatus::Delivered { code: 201 })
+        );
+        assert_eq!(
+            serde_json::to_value(statuses.get_at(&id, now).unwrap()).unwrap(),
+            serde_json::json!({"status": "delivered", "code": 201})
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(statuses.get_at(&id, later), None);
+        // Inserting drops the expired status
+        statuses.insert_pending_at(later);
+        assert_eq!(statuses.statuses.lock().unwrap().by_id.len(), 1);
+        assert_eq!(statuses.get(&Uuid::new_v4()), None);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let statuses = DeliveryStatuses::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        let ids: Vec<Uuid> = (0..3).map(|_| statuses.insert_pending_at(now)).collect();
+        assert_eq!(statuses.get_at(&ids[0], now), None);
+        assert_eq!(statuses.get_at(&ids[1], now), Some(DeliveryStatus::Pending));
+        assert_eq!(statuses.get_at(&ids[2], now), Some(DeliveryStatus::Pending));
+        let inner = statuses.statuses.lock().unwrap();
+        assert_eq!(inner.by_id.len(), 2);
+        assert_eq!(inner.accepted.len(), 2);
+    }
+}
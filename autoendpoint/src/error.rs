@@ -1,6 +1,7 @@
 //! Error types and transformations
 // TODO: Collpase these into `autopush_common::error`
 
+use crate::extractors::routers::RouterType;
 use crate::headers::vapid::VapidError;
 use crate::routers::RouterError;
 use actix_web::{
@@ -9,7 +10,7 @@ use actix_web::{
     http::header::{CacheControl, CacheDirective},
     http::StatusCode,
     middleware::ErrorHandlerResponse,
-    HttpResponse, Result,
+    HttpRequest, HttpResponse, Result,
 };
 // Sentry uses the backtrace crate, not std::backtrace.
 use backtrace::Backtrace;
@@ -18,6 +19,7 @@ use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::time::Duration;
 use thiserror::Error;
 use validator::{ValidationErrors, ValidationErrorsKind};
 
@@ -29,6 +31,8 @@ pub type ApiResult<T> = Result<T, ApiError>;
 /// A link for more info on the returned error
 const ERROR_URL: &str = "http://autopush.readthedocs.io/en/latest/http.html#error-codes";
 const RETRY_AFTER_PERIOD: &str = "120"; // retry after 2 minutes;
+/// The error number of errors without a more specific one
+const UNKNOWN_ERRNO: usize = 999;
 
 /// The main error type.
 #[derive(Debug)]
@@ -38,11 +42,22 @@ pub struct ApiError {
 }
 
 impl ApiError {
-    /// Render a 404 response
-    // wrapper during the move. this should switch to autopush-common's impl.
+    /// Render a 404 response for requests matching no route, in the same
+    /// JSON format as our errors. Responses of our errors are kept as is.
     pub fn render_404<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-        //TODO: remove unwrap here.
-        Ok(autopush_common::errors::render_404(res).unwrap())
+        if res.response().error().is_some() {
+            return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+        }
+        let resp = ApiError::from(ApiErrorKind::NotFound).error_response();
+        Ok(ErrorHandlerResponse::Response(
+            res.into_response(resp).map_into_right_body(),
+        ))
+    }
+
+    /// The `JsonConfig` error handler, so invalid and oversized JSON bodies
+    /// are rendered in the same JSON format as our errors
+    pub fn from_json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+        ApiError::from(ApiErrorKind::PayloadError(err.into())).into()
     }
 }
 
@@ -61,6 +76,10 @@ pub enum ApiErrorKind {
     #[error(transparent)]
     PayloadError(actix_web::Error),
 
+    /// The notification body exceeds `max_data_bytes`
+    #[error("Data payload must be smaller than {0} bytes")]
+    PayloadTooLarge(usize),
+
     #[error(transparent)]
     VapidError(#[from] VapidError),
 
@@ -85,6 +104,10 @@ pub enum ApiErrorKind {
     #[error("Invalid token")]
     InvalidToken,
 
+    /// The request matched no route (or had invalid path parameters)
+    #[error("Not found")]
+    NotFound,
+
     #[error("UAID not found")]
     NoUser,
 
@@ -102,15 +125,32 @@ pub enum ApiErrorKind {
     #[error("Missing TTL value")]
     NoTTL,
 
+    /// A non-numeric `TTL` header
+    #[error("Invalid TTL value")]
+    InvalidTtl,
+
     #[error("Invalid router type")]
     InvalidRouterType,
 
     #[error("Invalid router token")]
     InvalidRouterToken,
 
+    /// A registered `locale` that isn't a (basic) BCP 47 language tag
+    #[error("Invalid locale {0:?}, expected a language tag (e.g. en-US)")]
+    InvalidLocale(String),
+
+    /// The subscription's router type isn't in `allowed_bridges`
+    #[error("Notifications to {0} subscriptions are not accepted by this server")]
+    BridgeNotAllowed(RouterType),
+
     #[error("Invalid message ID")]
     InvalidMessageId,
 
+    /// A malformed bulk notification request (or one with more than
+    /// `max_bulk_items`)
+    #[error("Invalid bulk request: {0}")]
+    InvalidBulkRequest(String),
+
     #[error("Invalid Authentication")]
     InvalidAuthentication,
 
@@ -120,6 +160,23 @@ pub enum ApiErrorKind {
     #[error("General error {0}")]
     General(String),
 
+    /// The subscription's rate limit was exceeded, retry after the duration
+    #[error("Too many notifications for this subscription, retry later")]
+    RateLimited(Duration),
+
+    /// Handling the request took longer than `handler_deadline_secs`
+    #[error("Timed out handling the request")]
+    RequestTimeout,
+
+    /// Notifications aren't accepted while in `maintenance_mode`
+    #[error("The service is under maintenance, retry later")]
+    Maintenance,
+
+    /// An earlier notification with the same `Idempotency-Key` is still
+    /// being sent
+    #[error("A notification with this Idempotency-Key is already being sent")]
+    IdempotencyConflict,
+
     #[error("ERROR:Success")]
     LogCheck,
 }
@@ -131,12 +188,24 @@ impl ApiErrorKind {
             ApiErrorKind::PayloadError(e) => e.as_response_error().status_code(),
             ApiErrorKind::Router(e) => e.status(),
 
+            ApiErrorKind::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+
             ApiErrorKind::Validation(_)
             | ApiErrorKind::InvalidEncryption(_)
             | ApiErrorKind::NoTTL
+            | ApiErrorKind::InvalidTtl
             | ApiErrorKind::InvalidRouterType
+            | ApiErrorKind::BridgeNotAllowed(_)
             | ApiErrorKind::InvalidRouterToken
-            | ApiErrorKind::InvalidMessageId => StatusCode::BAD_REQUEST,
+            | ApiErrorKind::InvalidLocale(_)
+            | ApiErrorKind::InvalidMessageId
+            | ApiErrorKind::InvalidBulkRequest(_) => StatusCode::BAD_REQUEST,
+
+            // A valid VAPID key, but not the one the subscription is
+            // restricted to
+            ApiErrorKind::VapidError(VapidError::KeyMismatch) => StatusCode::FORBIDDEN,
+            // A valid VAPID token, but its sender's blocked
+            ApiErrorKind::VapidError(VapidError::BlockedSubject) => StatusCode::FORBIDDEN,
 
             ApiErrorKind::VapidError(_)
             | ApiErrorKind::Jwt(_)
@@ -144,12 +213,24 @@ impl ApiErrorKind {
             | ApiErrorKind::InvalidAuthentication
             | ApiErrorKind::InvalidLocalAuth(_) => StatusCode::UNAUTHORIZED,
 
-            ApiErrorKind::InvalidToken | ApiErrorKind::InvalidApiVersion => StatusCode::NOT_FOUND,
+            ApiErrorKind::InvalidToken
+            | ApiErrorKind::InvalidApiVersion
+            | ApiErrorKind::NotFound => StatusCode::NOT_FOUND,
 
             ApiErrorKind::NoUser | ApiErrorKind::NoSubscription => StatusCode::GONE,
 
+            ApiErrorKind::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+
+            ApiErrorKind::IdempotencyConflict => StatusCode::CONFLICT,
+
+            ApiErrorKind::RequestTimeout => StatusCode::GATEWAY_TIMEOUT,
+
             ApiErrorKind::LogCheck => StatusCode::IM_A_TEAPOT,
 
+            ApiErrorKind::Database(e) if e.is_unavailable() => StatusCode::SERVICE_UNAVAILABLE,
+
+            ApiErrorKind::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+
             ApiErrorKind::General(_)
             | ApiErrorKind::Io(_)
             | ApiErrorKind::Metrics(_)
@@ -163,14 +244,19 @@ impl ApiErrorKind {
     pub fn metric_label(&self) -> Option<&'static str> {
         Some(match self {
             ApiErrorKind::PayloadError(_) => "payload_error",
+            ApiErrorKind::PayloadTooLarge(_) => "payload_too_large",
             ApiErrorKind::Router(e) => return e.metric_label(),
 
             ApiErrorKind::Validation(_) => "validation",
             ApiErrorKind::InvalidEncryption(_) => "invalid_encryption",
             ApiErrorKind::NoTTL => "no_ttl",
+            ApiErrorKind::InvalidTtl => "invalid_ttl",
             ApiErrorKind::InvalidRouterType => "invalid_router_type",
+            ApiErrorKind::BridgeNotAllowed(_) => "bridge_not_allowed",
             ApiErrorKind::InvalidRouterToken => "invalid_router_token",
+            ApiErrorKind::InvalidLocale(_) => "invalid_locale",
             ApiErrorKind::InvalidMessageId => "invalid_message_id",
+            ApiErrorKind::InvalidBulkRequest(_) => "invalid_bulk_request",
 
             ApiErrorKind::VapidError(_) => "vapid_error",
             ApiErrorKind::Jwt(_) => "jwt",
@@ -180,10 +266,18 @@ impl ApiErrorKind {
 
             ApiErrorKind::InvalidToken => "invalid_token",
             ApiErrorKind::InvalidApiVersion => "invalid_api_version",
+            ApiErrorKind::NotFound => "not_found",
 
             ApiErrorKind::NoUser => "no_user",
             ApiErrorKind::NoSubscription => "no_subscription",
 
+            ApiErrorKind::RateLimited(_) => "rate_limited",
+            ApiErrorKind::IdempotencyConflict => "idempotency_conflict",
+
+            ApiErrorKind::RequestTimeout => "request_timeout",
+
+            ApiErrorKind::Maintenance => "maintenance",
+
             ApiErrorKind::LogCheck => "log_check",
 
             ApiErrorKind::General(_) => "general",
@@ -203,7 +297,8 @@ impl ApiErrorKind {
             _ => !matches!(
                 self,
                 // Ignore common webpush errors
-                ApiErrorKind::NoTTL | ApiErrorKind::InvalidEncryption(_) |
+                ApiErrorKind::NoTTL | ApiErrorKind::InvalidTtl | ApiErrorKind::InvalidEncryption(_) |
+                ApiErrorKind::BridgeNotAllowed(_) | ApiErrorKind::InvalidLocale(_) |
                 // Ignore common VAPID erros
                 ApiErrorKind::VapidError(_)
                 | ApiErrorKind::Jwt(_)
@@ -212,9 +307,16 @@ impl ApiErrorKind {
                 | ApiErrorKind::InvalidLocalAuth(_) |
                 // Ignore missing or invalid user errors
                 ApiErrorKind::NoUser | ApiErrorKind::NoSubscription |
+                ApiErrorKind::NotFound |
+                // Ignore rate limited senders (and their retries)
+                ApiErrorKind::RateLimited(_) | ApiErrorKind::IdempotencyConflict |
+                // Reported via the request.timeout metric
+                ApiErrorKind::RequestTimeout |
+                // Reported via the maintenance.rejected metric
+                ApiErrorKind::Maintenance |
                 // Ignore oversized payload.
-                ApiErrorKind::PayloadError(_) |
-                ApiErrorKind::Validation(_),
+                ApiErrorKind::PayloadError(_) | ApiErrorKind::PayloadTooLarge(_) |
+                ApiErrorKind::Validation(_) | ApiErrorKind::InvalidBulkRequest(_),
             ),
         }
     }
@@ -226,20 +328,30 @@ impl ApiErrorKind {
 
             ApiErrorKind::Validation(e) => errno_from_validation_errors(e),
 
-            ApiErrorKind::InvalidToken | ApiErrorKind::InvalidApiVersion => Some(102),
+            ApiErrorKind::InvalidToken
+            | ApiErrorKind::InvalidApiVersion
+            | ApiErrorKind::NotFound => Some(102),
 
             ApiErrorKind::NoUser => Some(103),
 
             ApiErrorKind::PayloadError(error)
                 if matches!(error.as_error(), Some(PayloadError::Overflow))
-                    || matches!(error.as_error(), Some(JsonPayloadError::Overflow { .. })) =>
+                    || matches!(
+                        error.as_error(),
+                        Some(
+                            JsonPayloadError::Overflow { .. }
+                                | JsonPayloadError::OverflowKnownLength { .. }
+                        )
+                    ) =>
             {
                 Some(104)
             }
 
+            ApiErrorKind::PayloadTooLarge(_) => Some(104),
+
             ApiErrorKind::NoSubscription => Some(106),
 
-            ApiErrorKind::InvalidRouterType => Some(108),
+            ApiErrorKind::InvalidRouterType | ApiErrorKind::BridgeNotAllowed(_) => Some(108),
 
             ApiErrorKind::VapidError(_)
             | ApiErrorKind::TokenHashValidation(_)
@@ -251,6 +363,17 @@ impl ApiErrorKind {
 
             ApiErrorKind::NoTTL => Some(111),
 
+            // As with the validation of negative TTLs
+            ApiErrorKind::InvalidTtl => Some(114),
+
+            ApiErrorKind::RateLimited(_) => Some(116),
+
+            ApiErrorKind::RequestTimeout => Some(201),
+
+            ApiErrorKind::Database(e) if e.is_unavailable() => Some(201),
+
+            ApiErrorKind::Maintenance => Some(201),
+
             ApiErrorKind::LogCheck => Some(999),
 
             ApiErrorKind::General(_)
@@ -259,9 +382,12 @@ impl ApiErrorKind {
             | ApiErrorKind::Database(_)
             | ApiErrorKind::PayloadError(_)
             | ApiErrorKind::InvalidRouterToken
+            | ApiErrorKind::InvalidLocale(_)
             | ApiErrorKind::RegistrationSecretHash(_)
             | ApiErrorKind::EndpointUrl(_)
-            | ApiErrorKind::InvalidMessageId => None,
+            | ApiErrorKind::InvalidMessageId
+            | ApiErrorKind::InvalidBulkRequest(_)
+            | ApiErrorKind::IdempotencyConflict => None,
         }
     }
 }
@@ -307,6 +433,17 @@ impl ResponseError for ApiError {
             StatusCode::SERVICE_UNAVAILABLE => {
                 builder.insert_header((header::RETRY_AFTER, RETRY_AFTER_PERIOD));
             }
+            StatusCode::PAYLOAD_TOO_LARGE => {
+                // The rest of the body's left unread
+                builder.force_close();
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                if let ApiErrorKind::RateLimited(retry_after) = &self.kind {
+                    // Round up, so a retry isn't limited again
+                    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    builder.insert_header((header::RETRY_AFTER, secs.max(1).to_string()));
+                }
+            }
             _ => {}
         }
 
@@ -323,7 +460,7 @@ impl Serialize for ApiError {
         let mut map = serializer.serialize_map(Some(5))?;
 
         map.serialize_entry("code", &status.as_u16())?;
-        map.serialize_entry("errno", &self.kind.errno())?;
+        map.serialize_entry("errno", &self.kind.errno().unwrap_or(UNKNOWN_ERRNO))?;
         map.serialize_entry("error", &status.canonical_reason())?;
         map.serialize_entry("message", &self.kind.to_string())?;
         map.serialize_entry("more_info", ERROR_URL)?;
@@ -384,3 +521,118 @@ fn errno_from_validation_errors(e: &ValidationErrors) -> Option<usize> {
         })
         .next()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{
+        http::StatusCode,
+        middleware::ErrorHandlers,
+        test::{call_and_read_body_json, call_service, init_service, read_body_json, TestRequest},
+        web, App, HttpResponse, ResponseError,
+    };
+    use serde_json::json;
+
+    use super::{ApiError, ApiErrorKind, ERROR_URL};
+    use crate::headers::vapid::VapidError;
+
+    #[test]
+    fn status_and_errno() {
+        let cases = [
+            (ApiErrorKind::InvalidEncryption("bad".to_owned()), 400, 110),
+            (ApiErrorKind::NoTTL, 400, 111),
+            (ApiErrorKind::InvalidTtl, 400, 114),
+            (ApiErrorKind::InvalidRouterType, 400, 108),
+            (ApiErrorKind::VapidError(VapidError::MissingKey), 401, 109),
+            (ApiErrorKind::VapidError(VapidError::KeyMismatch), 403, 109),
+            (ApiErrorKind::InvalidAuthentication, 401, 109),
+            (ApiErrorKind::InvalidToken, 404, 102),
+            (ApiErrorKind::NotFound, 404, 102),
+            (ApiErrorKind::NoUser, 410, 103),
+            (ApiErrorKind::NoSubscription, 410, 106),
+            (ApiErrorKind::PayloadTooLarge(4096), 413, 104),
+            (ApiErrorKind::RateLimited(Duration::from_secs(1)), 429, 116),
+            (ApiErrorKind::IdempotencyConflict, 409, 999),
+            (ApiErrorKind::General("oops".to_owned()), 500, 999),
+        ];
+        for (kind, code, errno) in cases {
+            let error = ApiError::from(kind);
+            assert_eq!(error.status_code().as_u16(), code, "{}", error);
+            let body = serde_json::to_value(&error).unwrap();
+            assert_eq!(
+                body,
+                json!({
+                    "code": code,
+                    "errno": errno,
+                    "error": error.status_code().canonical_reason(),
+                    "message": error.to_string(),
+                    "more_info": ERROR_URL,
+                })
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn render_404() {
+        let app = init_service(
+            App::new()
+                .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
+                .route(
+                    "/token",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(ApiError::from(ApiErrorKind::InvalidToken))
+                    }),
+                ),
+        )
+        .await;
+
+        // No such route
+        let req = TestRequest::get().uri("/nope").to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["code"], 404);
+        assert_eq!(body["errno"], 102);
+        assert_eq!(body["message"], "Not found");
+
+        // Our own 404s are kept
+        let req = TestRequest::get().uri("/token").to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["errno"], 102);
+        assert_eq!(body["message"], "Invalid token");
+    }
+
+    #[actix_rt::test]
+    async fn json_errors() {
+        let app = init_service(
+            App::new()
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(16)
+                        .error_handler(ApiError::from_json_error),
+                )
+                .route(
+                    "/json",
+                    web::post().to(|_: web::Json<Vec<u32>>| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/json")
+            .set_json(json!({"not": "a list"}))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], 400);
+        assert_eq!(body["errno"], 999);
+
+        let req = TestRequest::post()
+            .uri("/json")
+            .set_json(json!([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]))
+            .to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["code"], 413);
+        assert_eq!(body["errno"], 104);
+    }
+}
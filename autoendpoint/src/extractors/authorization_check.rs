@@ -1,12 +1,15 @@
 use crate::auth::sign_with_key;
-use crate::error::{ApiError, ApiErrorKind};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::headers::util::get_header;
 use crate::server::AppState;
+use crate::settings::Settings;
 use actix_web::dev::Payload;
 use actix_web::{web::Data, FromRequest, HttpRequest};
+use autopush_common::util::{is_admin_token, sec_since_epoch};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use openssl::error::ErrorStack;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 /// Verifies the request authorization via the authorization header.
@@ -74,7 +77,7 @@ impl FromRequest for AuthorizationCheck {
 }
 
 /// Get the token from a bearer authorization header
-fn get_token_from_auth_header(header: &str) -> Option<&str> {
+pub(crate) fn get_token_from_auth_header(header: &str) -> Option<&str> {
     let mut split = header.splitn(2, ' ');
     let scheme = split.next()?;
 
@@ -87,6 +90,106 @@ fn get_token_from_auth_header(header: &str) -> Option<&str> {
     split.next()
 }
 
+/// How the admin routes (`GET /stats` and the `debug` feature's routes)
+/// authenticate requests
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminAuthMode {
+    /// A bearer token matching `admin_token`
+    #[default]
+    Token,
+    /// An `X-Admin-Signature` of the request, signed with `admin_hmac_secret`
+    Hmac,
+}
+
+/// Verify an admin request (with its `body`) per the `admin_auth_mode`
+pub(crate) fn check_admin_auth(
+    request: &HttpRequest,
+    body: &[u8],
+    settings: &Settings,
+) -> ApiResult<()> {
+    match settings.admin_auth_mode {
+        AdminAuthMode::Token => check_admin_token(
+            get_header(request, "Authorization"),
+            settings.admin_token.as_deref(),
+        ),
+        AdminAuthMode::Hmac => check_admin_signature(
+            request,
+            body,
+            settings.admin_hmac_secret.as_deref(),
+            settings.admin_signature_window_secs,
+        ),
+    }
+}
+
+/// Sign an admin request: the hex HMAC-SHA256 of its method, path (and
+/// query), `X-Admin-Timestamp` and body, newline separated
+pub fn sign_admin_request(
+    secret: &str,
+    method: &str,
+    path: &str,
+    timestamp: &str,
+    body: &[u8],
+) -> Result<String, ErrorStack> {
+    let mut message = format!("{method}\n{path}\n{timestamp}\n").into_bytes();
+    message.extend_from_slice(body);
+    sign_with_key(secret.as_bytes(), &message)
+}
+
+/// Verify the request's `X-Admin-Signature` (see [sign_admin_request])
+/// against the configured `admin_hmac_secret`. Its `X-Admin-Timestamp` (UNIX
+/// seconds) must be within `window_secs` of now, so captured requests can't
+/// be replayed any later. Requests are always rejected when no
+/// `admin_hmac_secret` is configured.
+pub(crate) fn check_admin_signature(
+    request: &HttpRequest,
+    body: &[u8],
+    secret: Option<&str>,
+    window_secs: u64,
+) -> ApiResult<()> {
+    let secret = secret
+        .filter(|secret| !secret.is_empty())
+        .ok_or(ApiErrorKind::InvalidAuthentication)?;
+    let timestamp =
+        get_header(request, "X-Admin-Timestamp").ok_or(ApiErrorKind::InvalidAuthentication)?;
+    let signature =
+        get_header(request, "X-Admin-Signature").ok_or(ApiErrorKind::InvalidAuthentication)?;
+    let signed_at: u64 = timestamp
+        .parse()
+        .map_err(|_| ApiErrorKind::InvalidAuthentication)?;
+    if sec_since_epoch().abs_diff(signed_at) > window_secs {
+        debug!("Admin request signature expired: {}", signed_at);
+        return Err(ApiErrorKind::InvalidAuthentication.into());
+    }
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or(request.path(), |path| path.as_str());
+    let expected = sign_admin_request(secret, request.method().as_str(), path, timestamp, body)
+        .map_err(ApiErrorKind::RegistrationSecretHash)?;
+    if signature.len() == expected.len()
+        && openssl::memcmp::eq(signature.as_bytes(), expected.as_bytes())
+    {
+        Ok(())
+    } else {
+        Err(ApiErrorKind::InvalidAuthentication.into())
+    }
+}
+
+/// Verify the request's bearer token against the configured `admin_token`.
+/// Requests are always rejected when no `admin_token` is configured.
+pub(crate) fn check_admin_token(
+    auth_header: Option<&str>,
+    admin_token: Option<&str>,
+) -> ApiResult<()> {
+    if is_admin_token(auth_header, admin_token) {
+        Ok(())
+    } else {
+        Err(ApiErrorKind::InvalidAuthentication.into())
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -133,4 +236,69 @@ mod test {
         assert!(get_token_from_auth_header(&format!("random {}", &token)).is_none());
         Ok(())
     }
+
+    #[test]
+    fn unauthorized_status() {
+        let error = check_admin_token(Some("bearer wrong"), Some("s3cret")).unwrap_err();
+        assert_eq!(error.kind.status(), 401);
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        assert!(check_admin_token(Some("bearer s3cret"), Some("s3cret")).is_ok());
+        assert!(check_admin_token(Some("bearer wrong"), Some("s3cret")).is_err());
+        assert!(check_admin_token(Some("s3cret"), Some("s3cret")).is_err());
+        assert!(check_admin_token(None, Some("s3cret")).is_err());
+        // Never accessible without a configured token
+        assert!(check_admin_token(Some("bearer "), Some("")).is_err());
+        assert!(check_admin_token(Some("bearer s3cret"), None).is_err());
+    }
+
+    /// A `DELETE` of the admin route, signed at `signed_at` over `signed_body`
+    /// but sent with `body`
+    fn check_signed(signed_at: u64, signed_body: &[u8], body: &[u8]) -> ApiResult<()> {
+        let path = "/v1/admin/subscription/abc";
+        let timestamp = signed_at.to_string();
+        let signature =
+            sign_admin_request("s3cret", "DELETE", path, &timestamp, signed_body).unwrap();
+        let request = actix_web::test::TestRequest::delete()
+            .uri(path)
+            .insert_header(("X-Admin-Timestamp", timestamp))
+            .insert_header(("X-Admin-Signature", signature))
+            .to_http_request();
+        check_admin_signature(&request, body, Some("s3cret"), 300)
+    }
+
+    #[test]
+    fn signed_request() {
+        let now = sec_since_epoch();
+        assert!(check_signed(now, b"body", b"body").is_ok());
+        // Within the window, allowing for clock skew
+        assert!(check_signed(now - 200, b"", b"").is_ok());
+        assert!(check_signed(now + 200, b"", b"").is_ok());
+    }
+
+    #[test]
+    fn expired_signature() {
+        let error = check_signed(sec_since_epoch() - 301, b"", b"").unwrap_err();
+        assert_eq!(error.kind.status(), 401);
+        assert!(check_signed(sec_since_epoch() + 301, b"", b"").is_err());
+    }
+
+    #[test]
+    fn tampered_body() {
+        let error = check_signed(sec_since_epoch(), b"body", b"tampered").unwrap_err();
+        assert_eq!(error.kind.status(), 401);
+    }
+
+    #[test]
+    fn unsigned_request() {
+        let request = actix_web::test::TestRequest::get()
+            .uri("/stats")
+            .insert_header(("Authorization", "bearer s3cret"))
+            .to_http_request();
+        assert!(check_admin_signature(&request, b"", Some("s3cret"), 300).is_err());
+        // Never accessible without a configured secret
+        assert!(check_admin_signature(&request, b"", None, 300).is_err());
+    }
 }
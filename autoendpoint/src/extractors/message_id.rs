@@ -11,17 +11,22 @@ use uuid::Uuid;
 /// encrypted into a "message ID" which is presented to the user. Later, the
 /// user can send us the message ID to perform operations on the associated
 /// notification (e.g. delete it).
-#[derive(Debug)]
+///
+/// The `expiry` (seconds since epoch) is when the notification's TTL runs
+/// out. Message IDs issued before it was included have none.
+#[derive(Debug, Eq, PartialEq)]
 pub enum MessageId {
     WithTopic {
         uaid: Uuid,
         channel_id: Uuid,
         topic: String,
+        expiry: Option<u64>,
     },
     WithoutTopic {
         uaid: Uuid,
         channel_id: Uuid,
         timestamp: u64,
+        expiry: Option<u64>,
     },
 }
 
@@ -45,11 +50,12 @@ impl FromRequest for MessageId {
 impl MessageId {
     /// Encode and encrypt the message ID
     pub fn encrypt(&self, fernet: &MultiFernet) -> String {
-        let id_str = match self {
+        let mut id_str = match self {
             MessageId::WithTopic {
                 uaid,
                 channel_id,
                 topic,
+                ..
             } => format!(
                 "{}:{}:{}:{}",
                 TOPIC_NOTIFICATION_PREFIX,
@@ -61,6 +67,7 @@ impl MessageId {
                 uaid,
                 channel_id,
                 timestamp,
+                ..
             } => format!(
                 "{}:{}:{}:{}",
                 STANDARD_NOTIFICATION_PREFIX,
@@ -69,6 +76,9 @@ impl MessageId {
                 timestamp
             ),
         };
+        if let Some(expiry) = self.expiry() {
+            id_str.push_str(&format!(":{expiry}"));
+        }
 
         fernet.encrypt(id_str.as_bytes())
     }
@@ -81,18 +91,26 @@ impl MessageId {
         let decrypted_str = String::from_utf8_lossy(&decrypted_bytes);
         let segments: Vec<_> = decrypted_str.split(':').collect();
 
-        if segments.len() != 4 {
-            return Err(ApiErrorKind::InvalidMessageId.into());
-        }
-
-        let (version, uaid, chid, topic_or_timestamp) =
-            (segments[0], segments[1], segments[2], segments[3]);
+        let (version, uaid, chid, topic_or_timestamp, expiry) = match segments[..] {
+            [version, uaid, chid, topic_or_timestamp] => {
+                (version, uaid, chid, topic_or_timestamp, None)
+            }
+            [version, uaid, chid, topic_or_timestamp, expiry] => (
+                version,
+                uaid,
+                chid,
+                topic_or_timestamp,
+                Some(expiry.parse().map_err(|_| ApiErrorKind::InvalidMessageId)?),
+            ),
+            _ => return Err(ApiErrorKind::InvalidMessageId.into()),
+        };
 
         match version {
             "01" => Ok(MessageId::WithTopic {
                 uaid: Uuid::parse_str(uaid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
                 channel_id: Uuid::parse_str(chid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
                 topic: topic_or_timestamp.to_string(),
+                expiry,
             }),
             "02" => Ok(MessageId::WithoutTopic {
                 uaid: Uuid::parse_str(uaid).map_err(|_| ApiErrorKind::InvalidMessageId)?,
@@ -100,6 +118,7 @@ impl MessageId {
                 timestamp: topic_or_timestamp
                     .parse()
                     .map_err(|_| ApiErrorKind::InvalidMessageId)?,
+                expiry,
             }),
             _ => Err(ApiErrorKind::InvalidMessageId.into()),
         }
@@ -113,6 +132,22 @@ impl MessageId {
         }
     }
 
+    /// Get the channel ID of the associated notification
+    pub fn channel_id(&self) -> Uuid {
+        match self {
+            MessageId::WithTopic { channel_id, .. } => *channel_id,
+            MessageId::WithoutTopic { channel_id, .. } => *channel_id,
+        }
+    }
+
+    /// When the associated notification's TTL runs out, if known
+    pub fn expiry(&self) -> Option<u64> {
+        match self {
+            MessageId::WithTopic { expiry, .. } => *expiry,
+            MessageId::WithoutTopic { expiry, .. } => *expiry,
+        }
+    }
+
     /// Get the sort-key for the associated notification
     pub fn sort_key(&self) -> String {
         match self {
@@ -137,3 +172,39 @@ impl MessageId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    use super::MessageId;
+
+    /// Message IDs issued before the expiry was included remain valid
+    #[test]
+    fn decrypts_without_expiry() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let legacy = fernet
+            .encrypt(format!("02:{}:{}:1234", uaid.as_simple(), channel_id.as_simple()).as_bytes());
+        assert_eq!(
+            MessageId::decrypt(&fernet, &legacy).unwrap(),
+            MessageId::WithoutTopic {
+                uaid,
+                channel_id,
+                timestamp: 1234,
+                expiry: None,
+            }
+        );
+
+        let message_id = MessageId::WithTopic {
+            uaid,
+            channel_id,
+            topic: "topic".to_owned(),
+            expiry: Some(5678),
+        };
+        let decrypted = MessageId::decrypt(&fernet, &message_id.encrypt(&fernet)).unwrap();
+        assert_eq!(decrypted, message_id);
+        assert_eq!(decrypted.expiry(), Some(5678));
+    }
+}
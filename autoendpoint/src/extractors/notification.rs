@@ -1,16 +1,31 @@
-use crate::error::{ApiError, ApiErrorKind};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::extractors::{
-    message_id::MessageId, notification_headers::NotificationHeaders, subscription::Subscription,
+    message_id::MessageId,
+    notification_headers::{Compression, NotificationHeaders},
+    subscription::Subscription,
 };
+use crate::headers::util::RequestHeaders;
+use crate::idempotency::{IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LEN};
 use crate::server::AppState;
-use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use actix_web::{
+    dev::Payload, error::PayloadError, http::header::CONTENT_LENGTH, web, FromRequest, HttpRequest,
+};
 use autopush_common::util::{b64_encode_url, ms_since_epoch, sec_since_epoch};
 use cadence::CountedExt;
 use fernet::MultiFernet;
-use futures::{future, FutureExt};
+use futures::{future, FutureExt, Stream, StreamExt};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+/// The length of an `aes128gcm` header's salt
+const AES128GCM_SALT_LEN: usize = 16;
+/// The length of an `aes128gcm` header without its key id: the salt, record
+/// size and key id length
+const AES128GCM_HEADER_LEN: usize = AES128GCM_SALT_LEN + 4 + 1;
+/// The smallest valid `aes128gcm` record size: the 16 byte tag, a padding
+/// delimiter and at least one byte of data
+const AES128GCM_MIN_RS: u32 = 18;
+
 /// Extracts notification data from `Subscription` and request data
 #[derive(Clone, Debug)]
 pub struct Notification {
@@ -26,6 +41,15 @@ pub struct Notification {
     pub sort_key_timestamp: u64,
     /// The encrypted notification body
     pub data: Option<String>,
+    /// Validate and route the notification without sending it (only when
+    /// `dry_run_enabled`)
+    pub dry_run: bool,
+    /// Accept the notification before routing it, per `Prefer:
+    /// respond-async` (only when `respond_async_enabled`)
+    pub respond_async: bool,
+    /// The app server's `Idempotency-Key`, when deduplicating notifications
+    /// (`idempotency_ttl_secs`). Never set for dry runs.
+    pub idempotency_key: Option<String>,
 }
 
 impl FromRequest for Notification {
@@ -34,58 +58,40 @@ impl FromRequest for Notification {
 
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         let req = req.clone();
-        let mut payload = payload.take();
+        let payload = payload.take();
 
         async move {
-            let subscription = Subscription::extract(&req).await?;
             let app_state = web::Data::<AppState>::extract(&req)
                 .await
                 .expect("No server state found");
+            // Cheap to check, so reject oversized headers before the
+            // subscription lookup and reading the body
+            NotificationHeaders::check_crypto_key_limits(
+                &req,
+                app_state.settings.max_crypto_key_bytes,
+                app_state.settings.max_crypto_key_params,
+            )?;
+            let subscription = Subscription::extract(&req).await?;
 
             // Read data
-            let data = web::Bytes::from_request(&req, &mut payload)
-                .await
-                .map_err(|e| {
-                    debug!("▶▶ Request read payload error: {:?}", &e);
-                    ApiErrorKind::PayloadError(e)
-                })?;
-
-            // Convert data to base64
-            let data = if data.is_empty() {
-                None
-            } else {
-                Some(b64_encode_url(&data.to_vec()))
-            };
-
-            let headers = NotificationHeaders::from_request(&req, data.is_some())?;
-            let timestamp = sec_since_epoch();
-            let sort_key_timestamp = ms_since_epoch();
-            let message_id = Self::generate_message_id(
-                &app_state.fernet,
-                subscription.user.uaid,
-                subscription.channel_id,
-                headers.topic.as_deref(),
-                sort_key_timestamp,
-            );
+            let content_length = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            let data =
+                Self::read_body(payload, content_length, app_state.settings.max_data_bytes).await?;
+            let mut notification = Self::build(&req, subscription, data, &app_state)?;
 
-            // Record the encoding if we have an encrypted payload
-            if let Some(encoding) = &headers.encoding {
-                if data.is_some() {
-                    app_state
-                        .metrics
-                        .incr(&format!("updates.notification.encoding.{encoding}"))
-                        .ok();
-                }
-            }
-
-            Ok(Notification {
-                message_id,
-                subscription,
-                headers,
-                timestamp,
-                sort_key_timestamp,
-                data,
-            })
+            let dry_run = app_state.settings.dry_run_enabled && Self::is_dry_run(&req);
+            notification.idempotency_key = (app_state.settings.idempotency_ttl_secs > 0
+                && !dry_run)
+                .then(|| Self::idempotency_key(&req))
+                .flatten();
+            notification.dry_run = dry_run;
+            notification.respond_async =
+                app_state.settings.respond_async_enabled && Self::prefers_respond_async(&req);
+            Ok(notification)
         }
         .boxed_local()
     }
@@ -103,6 +109,7 @@ impl From<Notification> for autopush_common::notification::Notification {
             timestamp: notification.timestamp,
             data: notification.data,
             sortkey_timestamp,
+            nack_count: 0,
             headers: {
                 let headers: HashMap<String, String> = notification.headers.into();
                 if headers.is_empty() {
@@ -116,40 +123,225 @@ impl From<Notification> for autopush_common::notification::Notification {
 }
 
 impl Notification {
+    /// Build the notification to `subscription` from its request's headers
+    /// and (raw) body `data`. It's neither a dry run, nor accepted
+    /// asynchronously, nor deduplicated.
+    pub fn build(
+        req: &impl RequestHeaders,
+        subscription: Subscription,
+        data: web::Bytes,
+        app_state: &AppState,
+    ) -> ApiResult<Self> {
+        let max_data_bytes = app_state.settings.max_data_bytes;
+        let data = match Compression::from_request(req)? {
+            Some(_) if !app_state.settings.request_decompression_enabled => {
+                return Err(ApiErrorKind::InvalidEncryption(
+                    "Compressed notification bodies are not accepted".to_string(),
+                )
+                .into());
+            }
+            Some(compression) if !data.is_empty() => {
+                web::Bytes::from(compression.decompress(&data, max_data_bytes)?)
+            }
+            _ => data,
+        };
+        Self::check_data_size(&data, max_data_bytes)?;
+
+        NotificationHeaders::check_encoding(
+            req,
+            !data.is_empty(),
+            app_state.settings.aesgcm_enabled,
+        )?;
+        let ttl_limits = app_state.settings.ttl_limits();
+        if NotificationHeaders::requested_ttl(req)?.is_some_and(|ttl| ttl > ttl_limits.max) {
+            app_state.metrics.incr("ttl.clamped").ok();
+        }
+        let headers = NotificationHeaders::from_request(req, !data.is_empty(), &ttl_limits)?;
+        if headers.encoding.as_deref() == Some("aes128gcm") && !data.is_empty() {
+            Self::check_aes128gcm_header(&data)?;
+        }
+
+        // Convert data to base64
+        let data = if data.is_empty() {
+            None
+        } else {
+            Some(b64_encode_url(&data.to_vec()))
+        };
+
+        let timestamp = sec_since_epoch();
+        let sort_key_timestamp = ms_since_epoch();
+        let message_id = Self::generate_message_id(
+            &app_state.fernet,
+            subscription.user.uaid,
+            subscription.channel_id,
+            headers.topic.as_deref(),
+            sort_key_timestamp,
+            timestamp + headers.ttl as u64,
+        );
+
+        // Record the encoding if we have an encrypted payload
+        if let Some(encoding) = &headers.encoding {
+            if data.is_some() {
+                app_state
+                    .metrics
+                    .incr(&format!("updates.notification.encoding.{encoding}"))
+                    .ok();
+            }
+        }
+
+        Ok(Notification {
+            message_id,
+            subscription,
+            headers,
+            timestamp,
+            sort_key_timestamp,
+            data,
+            dry_run: false,
+            respond_async: false,
+            idempotency_key: None,
+        })
+    }
+
     /// Generate a message-id suitable for accessing the message
     ///
     /// For topic messages, a sort_key version of 01 is used, and the topic
     /// is included for reference:
     ///
-    ///     Encrypted('01' : uaid.hex : channel_id.hex : topic)
+    ///     Encrypted('01' : uaid.hex : channel_id.hex : topic : expiry)
     ///
     /// For non-topic messages, a sort_key version of 02 is used:
     ///
-    ///     Encrypted('02' : uaid.hex : channel_id.hex : timestamp)
+    ///     Encrypted('02' : uaid.hex : channel_id.hex : timestamp : expiry)
     fn generate_message_id(
         fernet: &MultiFernet,
         uaid: Uuid,
         channel_id: Uuid,
         topic: Option<&str>,
         timestamp: u64,
+        expiry: u64,
     ) -> String {
         let message_id = if let Some(topic) = topic {
             MessageId::WithTopic {
                 uaid,
                 channel_id,
                 topic: topic.to_string(),
+                expiry: Some(expiry),
             }
         } else {
             MessageId::WithoutTopic {
                 uaid,
                 channel_id,
                 timestamp,
+                expiry: Some(expiry),
             }
         };
 
         message_id.encrypt(fernet)
     }
 
+    /// Read the request body, aborting as soon as it (or its
+    /// `content_length`) exceeds `max_data_bytes`: the rest's never read, no
+    /// matter what `Content-Length` claimed
+    async fn read_body<S>(
+        mut payload: S,
+        content_length: Option<usize>,
+        max_data_bytes: usize,
+    ) -> ApiResult<web::Bytes>
+    where
+        S: Stream<Item = Result<web::Bytes, PayloadError>> + Unpin,
+    {
+        if matches!(content_length, Some(len) if len > max_data_bytes) {
+            return Err(ApiErrorKind::PayloadTooLarge(max_data_bytes).into());
+        }
+        let mut body = web::BytesMut::with_capacity(content_length.unwrap_or(0));
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| {
+                debug!("▶▶ Request read payload error: {:?}", &e);
+                ApiErrorKind::PayloadError(e.into())
+            })?;
+            if body.len() + chunk.len() > max_data_bytes {
+                trace!("Request body exceeds {} bytes", max_data_bytes);
+                return Err(ApiErrorKind::PayloadTooLarge(max_data_bytes).into());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.freeze())
+    }
+
+    /// Reject (decoded) notification bodies larger than `max_data_bytes`
+    fn check_data_size(data: &[u8], max_data_bytes: usize) -> ApiResult<()> {
+        if data.len() > max_data_bytes {
+            trace!("Data is too long by {} bytes", data.len() - max_data_bytes);
+            return Err(ApiErrorKind::PayloadTooLarge(max_data_bytes).into());
+        }
+        Ok(())
+    }
+
+    /// Sanity check the header of an `aes128gcm` body (RFC 8188 section 2.1):
+    /// a 16 byte salt, a 4 byte record size of at least 18 and the key id
+    /// (prefixed by its 1 byte length). The ciphertext following it is opaque
+    /// to us.
+    fn check_aes128gcm_header(data: &[u8]) -> ApiResult<()> {
+        let invalid = |reason: &str| {
+            ApiErrorKind::InvalidEncryption(format!("Invalid aes128gcm header: {reason}"))
+        };
+        if data.len() < AES128GCM_HEADER_LEN {
+            return Err(invalid("too short").into());
+        }
+        let rs = u32::from_be_bytes(
+            data[AES128GCM_SALT_LEN..AES128GCM_SALT_LEN + 4]
+                .try_into()
+                .unwrap(),
+        );
+        if rs < AES128GCM_MIN_RS {
+            return Err(
+                invalid(&format!("record size must be at least {AES128GCM_MIN_RS}")).into(),
+            );
+        }
+        let idlen = data[AES128GCM_HEADER_LEN - 1] as usize;
+        if data.len() < AES128GCM_HEADER_LEN + idlen {
+            return Err(invalid("key id longer than the body").into());
+        }
+        Ok(())
+    }
+
+    /// Whether the request asks for a dry run, via a `dry_run=true` query
+    /// parameter or an `X-Dry-Run: true` header
+    fn is_dry_run(req: &HttpRequest) -> bool {
+        let is_true = |value: &str| value.eq_ignore_ascii_case("true") || value == "1";
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string());
+        query
+            .ok()
+            .and_then(|query| query.get("dry_run").map(|value| is_true(value)))
+            .or_else(|| {
+                req.headers()
+                    .get("X-Dry-Run")
+                    .and_then(|value| value.to_str().ok())
+                    .map(is_true)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Whether the request's `Prefer` header includes the `respond-async`
+    /// preference (RFC 7240)
+    fn prefers_respond_async(req: &HttpRequest) -> bool {
+        req.headers()
+            .get_all("Prefer")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|preference| preference.split(';').next())
+            .any(|token| token.trim().eq_ignore_ascii_case("respond-async"))
+    }
+
+    /// The request's `Idempotency-Key` header, if any (and not too long)
+    fn idempotency_key(req: &HttpRequest) -> Option<String> {
+        req.headers()
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+            .map(str::to_owned)
+    }
+
     pub fn has_topic(&self) -> bool {
         self.headers.topic.is_some()
     }
@@ -182,3 +374,161 @@ impl Notification {
         map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::Notification;
+    use crate::error::ApiErrorKind;
+    use actix_web::{error::PayloadError, http::StatusCode, test::TestRequest, web, ResponseError};
+    use futures::{stream, Stream};
+
+    /// A body of `chunks` 1KiB chunks, counting the bytes read
+    fn chunked_body(
+        chunks: usize,
+        read: &Cell<usize>,
+    ) -> impl Stream<Item = Result<web::Bytes, PayloadError>> + Unpin + '_ {
+        stream::iter((0..chunks).map(move |_| {
+            read.set(read.get() + 1024);
+            Ok(web::Bytes::from(vec![0u8; 1024]))
+        }))
+    }
+
+    #[actix_rt::test]
+    async fn body_within_limit_is_read() {
+        let read = Cell::new(0);
+        let body = Notification::read_body(chunked_body(4, &read), None, 4096)
+            .await
+            .unwrap();
+        assert_eq!(body.len(), 4096);
+    }
+
+    /// An oversized (chunked) body is rejected without reading the rest
+    #[actix_rt::test]
+    async fn body_over_limit_is_aborted() {
+        let read = Cell::new(0);
+        let err = Notification::read_body(chunked_body(1024 * 1024, &read), None, 4096)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::PayloadTooLarge(4096)));
+        assert_eq!(read.get(), 5 * 1024);
+    }
+
+    /// An oversized `Content-Length` is rejected before reading anything
+    #[actix_rt::test]
+    async fn content_length_over_limit_is_rejected() {
+        let read = Cell::new(0);
+        let err = Notification::read_body(chunked_body(8, &read), Some(8192), 4096)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::PayloadTooLarge(4096)));
+        assert_eq!(read.get(), 0);
+        // The rest of the body's left unread, so the connection's closed
+        assert!(!err.error_response().head().keep_alive());
+    }
+
+    #[test]
+    fn data_at_limit_is_accepted() {
+        let data = vec![0u8; 4096];
+        assert!(Notification::check_data_size(&data, 4096).is_ok());
+    }
+
+    #[test]
+    fn data_over_limit_is_rejected() {
+        let data = vec![0u8; 4097];
+        let err = Notification::check_data_size(&data, 4096).unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::PayloadTooLarge(4096)));
+        assert_eq!(err.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(err.to_string().contains("4096"));
+    }
+
+    /// An aes128gcm body with the given record size and key id length byte
+    /// (followed by `keyid_len` bytes of key id)
+    fn aes128gcm_body(rs: u32, idlen: u8, keyid_len: usize) -> Vec<u8> {
+        let mut body = vec![0u8; 16];
+        body.extend(rs.to_be_bytes());
+        body.push(idlen);
+        body.extend(vec![4u8; keyid_len]);
+        // The (opaque) ciphertext
+        body.extend(vec![0u8; 32]);
+        body
+    }
+
+    #[test]
+    fn aes128gcm_header_valid() {
+        assert!(Notification::check_aes128gcm_header(&aes128gcm_body(4096, 65, 65)).is_ok());
+        assert!(Notification::check_aes128gcm_header(&aes128gcm_body(18, 0, 0)).is_ok());
+    }
+
+    #[test]
+    fn aes128gcm_record_size_too_small() {
+        let err = Notification::check_aes128gcm_header(&aes128gcm_body(17, 65, 65)).unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidEncryption(_)));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert!(err.to_string().contains("record size"));
+    }
+
+    #[test]
+    fn aes128gcm_keyid_length_mismatch() {
+        // A key id length byte exceeding what follows
+        let mut body = aes128gcm_body(4096, 255, 0);
+        body.truncate(16 + 4 + 1 + 10);
+        let err = Notification::check_aes128gcm_header(&body).unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidEncryption(_)));
+        assert!(err.to_string().contains("key id"));
+        // Or a truncated header
+        assert!(Notification::check_aes128gcm_header(&[0u8; 20]).is_err());
+    }
+
+    #[test]
+    fn dry_run_requests() {
+        let req = TestRequest::post().uri("/wpush/v1/token").to_http_request();
+        assert!(!Notification::is_dry_run(&req));
+        let req = TestRequest::post()
+            .uri("/wpush/v1/token?dry_run=true")
+            .to_http_request();
+        assert!(Notification::is_dry_run(&req));
+        let req = TestRequest::post()
+            .uri("/wpush/v1/token")
+            .insert_header(("X-Dry-Run", "true"))
+            .to_http_request();
+        assert!(Notification::is_dry_run(&req));
+        let req = TestRequest::post()
+            .uri("/wpush/v1/token?dry_run=false")
+            .insert_header(("X-Dry-Run", "true"))
+            .to_http_request();
+        assert!(!Notification::is_dry_run(&req));
+    }
+
+    #[test]
+    fn respond_async_requests() {
+        let prefers = |prefer: Option<&str>| {
+            let mut req = TestRequest::post().uri("/wpush/v1/token");
+            if let Some(prefer) = prefer {
+                req = req.insert_header(("Prefer", prefer));
+            }
+            Notification::prefers_respond_async(&req.to_http_request())
+        };
+        // Synchronous by default
+        assert!(!prefers(None));
+        assert!(!prefers(Some("return=minimal")));
+        assert!(prefers(Some("respond-async")));
+        assert!(prefers(Some("return=minimal, Respond-Async; wait=10")));
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let key = |value: &str| {
+            let req = TestRequest::post()
+                .insert_header(("Idempotency-Key", value))
+                .to_http_request();
+            Notification::idempotency_key(&req)
+        };
+        assert_eq!(key("retry-1").as_deref(), Some("retry-1"));
+        assert_eq!(key(""), None);
+        assert_eq!(key(&"k".repeat(256)), None);
+        let req = TestRequest::post().to_http_request();
+        assert_eq!(Notification::idempotency_key(&req), None);
+    }
+}
@@ -1,12 +1,13 @@
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::headers::crypto_key::CryptoKeyHeader;
-use crate::headers::util::{get_header, get_owned_header};
-use actix_web::HttpRequest;
+use crate::headers::util::{get_header, get_owned_header, RequestHeaders};
 use autopush_common::util::InsertOpt;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::cmp::min;
 use std::collections::HashMap;
+use std::io::Read;
 use validator::Validate;
 use validator_derive::Validate;
 
@@ -14,10 +15,38 @@ lazy_static! {
     static ref VALID_BASE64_URL: Regex = Regex::new(r"^[0-9A-Za-z\-_]+=*$").unwrap();
     static ref STRIP_PADDING: Regex =
         Regex::new(r"(?P<head>[0-9A-Za-z\-_]+)=+(?P<tail>[,;]|$)").unwrap();
+    static ref VALID_URGENCY: Regex = Regex::new(r"^(very-low|low|normal|high)$").unwrap();
 }
 
-/// 60 days
-const MAX_TTL: i64 = 60 * 60 * 24 * 60;
+/// The default longest TTL, 30 days
+pub const DEFAULT_MAX_TTL: i64 = 60 * 60 * 24 * 30;
+
+/// The `Urgency` used when the header is absent (RFC8030 §5.3)
+pub const DEFAULT_URGENCY: &str = "normal";
+
+/// The WebPush encodings accepted when the older `aesgcm` is enabled
+const ENCODINGS: &[&str] = &["aes128gcm", "aesgcm"];
+
+/// The WebPush encodings accepted when it's not
+const ENCODINGS_WITHOUT_AESGCM: &[&str] = &["aes128gcm"];
+
+/// How notifications' `TTL` header is limited
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TtlLimits {
+    /// Longer TTLs are reduced to this
+    pub max: i64,
+    /// The TTL of notifications without one, which are otherwise rejected
+    pub default: Option<i64>,
+}
+
+impl Default for TtlLimits {
+    fn default() -> Self {
+        Self {
+            max: DEFAULT_MAX_TTL,
+            default: None,
+        }
+    }
+}
 
 /// Extractor and validator for notification headers
 #[derive(Clone, Debug, Eq, PartialEq, Validate)]
@@ -40,6 +69,13 @@ pub struct NotificationHeaders {
     )]
     pub topic: Option<String>,
 
+    #[validate(regex(
+        path = "VALID_URGENCY",
+        message = "Urgency must be one of very-low, low, normal or high",
+        code = "115"
+    ))]
+    pub urgency: String,
+
     // These fields are validated separately, because the validation is complex
     // and based upon the content encoding
     pub encoding: Option<String>,
@@ -56,30 +92,101 @@ impl From<NotificationHeaders> for HashMap<String, String> {
         map.insert_opt("encryption", headers.encryption);
         map.insert_opt("encryption_key", headers.encryption_key);
         map.insert_opt("crypto_key", headers.crypto_key);
+        // Only preserve a non-default urgency, so messages sent without one
+        // are stored (and delivered) exactly as before
+        if headers.urgency != DEFAULT_URGENCY {
+            map.insert("urgency".to_owned(), headers.urgency);
+        }
 
         map
     }
 }
 
+/// A compression of the notification body on top of its WebPush encoding,
+/// e.g. `Content-Encoding: aes128gcm, gzip`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Deflate,
+}
+
+impl Compression {
+    fn from_coding(coding: &str) -> Option<Self> {
+        match coding.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Get the compression listed in the request's `Content-Encoding`
+    pub fn from_request(req: &impl RequestHeaders) -> ApiResult<Option<Self>> {
+        let Some(encoding) = get_header(req, "content-encoding") else {
+            return Ok(None);
+        };
+        let mut compressions = encoding
+            .split(',')
+            .filter_map(|coding| Self::from_coding(coding.trim()));
+        let compression = compressions.next();
+        if compressions.next().is_some() {
+            return Err(ApiErrorKind::InvalidEncryption(
+                "Content-Encoding header must have no more than one compression".to_string(),
+            )
+            .into());
+        }
+        Ok(compression)
+    }
+
+    /// Decompress the body, rejecting it once its decompressed size exceeds
+    /// `max_bytes` (without inflating the rest of it)
+    pub fn decompress(self, data: &[u8], max_bytes: usize) -> ApiResult<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Compression::Gzip => Box::new(GzDecoder::new(data)),
+            // HTTP's "deflate" is the zlib format
+            Compression::Deflate => Box::new(ZlibDecoder::new(data)),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .take((max_bytes as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|e| {
+                ApiErrorKind::InvalidEncryption(format!("Could not decompress the body: {e}"))
+            })?;
+        if decompressed.len() > max_bytes {
+            return Err(ApiErrorKind::PayloadTooLarge(max_bytes).into());
+        }
+        Ok(decompressed)
+    }
+}
+
 impl NotificationHeaders {
     /// Extract the notification headers from a request.
     /// This can not be implemented as a `FromRequest` impl because we need to
     /// know if the payload has data, without actually advancing the payload
     /// stream.
-    pub fn from_request(req: &HttpRequest, has_data: bool) -> ApiResult<Self> {
+    pub fn from_request(
+        req: &impl RequestHeaders,
+        has_data: bool,
+        ttl_limits: &TtlLimits,
+    ) -> ApiResult<Self> {
         // Collect raw headers
-        let ttl = get_header(req, "ttl")
-            .and_then(|ttl| ttl.parse().ok())
-            // Enforce a maximum TTL, but don't error
-            .map(|ttl| min(ttl, MAX_TTL))
-            .ok_or(ApiErrorKind::NoTTL)?;
+        let ttl = match Self::requested_ttl(req)? {
+            // Enforce a maximum TTL, but don't error (negative TTLs are
+            // rejected by the validation below)
+            Some(ttl) => min(ttl, ttl_limits.max),
+            None => ttl_limits.default.ok_or(ApiErrorKind::NoTTL)?,
+        };
         let topic = get_owned_header(req, "topic");
+        let urgency =
+            get_owned_header(req, "urgency").unwrap_or_else(|| DEFAULT_URGENCY.to_owned());
 
         let headers = if has_data {
             NotificationHeaders {
                 ttl,
                 topic,
-                encoding: get_owned_header(req, "content-encoding"),
+                urgency,
+                encoding: get_owned_header(req, "content-encoding")
+                    .and_then(Self::strip_compression),
                 encryption: get_owned_header(req, "encryption").map(Self::strip_header),
                 encryption_key: get_owned_header(req, "encryption-key"),
                 crypto_key: get_owned_header(req, "crypto-key").map(Self::strip_header),
@@ -89,6 +196,7 @@ impl NotificationHeaders {
             NotificationHeaders {
                 ttl,
                 topic,
+                urgency,
                 encoding: None,
                 encryption: None,
                 encryption_key: None,
@@ -108,6 +216,84 @@ impl NotificationHeaders {
         }
     }
 
+    /// The request's `TTL` header, `None` when it has none. Non-numeric TTLs
+    /// are rejected.
+    pub fn requested_ttl(req: &impl RequestHeaders) -> ApiResult<Option<i64>> {
+        get_header(req, "ttl")
+            .map(|ttl| ttl.parse().map_err(|_| ApiErrorKind::InvalidTtl.into()))
+            .transpose()
+    }
+
+    /// Reject `Crypto-Key` and `Encryption` headers longer than `max_bytes`
+    /// or with more than `max_params` parameters, before they're parsed
+    pub fn check_crypto_key_limits(
+        req: &impl RequestHeaders,
+        max_bytes: usize,
+        max_params: usize,
+    ) -> ApiResult<()> {
+        for (header_name, header) in [("Crypto-Key", "crypto-key"), ("Encryption", "encryption")] {
+            let Some(header) = get_header(req, header) else {
+                continue;
+            };
+            if header.len() > max_bytes {
+                return Err(ApiErrorKind::InvalidEncryption(format!(
+                    "{header_name} header must be no greater than {max_bytes} bytes"
+                ))
+                .into());
+            }
+            if header.split([',', ';']).count() > max_params {
+                return Err(ApiErrorKind::InvalidEncryption(format!(
+                    "{header_name} header must have no more than {max_params} parameters"
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject a body whose WebPush `Content-Encoding` isn't accepted (e.g. a
+    /// mistaken `application/json`). Bodyless notifications need none.
+    pub fn check_encoding(
+        req: &impl RequestHeaders,
+        has_data: bool,
+        aesgcm_enabled: bool,
+    ) -> ApiResult<()> {
+        if !has_data {
+            return Ok(());
+        }
+        let accepted = if aesgcm_enabled {
+            ENCODINGS
+        } else {
+            ENCODINGS_WITHOUT_AESGCM
+        };
+        match get_owned_header(req, "content-encoding").and_then(Self::strip_compression) {
+            Some(encoding) if !accepted.contains(&encoding.as_str()) => {
+                Err(Self::unknown_encoding(accepted))
+            }
+            // A missing header's rejected by validate_encryption
+            _ => Ok(()),
+        }
+    }
+
+    fn unknown_encoding(accepted: &[&str]) -> ApiError {
+        ApiErrorKind::InvalidEncryption(format!(
+            "Unknown Content-Encoding header, must be one of: {}",
+            accepted.join(", ")
+        ))
+        .into()
+    }
+
+    /// Remove any compression from the `Content-Encoding`, leaving the
+    /// WebPush encoding
+    fn strip_compression(encoding: String) -> Option<String> {
+        let codings: Vec<&str> = encoding
+            .split(',')
+            .map(str::trim)
+            .filter(|coding| Compression::from_coding(coding).is_none())
+            .collect();
+        (!codings.is_empty()).then(|| codings.join(", "))
+    }
+
     /// Remove Base64 padding and double-quotes
     fn strip_header(header: String) -> String {
         let header = header.replace('"', "");
@@ -124,12 +310,7 @@ impl NotificationHeaders {
         match encoding {
             "aesgcm" => self.validate_encryption_04_rules()?,
             "aes128gcm" => self.validate_encryption_06_rules()?,
-            _ => {
-                return Err(ApiErrorKind::InvalidEncryption(
-                    "Unknown Content-Encoding header".to_string(),
-                )
-                .into());
-            }
+            _ => return Err(Self::unknown_encoding(ENCODINGS)),
         }
 
         Ok(())
@@ -147,9 +328,9 @@ impl NotificationHeaders {
             .into());
         }
 
-        if self.crypto_key.is_some() {
-            Self::assert_base64_item_exists("Crypto-Key", self.crypto_key.as_deref(), "dh")?;
-        }
+        // The receiver needs the sender's public key to derive the
+        // decryption key
+        Self::assert_base64_item_exists("Crypto-Key", self.crypto_key.as_deref(), "dh")?;
 
         Ok(())
     }
@@ -216,9 +397,11 @@ impl NotificationHeaders {
 #[cfg(test)]
 mod tests {
     use super::NotificationHeaders;
-    use super::MAX_TTL;
+    use super::{Compression, TtlLimits, DEFAULT_MAX_TTL};
     use crate::error::{ApiErrorKind, ApiResult};
     use actix_web::test::TestRequest;
+    use flate2::{write::GzEncoder, write::ZlibEncoder};
+    use std::io::Write;
 
     /// Assert that a result is a validation error and check its serialization
     /// against the JSON value.
@@ -252,7 +435,7 @@ mod tests {
         let req = TestRequest::post()
             .insert_header(("TTL", "10"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap().ttl, 10);
@@ -264,7 +447,7 @@ mod tests {
         let req = TestRequest::post()
             .insert_header(("TTL", "-1"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
 
         assert_validation_error(
             result,
@@ -285,12 +468,55 @@ mod tests {
     #[test]
     fn maximum_ttl() {
         let req = TestRequest::post()
-            .insert_header(("TTL", (MAX_TTL + 1).to_string()))
+            .insert_header(("TTL", (DEFAULT_MAX_TTL + 1).to_string()))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
 
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().ttl, MAX_TTL);
+        assert_eq!(result.unwrap().ttl, DEFAULT_MAX_TTL);
+    }
+
+    /// Without a TTL, the configured default applies, otherwise it's rejected
+    #[test]
+    fn missing_ttl() {
+        let req = TestRequest::post().to_http_request();
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
+        assert!(matches!(result.unwrap_err().kind, ApiErrorKind::NoTTL));
+
+        let limits = TtlLimits {
+            default: Some(300),
+            ..Default::default()
+        };
+        let result = NotificationHeaders::from_request(&req, false, &limits);
+        assert_eq!(result.unwrap().ttl, 300);
+    }
+
+    /// Non-numeric TTLs are rejected
+    #[test]
+    fn invalid_ttl() {
+        for ttl in ["abc", "1.5", ""] {
+            let req = TestRequest::post()
+                .insert_header(("TTL", ttl))
+                .to_http_request();
+            let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
+            let err = result.unwrap_err();
+            assert!(matches!(err.kind, ApiErrorKind::InvalidTtl), "{ttl:?}");
+            assert_eq!(err.kind.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    /// The configured maximum TTL applies
+    #[test]
+    fn configured_maximum_ttl() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "3601"))
+            .to_http_request();
+        let limits = TtlLimits {
+            max: 3600,
+            default: None,
+        };
+        let result = NotificationHeaders::from_request(&req, false, &limits);
+        assert_eq!(result.unwrap().ttl, 3600);
     }
 
     /// A valid topic results in no errors
@@ -300,7 +526,7 @@ mod tests {
             .insert_header(("TTL", "10"))
             .insert_header(("TOPIC", "a-test-topic-which-is-just-right"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
 
         assert!(result.is_ok());
         assert_eq!(
@@ -316,7 +542,7 @@ mod tests {
             .insert_header(("TTL", "10"))
             .insert_header(("TOPIC", "test-topic-which-is-too-long-1234"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, false);
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
 
         assert_validation_error(
             result,
@@ -333,13 +559,55 @@ mod tests {
         );
     }
 
+    /// A missing urgency defaults to normal and valid values are accepted
+    #[test]
+    fn valid_urgency() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
+        assert_eq!(result.unwrap().urgency, "normal");
+
+        for urgency in ["very-low", "low", "normal", "high"] {
+            let req = TestRequest::post()
+                .insert_header(("TTL", "10"))
+                .insert_header(("Urgency", urgency))
+                .to_http_request();
+            let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
+            assert_eq!(result.unwrap().urgency, urgency);
+        }
+    }
+
+    /// Unknown urgency values return an error
+    #[test]
+    fn invalid_urgency() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Urgency", "urgent"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, false, &TtlLimits::default());
+
+        assert_validation_error(
+            result,
+            serde_json::json!({
+                "urgency": [{
+                    "code": "115",
+                    "message": "Urgency must be one of very-low, low, normal or high",
+                    "params": {
+                        "value": "urgent"
+                    }
+                }]
+            }),
+        );
+    }
+
     /// If there is a payload, there must be a content encoding header
     #[test]
     fn payload_without_content_encoding() {
         let req = TestRequest::post()
             .insert_header(("TTL", "10"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
 
         assert_encryption_error(result, "Missing Content-Encoding header");
     }
@@ -353,7 +621,7 @@ mod tests {
             .insert_header(("Encryption", "salt=foo"))
             .insert_header(("Crypto-Key", "dh=bar"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
 
         assert!(result.is_ok());
         assert_eq!(
@@ -361,6 +629,7 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: "normal".to_string(),
                 encoding: Some("aesgcm".to_string()),
                 encryption: Some("salt=foo".to_string()),
                 encryption_key: None,
@@ -378,7 +647,7 @@ mod tests {
             .insert_header(("Encryption", "notsalt=foo"))
             .insert_header(("Crypto-Key", "notdh=bar"))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
 
         assert!(result.is_ok());
         assert_eq!(
@@ -386,6 +655,7 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: "normal".to_string(),
                 encoding: Some("aes128gcm".to_string()),
                 encryption: Some("notsalt=foo".to_string()),
                 encryption_key: None,
@@ -404,7 +674,7 @@ mod tests {
             .insert_header(("Encryption", "salt=\"foo\""))
             .insert_header(("Crypto-Key", "keyid=\"p256dh\";dh=\"deadbeef==\""))
             .to_http_request();
-        let result = NotificationHeaders::from_request(&req, true);
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
 
         assert!(result.is_ok());
         assert_eq!(
@@ -412,6 +682,7 @@ mod tests {
             NotificationHeaders {
                 ttl: 10,
                 topic: None,
+                urgency: "normal".to_string(),
                 encoding: Some("aesgcm".to_string()),
                 encryption: Some("salt=foo".to_string()),
                 encryption_key: None,
@@ -420,5 +691,272 @@ mod tests {
         );
     }
 
-    // TODO: Add negative test cases for encryption validation?
+    /// aesgcm requires the Encryption salt and the Crypto-Key dh values
+    #[test]
+    fn invalid_04_encryption() {
+        let cases: [(Option<&str>, Option<&str>, &str); 6] = [
+            (None, Some("dh=bar"), "Missing Encryption header"),
+            (
+                Some("rs=4096"),
+                Some("dh=bar"),
+                "Missing salt value in Encryption header",
+            ),
+            (
+                Some("salt=f!o"),
+                Some("dh=bar"),
+                "Invalid salt value in Encryption header",
+            ),
+            (Some("salt=foo"), None, "Missing Crypto-Key header"),
+            (
+                Some("salt=foo"),
+                Some("p256ecdsa=bar"),
+                "Missing dh value in Crypto-Key header",
+            ),
+            (
+                Some("salt=foo"),
+                Some("dh=b+r"),
+                "Invalid dh value in Crypto-Key header",
+            ),
+        ];
+        for (encryption, crypto_key, expected_error) in cases {
+            let mut req = TestRequest::post()
+                .insert_header(("TTL", "10"))
+                .insert_header(("Content-Encoding", "aesgcm"));
+            if let Some(encryption) = encryption {
+                req = req.insert_header(("Encryption", encryption));
+            }
+            if let Some(crypto_key) = crypto_key {
+                req = req.insert_header(("Crypto-Key", crypto_key));
+            }
+            let result = NotificationHeaders::from_request(
+                &req.to_http_request(),
+                true,
+                &TtlLimits::default(),
+            );
+
+            assert_encryption_error(result, expected_error);
+        }
+    }
+
+    /// aes128gcm carries its encryption values in the payload, so mixing in
+    /// the aesgcm header values is rejected
+    #[test]
+    fn mixed_06_encryption() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "aes128gcm"))
+            .insert_header(("Encryption", "salt=foo"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
+        assert_encryption_error(
+            result,
+            "Do not include 'salt' header in aes128gcm Encryption header",
+        );
+
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "aes128gcm"))
+            .insert_header(("Crypto-Key", "dh=bar"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
+        assert_encryption_error(
+            result,
+            "Do not include 'dh' header in aes128gcm Crypto-Key header",
+        );
+    }
+
+    /// Unknown encodings are rejected
+    #[test]
+    fn unknown_encryption() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "aesgcm128"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
+
+        assert_encryption_error(
+            result,
+            "Unknown Content-Encoding header, must be one of: aes128gcm, aesgcm",
+        );
+    }
+
+    /// Each accepted encoding passes the allowlist, compressed or not
+    #[test]
+    fn accepted_encodings() {
+        for encoding in ["aes128gcm", "aesgcm", "aes128gcm, gzip"] {
+            let req = TestRequest::post()
+                .insert_header(("Content-Encoding", encoding))
+                .to_http_request();
+            assert!(
+                NotificationHeaders::check_encoding(&req, true, true).is_ok(),
+                "{encoding}"
+            );
+        }
+
+        let req = TestRequest::post()
+            .insert_header(("Content-Encoding", "aes128gcm"))
+            .to_http_request();
+        assert!(NotificationHeaders::check_encoding(&req, true, false).is_ok());
+    }
+
+    /// Other encodings, and aesgcm unless it's enabled, are rejected with
+    /// the accepted ones
+    #[test]
+    fn rejected_encodings() {
+        let req = TestRequest::post()
+            .insert_header(("Content-Encoding", "application/json"))
+            .to_http_request();
+        let error = NotificationHeaders::check_encoding(&req, true, true).unwrap_err();
+        assert_eq!(
+            error.kind.status(),
+            actix_web::http::StatusCode::BAD_REQUEST
+        );
+        assert!(matches!(
+            error.kind,
+            ApiErrorKind::InvalidEncryption(msg)
+                if msg == "Unknown Content-Encoding header, must be one of: aes128gcm, aesgcm"
+        ));
+
+        let req = TestRequest::post()
+            .insert_header(("Content-Encoding", "aesgcm"))
+            .to_http_request();
+        let error = NotificationHeaders::check_encoding(&req, true, false).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ApiErrorKind::InvalidEncryption(msg)
+                if msg == "Unknown Content-Encoding header, must be one of: aes128gcm"
+        ));
+    }
+
+    /// A notification without a body needs no encoding, whatever's sent
+    #[test]
+    fn empty_body_encoding() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .to_http_request();
+        assert!(NotificationHeaders::check_encoding(&req, false, false).is_ok());
+        assert!(NotificationHeaders::from_request(&req, false, &TtlLimits::default()).is_ok());
+
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "application/json"))
+            .to_http_request();
+        assert!(NotificationHeaders::check_encoding(&req, false, false).is_ok());
+    }
+
+    /// Oversized Crypto-Key and Encryption headers are rejected
+    #[test]
+    fn crypto_key_limits() {
+        let check = |name: &str, value: &str| {
+            let req = TestRequest::post()
+                .insert_header((name, value))
+                .to_http_request();
+            match NotificationHeaders::check_crypto_key_limits(&req, 64, 4) {
+                Ok(()) => None,
+                Err(e) => match e.kind {
+                    ApiErrorKind::InvalidEncryption(error) => Some(error),
+                    _ => panic!("Expected an encryption error"),
+                },
+            }
+        };
+
+        assert_eq!(
+            check("Crypto-Key", "keyid=p256dh;dh=bar,p256ecdsa=baz"),
+            None
+        );
+        assert_eq!(
+            check("Crypto-Key", &format!("dh={}", "a".repeat(64))),
+            Some("Crypto-Key header must be no greater than 64 bytes".to_owned())
+        );
+        assert_eq!(
+            check("Encryption", "salt=a;b=1;c=2,d=3;e=4"),
+            Some("Encryption header must have no more than 4 parameters".to_owned())
+        );
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The compression is stripped from the WebPush encoding
+    #[test]
+    fn compressed_encoding() {
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "aes128gcm, gzip"))
+            .to_http_request();
+        assert_eq!(
+            Compression::from_request(&req).unwrap(),
+            Some(Compression::Gzip)
+        );
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
+        assert_eq!(result.unwrap().encoding.as_deref(), Some("aes128gcm"));
+
+        let req = TestRequest::post()
+            .insert_header(("TTL", "10"))
+            .insert_header(("Content-Encoding", "gzip"))
+            .to_http_request();
+        let result = NotificationHeaders::from_request(&req, true, &TtlLimits::default());
+        assert_encryption_error(result, "Missing Content-Encoding header");
+
+        let req = TestRequest::post()
+            .insert_header(("Content-Encoding", "aes128gcm, gzip, deflate"))
+            .to_http_request();
+        assert!(Compression::from_request(&req).is_err());
+        let req = TestRequest::post()
+            .insert_header(("Content-Encoding", "aes128gcm"))
+            .to_http_request();
+        assert_eq!(Compression::from_request(&req).unwrap(), None);
+    }
+
+    /// Compressed bodies within the limit are decompressed
+    #[test]
+    fn decompress() {
+        let data = b"encrypted notification data".repeat(100);
+        assert_eq!(
+            Compression::Gzip.decompress(&gzip(&data), 4096).unwrap(),
+            data
+        );
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let deflated = encoder.finish().unwrap();
+        assert_eq!(
+            Compression::Deflate.decompress(&deflated, 4096).unwrap(),
+            data
+        );
+
+        let result = Compression::Gzip.decompress(b"not gzip", 4096);
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::InvalidEncryption(_)
+        ));
+    }
+
+    /// Compression bombs are rejected without inflating them entirely
+    #[test]
+    fn decompression_bomb() {
+        let bomb = gzip(&vec![0; 16 * 1024 * 1024]);
+        assert!(bomb.len() < 100 * 1024);
+        let result = Compression::Gzip.decompress(&bomb, 4096);
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::PayloadTooLarge(4096)
+        ));
+
+        // Truncated, so fully inflating it would fail instead
+        let truncated = &bomb[..bomb.len() / 2];
+        let result = Compression::Gzip.decompress(truncated, 4096);
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::PayloadTooLarge(4096)
+        ));
+        let result = Compression::Gzip.decompress(truncated, 16 * 1024 * 1024);
+        assert!(matches!(
+            result.unwrap_err().kind,
+            ApiErrorKind::InvalidEncryption(_)
+        ));
+    }
 }
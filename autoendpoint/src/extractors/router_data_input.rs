@@ -10,6 +10,10 @@ lazy_static! {
     static ref VALID_TOKEN: Regex = Regex::new(r"^[^ ]{8,}$").unwrap();
     static ref VALID_ADM_TOKEN: Regex =
         Regex::new(r"^amzn1.adm-registration.v3.[^ ]{256,}$").unwrap();
+    /// The basic form of a BCP 47 language tag: a language, optionally
+    /// followed by a script and a region (e.g. `en`, `pt-BR`, `zh-Hant-TW`)
+    static ref VALID_LOCALE: Regex =
+        Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z]{4})?(-([A-Za-z]{2}|[0-9]{3}))?$").unwrap();
 }
 
 /// Extracts the router data from the request body and validates the token
@@ -21,6 +25,64 @@ pub struct RouterDataInput {
     pub channel_id: Option<Uuid>,
     pub key: Option<String>,
     pub aps: Option<String>,
+    /// The APNS topic (bundle ID) to send this subscription's notifications
+    /// with, instead of the release channel's
+    pub apns_topic: Option<String>,
+    /// The `apns-push-type` of this subscription's notifications, instead of
+    /// the default (`apns.default_push_type`)
+    pub apns_push_type: Option<String>,
+    /// The app the subscription's for, tagging its notifications' metrics.
+    /// Only stored when it's one of the `registered_apps`.
+    pub app: Option<String>,
+    /// The subscription's preferred locale (a BCP 47 language tag), passed
+    /// on to its bridge for localizing any fallback text
+    pub locale: Option<String>,
+    /// The bridges to fall back to, in order, when this one's permanently
+    /// gone
+    #[serde(default)]
+    pub fallback_routes: Vec<FallbackRouteInput>,
+}
+
+/// A bridge a subscription falls back to, registered like the primary one
+#[derive(serde::Deserialize)]
+pub struct FallbackRouteInput {
+    pub router_type: String,
+    pub app_id: String,
+    pub token: String,
+    pub aps: Option<String>,
+    pub apns_topic: Option<String>,
+    pub apns_push_type: Option<String>,
+}
+
+impl FallbackRouteInput {
+    /// The input to register the fallback with its router
+    pub fn router_data_input(&self) -> RouterDataInput {
+        RouterDataInput {
+            token: self.token.clone(),
+            channel_id: None,
+            key: None,
+            aps: self.aps.clone(),
+            apns_topic: self.apns_topic.clone(),
+            apns_push_type: self.apns_push_type.clone(),
+            app: None,
+            locale: None,
+            fallback_routes: Vec::new(),
+        }
+    }
+}
+
+/// Validate the locale against the basic form of a BCP 47 language tag
+pub fn is_valid_locale(locale: &str) -> bool {
+    VALID_LOCALE.is_match(locale)
+}
+
+/// Validate the token according to the router's token schema
+pub fn is_valid_token(router_type: RouterType, token: &str) -> bool {
+    match router_type {
+        RouterType::WebPush => true,
+        RouterType::FCM | RouterType::GCM | RouterType::APNS => VALID_TOKEN.is_match(token),
+        RouterType::ADM => VALID_ADM_TOKEN.is_match(token),
+    }
 }
 
 impl FromRequest for RouterDataInput {
@@ -37,21 +99,42 @@ impl FromRequest for RouterDataInput {
                 .await
                 .map_err(ApiErrorKind::PayloadError)?;
 
-            // Validate the token according to each router's token schema
-            let is_valid = match path_args.router_type {
-                RouterType::WebPush => true,
-                RouterType::FCM | RouterType::GCM | RouterType::APNS => {
-                    VALID_TOKEN.is_match(&data.token)
-                }
-                RouterType::ADM => VALID_ADM_TOKEN.is_match(&data.token),
-            };
-
-            if !is_valid {
+            if !is_valid_token(path_args.router_type, &data.token) {
                 return Err(ApiErrorKind::InvalidRouterToken.into());
             }
+            if let Some(locale) = data.locale.as_ref().filter(|l| !is_valid_locale(l)) {
+                return Err(ApiErrorKind::InvalidLocale(locale.clone()).into());
+            }
 
             Ok(data.into_inner())
         }
         .boxed_local()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_locale;
+
+    #[test]
+    fn valid_locales() {
+        for locale in ["en", "en-US", "pt-BR", "zh-Hant-TW", "es-419", "fil"] {
+            assert!(is_valid_locale(locale), "{locale}");
+        }
+    }
+
+    #[test]
+    fn invalid_locales() {
+        for locale in [
+            "",
+            "e",
+            "en_US",
+            "en-",
+            "english",
+            "en-US-x-private",
+            "en US",
+        ] {
+            assert!(!is_valid_locale(locale), "{locale}");
+        }
+    }
+}
@@ -1,13 +1,19 @@
 use crate::error::{ApiError, ApiResult};
 use crate::routers::adm::router::AdmRouter;
 use crate::routers::apns::router::ApnsRouter;
+use crate::routers::circuit_breaker::{BridgeBreakers, CircuitBreaker};
+use crate::routers::concurrency::{BridgeLimiters, ConcurrencyLimiter};
 use crate::routers::fcm::router::FcmRouter;
+use crate::routers::retry::BridgeRetry;
 use crate::routers::webpush::WebPushRouter;
 use crate::routers::Router;
 use crate::server::AppState;
+use crate::stats::Stats;
 use actix_web::dev::Payload;
 use actix_web::web::Data;
 use actix_web::{FromRequest, HttpRequest};
+use autopush_common::db::client::DbClient;
+use cadence::StatsdClient;
 use futures::future;
 use std::fmt::{self, Display};
 use std::str::FromStr;
@@ -24,6 +30,16 @@ pub enum RouterType {
     ADM,
 }
 
+impl RouterType {
+    pub const ALL: [RouterType; 5] = [
+        RouterType::WebPush,
+        RouterType::FCM,
+        RouterType::GCM,
+        RouterType::APNS,
+        RouterType::ADM,
+    ];
+}
+
 impl FromStr for RouterType {
     type Err = ();
 
@@ -58,6 +74,14 @@ pub struct Routers {
     fcm: Arc<FcmRouter>,
     apns: Arc<ApnsRouter>,
     adm: Arc<AdmRouter>,
+    breakers: Arc<BridgeBreakers>,
+    limiters: Arc<BridgeLimiters>,
+    retry: Arc<BridgeRetry>,
+    metrics: Arc<StatsdClient>,
+    stats: Arc<Stats>,
+    db: Box<dyn DbClient>,
+    /// The router types in `allowed_bridges`
+    allowed_bridges: Vec<RouterType>,
 }
 
 impl FromRequest for Routers {
@@ -69,21 +93,39 @@ impl FromRequest for Routers {
             .into_inner()
             .expect("No server state found");
 
-        future::ok(Routers {
+        future::ok(Routers::new(&app_state))
+    }
+}
+
+impl Routers {
+    pub fn new(app_state: &AppState) -> Self {
+        Routers {
             webpush: WebPushRouter {
                 db: app_state.db.clone(),
                 metrics: app_state.metrics.clone(),
                 http: app_state.http.clone(),
                 endpoint_url: app_state.settings.endpoint_url(),
+                max_stored_messages: app_state.settings.max_stored_messages_per_subscription,
+                stored_message_overflow: app_state.settings.stored_message_overflow,
+                storage_enabled: app_state.settings.storage_enabled,
+                stats: app_state.stats.clone(),
             },
             fcm: app_state.fcm_router.clone(),
             apns: app_state.apns_router.clone(),
             adm: app_state.adm_router.clone(),
-        })
+            breakers: app_state.bridge_breakers.clone(),
+            limiters: app_state.bridge_limiters.clone(),
+            retry: app_state.bridge_retry.clone(),
+            metrics: app_state.metrics.clone(),
+            stats: app_state.stats.clone(),
+            db: app_state.db.clone(),
+            allowed_bridges: RouterType::ALL
+                .into_iter()
+                .filter(|router_type| app_state.settings.bridge_allowed(*router_type))
+                .collect(),
+        }
     }
-}
 
-impl Routers {
     /// Get the router which handles the router type
     pub fn get(&self, router_type: RouterType) -> &dyn Router {
         match router_type {
@@ -93,4 +135,52 @@ impl Routers {
             RouterType::ADM => self.adm.as_ref(),
         }
     }
+
+    pub fn metrics(&self) -> &StatsdClient {
+        &self.metrics
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn db(&self) -> &dyn DbClient {
+        self.db.as_ref()
+    }
+
+    /// Whether notifications may be routed via the router type
+    pub fn bridge_allowed(&self, router_type: RouterType) -> bool {
+        self.allowed_bridges.contains(&router_type)
+    }
+
+    /// Get the circuit breaker for the router type (WebPush has none)
+    pub fn breaker(&self, router_type: RouterType) -> Option<&CircuitBreaker> {
+        match router_type {
+            RouterType::WebPush => None,
+            RouterType::FCM | RouterType::GCM => Some(&self.breakers.fcm),
+            RouterType::APNS => Some(&self.breakers.apns),
+            RouterType::ADM => Some(&self.breakers.adm),
+        }
+    }
+
+    /// Get the concurrency limiter for the router type (WebPush has none)
+    pub fn limiter(&self, router_type: RouterType) -> Option<&ConcurrencyLimiter> {
+        match router_type {
+            RouterType::WebPush => None,
+            RouterType::FCM | RouterType::GCM => Some(&self.limiters.fcm),
+            RouterType::APNS => Some(&self.limiters.apns),
+            RouterType::ADM => Some(&self.limiters.adm),
+        }
+    }
+
+    /// Get the retrier of bridge requests for the router type (WebPush has
+    /// none)
+    pub fn retry(&self, router_type: RouterType) -> Option<&BridgeRetry> {
+        match router_type {
+            RouterType::WebPush => None,
+            RouterType::FCM | RouterType::GCM | RouterType::APNS | RouterType::ADM => {
+                Some(&self.retry)
+            }
+        }
+    }
 }
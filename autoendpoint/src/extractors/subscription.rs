@@ -9,6 +9,7 @@ use autopush_common::{
     util::{b64_decode_std, b64_decode_url, sec_since_epoch},
 };
 use cadence::{CountedExt, StatsdClient};
+use fernet::Fernet;
 use futures::{future::LocalBoxFuture, FutureExt};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use openssl::hash::MessageDigest;
@@ -26,6 +27,7 @@ use crate::headers::{
     vapid::{VapidError, VapidHeader, VapidHeaderWithKey, VapidVersionData},
 };
 use crate::metrics::Metrics;
+use crate::otel;
 use crate::server::AppState;
 
 const ONE_DAY_IN_SECONDS: u64 = 60 * 60 * 24;
@@ -41,8 +43,14 @@ pub struct Subscription {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VapidClaims {
     exp: u64,
+    // A missing `aud` is reported as an invalid audience
+    #[serde(default)]
     aud: String,
+    // A missing `sub` is checked by `check_subject_uri`
+    #[serde(default)]
     sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat: Option<u64>,
 }
 
 impl Default for VapidClaims {
@@ -51,6 +59,7 @@ impl Default for VapidClaims {
             exp: sec_since_epoch() + ONE_DAY_IN_SECONDS,
             aud: "No audience".to_owned(),
             sub: "No sub".to_owned(),
+            iat: None,
         }
     }
 }
@@ -61,6 +70,7 @@ impl fmt::Display for VapidClaims {
             .field("exp", &self.exp)
             .field("aud", &self.aud)
             .field("sub", &self.sub)
+            .field("iat", &self.iat)
             .finish()
     }
 }
@@ -74,70 +84,96 @@ impl FromRequest for Subscription {
         async move {
             // Collect token info and server state
             let token_info = TokenInfo::extract(&req).await?;
-            trace!("Token info: {:?}", &token_info);
             let app_state: Data<AppState> =
                 Data::extract(&req).await.expect("No server state found");
-            let metrics = Metrics::from(&app_state);
-
-            // Decrypt the token
-            let token = app_state
-                .fernet
-                .decrypt(&repad_base64(&token_info.token))
-                .map_err(|e| {
-                    error!("fernet: {:?}", e);
-                    ApiErrorKind::InvalidToken
-                })?;
-
-            // Parse VAPID and extract public key.
-            let vapid: Option<VapidHeaderWithKey> = parse_vapid(&token_info, &app_state.metrics)?
-                .map(|vapid| extract_public_key(vapid, &token_info))
-                .transpose()?;
-
-            trace!("Vapid: {:?}", &vapid);
-
-            match token_info.api_version {
-                ApiVersion::Version1 => version_1_validation(&token)?,
-                ApiVersion::Version2 => version_2_validation(&token, vapid.as_ref())?,
-            }
+            Self::validate(token_info, &app_state).await
+        }
+        .boxed_local()
+    }
+}
 
-            // Load and validate user data.
-            // Note: It is safe to unwrap the Uuid result because an error is
-            // only returned if the slice length is not 16.
-            let uaid = Uuid::from_slice(&token[..16]).unwrap();
-            let channel_id = Uuid::from_slice(&token[16..32]).unwrap();
+impl Subscription {
+    /// Look up and validate the subscription of an endpoint's `token_info`,
+    /// including its VAPID authorization
+    pub async fn validate(token_info: TokenInfo, app_state: &Data<AppState>) -> ApiResult<Self> {
+        trace!("Token info: {:?}", &token_info);
+        let metrics = Metrics::from(app_state);
 
-            trace!("UAID: {:?}, CHID: {:?}", uaid, channel_id);
+        // Reject structurally invalid tokens before doing any work
+        validate_token_structure(
+            &token_info.token,
+            token_info.api_version,
+            &app_state.settings.endpoint_token_versions,
+            &metrics,
+        )?;
 
-            let user = app_state
-                .db
-                .get_user(&uaid)
-                .await?
-                .ok_or(ApiErrorKind::NoSubscription)?;
+        // Decrypt the token
+        let token = decrypt_token(
+            &app_state.fernet_keys,
+            &repad_base64(&token_info.token),
+            &metrics,
+        )?;
 
-            trace!("user: {:?}", &user);
-            validate_user(&user, &channel_id, &app_state).await?;
+        // Parse VAPID and extract public key.
+        let vapid: Option<VapidHeaderWithKey> = parse_vapid(&token_info, &app_state.metrics)?
+            .map(|vapid| extract_public_key(vapid, &token_info))
+            .transpose()?;
 
-            // Validate the VAPID JWT token and record the version
-            if let Some(vapid) = &vapid {
-                validate_vapid_jwt(vapid, &app_state.settings.endpoint_url(), &metrics)?;
+        trace!("Vapid: {:?}", &vapid);
 
-                app_state
-                    .metrics
-                    .incr(&format!("updates.vapid.draft{:02}", vapid.vapid.version()))?;
-            }
+        match token_info.api_version {
+            ApiVersion::Version1 => version_1_validation(&token)?,
+            ApiVersion::Version2 => version_2_validation(&token, vapid.as_ref(), &metrics)?,
+        }
+
+        // Load and validate user data.
+        // Note: It is safe to unwrap the Uuid result because an error is
+        // only returned if the slice length is not 16.
+        let uaid = Uuid::from_slice(&token[..16]).unwrap();
+        let channel_id = Uuid::from_slice(&token[16..32]).unwrap();
+
+        trace!("UAID: {:?}, CHID: {:?}", uaid, channel_id);
 
-            Ok(Subscription {
-                user,
-                channel_id,
+        let user = app_state
+            .db
+            .get_user(&uaid)
+            .await?
+            .ok_or(ApiErrorKind::NoSubscription)?;
+
+        trace!("user: {:?}", &user);
+        validate_user(&user, &channel_id, app_state).await?;
+
+        // Validate the VAPID JWT token and record the version
+        if let Some(vapid) = &vapid {
+            let _span = otel::span("vapid.validate");
+            let claims = validate_vapid_jwt(
                 vapid,
-            })
+                &app_state.vapid_audiences,
+                app_state.settings.vapid_exp_leeway_secs,
+                &metrics,
+            )?;
+            check_subject_uri(&claims.sub, app_state.settings.vapid_require_sub, &metrics)?;
+            check_subject(
+                &claims.sub,
+                &app_state.live_settings.get().blocked_vapid_subjects,
+                &metrics,
+            )?;
+
+            app_state
+                .metrics
+                .incr(&format!("updates.vapid.draft{:02}", vapid.vapid.version()))?;
         }
-        .boxed_local()
+
+        Ok(Subscription {
+            user,
+            channel_id,
+            vapid,
+        })
     }
 }
 
 /// Add back padding to a base64 string
-fn repad_base64(data: &str) -> Cow<'_, str> {
+pub(crate) fn repad_base64(data: &str) -> Cow<'_, str> {
     let trailing_chars = data.len() % 4;
 
     if trailing_chars != 0 {
@@ -162,10 +198,14 @@ fn parse_vapid(token_info: &TokenInfo, metrics: &StatsdClient) -> ApiResult<Opti
 
     let vapid = VapidHeader::parse(auth_header)?;
 
+    metrics
+        .incr_with_tags("vapid.scheme")
+        .with_tag("scheme", vapid.scheme.as_str())
+        .send();
     metrics
         .incr_with_tags("notification.auth")
         .with_tag("vapid", &vapid.version().to_string())
-        .with_tag("scheme", &vapid.scheme)
+        .with_tag("scheme", vapid.scheme.as_str())
         .send();
 
     Ok(Some(vapid))
@@ -197,6 +237,61 @@ fn extract_public_key(vapid: VapidHeader, token_info: &TokenInfo) -> ApiResult<V
     })
 }
 
+/// Fernet token overhead: version (1), timestamp (8), IV (16) and HMAC (32)
+const FERNET_OVERHEAD: usize = 57;
+
+/// The length of a decoded Fernet endpoint token for the given API version,
+/// whose `plaintext_len` bytes are padded to the next AES block
+fn expected_token_len(api_version: ApiVersion) -> usize {
+    let plaintext_len = match api_version {
+        ApiVersion::Version1 => 32,
+        ApiVersion::Version2 => 64,
+    };
+    FERNET_OVERHEAD + (plaintext_len / 16 + 1) * 16
+}
+
+/// Check the endpoint token's encoding, Fernet version byte and length
+/// without decrypting it, so junk tokens are rejected cheaply
+fn validate_token_structure(
+    token: &str,
+    api_version: ApiVersion,
+    versions: &[u8],
+    metrics: &Metrics,
+) -> ApiResult<()> {
+    let reason = match b64_decode_url(token) {
+        Err(_) => "encoding",
+        Ok(decoded) if !decoded.first().map_or(false, |v| versions.contains(v)) => "version",
+        Ok(decoded) if decoded.len() != expected_token_len(api_version) => "length",
+        Ok(_) => return Ok(()),
+    };
+    let mut tags = Tags::default();
+    tags.tags.insert("reason".to_owned(), reason.to_owned());
+    metrics
+        .clone()
+        .incr_with_tags("token.invalid_structure", Some(tags));
+    Err(ApiErrorKind::InvalidToken.into())
+}
+
+/// Decrypt an endpoint token with the first of the crypto keys (newest
+/// first) able to. Tokens minted with an older key are counted, to tell when
+/// the key is no longer in use.
+fn decrypt_token(fernets: &[Fernet], token: &str, metrics: &Metrics) -> ApiResult<Vec<u8>> {
+    let mut last_error = None;
+    for (position, fernet) in fernets.iter().enumerate() {
+        match fernet.decrypt(token) {
+            Ok(decrypted) => {
+                if position > 0 {
+                    metrics.clone().incr("token.decrypted_with_old_key");
+                }
+                return Ok(decrypted);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    error!("fernet: {:?}", last_error);
+    Err(ApiErrorKind::InvalidToken.into())
+}
+
 /// `/webpush/v1/` validations
 fn version_1_validation(token: &[u8]) -> ApiResult<()> {
     if token.len() != 32 {
@@ -224,8 +319,13 @@ fn decode_public_key(public_key: &str) -> ApiResult<Vec<u8>> {
     })
 }
 
-/// `/webpush/v2/` validations
-fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> ApiResult<()> {
+/// `/webpush/v2/` validations: the subscription is restricted to the VAPID
+/// public key given when it was registered
+fn version_2_validation(
+    token: &[u8],
+    vapid: Option<&VapidHeaderWithKey>,
+    metrics: &Metrics,
+) -> ApiResult<()> {
     if token.len() != 64 {
         // Corrupted token
         return Err(ApiErrorKind::InvalidToken.into());
@@ -243,6 +343,7 @@ fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> Api
 
     // Verify that the VAPID public key equals the (expected) token public key
     if !openssl::memcmp::eq(&key_hash, token_key) {
+        metrics.clone().incr("vapid.key_mismatch");
         return Err(VapidError::KeyMismatch.into());
     }
 
@@ -251,22 +352,30 @@ fn version_2_validation(token: &[u8], vapid: Option<&VapidHeaderWithKey>) -> Api
 
 /// Validate the VAPID JWT token. Specifically,
 /// - Check the signature
-/// - Make sure it hasn't expired
+/// - Make sure it hasn't expired, or been issued in the future, allowing
+///   `exp_leeway` seconds of clock skew
 /// - Make sure the expiration isn't too far into the future
+/// - Make sure the audience is the origin of one of our `audiences`
 ///
-/// This is mostly taken care of by the jsonwebtoken library
+/// This is mostly taken care of by the jsonwebtoken library. Returns the
+/// validated claims.
 fn validate_vapid_jwt(
     vapid: &VapidHeaderWithKey,
-    domain: &Url,
+    audiences: &[Url],
+    exp_leeway: u64,
     metrics: &Metrics,
-) -> ApiResult<()> {
+) -> ApiResult<VapidClaims> {
     let VapidHeaderWithKey { vapid, public_key } = vapid;
 
     let public_key = decode_public_key(public_key)?;
+    // `exp` and `iat` are checked below, to report which way the token is
+    // out of date
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.validate_exp = false;
     let token_data = match jsonwebtoken::decode::<VapidClaims>(
         &vapid.token,
         &DecodingKey::from_ec_der(&public_key),
-        &Validation::new(Algorithm::ES256),
+        &validation,
     ) {
         Ok(v) => v,
         Err(e) => match e.kind() {
@@ -321,7 +430,19 @@ fn validate_vapid_jwt(
         );
     };
 
-    if token_data.claims.exp > (sec_since_epoch() + ONE_DAY_IN_SECONDS) {
+    let now = sec_since_epoch();
+    if token_data.claims.exp.saturating_add(exp_leeway) < now {
+        metrics.clone().incr("notification.auth.bad_vapid.expired");
+        return Err(VapidError::ExpiredToken.into());
+    }
+    if matches!(token_data.claims.iat, Some(iat) if iat > now + exp_leeway) {
+        metrics
+            .clone()
+            .incr("notification.auth.bad_vapid.not_yet_valid");
+        return Err(VapidError::NotYetValidToken.into());
+    }
+
+    if token_data.claims.exp > (now + ONE_DAY_IN_SECONDS + exp_leeway) {
         // The expiration is too far in the future
         return Err(VapidError::FutureExpirationToken.into());
     }
@@ -335,27 +456,86 @@ fn validate_vapid_jwt(
         }
     };
 
-    if domain != &aud {
-        error!("Bad Aud: I am <{:?}>, asked for <{:?}> ", domain, aud);
+    if !audiences
+        .iter()
+        .any(|audience| audience.origin() == aud.origin())
+    {
+        error!("Bad Aud: I am <{:?}>, asked for <{:?}> ", audiences, aud);
         metrics.clone().incr("notification.auth.bad_vapid.domain");
         return Err(VapidError::InvalidAudience.into());
     }
 
+    Ok(token_data.claims)
+}
+
+/// Reject a VAPID `sub` claim that's missing or not a `mailto:` or `https:`
+/// contact URI, when `required`
+fn check_subject_uri(sub: &str, required: bool, metrics: &Metrics) -> ApiResult<()> {
+    if !required {
+        return Ok(());
+    }
+    if sub.is_empty() {
+        metrics.clone().incr("notification.auth.bad_vapid.sub");
+        return Err(VapidError::MissingSubject.into());
+    }
+    let valid = Url::parse(sub)
+        .map(|uri| match uri.scheme() {
+            "mailto" => !uri.path().is_empty(),
+            "https" => uri.host().is_some(),
+            _ => false,
+        })
+        .unwrap_or(false);
+    if !valid {
+        metrics.clone().incr("notification.auth.bad_vapid.sub");
+        return Err(VapidError::InvalidSubject.into());
+    }
+    Ok(())
+}
+
+/// Reject a VAPID `sub` claim matching one of the `blocked` patterns: exact,
+/// or a prefix (ending with `*`) or suffix (starting with `*`), ignoring
+/// ASCII case
+fn check_subject(sub: &str, blocked: &[String], metrics: &Metrics) -> ApiResult<()> {
+    let sub = sub.to_ascii_lowercase();
+    let matches = |pattern: &String| {
+        let pattern = pattern.to_ascii_lowercase();
+        if let Some(prefix) = pattern.strip_suffix('*') {
+            sub.starts_with(prefix)
+        } else if let Some(suffix) = pattern.strip_prefix('*') {
+            sub.ends_with(suffix)
+        } else {
+            sub == pattern
+        }
+    };
+    if blocked.iter().any(matches) {
+        warn!("Blocked VAPID subject"; "sub" => &sub);
+        metrics.clone().incr("request.blocked");
+        return Err(VapidError::BlockedSubject.into());
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{validate_vapid_jwt, VapidClaims};
+    use super::{
+        check_subject, check_subject_uri, decrypt_token, validate_token_structure,
+        validate_vapid_jwt, version_1_validation, version_2_validation, VapidClaims,
+    };
     use crate::error::ApiErrorKind;
     use crate::extractors::subscription::repad_base64;
-    use crate::headers::vapid::{VapidError, VapidHeader, VapidHeaderWithKey, VapidVersionData};
+    use crate::extractors::token_info::ApiVersion;
+    use crate::headers::vapid::{
+        VapidError, VapidHeader, VapidHeaderWithKey, VapidScheme, VapidVersionData,
+    };
     use crate::metrics::Metrics;
-    use autopush_common::util::{b64_decode_std, sec_since_epoch};
+    use crate::settings::FERNET_VERSION;
+    use autopush_common::util::{b64_decode_std, b64_decode_url, b64_encode_url, sec_since_epoch};
     use serde::{Deserialize, Serialize};
     use std::str::FromStr;
     use url::Url;
 
+    const LEEWAY: u64 = 60;
+
     #[test]
     fn repad_base64_1_padding() {
         assert_eq!(repad_base64("Zm9vYmE"), "Zm9vYmE=")
@@ -383,18 +563,24 @@ mod tests {
             exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
             aud: domain.to_owned(),
             sub: "mailto:admin@example.com".to_owned(),
+            iat: None,
         };
         let token = jsonwebtoken::encode(&jwk_header, &claims, &enc_key).unwrap();
 
         let header = VapidHeaderWithKey {
             public_key,
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token,
                 version_data: VapidVersionData::Version1,
             },
         };
-        let result = validate_vapid_jwt(&header, &Url::from_str(domain).unwrap(), &Metrics::noop());
+        let result = validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop(),
+        );
         assert!(result.is_ok());
     }
 
@@ -414,12 +600,13 @@ mod tests {
             exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
             aud: domain.to_owned(),
             sub: "mailto:admin@example.com".to_owned(),
+            iat: None,
         };
         let token = jsonwebtoken::encode(&jwk_header, &claims, &enc_key).unwrap();
         let header = VapidHeaderWithKey {
             public_key,
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token,
                 version_data: VapidVersionData::Version1,
             },
@@ -427,7 +614,8 @@ mod tests {
         assert!(matches!(
             validate_vapid_jwt(
                 &header,
-                &Url::from_str("http://example.org").unwrap(),
+                &[Url::from_str("http://example.org").unwrap()],
+                LEEWAY,
                 &Metrics::noop()
             )
             .unwrap_err()
@@ -464,14 +652,15 @@ mod tests {
         let header = VapidHeaderWithKey {
             public_key,
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token,
                 version_data: VapidVersionData::Version1,
             },
         };
         let vv = validate_vapid_jwt(
             &header,
-            &Url::from_str("http://example.org").unwrap(),
+            &[Url::from_str("http://example.org").unwrap()],
+            LEEWAY,
             &Metrics::noop(),
         )
         .unwrap_err()
@@ -507,56 +696,73 @@ mod tests {
             exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
             aud: domain.to_owned(),
             sub: "mailto:admin@example.com".to_owned(),
+            iat: None,
         };
         let token = jsonwebtoken::encode(&jwk_header, &claims, &enc_key).unwrap();
         // try standard form with padding
         let header = VapidHeaderWithKey {
             public_key: public_key_standard.clone(),
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token: token.clone(),
                 version_data: VapidVersionData::Version1,
             },
         };
-        assert!(
-            validate_vapid_jwt(&header, &Url::from_str(domain).unwrap(), &Metrics::noop()).is_ok()
-        );
+        assert!(validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop()
+        )
+        .is_ok());
         // try standard form with no padding
         let header = VapidHeaderWithKey {
             public_key: public_key_standard.trim_end_matches('=').to_owned(),
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token: token.clone(),
                 version_data: VapidVersionData::Version1,
             },
         };
-        assert!(
-            validate_vapid_jwt(&header, &Url::from_str(domain).unwrap(), &Metrics::noop()).is_ok()
-        );
+        assert!(validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop()
+        )
+        .is_ok());
         // try URL safe form with padding
         let header = VapidHeaderWithKey {
             public_key: public_key_url_safe.clone(),
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token: token.clone(),
                 version_data: VapidVersionData::Version1,
             },
         };
-        assert!(
-            validate_vapid_jwt(&header, &Url::from_str(domain).unwrap(), &Metrics::noop()).is_ok()
-        );
+        assert!(validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop()
+        )
+        .is_ok());
         // try URL safe form without padding
         let header = VapidHeaderWithKey {
             public_key: public_key_url_safe.trim_end_matches('=').to_owned(),
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token,
                 version_data: VapidVersionData::Version1,
             },
         };
-        assert!(
-            validate_vapid_jwt(&header, &Url::from_str(domain).unwrap(), &Metrics::noop()).is_ok()
-        );
+        assert!(validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop()
+        )
+        .is_ok());
     }
 
     #[test]
@@ -587,14 +793,15 @@ mod tests {
         let header = VapidHeaderWithKey {
             public_key,
             vapid: VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token,
                 version_data: VapidVersionData::Version1,
             },
         };
         let vv = validate_vapid_jwt(
             &header,
-            &Url::from_str("http://example.org").unwrap(),
+            &[Url::from_str("http://example.org").unwrap()],
+            LEEWAY,
             &Metrics::noop(),
         )
         .unwrap_err()
@@ -604,4 +811,334 @@ mod tests {
             ApiErrorKind::VapidError(VapidError::InvalidVapid(_))
         ])
     }
+
+    /// Sign the claims with the test key, returning the VAPID header
+    fn signed_header<T: Serialize>(claims: &T) -> VapidHeaderWithKey {
+        let priv_key = b64_decode_std(
+            "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgZImOgpRszunnU3j1\
+                    oX5UQiX8KU4X2OdbENuvc/t8wpmhRANCAATN21Y1v8LmQueGpSG6o022gTbbYa4l\
+                    bXWZXITsjknW1WHmELtouYpyXX7e41FiAMuDvcRwW2Nfehn/taHW/IXb",
+        )
+        .unwrap();
+        let public_key = "BM3bVjW_wuZC54alIbqjTbaBNtthriVtdZlchOyOSdbVYeYQu2i5inJdft7jUWIAy4O9xHBbY196Gf-1odb8hds".to_owned();
+        let jwk_header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256);
+        let enc_key = jsonwebtoken::EncodingKey::from_ec_der(&priv_key);
+        let token = jsonwebtoken::encode(&jwk_header, claims, &enc_key).unwrap();
+        VapidHeaderWithKey {
+            public_key,
+            vapid: VapidHeader {
+                scheme: VapidScheme::Vapid,
+                token,
+                version_data: VapidVersionData::Version1,
+            },
+        }
+    }
+
+    #[test]
+    fn vapid_aud_multiple_allowed() {
+        let audiences = [
+            Url::from_str("https://push.services.mozilla.org").unwrap(),
+            Url::from_str("https://updates.push.services.mozilla.com").unwrap(),
+        ];
+        for aud in [
+            "https://updates.push.services.mozilla.com",
+            "https://updates.push.services.mozilla.com/",
+        ] {
+            let header = signed_header(&VapidClaims {
+                exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
+                aud: aud.to_owned(),
+                sub: "mailto:admin@example.com".to_owned(),
+                iat: None,
+            });
+            assert!(validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop()).is_ok());
+        }
+
+        let header = signed_header(&VapidClaims {
+            exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
+            aud: "https://push.example.com".to_owned(),
+            sub: "mailto:admin@example.com".to_owned(),
+            iat: None,
+        });
+        let err = validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop()).unwrap_err();
+        assert_eq!(err.kind.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::InvalidAudience)
+        ));
+    }
+
+    #[test]
+    fn vapid_missing_aud() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct NoAudVapidClaims {
+            exp: u64,
+            sub: String,
+        }
+
+        let header = signed_header(&NoAudVapidClaims {
+            exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
+            sub: "mailto:admin@example.com".to_owned(),
+        });
+        let audiences = [Url::from_str("https://push.services.mozilla.org").unwrap()];
+        assert!(matches!(
+            validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop())
+                .unwrap_err()
+                .kind,
+            ApiErrorKind::VapidError(VapidError::InvalidAudience)
+        ));
+    }
+
+    #[test]
+    fn vapid_exp_leeway() {
+        let audiences = [Url::from_str("https://push.services.mozilla.org").unwrap()];
+        let claims = |exp, iat| VapidClaims {
+            exp,
+            aud: "https://push.services.mozilla.org".to_owned(),
+            sub: "mailto:admin@example.com".to_owned(),
+            iat,
+        };
+        let now = sec_since_epoch();
+
+        // Within the leeway
+        let header = signed_header(&claims(now - LEEWAY + 5, Some(now + LEEWAY - 5)));
+        assert!(validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop()).is_ok());
+
+        // Just beyond it
+        let header = signed_header(&claims(now - LEEWAY - 5, None));
+        let err = validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop()).unwrap_err();
+        assert_eq!(err.kind.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::ExpiredToken)
+        ));
+
+        let header = signed_header(&claims(now + 100, Some(now + LEEWAY + 5)));
+        let err = validate_vapid_jwt(&header, &audiences, LEEWAY, &Metrics::noop()).unwrap_err();
+        assert_eq!(err.kind.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::NotYetValidToken)
+        ));
+    }
+
+    /// A VAPID header (its JWT is validated separately) signed by `public_key`
+    fn vapid_with_key(public_key: &str) -> VapidHeaderWithKey {
+        VapidHeaderWithKey {
+            public_key: public_key.to_owned(),
+            vapid: VapidHeader {
+                scheme: VapidScheme::Vapid,
+                token: "".to_owned(),
+                version_data: VapidVersionData::Version1,
+            },
+        }
+    }
+
+    /// A v2 token restricted to `public_key`
+    fn restricted_token(public_key: &str) -> Vec<u8> {
+        let mut token = vec![1; 32];
+        let key = b64_decode_url(public_key.trim_end_matches('=')).unwrap();
+        token.extend(
+            openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &key)
+                .unwrap()
+                .iter(),
+        );
+        token
+    }
+
+    const PUBLIC_KEY: &str =
+        "BM3bVjW_wuZC54alIbqjTbaBNtthriVtdZlchOyOSdbVYeYQu2i5inJdft7jUWIAy4O9xHBbY196Gf-1odb8hds";
+
+    #[test]
+    fn restricted_subscription_matching_key() {
+        let token = restricted_token(PUBLIC_KEY);
+        let vapid = vapid_with_key(PUBLIC_KEY);
+        assert!(version_2_validation(&token, Some(&vapid), &Metrics::noop()).is_ok());
+    }
+
+    #[test]
+    fn restricted_subscription_mismatched_key() {
+        let token = restricted_token(PUBLIC_KEY);
+        let other_key = b64_encode_url(&[4; 65].to_vec());
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = Metrics::from(cadence::StatsdClient::builder("", sink).build());
+
+        let err =
+            version_2_validation(&token, Some(&vapid_with_key(&other_key)), &metrics).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::KeyMismatch)
+        ));
+        assert_eq!(err.kind.status().as_u16(), 403);
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics
+            .iter()
+            .any(|m| m.starts_with("vapid.key_mismatch:1|c")));
+    }
+
+    #[test]
+    fn unrestricted_subscription() {
+        // v1 tokens carry no key: any (valid) VAPID token may push
+        assert!(version_1_validation(&[1; 32]).is_ok());
+        // Unlike restricted ones, which always require it
+        let token = restricted_token(PUBLIC_KEY);
+        let err = version_2_validation(&token, None, &Metrics::noop()).unwrap_err();
+        assert_eq!(err.kind.status().as_u16(), 401);
+    }
+
+    #[test]
+    fn decrypts_with_old_keys() {
+        let new_key = fernet::Fernet::new(&fernet::Fernet::generate_key()).unwrap();
+        let old_key = fernet::Fernet::new(&fernet::Fernet::generate_key()).unwrap();
+        let old_token = old_key.encrypt(&[1; 32]);
+
+        // Accepted while the retired key remains in the list
+        let keys = vec![new_key.clone(), old_key];
+        assert_eq!(
+            decrypt_token(&keys, &old_token, &Metrics::noop()).unwrap(),
+            vec![1; 32]
+        );
+        let new_token = new_key.encrypt(&[2; 32]);
+        assert_eq!(
+            decrypt_token(&keys, &new_token, &Metrics::noop()).unwrap(),
+            vec![2; 32]
+        );
+
+        // Rejected once it's removed
+        let err = decrypt_token(&[new_key], &old_token, &Metrics::noop()).unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidToken));
+    }
+
+    #[test]
+    fn token_structure() {
+        let fernet = fernet::Fernet::new(&fernet::Fernet::generate_key()).unwrap();
+        let versions = [FERNET_VERSION];
+        let check = |token: &str, api_version| {
+            validate_token_structure(token, api_version, &versions, &Metrics::noop())
+        };
+        let v1_token = fernet.encrypt(&[0; 32]);
+        let v2_token = fernet.encrypt(&[0; 64]);
+        assert!(check(&v1_token, ApiVersion::Version1).is_ok());
+        assert!(check(v1_token.trim_end_matches('='), ApiVersion::Version1).is_ok());
+        assert!(check(&v2_token, ApiVersion::Version2).is_ok());
+
+        let assert_invalid = |result: crate::error::ApiResult<()>| {
+            assert!(matches!(
+                result.unwrap_err().kind,
+                ApiErrorKind::InvalidToken
+            ));
+        };
+        // Wrong version
+        let mut decoded = b64_decode_url(&v1_token).unwrap();
+        decoded[0] = 0x81;
+        assert_invalid(check(&b64_encode_url(&decoded), ApiVersion::Version1));
+        assert!(validate_token_structure(
+            &b64_encode_url(&decoded),
+            ApiVersion::Version1,
+            &[FERNET_VERSION, 0x81],
+            &Metrics::noop()
+        )
+        .is_ok());
+        // Truncated
+        assert_invalid(check(&v1_token[..v1_token.len() - 8], ApiVersion::Version1));
+        // Valid, but for the other API version
+        assert_invalid(check(&v2_token, ApiVersion::Version1));
+        // Not base64
+        assert_invalid(check("not+a+token", ApiVersion::Version1));
+        assert_invalid(check("", ApiVersion::Version1));
+    }
+
+    #[test]
+    fn blocked_subjects() {
+        let blocked = [
+            "mailto:abuse@example.com".to_owned(),
+            "*@spam.example.com".to_owned(),
+            "https://evil.example.com*".to_owned(),
+        ];
+        let check = |sub: &str| check_subject(sub, &blocked, &Metrics::noop());
+        let assert_blocked = |result: crate::error::ApiResult<()>| {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind.status(), actix_web::http::StatusCode::FORBIDDEN);
+            assert!(matches!(
+                err.kind,
+                ApiErrorKind::VapidError(VapidError::BlockedSubject)
+            ));
+        };
+        // Exact match
+        assert_blocked(check("mailto:abuse@example.com"));
+        assert_blocked(check("mailto:Abuse@Example.com"));
+        // Pattern matches
+        assert_blocked(check("mailto:anyone@spam.example.com"));
+        assert_blocked(check("https://evil.example.com/app"));
+        // Not matching
+        assert!(check("mailto:admin@example.com").is_ok());
+        assert!(check("mailto:abuse@example.com.au").is_ok());
+        assert!(check("https://example.com").is_ok());
+        assert!(check_subject("mailto:abuse@example.com", &[], &Metrics::noop()).is_ok());
+    }
+
+    #[test]
+    fn subject_uri() {
+        let check = |sub: &str, required: bool| check_subject_uri(sub, required, &Metrics::noop());
+        let assert_rejected = |result: crate::error::ApiResult<()>, missing: bool| {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+            if missing {
+                assert!(matches!(
+                    err.kind,
+                    ApiErrorKind::VapidError(VapidError::MissingSubject)
+                ));
+            } else {
+                assert!(matches!(
+                    err.kind,
+                    ApiErrorKind::VapidError(VapidError::InvalidSubject)
+                ));
+            }
+        };
+        assert!(check("mailto:admin@example.com", true).is_ok());
+        assert!(check("https://example.com/contact", true).is_ok());
+        assert!(check("HTTPS://example.com", true).is_ok());
+        // Disallowed schemes
+        assert_rejected(check("http://example.com", true), false);
+        assert_rejected(check("tel:+15555550100", true), false);
+        assert_rejected(check("admin@example.com", true), false);
+        assert_rejected(check("mailto:", true), false);
+        // Missing
+        assert_rejected(check("", true), true);
+
+        // Anything goes when not required
+        assert!(check("http://example.com", false).is_ok());
+        assert!(check("", false).is_ok());
+    }
+
+    /// A token without a `sub` claim is only rejected when one's required
+    #[test]
+    fn vapid_absent_sub() {
+        #[derive(Serialize)]
+        struct NoSubVapidClaims {
+            exp: u64,
+            aud: String,
+        }
+
+        let domain = "https://push.services.mozilla.org";
+        let header = signed_header(&NoSubVapidClaims {
+            exp: sec_since_epoch() + super::ONE_DAY_IN_SECONDS - 100,
+            aud: domain.to_owned(),
+        });
+        let claims = validate_vapid_jwt(
+            &header,
+            &[Url::from_str(domain).unwrap()],
+            LEEWAY,
+            &Metrics::noop(),
+        )
+        .unwrap();
+        assert!(check_subject_uri(&claims.sub, false, &Metrics::noop()).is_ok());
+        let err = check_subject_uri(&claims.sub, true, &Metrics::noop()).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            ApiErrorKind::VapidError(VapidError::MissingSubject)
+        ));
+    }
 }
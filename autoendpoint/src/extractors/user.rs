@@ -9,7 +9,7 @@ use cadence::{CountedExt, StatsdClient};
 use uuid::Uuid;
 
 /// Perform some validations on the user, including:
-/// - Validate router type
+/// - Validate router type, and that it's allowed (`allowed_bridges`)
 /// - (WebPush) Check that the subscription/channel exists
 /// - (WebPush) Drop user if inactive
 ///
@@ -46,6 +46,15 @@ pub async fn validate_user(
         return Err(ApiErrorKind::Router(crate::routers::RouterError::NotFound).into());
     }
 
+    if !app_state.settings.bridge_allowed(router_type) {
+        app_state
+            .metrics
+            .incr_with_tags("bridge.disallowed")
+            .with_tag("platform", &router_type.to_string())
+            .send();
+        return Err(ApiErrorKind::BridgeNotAllowed(router_type).into());
+    }
+
     if router_type == RouterType::WebPush {
         validate_webpush_user(user, channel_id, app_state.db.as_ref(), &app_state.metrics).await?;
     }
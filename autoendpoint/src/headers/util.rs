@@ -1,14 +1,32 @@
 //! Utilities for working with headers
 
-use actix_web::HttpRequest;
+use actix_web::{http::header::HeaderMap, HttpRequest};
+
+/// Something with request headers: a request, or the headers of an item of a
+/// bulk request
+pub trait RequestHeaders {
+    fn headers(&self) -> &HeaderMap;
+}
+
+impl RequestHeaders for HttpRequest {
+    fn headers(&self) -> &HeaderMap {
+        HttpRequest::headers(self)
+    }
+}
+
+impl RequestHeaders for HeaderMap {
+    fn headers(&self) -> &HeaderMap {
+        self
+    }
+}
 
 /// Get a header from the request
-pub fn get_header<'r>(req: &'r HttpRequest, header: &str) -> Option<&'r str> {
+pub fn get_header<'r>(req: &'r impl RequestHeaders, header: &str) -> Option<&'r str> {
     req.headers().get(header).and_then(|h| h.to_str().ok())
 }
 
 /// Get an owned copy of a header from the request
-pub fn get_owned_header(req: &HttpRequest, header: &str) -> Option<String> {
+pub fn get_owned_header(req: &impl RequestHeaders, header: &str) -> Option<String> {
     get_header(req, header).map(str::to_string)
 }
 
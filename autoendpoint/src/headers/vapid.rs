@@ -2,12 +2,41 @@ use crate::headers::util::split_key_value;
 use std::collections::HashMap;
 use thiserror::Error;
 
-pub const ALLOWED_SCHEMES: [&str; 3] = ["bearer", "webpush", "vapid"];
+/// The accepted authorization schemes (case-insensitive)
+pub const ALLOWED_SCHEMES: [&str; 3] = ["vapid", "webpush", "bearer"];
+
+/// The authorization scheme of a VAPID header
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VapidScheme {
+    /// `vapid t=<JWT>,k=<public key>` (VAPID draft 02 and later)
+    Vapid,
+    /// `WebPush <JWT>` (VAPID draft 01), the public key being in the
+    /// `Crypto-Key` header. `Bearer` is accepted as an alias.
+    WebPush,
+}
+
+impl VapidScheme {
+    /// Parse a scheme token, ignoring case
+    pub fn parse(scheme: &str) -> Option<Self> {
+        match scheme.to_lowercase().as_str() {
+            "vapid" => Some(VapidScheme::Vapid),
+            "webpush" | "bearer" => Some(VapidScheme::WebPush),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VapidScheme::Vapid => "vapid",
+            VapidScheme::WebPush => "webpush",
+        }
+    }
+}
 
 /// Parses the VAPID authorization header
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct VapidHeader {
-    pub scheme: String,
+    pub scheme: VapidScheme,
     pub token: String,
     pub version_data: VapidVersionData,
 }
@@ -31,34 +60,46 @@ impl VapidHeader {
     /// Parse the VAPID authorization header. The public key is available if the
     /// version is 2 ("vapid" scheme).
     pub fn parse(header: &str) -> Result<VapidHeader, VapidError> {
-        let mut scheme_split = header.splitn(2, ' ');
-        let scheme = scheme_split
-            .next()
-            .ok_or(VapidError::MissingToken)?
-            .to_lowercase();
+        let mut scheme_split = header.trim().splitn(2, ' ');
+        let scheme = scheme_split.next().ok_or(VapidError::MissingToken)?;
+        let scheme = VapidScheme::parse(scheme).ok_or(VapidError::UnknownScheme)?;
         let data = scheme_split
             .next()
             .ok_or(VapidError::MissingToken)?
             .replace(' ', "");
 
-        if !ALLOWED_SCHEMES.contains(&scheme.as_str()) {
-            return Err(VapidError::UnknownScheme);
-        }
-
-        let (token, version_data) = if scheme == "vapid" {
-            let data: HashMap<&str, &str> = data.split(',').filter_map(split_key_value).collect();
+        let (token, version_data) = match scheme {
+            VapidScheme::Vapid => {
+                let data = data
+                    .split(',')
+                    .filter(|item| !item.is_empty())
+                    .map(|item| {
+                        split_key_value(item).ok_or_else(|| {
+                            VapidError::InvalidVapid(format!(
+                                "expected t=<token>,k=<key> parameters, got {:?}",
+                                item
+                            ))
+                        })
+                    })
+                    .collect::<Result<HashMap<&str, &str>, _>>()?;
 
-            let public_key = *data.get("k").ok_or(VapidError::MissingKey)?;
-            let token = *data.get("t").ok_or(VapidError::MissingToken)?;
+                let public_key = data
+                    .get("k")
+                    .filter(|key| !key.is_empty())
+                    .ok_or(VapidError::MissingKey)?;
+                let token = data
+                    .get("t")
+                    .filter(|token| !token.is_empty())
+                    .ok_or(VapidError::MissingToken)?;
 
-            (
-                token.to_string(),
-                VapidVersionData::Version2 {
-                    public_key: public_key.to_string(),
-                },
-            )
-        } else {
-            (data, VapidVersionData::Version1)
+                (
+                    token.to_string(),
+                    VapidVersionData::Version2 {
+                        public_key: public_key.to_string(),
+                    },
+                )
+            }
+            VapidScheme::WebPush => (data, VapidVersionData::Version1),
         };
 
         Ok(Self {
@@ -93,15 +134,25 @@ pub enum VapidError {
     InvalidExpiry,
     #[error("VAPID public key mismatch")]
     KeyMismatch,
+    #[error("The VAPID token has expired")]
+    ExpiredToken,
+    #[error("The VAPID token is not yet valid")]
+    NotYetValidToken,
     #[error("The VAPID token expiration is too long")]
     FutureExpirationToken,
-    #[error("Unknown auth scheme")]
+    #[error("The VAPID subject is blocked")]
+    BlockedSubject,
+    #[error("Missing VAPID subject, expected a mailto: or https: contact URI")]
+    MissingSubject,
+    #[error("Invalid VAPID subject, expected a mailto: or https: contact URI")]
+    InvalidSubject,
+    #[error("Unknown auth scheme, expected one of: {}", ALLOWED_SCHEMES.join(", "))]
     UnknownScheme,
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{VapidHeader, VapidVersionData};
+    use super::{VapidError, VapidHeader, VapidScheme, VapidVersionData};
 
     const TOKEN: &str = "eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.eyJhdWQiOiJodHRwc\
         zovL3B1c2guc2VydmljZXMubW96aWxsYS5jb20iLCJzdWIiOiJtYWlsdG86YWRtaW5AZXhh\
@@ -121,7 +172,7 @@ mod tests {
         assert_eq!(
             VapidHeader::parse(VALID_HEADER),
             Ok(VapidHeader {
-                scheme: "vapid".to_string(),
+                scheme: VapidScheme::Vapid,
                 token: TOKEN.to_string(),
                 version_data: VapidVersionData::Version2 {
                     public_key: KEY.to_string()
@@ -129,4 +180,57 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn parse_webpush() {
+        for scheme in ["WebPush", "webpush", "Bearer"] {
+            assert_eq!(
+                VapidHeader::parse(&format!("{scheme} {TOKEN}")),
+                Ok(VapidHeader {
+                    scheme: VapidScheme::WebPush,
+                    token: TOKEN.to_string(),
+                    version_data: VapidVersionData::Version1,
+                }),
+                "{scheme}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_case_insensitive_scheme() {
+        let header = VapidHeader::parse(&format!("VAPID t={TOKEN}, k={KEY}")).unwrap();
+        assert_eq!(header.scheme, VapidScheme::Vapid);
+        assert_eq!(header.token, TOKEN);
+        assert_eq!(header.version(), 2);
+    }
+
+    #[test]
+    fn parse_malformed_params() {
+        assert!(matches!(
+            VapidHeader::parse(&format!("vapid t={TOKEN},k")),
+            Err(VapidError::InvalidVapid(_))
+        ));
+        assert!(matches!(
+            VapidHeader::parse(&format!("vapid {TOKEN},k={KEY}")),
+            Err(VapidError::InvalidVapid(_))
+        ));
+        assert_eq!(
+            VapidHeader::parse(&format!("vapid t=,k={KEY}")),
+            Err(VapidError::MissingToken)
+        );
+        assert_eq!(
+            VapidHeader::parse(&format!("vapid t={TOKEN}")),
+            Err(VapidError::MissingKey)
+        );
+    }
+
+    #[test]
+    fn parse_unknown_scheme() {
+        let err = VapidHeader::parse(&format!("Basic {TOKEN}")).unwrap_err();
+        assert_eq!(err, VapidError::UnknownScheme);
+        assert_eq!(
+            err.to_string(),
+            "Unknown auth scheme, expected one of: vapid, webpush, bearer"
+        );
+    }
 }
@@ -0,0 +1,321 @@
+//! Deduplication of notifications by their `Idempotency-Key` header
+//!
+//! App servers retrying a timed out request may send the same notification
+//! twice. The response to a notification sent with an `Idempotency-Key` is
+//! remembered for `ttl`: a notification to the same subscription with the
+//! same key within that window gets the original response instead of being
+//! sent again. The key's reserved while the first notification's being sent,
+//! repeats meanwhile being rejected (with a 409) rather than sent too. At
+//! most `capacity` keys are kept in memory, the oldest being forgotten first.
+//!
+//! Keys are only remembered by the instance that received them: repeats
+//! reaching another instance are sent again.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::routers::RouterResponse;
+
+/// The header carrying the app server's idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Longer idempotency keys are ignored
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// A key scoped to its subscription (UAID and channel ID)
+type ScopedKey = (Uuid, Uuid, String);
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    /// When each key was stored, with its response (`None` while reserved)
+    responses: HashMap<ScopedKey, (Instant, Option<RouterResponse>)>,
+    /// The keys in the order they were stored, oldest (so first to expire)
+    /// first. May include keys since replaced, see `forget_oldest`.
+    order: VecDeque<(Instant, ScopedKey)>,
+}
+
+impl CacheInner {
+    /// Forget the oldest stored key
+    fn forget_oldest(&mut self) -> Option<Instant> {
+        let (stored, key) = self.order.pop_front()?;
+        // Only when it wasn't since replaced by a newer response
+        if self
+            .responses
+            .get(&key)
+            .map_or(false, |(current, _)| *current == stored)
+        {
+            self.responses.remove(&key);
+        }
+        Some(stored)
+    }
+}
+
+/// The outcome of reserving an idempotency key
+#[derive(Debug)]
+pub enum Reserved<'a> {
+    /// The key's new: send the notification, then complete the reservation
+    New(Reservation<'a>),
+    /// The response to the earlier notification with the key
+    Done(RouterResponse),
+    /// The earlier notification with the key is still being sent
+    InProgress,
+}
+
+/// A reserved idempotency key. Released (so a retry is sent again) when
+/// dropped without being completed, e.g. when sending failed.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: Option<ScopedKey>,
+    stored: Instant,
+}
+
+impl Reservation<'_> {
+    /// Remember the response to the notification sent with the key, for the
+    /// window starting when it was reserved
+    pub fn complete(mut self, response: &RouterResponse) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut guard = self
+            .cache
+            .inner
+            .lock()
+            .expect("IdempotencyCache lock poisoned");
+        let pending = matches!(
+            guard.responses.get(&key),
+            Some((stored, None)) if *stored == self.stored
+        );
+        if pending {
+            guard
+                .responses
+                .insert(key, (self.stored, Some(response.clone())));
+        } else {
+            // Forgotten meanwhile
+            self.cache
+                .store(&mut guard, key, Some(response.clone()), self.stored);
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut inner = self
+            .cache
+            .inner
+            .lock()
+            .expect("IdempotencyCache lock poisoned");
+        if matches!(inner.responses.get(&key), Some((stored, None)) if *stored == self.stored) {
+            inner.responses.remove(&key);
+        }
+    }
+}
+
+/// Remembers the responses to notifications sent with an idempotency key
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    /// How long responses are remembered, 0 disables deduplication
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Reserve the key for a notification to the subscription, unless an
+    /// earlier notification with the same key is (or was, within the
+    /// window) being sent
+    pub fn reserve(&self, uaid: &Uuid, channel_id: &Uuid, key: &str) -> Reserved<'_> {
+        self.reserve_at(uaid, channel_id, key, Instant::now())
+    }
+
+    fn reserve_at(&self, uaid: &Uuid, channel_id: &Uuid, key: &str, now: Instant) -> Reserved<'_> {
+        let key = (*uaid, *channel_id, key.to_owned());
+        if !self.enabled() {
+            return Reserved::New(Reservation {
+                cache: self,
+                key: None,
+                stored: now,
+            });
+        }
+        let mut guard = self.inner.lock().expect("IdempotencyCache lock poisoned");
+        let inner = &mut *guard;
+        match inner.responses.get(&key) {
+            Some((stored, _)) if now.saturating_duration_since(*stored) >= self.ttl => {}
+            Some((_, Some(response))) => return Reserved::Done(response.clone()),
+            Some((_, None)) => return Reserved::InProgress,
+            None => {}
+        }
+        self.store(inner, key.clone(), None, now);
+        Reserved::New(Reservation {
+            cache: self,
+            key: Some(key),
+            stored: now,
+        })
+    }
+
+    fn store(
+        &self,
+        inner: &mut CacheInner,
+        key: ScopedKey,
+        response: Option<RouterResponse>,
+        now: Instant,
+    ) {
+        while inner.order.front().map_or(false, |(stored, _)| {
+            now.saturating_duration_since(*stored) >= self.ttl
+        }) {
+            inner.forget_oldest();
+        }
+        inner.responses.remove(&key);
+        while inner.responses.len() >= self.capacity && inner.forget_oldest().is_some() {}
+
+        inner.order.push_back((now, key.clone()));
+        inner.responses.insert(key, (now, response));
+    }
+
+    /// The number of keys currently remembered
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("IdempotencyCache lock poisoned")
+            .responses
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::http::StatusCode;
+    use uuid::Uuid;
+
+    use super::{IdempotencyCache, Reserved};
+    use crate::routers::RouterResponse;
+
+    fn response() -> RouterResponse {
+        let mut response = RouterResponse::success("https://push.example.com/m/1".to_owned(), 60);
+        response.status = StatusCode::CREATED;
+        response
+    }
+
+    /// Reserve the key and complete it with `response()`
+    fn send(cache: &IdempotencyCache, uaid: &Uuid, chid: &Uuid, now: Instant) {
+        match cache.reserve_at(uaid, chid, "key", now) {
+            Reserved::New(reservation) => reservation.complete(&response()),
+            other => panic!("Unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn dedupes_within_window() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        send(&cache, &uaid, &chid, now);
+
+        let later = now + Duration::from_secs(59);
+        assert!(
+            matches!(cache.reserve_at(&uaid, &chid, "key", later), Reserved::Done(r) if r == response())
+        );
+        // Keys are scoped to the subscription
+        assert!(matches!(
+            cache.reserve_at(&uaid, &Uuid::new_v4(), "key", later),
+            Reserved::New(_)
+        ));
+        assert!(matches!(
+            cache.reserve_at(&uaid, &chid, "other", later),
+            Reserved::New(_)
+        ));
+    }
+
+    /// Repeats are rejected while the first is being sent, and are sent
+    /// again once it's failed
+    #[test]
+    fn reserved_while_sending() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        let Reserved::New(reservation) = cache.reserve_at(&uaid, &chid, "key", now) else {
+            panic!("Not reserved");
+        };
+        assert!(matches!(
+            cache.reserve_at(&uaid, &chid, "key", now),
+            Reserved::InProgress
+        ));
+        // Failed
+        drop(reservation);
+        assert_eq!(cache.len(), 0);
+        send(&cache, &uaid, &chid, now);
+        assert!(matches!(
+            cache.reserve_at(&uaid, &chid, "key", now),
+            Reserved::Done(_)
+        ));
+    }
+
+    #[test]
+    fn resends_after_window() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        send(&cache, &uaid, &chid, now);
+
+        let later = now + Duration::from_secs(60);
+        // The expired response is forgotten once another is stored
+        send(&cache, &uaid, &Uuid::new_v4(), later);
+        assert_eq!(cache.len(), 1);
+        assert!(matches!(
+            cache.reserve_at(&uaid, &chid, "key", later),
+            Reserved::New(_)
+        ));
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 2);
+        let uaid = Uuid::new_v4();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        for chid in [first, second, third] {
+            send(&cache, &uaid, &chid, now);
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(
+            cache.reserve_at(&uaid, &third, "key", now),
+            Reserved::Done(_)
+        ));
+        assert!(matches!(
+            cache.reserve_at(&uaid, &first, "key", now),
+            Reserved::New(_)
+        ));
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = IdempotencyCache::new(Duration::ZERO, 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        send(&cache, &uaid, &chid, Instant::now());
+        assert!(matches!(
+            cache.reserve(&uaid, &chid, "key"),
+            Reserved::New(_)
+        ));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,168 @@
+//! Electing the instance running a shared background task
+//!
+//! Some background tasks (e.g. the message GC) act on the storage shared by
+//! all the instances, so only one of them needs to. Each is guarded by a
+//! lease stored in the message table under the reserved [LEASE_UAID]: the
+//! instance holding an unexpired lease runs the task (renewing the lease),
+//! the others skip it until the lease expires.
+//!
+//! The storage backends lack conditional writes, so a lease is claimed by
+//! writing it then checking the stored claim is still this instance's once
+//! the concurrent claims had time to land.
+use std::time::Duration;
+
+use autopush_common::db::{client::DbClient, error::DbResult};
+use autopush_common::notification::Notification as StoredNotification;
+use autopush_common::util::sec_since_epoch;
+use uuid::Uuid;
+
+/// The UAID the leases are stored under. Never issued to a client (UAIDs are
+/// random v4 UUIDs).
+pub const LEASE_UAID: Uuid = Uuid::from_u128(1);
+
+/// The channel of the stored leases, each stored under its name as the topic
+const LEASE_CHANNEL_ID: Uuid = Uuid::from_u128(1);
+
+/// How long after claiming a lease it's checked
+const CLAIM_SETTLE: Duration = Duration::from_secs(5);
+
+pub struct Lease {
+    db: Box<dyn DbClient>,
+    name: String,
+    /// Identifies this instance's claims
+    holder: String,
+    duration: Duration,
+    settle: Duration,
+}
+
+impl Lease {
+    pub fn new(db: Box<dyn DbClient>, name: &str, duration: Duration) -> Self {
+        Self {
+            db,
+            name: name.to_owned(),
+            holder: Uuid::new_v4().as_simple().to_string(),
+            duration,
+            settle: CLAIM_SETTLE,
+        }
+    }
+
+    /// A lease for a task run every `interval`. Lasting twice the interval,
+    /// so the holder renews it before it expires.
+    pub fn for_interval(db: Box<dyn DbClient>, name: &str, interval: Duration) -> Self {
+        Self::new(db, name, interval.saturating_mul(2))
+    }
+
+    /// Whether this instance holds the lease, for `duration` from now:
+    /// renewing it, or claiming it when unheld or expired
+    pub async fn acquire(&self) -> DbResult<bool> {
+        let now = sec_since_epoch();
+        match self.current().await? {
+            Some(current) if current.version == self.holder => {
+                self.save(now).await?;
+                return Ok(true);
+            }
+            Some(current) if now < current.timestamp.saturating_add(current.ttl) => {
+                return Ok(false);
+            }
+            _ => (),
+        }
+        self.save(now).await?;
+        actix_rt::time::sleep(self.settle).await;
+        Ok(self
+            .current()
+            .await?
+            .is_some_and(|current| current.version == self.holder))
+    }
+
+    /// The stored lease, whoever holds it
+    async fn current(&self) -> DbResult<Option<StoredNotification>> {
+        self.db
+            .get_message(&LEASE_UAID, &self.stored(0).chidmessageid())
+            .await
+    }
+
+    /// Store this instance's claim, from `now`
+    async fn save(&self, now: u64) -> DbResult<()> {
+        self.db.save_message(&LEASE_UAID, self.stored(now)).await
+    }
+
+    fn stored(&self, now: u64) -> StoredNotification {
+        StoredNotification {
+            channel_id: LEASE_CHANNEL_ID,
+            version: self.holder.clone(),
+            ttl: self.duration.as_secs(),
+            topic: Some(self.name.clone()),
+            timestamp: now,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::notification::Notification as StoredNotification;
+    use autopush_common::util::sec_since_epoch;
+
+    use super::{Lease, LEASE_UAID};
+
+    /// A lease backed by a single stored row, initially `stored`
+    fn make_lease(
+        stored: Option<StoredNotification>,
+    ) -> (Lease, Arc<Mutex<Option<StoredNotification>>>) {
+        let row = Arc::new(Mutex::new(stored));
+        let mut db = MockDbClient::new();
+        let read = row.clone();
+        db.expect_get_message()
+            .withf(|uaid, _| uaid == &LEASE_UAID)
+            .returning(move |_, _| Ok(read.lock().unwrap().clone()));
+        let written = row.clone();
+        db.expect_save_message()
+            .withf(|uaid, _| uaid == &LEASE_UAID)
+            .returning(move |_, stored| {
+                *written.lock().unwrap() = Some(stored);
+                Ok(())
+            });
+        let lease = Lease {
+            settle: Duration::ZERO,
+            ..Lease::new(db.into_boxed_arc(), "test", Duration::from_secs(60))
+        };
+        (lease, row)
+    }
+
+    /// Held by another instance since `leased_at`
+    fn held_since(leased_at: u64) -> Option<StoredNotification> {
+        Some(StoredNotification {
+            version: "other".to_owned(),
+            ttl: 60,
+            timestamp: leased_at,
+            ..Default::default()
+        })
+    }
+
+    #[actix_rt::test]
+    async fn claims_and_renews() {
+        let (lease, row) = make_lease(None);
+        assert!(lease.acquire().await.unwrap());
+        assert!(lease.acquire().await.unwrap());
+        let stored = row.lock().unwrap().clone().unwrap();
+        assert_eq!(stored.version, lease.holder);
+        assert_eq!(stored.topic.as_deref(), Some("test"));
+    }
+
+    #[actix_rt::test]
+    async fn held_by_another() {
+        let (lease, row) = make_lease(held_since(sec_since_epoch()));
+        assert!(!lease.acquire().await.unwrap());
+        assert_eq!(row.lock().unwrap().as_ref().unwrap().version, "other");
+    }
+
+    #[actix_rt::test]
+    async fn claims_expired() {
+        let (lease, _) = make_lease(held_since(sec_since_epoch() - 61));
+        assert!(lease.acquire().await.unwrap());
+    }
+}
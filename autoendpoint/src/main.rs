@@ -5,14 +5,31 @@
 extern crate slog_scope;
 
 mod auth;
+#[cfg(feature = "debug")]
+mod debug_mirror;
+mod delivery_queue;
+mod delivery_status;
 mod error;
 mod extractors;
 mod headers;
+mod idempotency;
+mod lease;
+mod maintenance;
+mod message_gc;
 mod metrics;
+mod otel;
+mod outbox;
+mod receipts;
+mod reload;
+mod route_metrics;
 mod routers;
 mod routes;
 mod server;
 mod settings;
+mod stale_subscriptions;
+mod stats;
+mod subscription_cache;
+mod timeout;
 
 use docopt::Docopt;
 use serde::Deserialize;
@@ -26,11 +43,14 @@ Usage: autoendpoint [options]
 Options:
     -h, --help              Show this message
     --config=CONFIGFILE     AutoEndpoint configuration file path.
+    --dump-config           Print the resolved settings (secrets redacted) as
+                            JSON and exit.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_dump_config: bool,
 }
 
 #[actix_rt::main]
@@ -39,34 +59,49 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
     let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
-    let host_port = format!("{}:{}", &settings.host, &settings.port);
+    if args.flag_dump_config {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&settings.redacted_json())?
+        );
+        return Ok(());
+    }
+    let banner = settings.banner();
     logging::init_logging(
         !settings.human_logs,
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION"),
+        Some(settings.log_level.as_str()),
     )
     .expect("Logging failed to initialize");
     debug!("Starting up autoendpoint...");
+    otel::init(&settings).expect("Tracing failed to initialize");
 
-    let _sentry = sentry::init(sentry::ClientOptions {
-        release: sentry::release_name!(),
-        attach_stacktrace: true,
-        ..autopush_common::sentry::client_options()
+    // Sentry is only initialized (capturing panics and errors) when a DSN is
+    // configured
+    let _sentry = settings.sentry_dsn().map(|dsn| {
+        sentry::init(sentry::ClientOptions {
+            dsn: dsn.parse().ok(),
+            release: sentry::release_name!(),
+            attach_stacktrace: true,
+            ..autopush_common::sentry::client_options()
+        })
     });
 
     // Run server...
-    let server = server::Server::with_settings(settings)
+    let server = server::Server::with_settings(settings, args.flag_config)
         .await
         .expect("Could not start server");
     info!(
-        "Starting autoendpoint on port: {} (available_parallelism: {:?})",
-        host_port,
+        "Starting autoendpoint on: {} (available_parallelism: {:?})",
+        banner,
         std::thread::available_parallelism()
     );
     server.await?;
 
     // Shutdown
     info!("Shutting down autoendpoint");
+    otel::shutdown();
     logging::reset_logging();
     Ok(())
 }
@@ -0,0 +1,161 @@
+//! Rejecting notifications while in `maintenance_mode`
+//!
+//! Wraps the notification routes only, so the health checks (e.g.
+//! `/__lbheartbeat__`) keep responding and the load balancer doesn't evict
+//! us. Rejected requests are answered with a 503 (and a `Retry-After`)
+//! before their body's read or their subscription's looked up. The setting's
+//! read per request, so a SIGHUP reload takes effect immediately.
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, ResponseError,
+};
+use cadence::{CountedExt, StatsdClient};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt as _;
+use futures_util::future::{ok, Ready};
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::reload::LiveSettings;
+
+pub struct Maintenance {
+    live_settings: Arc<LiveSettings>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl Maintenance {
+    pub fn new(live_settings: Arc<LiveSettings>, metrics: Arc<StatsdClient>) -> Self {
+        Self {
+            live_settings,
+            metrics,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Maintenance
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = MaintenanceMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MaintenanceMiddleware {
+            service: Rc::new(service),
+            live_settings: self.live_settings.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct MaintenanceMiddleware<S> {
+    service: Rc<S>,
+    live_settings: Arc<LiveSettings>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        if !self.live_settings.get().maintenance_mode {
+            return self
+                .service
+                .call(sreq)
+                .map(|res| res.map(ServiceResponse::map_into_left_body))
+                .boxed_local();
+        }
+        self.metrics.incr("maintenance.rejected").ok();
+        let response = ApiError::from(ApiErrorKind::Maintenance).error_response();
+        let (request, _) = sreq.into_parts();
+        async move { Ok(ServiceResponse::new(request, response).map_into_right_body()) }
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{http::header, http::StatusCode, test, web, App, HttpResponse};
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::Maintenance;
+    use crate::reload::LiveSettings;
+    use crate::routes::health::lb_heartbeat_route;
+    use crate::settings::Settings;
+
+    async fn app_status(maintenance_mode: bool) -> (StatusCode, StatusCode, Vec<String>) {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+        let live_settings = Arc::new(LiveSettings::new(Settings {
+            maintenance_mode,
+            ..Default::default()
+        }));
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/wpush/{token}")
+                        .wrap(Maintenance::new(live_settings, metrics))
+                        .route(web::post().to(HttpResponse::Created)),
+                )
+                .service(
+                    web::resource("/__lbheartbeat__").route(web::get().to(lb_heartbeat_route)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/wpush/abc").to_request();
+        let resp = test::call_service(&app, req).await;
+        let notification = resp.status();
+        if maintenance_mode {
+            assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "120");
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["errno"], 201);
+            assert!(body["message"].as_str().unwrap().contains("maintenance"));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/__lbheartbeat__")
+            .to_request();
+        let heartbeat = test::call_service(&app, req).await.status();
+        let metrics = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        (notification, heartbeat, metrics)
+    }
+
+    #[actix_rt::test]
+    async fn rejects_notifications() {
+        let (notification, heartbeat, metrics) = app_status(true).await;
+        assert_eq!(notification, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(heartbeat, StatusCode::OK);
+        assert_eq!(metrics, ["autoendpoint.maintenance.rejected:1|c"]);
+    }
+
+    #[actix_rt::test]
+    async fn disabled() {
+        let (notification, heartbeat, metrics) = app_status(false).await;
+        assert_eq!(notification, StatusCode::CREATED);
+        assert_eq!(heartbeat, StatusCode::OK);
+        assert!(metrics.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! Periodic purging of expired stored messages
+//!
+//! Storage backends relying on lazy garbage collection (Bigtable) can hold
+//! on to expired messages for a long time. When `message_gc_enabled`, a
+//! background task deletes them every `message_gc_interval_secs`, in batches
+//! of `message_gc_batch_size`. Only the instance holding its lease (see
+//! [crate::lease]) does.
+use std::sync::Arc;
+use std::time::Duration;
+
+use autopush_common::db::{client::DbClient, error::DbResult};
+use cadence::{Counted, StatsdClient};
+
+use crate::lease::Lease;
+
+/// Delay before retrying after the first failed sweep, doubled on each
+/// subsequent failure (up to the sweep interval)
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+
+pub struct MessageGc {
+    db: Box<dyn DbClient>,
+    metrics: Arc<StatsdClient>,
+    interval: Duration,
+    batch_size: usize,
+    lease: Lease,
+}
+
+impl MessageGc {
+    pub fn new(
+        db: Box<dyn DbClient>,
+        metrics: Arc<StatsdClient>,
+        interval: Duration,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            lease: Lease::for_interval(db.clone(), "message_gc", interval),
+            db,
+            metrics,
+            interval,
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    /// Sweep for expired messages forever, while holding the lease
+    pub async fn run(self) {
+        let mut failures = 0;
+        loop {
+            let wait = match self.sweep_if_leased().await {
+                Ok(None) => {
+                    failures = 0;
+                    debug!("🗑️ Another instance holds the message GC lease");
+                    self.interval
+                }
+                Ok(Some(removed)) => {
+                    failures = 0;
+                    info!("🗑️ Purged {} expired message(s)", removed; "removed" => removed);
+                    self.interval
+                }
+                Err(e) => {
+                    failures += 1;
+                    let backoff = self.backoff(failures);
+                    warn!(
+                        "🗑️ Expired message purge failed, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    backoff
+                }
+            };
+            actix_rt::time::sleep(wait).await;
+        }
+    }
+
+    /// Sweep when holding the lease, returning the number deleted
+    async fn sweep_if_leased(&self) -> DbResult<Option<usize>> {
+        if !self.lease.acquire().await? {
+            return Ok(None);
+        }
+        self.sweep().await.map(Some)
+    }
+
+    /// Delete expired messages, a batch at a time, until none are left.
+    /// Returns the number deleted.
+    async fn sweep(&self) -> DbResult<usize> {
+        let mut total = 0;
+        loop {
+            let removed = self.db.remove_expired_messages(self.batch_size).await?;
+            total += removed;
+            if removed > 0 {
+                self.metrics
+                    .count("message_gc.deleted", removed as i64)
+                    .ok();
+            }
+            if removed < self.batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// How long to wait after `failures` consecutive failed sweeps
+    fn backoff(&self, failures: u32) -> Duration {
+        INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use autopush_common::db::{error::DbError, mock::MockDbClient};
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::MessageGc;
+
+    fn make_gc(db: MockDbClient) -> MessageGc {
+        MessageGc::new(
+            db.into_boxed_arc(),
+            Arc::new(StatsdClient::from_sink("autoendpoint", NopMetricSink)),
+            Duration::from_secs(3600),
+            2,
+        )
+    }
+
+    /// Batches are deleted until one comes back short
+    #[tokio::test]
+    async fn sweeps_until_exhausted() {
+        let mut db = MockDbClient::new();
+        let mut batches = vec![2, 2, 1].into_iter();
+        db.expect_remove_expired_messages()
+            .times(3)
+            .returning(move |_| Ok(batches.next().unwrap()));
+        assert_eq!(make_gc(db).sweep().await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn sweep_error() {
+        let mut db = MockDbClient::new();
+        db.expect_remove_expired_messages()
+            .times(1)
+            .returning(|_| Err(DbError::General("unavailable".to_owned())));
+        let gc = make_gc(db);
+        assert!(gc.sweep().await.is_err());
+
+        assert_eq!(gc.backoff(1), Duration::from_secs(10));
+        assert_eq!(gc.backoff(3), Duration::from_secs(40));
+        assert_eq!(gc.backoff(100), Duration::from_secs(3600));
+    }
+}
@@ -1,12 +1,18 @@
-use std::{sync::Arc, time::Instant};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use actix_web::{dev::Payload, web::Data, FromRequest, HttpMessage, HttpRequest};
-use cadence::{CountedExt, Metric, MetricError, NopMetricSink, StatsdClient, Timed};
+use cadence::{CountedExt, Metric, MetricError, MetricSink, NopMetricSink, StatsdClient, Timed};
 use futures::future;
 
-use autopush_common::tags::Tags;
+use autopush_common::{metrics::BoxedMetricSink, tags::Tags};
 
-use crate::{error::ApiError, server::AppState, settings::Settings};
+use crate::{error::ApiError, reload::LiveSettings, server::AppState};
 
 #[derive(Debug, Clone)]
 pub struct MetricTimer {
@@ -70,8 +76,11 @@ impl From<&HttpRequest> for Metrics {
         let exts = req.extensions();
         let def_tags = Tags::from_request_head(req.head());
         let tags = exts.get::<Tags>().unwrap_or(&def_tags);
+        let state = req
+            .app_data::<Data<AppState>>()
+            .expect("Could not get state in Metrics::from");
         Metrics {
-            client: Some(metrics_from_req(req)),
+            client: Some(state.metrics.clone()),
             tags: Some(tags.clone()),
             timer: None,
         }
@@ -157,20 +166,384 @@ impl Metrics {
     }
 }
 
-pub fn metrics_from_req(req: &HttpRequest) -> Arc<StatsdClient> {
-    req.app_data::<Data<AppState>>()
-        .expect("Could not get state in metrics_from_req")
-        .metrics
-        .clone()
+/// Create a cadence StatsdClient from the given options
+///
+/// When a `PrometheusRegistry` is given every metric is also recorded in it,
+/// in addition to being sent to statsd. Counters and timers are sampled at
+/// the current `statsd_sample_rate` of `live_settings`.
+pub fn metrics_from_settings(
+    live_settings: Arc<LiveSettings>,
+    prometheus: Option<Arc<PrometheusRegistry>>,
+) -> Result<StatsdClient, MetricError> {
+    let settings = live_settings.get();
+    let statsd = autopush_common::metrics::sink(&settings.statsd_host, settings.statsd_port)?;
+    let sink: BoxedMetricSink = match prometheus {
+        None => statsd,
+        Some(registry) => Box::new(PrometheusSink { registry, statsd }),
+    };
+    let sink = SampledSink {
+        live_settings,
+        inner: sink,
+    };
+    let builder = StatsdClient::builder(&settings.statsd_prefix(), sink)
+        .with_error_handler(|err| warn!("⚠️ Metric send error: {:?}", err));
+    let builder = settings
+        .statsd_global_tags
+        .iter()
+        .filter_map(|tag| tag.split_once(':'))
+        .fold(builder, |builder, (key, value)| {
+            builder.with_tag(key, value)
+        });
+    Ok(builder.build())
 }
 
-/// Create a cadence StatsdClient from the given options
-pub fn metrics_from_settings(settings: &Settings) -> Result<StatsdClient, MetricError> {
-    let client = autopush_common::metrics::builder(
-        &settings.statsd_label,
-        &settings.statsd_host,
-        settings.statsd_port,
-    )?
-    .build();
-    Ok(client)
+/// The accumulated value of a single Prometheus series
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sample {
+    Counter(f64),
+    Gauge(f64),
+    /// Timers and histograms
+    Summary {
+        count: f64,
+        sum: f64,
+    },
+}
+
+impl Sample {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Sample::Counter(_) => "counter",
+            Sample::Gauge(_) => "gauge",
+            Sample::Summary { .. } => "summary",
+        }
+    }
+
+    fn merge(&mut self, other: Sample) {
+        match (self, other) {
+            (Sample::Counter(total), Sample::Counter(value)) => *total += value,
+            (Sample::Summary { count, sum }, Sample::Summary { count: c, sum: s }) => {
+                *count += c;
+                *sum += s;
+            }
+            // Gauges (or a metric that changed type) take the latest value
+            (current, other) => *current = other,
+        }
+    }
+}
+
+/// Mirrors the metrics sent to statsd for the `/metrics` Prometheus endpoint
+///
+/// Metric names are the statsd names with `.` (and any other character
+/// Prometheus disallows) replaced by `_`, and statsd tags become labels.
+#[derive(Debug, Default)]
+pub struct PrometheusRegistry {
+    /// Series keyed by metric name then by their rendered label set
+    series: Mutex<BTreeMap<String, BTreeMap<String, Sample>>>,
+}
+
+impl PrometheusRegistry {
+    /// Record a statsd formatted metric, e.g.
+    /// `autoendpoint.notification.bridge.sent:1|c|@0.5|#platform:fcm`
+    ///
+    /// Sampled counters and timers are scaled back up by their rate. Sets
+    /// and meters aren't mirrored.
+    pub fn record(&self, metric: &str) {
+        for line in metric.lines() {
+            self.record_line(line);
+        }
+    }
+
+    fn record_line(&self, line: &str) {
+        let Some((name, rest)) = line.split_once(':') else {
+            return;
+        };
+        let mut parts = rest.split('|');
+        let Some(value) = parts.next().and_then(|v| v.parse::<f64>().ok()) else {
+            return;
+        };
+        let kind = parts.next().unwrap_or_default();
+        let mut rate: f64 = 1.0;
+        let mut labels = String::new();
+        for part in parts {
+            if let Some(r) = part.strip_prefix('@') {
+                rate = r.parse().ok().filter(|r| *r > 0.0).unwrap_or(1.0);
+            } else if let Some(tags) = part.strip_prefix('#') {
+                labels = render_labels(tags);
+            }
+        }
+        let sample = match kind {
+            "c" => Sample::Counter(value / rate),
+            "g" => Sample::Gauge(value),
+            "ms" | "h" | "d" => Sample::Summary {
+                count: 1.0 / rate,
+                sum: value / rate,
+            },
+            _ => return,
+        };
+
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        series
+            .entry(sanitize_name(name))
+            .or_default()
+            .entry(labels)
+            .and_modify(|current| current.merge(sample))
+            .or_insert(sample);
+    }
+
+    /// Render every series in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, samples) in series.iter() {
+            let Some(first) = samples.values().next() else {
+                continue;
+            };
+            let _ = writeln!(out, "# TYPE {name} {}", first.type_name());
+            for (labels, sample) in samples {
+                let _ = match sample {
+                    Sample::Counter(value) | Sample::Gauge(value) => {
+                        writeln!(out, "{name}{labels} {value}")
+                    }
+                    Sample::Summary { count, sum } => writeln!(out, "{name}_count{labels} {count}")
+                        .and_then(|_| writeln!(out, "{name}_sum{labels} {sum}")),
+                };
+            }
+        }
+        out
+    }
+}
+
+/// Replace characters not allowed in Prometheus names with `_`
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Render statsd tags (`key:value,key2:value2`) as sorted Prometheus labels
+fn render_labels(tags: &str) -> String {
+    let labels: BTreeMap<String, String> = tags
+        .split(',')
+        .filter_map(|tag| tag.split_once(':'))
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            (sanitize_name(key), value)
+        })
+        .collect();
+    if labels.is_empty() {
+        return String::new();
+    }
+    let rendered: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{value}\""))
+        .collect();
+    format!("{{{}}}", rendered.join(","))
+}
+
+/// A `MetricSink` recording each metric in a `PrometheusRegistry` before
+/// forwarding it on to statsd
+struct PrometheusSink {
+    registry: Arc<PrometheusRegistry>,
+    statsd: BoxedMetricSink,
+}
+
+impl MetricSink for PrometheusSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.registry.record(metric);
+        self.statsd.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.statsd.flush()
+    }
+}
+
+/// Samples the counters and timers passed to `inner` at the current
+/// `statsd_sample_rate`, adding the rate for statsd to scale them back up
+struct SampledSink {
+    live_settings: Arc<LiveSettings>,
+    inner: BoxedMetricSink,
+}
+
+impl SampledSink {
+    /// The `metric` as sent at `rate`, or `None` when sampled out by the
+    /// random `roll` (in `[0, 1)`)
+    fn sample(metric: &str, rate: f32, roll: f32) -> Option<String> {
+        let mut fields = metric.split('|');
+        let (Some(_), Some(kind)) = (fields.next(), fields.next()) else {
+            return Some(metric.to_owned());
+        };
+        // Gauges and sets aren't sampled, nor metrics given their own rate
+        if rate >= 1.0
+            || !matches!(kind, "c" | "ms" | "h" | "d")
+            || fields.any(|field| field.starts_with('@'))
+        {
+            return Some(metric.to_owned());
+        }
+        if roll >= rate {
+            return None;
+        }
+        let at = metric.find('|').unwrap_or_default() + 1 + kind.len();
+        Some(format!("{}|@{}{}", &metric[..at], rate, &metric[at..]))
+    }
+}
+
+impl MetricSink for SampledSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let rate = self.live_settings.get().statsd_sample_rate;
+        match Self::sample(metric, rate, rand::random()) {
+            Some(metric) => self.inner.emit(&metric),
+            None => Ok(0),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cadence::{CountedExt, Gauged, StatsdClient, Timed};
+
+    use super::{metrics_from_settings, PrometheusRegistry, SampledSink};
+    use crate::reload::LiveSettings;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_prometheus_mirrors_statsd() {
+        let registry = Arc::new(PrometheusRegistry::default());
+        let live_settings = Arc::new(LiveSettings::new(Settings::default()));
+        let client: StatsdClient =
+            metrics_from_settings(live_settings, Some(registry.clone())).unwrap();
+
+        client
+            .incr_with_tags("notification.bridge.sent")
+            .with_tag("platform", "fcm")
+            .send();
+        client
+            .incr_with_tags("notification.bridge.sent")
+            .with_tag("platform", "fcm")
+            .send();
+        client
+            .time_with_tags("notification.total_request_time", 30)
+            .with_tag("platform", "fcm")
+            .send();
+        client
+            .time_with_tags("notification.total_request_time", 12)
+            .with_tag("platform", "fcm")
+            .send();
+        client.gauge("database.pool.active", 3).unwrap();
+        client.gauge("database.pool.active", 2).unwrap();
+
+        let rendered = registry.render();
+        assert_eq!(
+            rendered,
+            "# TYPE autoendpoint_database_pool_active gauge\n\
+             autoendpoint_database_pool_active 2\n\
+             # TYPE autoendpoint_notification_bridge_sent counter\n\
+             autoendpoint_notification_bridge_sent{platform=\"fcm\"} 2\n\
+             # TYPE autoendpoint_notification_total_request_time summary\n\
+             autoendpoint_notification_total_request_time_count{platform=\"fcm\"} 2\n\
+             autoendpoint_notification_total_request_time_sum{platform=\"fcm\"} 42\n"
+        );
+    }
+
+    #[test]
+    fn test_statsd_namespace_and_global_tags() {
+        let registry = Arc::new(PrometheusRegistry::default());
+        let settings = Settings {
+            statsd_namespace: Some("autopush.prod".to_owned()),
+            statsd_global_tags: vec!["cluster:east".to_owned()],
+            ..Default::default()
+        };
+        let client: StatsdClient = metrics_from_settings(
+            Arc::new(LiveSettings::new(settings)),
+            Some(registry.clone()),
+        )
+        .unwrap();
+
+        client
+            .incr_with_tags("notification.bridge.sent")
+            .with_tag("platform", "fcm")
+            .send();
+
+        assert_eq!(
+            registry.render(),
+            "# TYPE autopush_prod_autoendpoint_notification_bridge_sent counter\n\
+             autopush_prod_autoendpoint_notification_bridge_sent{cluster=\"east\",platform=\"fcm\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_sampled_and_escaped() {
+        let registry = PrometheusRegistry::default();
+        registry.record("autoendpoint.ua.command:1|c|@0.5|#os:Mac \"OS\",1st:x");
+        registry.record("autoendpoint.unique:1|s");
+        assert_eq!(
+            registry.render(),
+            "# TYPE autoendpoint_ua_command counter\n\
+             autoendpoint_ua_command{_1st=\"x\",os=\"Mac \\\"OS\\\"\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn test_sampled_sink() {
+        let counter = "autoendpoint.notification.message_data:1|c|#platform:fcm";
+        assert_eq!(
+            SampledSink::sample(counter, 0.25, 0.1).as_deref(),
+            Some("autoendpoint.notification.message_data:1|c|@0.25|#platform:fcm")
+        );
+        assert_eq!(SampledSink::sample(counter, 0.25, 0.3), None);
+        assert_eq!(
+            SampledSink::sample(counter, 1.0, 0.9).as_deref(),
+            Some(counter)
+        );
+        assert_eq!(
+            SampledSink::sample("autoendpoint.updates.total:12|ms", 0.5, 0.1).as_deref(),
+            Some("autoendpoint.updates.total:12|ms|@0.5")
+        );
+        // Gauges and already sampled metrics pass through
+        let gauge = "autoendpoint.database.pool.active:3|g";
+        assert_eq!(
+            SampledSink::sample(gauge, 0.25, 0.9).as_deref(),
+            Some(gauge)
+        );
+        let own_rate = "autoendpoint.ua.command:1|c|@0.5";
+        assert_eq!(
+            SampledSink::sample(own_rate, 0.25, 0.9).as_deref(),
+            Some(own_rate)
+        );
+    }
+
+    #[test]
+    fn test_direct_metrics_sampled() {
+        let registry = Arc::new(PrometheusRegistry::default());
+        let live_settings = Arc::new(LiveSettings::new(Settings {
+            statsd_sample_rate: 0.0,
+            ..Default::default()
+        }));
+        let client: StatsdClient =
+            metrics_from_settings(live_settings, Some(registry.clone())).unwrap();
+
+        client.incr("notification.bridge.sent").unwrap();
+        client.time("notification.total_request_time", 30).unwrap();
+        client.gauge("database.pool.active", 3).unwrap();
+
+        assert_eq!(
+            registry.render(),
+            "# TYPE autoendpoint_database_pool_active gauge\n\
+             autoendpoint_database_pool_active 3\n"
+        );
+    }
 }
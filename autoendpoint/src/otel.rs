@@ -0,0 +1,486 @@
+//! OpenTelemetry tracing
+//!
+//! When `otel_endpoint` is set, spans are exported to it over OTLP/HTTP: one
+//! per request (continuing an incoming `traceparent`), with children for
+//! VAPID validation, storage access and bridge requests. Bridge requests
+//! carry the `traceparent` of their span. Otherwise no exporter is created
+//! and the spans are no-ops.
+use std::collections::HashSet;
+use std::future::Future;
+use std::rc::Rc;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::HeaderMap,
+    Error,
+};
+use async_trait::async_trait;
+use autopush_common::db::{
+    client::{DbClient, FetchMessageResponse},
+    error::DbResult,
+    User,
+};
+use autopush_common::notification::Notification;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt as _;
+use futures_util::future::{ok, Ready};
+use opentelemetry::{
+    global::{self, BoxedSpan, BoxedTracer},
+    propagation::{Extractor, Injector},
+    trace::{FutureExt as _, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use uuid::Uuid;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::Settings;
+
+const TRACER_NAME: &str = "autoendpoint";
+
+/// Install the OTLP exporter, if `otel_endpoint` is set
+pub fn init(settings: &Settings) -> ApiResult<()> {
+    let Some(endpoint) = &settings.otel_endpoint else {
+        return Ok(());
+    };
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            settings.otel_service_name.clone(),
+        )])))
+        // actix runs a single threaded runtime per worker, so export from
+        // a runtime of its own
+        .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)
+        .map_err(|e| ApiErrorKind::General(format!("Could not install OTLP exporter: {}", e)))?;
+    Ok(())
+}
+
+/// Export any remaining spans
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// Start a child span of the current span, ended when dropped
+pub fn span(name: &'static str) -> BoxedSpan {
+    tracer().start(name)
+}
+
+/// A context with a new child span of the current span, e.g. for tracing
+/// work spawned while handling a request
+pub fn child_context(name: &'static str) -> Context {
+    Context::current_with_span(span(name))
+}
+
+/// Run `future` in a child span of the current span
+pub async fn in_span<F: Future>(name: &'static str, future: F) -> F::Output {
+    future.with_context(child_context(name)).await
+}
+
+/// The `traceparent` (and `tracestate`) headers of the current span, for
+/// propagating it to a bridge
+pub fn trace_headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject(&mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Handles each request in a server span, the parent of the spans started
+/// while handling it
+#[derive(Clone, Default)]
+pub struct TracingWrapper;
+
+impl<S, B> Transform<S, ServiceRequest> for TracingWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TracingMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(sreq.headers()))
+        });
+        // Named by the route rather than the path, which includes tokens
+        let route = sreq
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let tracer = tracer();
+        let span = tracer
+            .span_builder(format!("{} {}", sreq.method(), route))
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", sreq.method().to_string()),
+                KeyValue::new("http.route", route),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+        let service = self.service.clone();
+
+        let request_cx = cx.clone();
+        async move {
+            let result = service.call(sreq).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(error) => error.as_response_error().status_code(),
+            };
+            let span = request_cx.span();
+            span.set_attribute(KeyValue::new(
+                "http.status_code",
+                i64::from(status.as_u16()),
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+            result
+        }
+        .with_context(cx)
+        .boxed_local()
+    }
+}
+
+/// Wraps a client, tracing each storage request in a span
+#[derive(Clone)]
+pub struct TracedDbClient(Box<dyn DbClient>);
+
+impl TracedDbClient {
+    pub fn new(db: Box<dyn DbClient>) -> Self {
+        Self(db)
+    }
+}
+
+#[async_trait]
+impl DbClient for TracedDbClient {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        in_span("db.add_user", self.0.add_user(user)).await
+    }
+
+    async fn update_user(&self, user: &User) -> DbResult<bool> {
+        in_span("db.update_user", self.0.update_user(user)).await
+    }
+
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        in_span(
+            "db.update_user_router",
+            self.0.update_user_router(user, previous_router_type),
+        )
+        .await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        in_span("db.get_user", self.0.get_user(uaid)).await
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        in_span("db.remove_user", self.0.remove_user(uaid)).await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        in_span("db.add_channel", self.0.add_channel(uaid, channel_id)).await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        in_span("db.get_channels", self.0.get_channels(uaid)).await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        in_span("db.remove_channel", self.0.remove_channel(uaid, channel_id)).await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+    ) -> DbResult<bool> {
+        in_span(
+            "db.remove_node_id",
+            self.0.remove_node_id(uaid, node_id, connected_at),
+        )
+        .await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        in_span("db.save_message", self.0.save_message(uaid, message)).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        in_span("db.save_messages", self.0.save_messages(uaid, messages)).await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        in_span(
+            "db.fetch_topic_messages",
+            self.0.fetch_topic_messages(uaid, limit),
+        )
+        .await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        in_span(
+            "db.fetch_timestamp_messages",
+            self.0.fetch_timestamp_messages(uaid, timestamp, limit),
+        )
+        .await
+    }
+
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        in_span(
+            "db.update_last_notified_at",
+            self.0.update_last_notified_at(uaid, timestamp),
+        )
+        .await
+    }
+
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        in_span("db.count_stale_users", self.0.count_stale_users(cutoff)).await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        in_span(
+            "db.increment_storage",
+            self.0.increment_storage(uaid, timestamp),
+        )
+        .await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        in_span("db.get_message", self.0.get_message(uaid, sort_key)).await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        in_span("db.remove_message", self.0.remove_message(uaid, sort_key)).await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        in_span(
+            "db.remove_messages",
+            self.0.remove_messages(uaid, sort_keys),
+        )
+        .await
+    }
+
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        in_span(
+            "db.remove_expired_messages",
+            self.0.remove_expired_messages(limit),
+        )
+        .await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        in_span("db.router_table_exists", self.0.router_table_exists()).await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        in_span("db.message_table_exists", self.0.message_table_exists()).await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        in_span("db.health_check", self.0.health_check()).await
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.0.rotating_message_table()
+    }
+
+    fn shutdown(&self) {
+        self.0.shutdown()
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{test, web, App, HttpResponse};
+    use autopush_common::db::{client::DbClient, mock::MockDbClient};
+    use opentelemetry::{
+        global,
+        trace::{SpanId, TraceId, TraceResult},
+        Context,
+    };
+    use opentelemetry_sdk::{
+        export::trace::SpanData,
+        propagation::TraceContextPropagator,
+        trace::{Span, SpanProcessor, TracerProvider},
+    };
+    use uuid::Uuid;
+
+    use super::{in_span, span, trace_headers, TracedDbClient, TracingWrapper};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// Records the ended spans
+    #[derive(Clone, Debug, Default)]
+    struct Recorder(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanProcessor for Recorder {
+        fn on_start(&self, _: &mut Span, _: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A request handled like a notification: validating VAPID, looking up
+    /// the user then sending to a bridge
+    async fn notify(db: web::Data<Box<dyn DbClient>>) -> HttpResponse {
+        drop(span("vapid.validate"));
+        db.get_user(&Uuid::new_v4()).await.unwrap();
+        in_span("fcm.send", async {
+            reqwest::Client::new()
+                .post(mockito::server_url() + "/traced-bridge")
+                .headers(trace_headers())
+                .send()
+                .await
+                .unwrap()
+        })
+        .await;
+        HttpResponse::Created().finish()
+    }
+
+    #[actix_rt::test]
+    async fn request_span_tree() {
+        let recorder = Recorder::default();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(
+            TracerProvider::builder()
+                .with_span_processor(recorder.clone())
+                .build(),
+        );
+        let bridge = mockito::mock("POST", "/traced-bridge")
+            .match_header(
+                "traceparent",
+                mockito::Matcher::Regex(format!("^00-{TRACE_ID}-[0-9a-f]{{16}}-01$")),
+            )
+            .create();
+
+        let mut db = MockDbClient::new();
+        db.expect_get_user().times(1).return_once(|_| Ok(None));
+        let db: Box<dyn DbClient> = Box::new(TracedDbClient::new(db.into_boxed_arc()));
+        let app = test::init_service(
+            App::new()
+                .wrap(TracingWrapper)
+                .app_data(web::Data::new(db))
+                .route("/wpush/{token}", web::post().to(notify)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/wpush/abc")
+            .insert_header(("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        bridge.assert();
+
+        let trace_id = TraceId::from_hex(TRACE_ID).unwrap();
+        let spans: Vec<SpanData> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| span.span_context.trace_id() == trace_id)
+            .cloned()
+            .collect();
+        let root = spans
+            .iter()
+            .find(|span| span.name == "POST /wpush/{token}")
+            .expect("No request span");
+        assert_eq!(root.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+        let mut children: Vec<&str> = spans
+            .iter()
+            .filter(|span| span.parent_span_id == root.span_context.span_id())
+            .map(|span| span.name.as_ref())
+            .collect();
+        children.sort_unstable();
+        assert_eq!(children, ["db.get_user", "fcm.send", "vapid.validate"]);
+    }
+}
@@ -0,0 +1,459 @@
+//! A durable outbox for notifications accepted with `Prefer: respond-async`
+//!
+//! An asynchronously accepted notification is only delivered after its 202
+//! response, so a crash in between would silently lose it. When
+//! `async_outbox_enabled`, it's first stored in the message table under the
+//! reserved [OUTBOX_UAID] and only removed once its delivery settles.
+//!
+//! Every instance periodically (and on startup) sweeps the outbox, delivering
+//! again the entries left behind: by a crash, or kept after failing on the
+//! bridge's side. The outbox is shared by all the instances, so each entry
+//! carries a lease: its stored `timestamp`, the time it was accepted or last
+//! claimed by a sweep (claiming moves it up, shortening the TTL to match).
+//! Only entries whose lease is older than `async_outbox_lease_secs` are
+//! claimed, so those an instance is still delivering aren't delivered twice.
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use autopush_common::db::client::DbClient;
+use autopush_common::notification::Notification as StoredNotification;
+use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+use cadence::{Counted, StatsdClient};
+use fernet::MultiFernet;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::extractors::{
+    message_id::MessageId,
+    notification::Notification,
+    notification_headers::{NotificationHeaders, DEFAULT_URGENCY},
+    routers::{RouterType, Routers},
+    subscription::Subscription,
+};
+use crate::routers::RouterResponse;
+use crate::routes::webpush::route;
+
+/// The UAID the outbox's entries are stored under. Never issued to a client
+/// (UAIDs are random v4 UUIDs).
+pub const OUTBOX_UAID: Uuid = Uuid::nil();
+
+/// The entries fetched at a time by a sweep
+const PAGE_SIZE: usize = 100;
+
+pub struct Outbox {
+    db: Box<dyn DbClient>,
+    /// How long an entry's left to the instance delivering it
+    lease: Duration,
+    metrics: Arc<StatsdClient>,
+}
+
+impl Outbox {
+    pub fn new(db: Box<dyn DbClient>, lease: Duration, metrics: Arc<StatsdClient>) -> Self {
+        Self { db, lease, metrics }
+    }
+
+    /// Store an accepted notification until its delivery settles, leased
+    /// from its acceptance
+    pub async fn add(&self, notification: &Notification) -> ApiResult<()> {
+        self.db
+            .save_message(&OUTBOX_UAID, notification.clone().into())
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the entry's lease has expired at `now`
+    fn lease_expired(&self, stored: &StoredNotification, now: u64) -> bool {
+        now >= stored.timestamp.saturating_add(self.lease.as_secs())
+    }
+
+    /// Remove the notification once its delivery `result` settles it: when
+    /// delivered or permanently rejected. Entries failing on the bridge's
+    /// side are kept, for a sweep to retry once their lease expires.
+    pub async fn settle(&self, notification: &Notification, result: &ApiResult<RouterResponse>) {
+        if !settles(result) {
+            return;
+        }
+        let stored: StoredNotification = notification.clone().into();
+        self.remove(&stored).await;
+    }
+
+    async fn remove(&self, stored: &StoredNotification) {
+        if let Err(e) = self
+            .db
+            .remove_message(&OUTBOX_UAID, &stored.chidmessageid())
+            .await
+        {
+            warn!("Could not remove an outbox entry: {}", e);
+        }
+    }
+
+    /// Sweep the outbox on startup then every `interval` (jittered, so the
+    /// instances' sweeps spread out), delivering via `routers`
+    pub async fn recover_periodically(
+        self,
+        fernet: MultiFernet,
+        routers: Routers,
+        interval: Duration,
+    ) {
+        let routers = &routers;
+        loop {
+            let result = self
+                .recover(&fernet, move |notification| async move {
+                    let router_type =
+                        RouterType::from_str(&notification.subscription.user.router_type)
+                            .map_err(|_| ApiErrorKind::InvalidRouterType)?;
+                    route(&notification, routers, router_type).await
+                })
+                .await;
+            match result {
+                Ok(recovered) => debug!("Recovered {} outbox entries", recovered),
+                Err(e) => error!("Could not recover the outbox: {}", e),
+            }
+            let jitter = rand::thread_rng().gen_range(0..=interval.as_millis() as u64 / 2);
+            actix_rt::time::sleep(interval + Duration::from_millis(jitter)).await;
+        }
+    }
+
+    /// Deliver the outbox's entries whose lease expired, returning the number
+    /// delivered
+    async fn recover<F, Fut>(&self, fernet: &MultiFernet, deliver: F) -> ApiResult<usize>
+    where
+        F: Fn(Notification) -> Fut,
+        Fut: Future<Output = ApiResult<RouterResponse>>,
+    {
+        let now = sec_since_epoch();
+        let mut recovered = 0;
+        // Topic messages can't be paged through, but the settled ones are
+        // removed: fetch until a page settles none
+        loop {
+            let page = self
+                .db
+                .fetch_topic_messages(&OUTBOX_UAID, PAGE_SIZE)
+                .await?;
+            let full = page.messages.len() >= PAGE_SIZE;
+            let (delivered, removed) = self
+                .recover_page(fernet, &deliver, page.messages, now)
+                .await?;
+            recovered += delivered;
+            if !full || removed == 0 {
+                break;
+            }
+        }
+        let mut cursor = None;
+        loop {
+            let page = self
+                .db
+                .fetch_timestamp_messages(&OUTBOX_UAID, cursor, PAGE_SIZE)
+                .await?;
+            if page.messages.is_empty() {
+                break;
+            }
+            let (delivered, _) = self
+                .recover_page(fernet, &deliver, page.messages, now)
+                .await?;
+            recovered += delivered;
+            match page.timestamp {
+                Some(timestamp) if cursor != Some(timestamp) => cursor = Some(timestamp),
+                _ => break,
+            }
+        }
+        if recovered > 0 {
+            self.metrics
+                .count("outbox.recovered", recovered as i64)
+                .ok();
+        }
+        Ok(recovered)
+    }
+
+    /// Claim and deliver the `entries` whose lease expired, returning the
+    /// number delivered and the number removed from the outbox
+    async fn recover_page<F, Fut>(
+        &self,
+        fernet: &MultiFernet,
+        deliver: &F,
+        entries: Vec<StoredNotification>,
+        now: u64,
+    ) -> ApiResult<(usize, usize)>
+    where
+        F: Fn(Notification) -> Fut,
+        Fut: Future<Output = ApiResult<RouterResponse>>,
+    {
+        let (mut delivered, mut removed) = (0, 0);
+        for stored in entries {
+            if !self.lease_expired(&stored, now) {
+                continue;
+            }
+            let stored = leased(stored, now);
+            let Some(notification) = self.rebuild(fernet, &stored).await? else {
+                self.remove(&stored).await;
+                removed += 1;
+                continue;
+            };
+            // Claim it, so other sweeps leave it be
+            self.db.save_message(&OUTBOX_UAID, stored).await?;
+            let result = deliver(notification.clone()).await;
+            if let Err(e) = &result {
+                let uaid = notification.subscription.user.uaid;
+                warn!("Outbox redelivery failed: {}", e; "uaid" => %uaid);
+            } else {
+                delivered += 1;
+            }
+            if settles(&result) {
+                removed += 1;
+            }
+            self.settle(&notification, &result).await;
+        }
+        Ok((delivered, removed))
+    }
+
+    /// Rebuild the notification of an outbox entry, `None` when it's no
+    /// longer deliverable (its TTL ran out or its subscriber's gone)
+    async fn rebuild(
+        &self,
+        fernet: &MultiFernet,
+        stored: &StoredNotification,
+    ) -> ApiResult<Option<Notification>> {
+        // The message id names the subscriber
+        let Ok(message_id) = MessageId::decrypt(fernet, &stored.version) else {
+            warn!("Dropping an outbox entry with an invalid message id");
+            return Ok(None);
+        };
+        let elapsed = sec_since_epoch().saturating_sub(stored.timestamp);
+        if elapsed >= stored.ttl {
+            return Ok(None);
+        }
+        let Some(user) = self.db.get_user(&message_id.uaid()).await? else {
+            return Ok(None);
+        };
+
+        let mut headers = stored.headers.clone().unwrap_or_default();
+        Ok(Some(Notification {
+            message_id: stored.version.clone(),
+            subscription: Subscription {
+                user,
+                channel_id: stored.channel_id,
+                vapid: None,
+            },
+            headers: NotificationHeaders {
+                ttl: (stored.ttl - elapsed) as i64,
+                topic: stored.topic.clone(),
+                urgency: headers
+                    .remove("urgency")
+                    .unwrap_or_else(|| DEFAULT_URGENCY.to_owned()),
+                encoding: headers.remove("encoding"),
+                encryption: headers.remove("encryption"),
+                encryption_key: headers.remove("encryption_key"),
+                crypto_key: headers.remove("crypto_key"),
+            },
+            timestamp: stored.timestamp,
+            sort_key_timestamp: stored.sortkey_timestamp.unwrap_or_else(ms_since_epoch),
+            data: stored.data.clone(),
+            dry_run: false,
+            respond_async: true,
+            idempotency_key: None,
+        }))
+    }
+}
+
+/// The entry leased from `now`: its `timestamp` moved up to `now` and its TTL
+/// shortened by as much, so it still expires when it would have. Its sort key
+/// is unchanged, so storing it replaces the entry.
+fn leased(mut stored: StoredNotification, now: u64) -> StoredNotification {
+    let elapsed = now.saturating_sub(stored.timestamp);
+    stored.ttl = stored.ttl.saturating_sub(elapsed);
+    stored.timestamp = now;
+    stored
+}
+
+/// Whether the delivery `result` settles its entry (see [Outbox::settle])
+fn settles(result: &ApiResult<RouterResponse>) -> bool {
+    !matches!(result, Err(e) if e.kind.status().is_server_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use autopush_common::db::{
+        client::FetchMessageResponse, mock::MockDbClient, NotificationRecord, User,
+    };
+    use autopush_common::notification::Notification as StoredNotification;
+    use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+    use cadence::{NopMetricSink, SpyMetricSink, StatsdClient};
+    use fernet::{Fernet, MultiFernet};
+    use uuid::Uuid;
+
+    use super::{leased, Outbox, OUTBOX_UAID};
+    use crate::extractors::message_id::MessageId;
+    use crate::routers::RouterResponse;
+
+    const LEASE: Duration = Duration::from_secs(300);
+
+    /// An outbox entry to `uaid`, accepted `age` seconds ago
+    fn entry(fernet: &MultiFernet, uaid: Uuid, age: u64) -> StoredNotification {
+        let (channel_id, timestamp) = (Uuid::new_v4(), ms_since_epoch());
+        let version = MessageId::WithoutTopic {
+            uaid,
+            channel_id,
+            timestamp,
+            expiry: Some(sec_since_epoch() + 3600),
+        }
+        .encrypt(fernet);
+        StoredNotification {
+            channel_id,
+            version,
+            ttl: 3600,
+            timestamp: sec_since_epoch() - age,
+            data: Some("data".to_owned()),
+            sortkey_timestamp: Some(timestamp),
+            ..Default::default()
+        }
+    }
+
+    /// An entry left behind (by a crash) past its lease is claimed,
+    /// delivered, then removed
+    #[actix_rt::test]
+    async fn recovers_entries() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let user = User::default();
+        let uaid = user.uaid;
+        let stored = entry(&fernet, uaid, 600);
+        let (version, channel_id) = (stored.version.clone(), stored.channel_id);
+        let sort_key = stored.chidmessageid();
+        let sortkey_timestamp = stored.sortkey_timestamp;
+
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .withf(|uaid, _| uaid == &OUTBOX_UAID)
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        let mut pages = vec![
+            FetchMessageResponse {
+                timestamp: sortkey_timestamp,
+                messages: vec![stored],
+            },
+            Default::default(),
+        ]
+        .into_iter();
+        db.expect_fetch_timestamp_messages()
+            .withf(|uaid, _, _| uaid == &OUTBOX_UAID)
+            .times(2)
+            .returning(move |_, _, _| Ok(pages.next().unwrap()));
+        db.expect_get_user()
+            .withf(move |id| id == &uaid)
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let claimed_key = sort_key.clone();
+        db.expect_save_message()
+            .withf(move |uaid, stored| {
+                uaid == &OUTBOX_UAID
+                    && stored.chidmessageid() == claimed_key
+                    && stored.timestamp + 5 >= sec_since_epoch()
+                    && stored.ttl <= 3600 - 600
+            })
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        db.expect_remove_message()
+            .withf(move |uaid, key| uaid == &OUTBOX_UAID && key == sort_key)
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let (rx, sink) = SpyMetricSink::new();
+        let outbox = Outbox::new(
+            db.into_boxed_arc(),
+            LEASE,
+            Arc::new(StatsdClient::builder("", sink).build()),
+        );
+
+        let delivered = RefCell::new(Vec::new());
+        let recovered = outbox
+            .recover(&fernet, |notification| {
+                delivered.borrow_mut().push(notification);
+                async { Ok(RouterResponse::success("location".to_owned(), 60)) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(recovered, 1);
+        let delivered = delivered.into_inner();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].message_id, version);
+        assert_eq!(delivered[0].subscription.user.uaid, uaid);
+        assert_eq!(delivered[0].subscription.channel_id, channel_id);
+        assert_eq!(delivered[0].data.as_deref(), Some("data"));
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert!(metrics.iter().any(|m| m.contains("outbox.recovered:1|c")));
+    }
+
+    /// Entries within their lease (still being delivered by another
+    /// instance, or recently claimed) are left alone
+    #[actix_rt::test]
+    async fn skips_leased_entries() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let fresh = entry(&fernet, Uuid::new_v4(), 10);
+        let claimed = leased(entry(&fernet, Uuid::new_v4(), 600), sec_since_epoch() - 10);
+
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![fresh, claimed],
+                })
+            });
+        let outbox = Outbox::new(
+            db.into_boxed_arc(),
+            LEASE,
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+        );
+
+        let delivered = RefCell::new(0);
+        let recovered = outbox
+            .recover(&fernet, |_| {
+                *delivered.borrow_mut() += 1;
+                async { Ok(RouterResponse::success("location".to_owned(), 60)) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(*delivered.borrow(), 0);
+        assert_eq!(recovered, 0);
+    }
+
+    /// A claim is kept in the entry's stored fields, so it survives the round
+    /// trip through storage
+    #[test]
+    fn claim_survives_storage() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let accepted = entry(&fernet, Uuid::new_v4(), 600);
+        let now = accepted.timestamp + 600;
+        let claimed = leased(accepted.clone(), now);
+        assert_eq!(claimed.chidmessageid(), accepted.chidmessageid());
+
+        let stored = NotificationRecord::from_notif(&OUTBOX_UAID, claimed)
+            .into_notif()
+            .unwrap();
+        assert_eq!(stored.timestamp, now);
+        assert_eq!(stored.ttl, 3600 - 600);
+        assert_eq!(stored.chidmessageid(), accepted.chidmessageid());
+
+        let outbox = Outbox::new(
+            MockDbClient::new().into_boxed_arc(),
+            LEASE,
+            Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+        );
+        assert!(outbox.lease_expired(&accepted, now));
+        assert!(!outbox.lease_expired(&stored, now + 10));
+        assert!(outbox.lease_expired(&stored, now + LEASE.as_secs()));
+    }
+}
@@ -0,0 +1,234 @@
+//! Delivery receipts, POSTed to the `receipt_sink_url` webhook
+//!
+//! A receipt's emitted per terminal outcome of routing a notification (as
+//! seen by the app server: dry runs and the outbox's recovery emit none).
+//! They're queued (up to `receipt_buffer_size`) and sent one at a time in the
+//! background, never delaying the request. Receipts that don't fit in the
+//! queue are dropped, counted by the `receipt.dropped` metric.
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::http::StatusCode;
+use autopush_common::util::{b64_encode_url, sec_since_epoch};
+use cadence::{CountedExt, StatsdClient};
+use openssl::hash::MessageDigest;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::error::ApiResult;
+use crate::extractors::routers::RouterType;
+use crate::routers::RouterResponse;
+
+/// The base64url SHA-256 hash of an endpoint's token: identifying the
+/// endpoint without allowing its holder to send to it
+pub fn token_hash(token: &str) -> String {
+    let hash = openssl::hash::hash(MessageDigest::sha256(), token.as_bytes())
+        .expect("SHA-256 is unavailable");
+    b64_encode_url(&hash.to_vec())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Delivered to the client or its bridge, or stored for the client
+    Delivered,
+    /// Dropped undelivered, having a TTL of 0
+    Expired,
+    /// The subscription's gone (410)
+    Gone,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Receipt {
+    token_hash: String,
+    bridge: String,
+    /// Milliseconds spent routing the notification
+    latency_ms: u64,
+    outcome: Outcome,
+    /// The status code of the outcome
+    code: u16,
+    /// UNIX timestamp in seconds
+    timestamp: u64,
+}
+
+impl Receipt {
+    /// The receipt for routing a notification to `token` (via `router_type`)
+    /// since `started`
+    pub fn new(
+        token: &str,
+        router_type: RouterType,
+        started: Instant,
+        result: &ApiResult<RouterResponse>,
+    ) -> Self {
+        let (outcome, code) = match result {
+            Ok(response) if response.expired => (Outcome::Expired, response.status),
+            Ok(response) => (Outcome::Delivered, response.status),
+            Err(e) if e.kind.status() == StatusCode::GONE => (Outcome::Gone, e.kind.status()),
+            Err(e) => (Outcome::Failed, e.kind.status()),
+        };
+        Self {
+            token_hash: token_hash(token),
+            bridge: router_type.to_string(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            outcome,
+            code: code.as_u16(),
+            timestamp: sec_since_epoch(),
+        }
+    }
+}
+
+/// The queue of receipts to send
+pub struct ReceiptSink {
+    queue: mpsc::Sender<Receipt>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl ReceiptSink {
+    /// Start sending receipts to the webhook at `url`, queueing at most
+    /// `buffer_size`
+    pub fn start(
+        http: reqwest::Client,
+        url: String,
+        buffer_size: usize,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        let (queue, receipts) = mpsc::channel(buffer_size);
+        actix_rt::spawn(send_receipts(receipts, http, url, metrics.clone()));
+        Self { queue, metrics }
+    }
+
+    /// Queue the receipt, dropping it when the queue's full
+    pub fn emit(&self, receipt: Receipt) {
+        if self.queue.try_send(receipt).is_err() {
+            self.metrics.incr("receipt.dropped").ok();
+        }
+    }
+}
+
+async fn send_receipts(
+    mut receipts: mpsc::Receiver<Receipt>,
+    http: reqwest::Client,
+    url: String,
+    metrics: Arc<StatsdClient>,
+) {
+    while let Some(receipt) = receipts.recv().await {
+        let result = http
+            .post(&url)
+            .json(&receipt)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = result {
+            warn!("Could not send the delivery receipt: {}", e);
+            metrics.incr("receipt.error").ok();
+        } else {
+            metrics.incr("receipt.sent").ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use cadence::{SpyMetricSink, StatsdClient};
+    use serde_json::json;
+
+    use super::{Outcome, Receipt, ReceiptSink};
+    use crate::error::ApiErrorKind;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::RouterResponse;
+
+    #[test]
+    fn outcomes() {
+        let outcome = |result| {
+            let receipt = Receipt::new("token", RouterType::FCM, Instant::now(), &result);
+            (receipt.outcome, receipt.code)
+        };
+        let mut response = RouterResponse::success("location".to_owned(), 0);
+        assert_eq!(outcome(Ok(response.clone())), (Outcome::Delivered, 200));
+        response.expired = true;
+        assert_eq!(outcome(Ok(response)), (Outcome::Expired, 200));
+        assert_eq!(
+            outcome(Err(ApiErrorKind::NoSubscription.into())),
+            (Outcome::Gone, 410)
+        );
+        assert_eq!(
+            outcome(Err(ApiErrorKind::RequestTimeout.into())),
+            (Outcome::Failed, 504)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn sends_receipt() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+        let mock = mockito::mock("POST", "/receipts")
+            .match_header("content-type", "application/json")
+            .match_body(mockito::Matcher::PartialJson(json!({
+                // base64url(sha256("token"))
+                "token_hash": "PEaenWxYddN6Q_NT1PiOYfz4EsZu7jRXRlpAsNpBU-A",
+                "bridge": "apns",
+                "outcome": "delivered",
+                "code": 200,
+            })))
+            .create();
+        let receipts = ReceiptSink::start(
+            reqwest::Client::new(),
+            mockito::server_url() + "/receipts",
+            10,
+            metrics,
+        );
+        let result = Ok(RouterResponse::success("location".to_owned(), 60));
+        receipts.emit(Receipt::new(
+            "token",
+            RouterType::APNS,
+            Instant::now(),
+            &result,
+        ));
+
+        // Sent in the background
+        let mut metrics = Vec::new();
+        for _ in 0..50 {
+            metrics.extend(rx.try_iter().map(|m| String::from_utf8(m).unwrap()));
+            if !metrics.is_empty() {
+                break;
+            }
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(metrics, ["autoendpoint.receipt.sent:1|c"]);
+        mock.assert();
+    }
+
+    /// Receipts beyond the queue's capacity are dropped, not awaited
+    #[actix_rt::test]
+    async fn drops_receipts_when_full() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+        // Unreachable, but the sender task doesn't run before the receipts
+        // are queued (the test's single threaded)
+        let receipts = ReceiptSink::start(
+            reqwest::Client::new(),
+            "http://127.0.0.1:1/receipts".to_owned(),
+            2,
+            metrics,
+        );
+        let result = Ok(RouterResponse::success("location".to_owned(), 60));
+        for _ in 0..5 {
+            receipts.emit(Receipt::new(
+                "token",
+                RouterType::FCM,
+                Instant::now(),
+                &result,
+            ));
+        }
+        let dropped = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .filter(|m| m == "autoendpoint.receipt.dropped:1|c")
+            .count();
+        assert_eq!(dropped, 3);
+    }
+}
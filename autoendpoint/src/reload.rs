@@ -0,0 +1,210 @@
+//! Reloading the settings on SIGHUP
+//!
+//! The config file and environment are re-read and validated, then the
+//! reloadable subset of the settings (see [Settings]) swapped in. Invalid
+//! settings are rejected, keeping the current ones.
+use std::sync::{Arc, RwLock};
+
+use autopush_common::logging;
+use config::ConfigError;
+
+use crate::routers::rate_limiter::SubscriptionRateLimiter;
+use crate::settings::Settings;
+
+/// The current settings, including any reloaded ones
+pub struct LiveSettings(RwLock<Arc<Settings>>);
+
+impl LiveSettings {
+    pub fn new(settings: Settings) -> Self {
+        Self(RwLock::new(Arc::new(settings)))
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        self.0.read().expect("LiveSettings lock poisoned").clone()
+    }
+
+    /// Swap in the reloadable settings of `reloaded`, returning the new
+    /// current settings
+    fn apply(&self, reloaded: Settings) -> Arc<Settings> {
+        let mut current = self.0.write().expect("LiveSettings lock poisoned");
+        *current = Arc::new(merge(&current, reloaded));
+        current.clone()
+    }
+}
+
+/// The `current` settings, updated with the reloadable ones of `reloaded`
+fn merge(current: &Settings, reloaded: Settings) -> Settings {
+    let fixed = [
+        ("scheme", current.scheme != reloaded.scheme),
+        ("host", current.host != reloaded.host),
+        ("port", current.port != reloaded.port),
+        ("tcp_backlog", current.tcp_backlog != reloaded.tcp_backlog),
+        ("tcp_nodelay", current.tcp_nodelay != reloaded.tcp_nodelay),
+        (
+            "tls_cert_path",
+            current.tls_cert_path != reloaded.tls_cert_path,
+        ),
+        (
+            "tls_key_path",
+            current.tls_key_path != reloaded.tls_key_path,
+        ),
+        (
+            "tls_min_version",
+            current.tls_min_version != reloaded.tls_min_version,
+        ),
+        (
+            "http2_enabled",
+            current.http2_enabled != reloaded.http2_enabled,
+        ),
+        (
+            "http2_cleartext",
+            current.http2_cleartext != reloaded.http2_cleartext,
+        ),
+        (
+            "url_path_prefix",
+            current.url_path_prefix != reloaded.url_path_prefix,
+        ),
+        ("db_dsn", current.db_dsn != reloaded.db_dsn),
+        ("db_read_dsn", current.db_read_dsn != reloaded.db_read_dsn),
+        ("db_settings", current.db_settings != reloaded.db_settings),
+        (
+            "database_table_prefix",
+            current.database_table_prefix != reloaded.database_table_prefix,
+        ),
+        (
+            "subscription_cache_ttl_secs",
+            current.subscription_cache_ttl_secs != reloaded.subscription_cache_ttl_secs,
+        ),
+        (
+            "subscription_cache_size",
+            current.subscription_cache_size != reloaded.subscription_cache_size,
+        ),
+        // Built into the bridges' clients
+        (
+            "bridge_pool_idle_timeout_secs",
+            current.bridge_pool_idle_timeout_secs != reloaded.bridge_pool_idle_timeout_secs,
+        ),
+    ];
+    for (field, _) in fixed.iter().filter(|(_, changed)| *changed) {
+        warn!(
+            "Ignoring the changed {} setting, it requires a restart",
+            field
+        );
+    }
+
+    let mut settings = current.clone();
+    settings.log_level = reloaded.log_level;
+    settings.subscription_rate_limit_per_sec = reloaded.subscription_rate_limit_per_sec;
+    settings.subscription_rate_limit_burst = reloaded.subscription_rate_limit_burst;
+    settings.statsd_sample_rate = reloaded.statsd_sample_rate;
+    settings.blocked_vapid_subjects = reloaded.blocked_vapid_subjects;
+    settings.maintenance_mode = reloaded.maintenance_mode;
+    // Applied by the bridges per request
+    settings.bridge_request_timeout_ms = reloaded.bridge_request_timeout_ms;
+    settings.fcm.timeout_ms = reloaded.fcm.timeout_ms;
+    settings.fcm.timeout = reloaded.fcm.timeout;
+    settings.apns.timeout_ms = reloaded.apns.timeout_ms;
+    settings.adm.timeout = reloaded.adm.timeout;
+    settings
+}
+
+/// Reload the settings from `filename` (and the environment), applying them
+/// to the logger and rate limiter
+fn reload(
+    filename: &Option<String>,
+    live: &LiveSettings,
+    rate_limiter: &SubscriptionRateLimiter,
+) -> Result<Arc<Settings>, ConfigError> {
+    let settings = live.apply(Settings::with_env_and_config_file(filename)?);
+    logging::set_log_level(Some(settings.log_level.as_str()));
+    rate_limiter.set_limits(
+        settings.subscription_rate_limit_per_sec,
+        settings.subscription_rate_limit_burst,
+    );
+    Ok(settings)
+}
+
+/// Reload the settings on every SIGHUP
+pub async fn reload_on_sighup(
+    filename: Option<String>,
+    live: Arc<LiveSettings>,
+    rate_limiter: Arc<SubscriptionRateLimiter>,
+) {
+    let mut sighup =
+        match actix_rt::signal::unix::signal(actix_rt::signal::unix::SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                error!("Could not install SIGHUP handler: {:?}", e);
+                return;
+            }
+        };
+    while sighup.recv().await.is_some() {
+        match reload(&filename, &live, &rate_limiter) {
+            Ok(_) => info!("SIGHUP received, settings reloaded"),
+            Err(e) => error!("SIGHUP received, keeping the current settings: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use uuid::Uuid;
+
+    use super::{reload, LiveSettings};
+    use crate::routers::rate_limiter::SubscriptionRateLimiter;
+    use crate::settings::Settings;
+
+    #[test]
+    fn reloads_subset() {
+        let mut config = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(
+            config,
+            "log_level = \"debug\"\nsubscription_rate_limit_per_sec = 1.0\n\
+             subscription_rate_limit_burst = 1\nstatsd_sample_rate = 0.5\n\
+             blocked_vapid_subjects = [\"mailto:abuse@example.com\"]\n\
+             maintenance_mode = true\n\
+             port = 9000\nrouter_table_name = \"other_router\"\n\
+             bridge_request_timeout_ms = 100"
+        )
+        .unwrap();
+        let filename = Some(config.path().to_string_lossy().into_owned());
+        let live = LiveSettings::new(Settings::default());
+        let rate_limiter = SubscriptionRateLimiter::new(0.0, 10, 10);
+
+        let settings = reload(&filename, &live, &rate_limiter).unwrap();
+        assert_eq!(settings.log_level, "debug");
+        assert_eq!(settings.subscription_rate_limit_per_sec, 1.0);
+        assert_eq!(settings.subscription_rate_limit_burst, 1);
+        assert_eq!(settings.statsd_sample_rate, 0.5);
+        assert_eq!(
+            settings.blocked_vapid_subjects,
+            ["mailto:abuse@example.com"]
+        );
+        assert!(settings.maintenance_mode);
+        assert_eq!(settings.bridge_request_timeout_ms, 100);
+        // Not reloadable
+        assert_eq!(settings.port, Settings::default().port);
+        assert_eq!(settings.router_table_name, "router");
+        assert_eq!(live.get().log_level, "debug");
+
+        // The rate limiter's now enabled
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(rate_limiter.check(&uaid, &chid).is_ok());
+        assert!(rate_limiter.check(&uaid, &chid).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let mut config = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(config, "log_level = \"debug\"\nstatsd_sample_rate = 5.0").unwrap();
+        let filename = Some(config.path().to_string_lossy().into_owned());
+        let live = LiveSettings::new(Settings::default());
+        let rate_limiter = SubscriptionRateLimiter::new(0.0, 10, 10);
+
+        assert!(reload(&filename, &live, &rate_limiter).is_err());
+        assert_eq!(live.get().log_level, Settings::default().log_level);
+        assert_eq!(live.get().statsd_sample_rate, 1.0);
+    }
+}
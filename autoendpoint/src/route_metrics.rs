@@ -0,0 +1,160 @@
+//! Per route request metrics
+//!
+//! Every request is counted (`request.count`) and timed (`request.duration`,
+//! in milliseconds), tagged with its route's template (e.g.
+//! `/wpush/{token}`, never the concrete path, which includes tokens) and its
+//! response's status class (e.g. `2xx`).
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    Error,
+};
+use cadence::{CountedExt, StatsdClient, Timed};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt as _;
+use futures_util::future::{ok, Ready};
+
+pub struct RouteMetrics {
+    metrics: Arc<StatsdClient>,
+}
+
+impl RouteMetrics {
+    pub fn new(metrics: Arc<StatsdClient>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RouteMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RouteMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RouteMetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct RouteMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl<S, B> Service<ServiceRequest> for RouteMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let route = sreq
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let metrics = self.metrics.clone();
+        let handling = self.service.call(sreq);
+        async move {
+            let res = handling.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            let status = status_class(status);
+            metrics
+                .incr_with_tags("request.count")
+                .with_tag("route", &route)
+                .with_tag("status", status)
+                .send();
+            metrics
+                .time_with_tags("request.duration", start.elapsed().as_millis() as u64)
+                .with_tag("route", &route)
+                .with_tag("status", status)
+                .send();
+            res
+        }
+        .boxed_local()
+    }
+}
+
+/// The class of a status code (e.g. `4xx`)
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, web, App, HttpResponse};
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::RouteMetrics;
+
+    #[actix_rt::test]
+    async fn tags_by_route_template() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+        let app = test::init_service(
+            App::new()
+                .wrap(RouteMetrics::new(metrics))
+                .route("/wpush/{token}", web::post().to(HttpResponse::Created))
+                .route("/gone/{token}", web::post().to(HttpResponse::Gone)),
+        )
+        .await;
+
+        for uri in ["/wpush/abc", "/wpush/def", "/gone/abc", "/nowhere"] {
+            let req = test::TestRequest::post().uri(uri).to_request();
+            test::call_service(&app, req).await;
+        }
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        // Both tokens aggregate under the one route
+        let counts: Vec<_> = metrics
+            .iter()
+            .filter(|m| m.starts_with("autoendpoint.request.count:1|c|"))
+            .map(|m| m.split_once("|#").unwrap().1)
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                "route:/wpush/{token},status:2xx",
+                "route:/wpush/{token},status:2xx",
+                "route:/gone/{token},status:4xx",
+                "route:unmatched,status:4xx",
+            ]
+        );
+        let durations = metrics
+            .iter()
+            .filter(|m| m.starts_with("autoendpoint.request.duration:"))
+            .filter(|m| m.ends_with("|ms|#route:/wpush/{token},status:2xx"))
+            .count();
+        assert_eq!(durations, 2);
+    }
+}
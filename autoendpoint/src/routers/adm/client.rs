@@ -1,3 +1,6 @@
+use crate::extractors::routers::RouterType;
+use crate::otel;
+use crate::reload::LiveSettings;
 use crate::routers::adm::error::AdmError;
 use crate::routers::adm::settings::{AdmProfile, AdmSettings};
 use crate::routers::common::message_size_check;
@@ -7,16 +10,21 @@ use futures::lock::Mutex;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
 use url::Url;
 
+/// ADM error reasons indicating the registration ID is no longer valid
+const UNREGISTERED_REASONS: &[&str] = &["Unregistered", "InvalidRegistrationId"];
+
 /// Holds profile-specific ADM data and authentication. This client handles
 /// sending notifications to ADM.
 pub struct AdmClient {
     base_url: Url,
     profile: AdmProfile,
-    timeout: Duration,
     max_data: usize,
+    /// The current settings, read for each request's timeout (so reloads
+    /// apply)
+    live_settings: Arc<LiveSettings>,
     http: reqwest::Client,
     token_info: Mutex<TokenInfo>,
 }
@@ -50,18 +58,28 @@ struct AdmResponseError {
 
 impl AdmClient {
     /// Create an `AdmClient` using the provided profile
-    pub fn new(settings: &AdmSettings, profile: AdmProfile, http: reqwest::Client) -> Self {
+    pub fn new(
+        settings: &AdmSettings,
+        profile: AdmProfile,
+        http: reqwest::Client,
+        live_settings: Arc<LiveSettings>,
+    ) -> Self {
         AdmClient {
             base_url: settings.base_url.clone(),
             profile,
-            timeout: Duration::from_secs(settings.timeout as u64),
             max_data: settings.max_data,
+            live_settings,
             http,
             // The default TokenInfo has dummy values to trigger a token fetch
             token_info: Mutex::default(),
         }
     }
 
+    /// Apply the current ADM request timeout to a request
+    fn with_timeout(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.timeout(self.live_settings.get().bridge_timeout(RouterType::ADM))
+    }
+
     /// Get an ADM access token (from cache or request a new one)
     async fn get_access_token(&self) -> Result<String, RouterError> {
         let mut token_info = self.token_info.lock().await;
@@ -73,15 +91,14 @@ impl AdmClient {
 
         trace!("Access token is out of date, requesting a new one");
         let oauth_url = self.base_url.join("auth/O2/token").unwrap();
+        let request = self.http.post(oauth_url).form(&serde_json::json!({
+            "grant_type": "client_credentials",
+            "scope": "messaging:push",
+            "client_id": &self.profile.client_id,
+            "client_secret": &self.profile.client_secret,
+        }));
         let response = self
-            .http
-            .post(oauth_url)
-            .form(&serde_json::json!({
-                "grant_type": "client_credentials",
-                "scope": "messaging:push",
-                "client_id": &self.profile.client_id,
-                "client_secret": &self.profile.client_secret,
-            }))
+            .with_timeout(request)
             .send()
             .await
             .map_err(AdmError::Http)?;
@@ -111,6 +128,11 @@ impl AdmClient {
         Ok(token_info.token.clone())
     }
 
+    /// Check the profile can authenticate, by fetching an access token
+    pub async fn preflight(&self) -> Result<(), RouterError> {
+        self.get_access_token().await.map(|_| ())
+    }
+
     /// Send the message data to ADM. The device's current registration ID is
     /// returned. If it is different than the current stored ID, the stored ID
     /// should be updated.
@@ -138,31 +160,33 @@ impl AdmClient {
             .map_err(AdmError::ParseUrl)?;
 
         // Make the request
-        let response = self
-            .http
-            .post(url)
-            .header("Authorization", format!("Bearer {}", access_token.as_str()))
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .header(
-                "X-Amzn-Type-Version",
-                "com.amazon.device.messaging.ADMMessage@1.0",
-            )
-            .header(
-                "X-Amzn-Accept-Type",
-                "com.amazon.device.messaging.ADMSendResult@1.0",
-            )
-            .body(message_json)
-            .timeout(self.timeout)
-            .send()
-            .await
-            .map_err(|e| {
-                if e.is_timeout() {
-                    RouterError::RequestTimeout
-                } else {
-                    RouterError::Connect(e)
-                }
-            })?;
+        let response = otel::in_span("adm.send", async {
+            let request = self
+                .http
+                .post(url)
+                .header("Authorization", format!("Bearer {}", access_token.as_str()))
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .header(
+                    "X-Amzn-Type-Version",
+                    "com.amazon.device.messaging.ADMMessage@1.0",
+                )
+                .header(
+                    "X-Amzn-Accept-Type",
+                    "com.amazon.device.messaging.ADMSendResult@1.0",
+                )
+                .headers(otel::trace_headers())
+                .body(message_json);
+            self.with_timeout(request).send().await
+        })
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                RouterError::RequestTimeout
+            } else {
+                RouterError::Connect(e)
+            }
+        })?;
 
         // Handle error
         let status = response.status();
@@ -175,6 +199,9 @@ impl AdmClient {
             return Err(match (status, response_error.reason) {
                 (StatusCode::UNAUTHORIZED, _) => RouterError::Authentication,
                 (StatusCode::NOT_FOUND, _) => RouterError::NotFound,
+                (_, Some(reason)) if UNREGISTERED_REASONS.contains(&reason.as_str()) => {
+                    RouterError::NotFound
+                }
                 (status, reason) => RouterError::Upstream {
                     status: status.to_string(),
                     message: reason.unwrap_or_else(|| "Unknown reason".to_string()),
@@ -192,10 +219,13 @@ impl AdmClient {
 
 #[cfg(test)]
 pub mod tests {
+    use crate::reload::LiveSettings;
     use crate::routers::adm::client::AdmClient;
     use crate::routers::adm::settings::{AdmProfile, AdmSettings};
     use crate::routers::RouterError;
+    use crate::settings::Settings;
     use std::collections::HashMap;
+    use std::sync::Arc;
     use url::Url;
 
     pub const REGISTRATION_ID: &str = "test-registration-id";
@@ -238,6 +268,7 @@ pub mod tests {
                 client_secret: CLIENT_SECRET.to_string(),
             },
             reqwest::Client::new(),
+            Arc::new(LiveSettings::new(Settings::default())),
         )
     }
 
@@ -272,6 +303,37 @@ pub mod tests {
         adm_mock.assert();
     }
 
+    /// The preflight check fetches an access token, failing when the
+    /// profile can't authenticate
+    #[tokio::test]
+    async fn preflight() {
+        let client = make_client();
+        let _token_mock = mock_token_endpoint();
+        assert!(client.preflight().await.is_ok());
+
+        // A separate path, so other tests' token mocks aren't matched
+        let client = AdmClient::new(
+            &AdmSettings {
+                base_url: Url::parse(&(mockito::server_url() + "/unauthorized/")).unwrap(),
+                ..Default::default()
+            },
+            AdmProfile {
+                client_id: CLIENT_ID.to_string(),
+                client_secret: "wrong-secret".to_string(),
+            },
+            reqwest::Client::new(),
+            Arc::new(LiveSettings::new(Settings::default())),
+        );
+        let _token_mock = mockito::mock("POST", "/unauthorized/auth/O2/token")
+            .with_status(401)
+            .with_body(r#"{"reason":"invalid_client"}"#)
+            .create();
+        assert!(matches!(
+            client.preflight().await,
+            Err(RouterError::Upstream { .. })
+        ));
+    }
+
     /// Authorization errors are handled
     #[tokio::test]
     async fn unauthorized() {
@@ -312,6 +374,27 @@ pub mod tests {
         );
     }
 
+    /// Unregistered and invalid registration IDs are treated as not found
+    #[tokio::test]
+    async fn unregistered() {
+        for (status, reason) in [(410, "Unregistered"), (400, "InvalidRegistrationId")] {
+            let client = make_client();
+            let _token_mock = mock_token_endpoint();
+            let _adm_mock = mock_adm_endpoint_builder()
+                .with_status(status)
+                .with_body(format!(r#"{{"reason":"{reason}"}}"#))
+                .create();
+
+            let result = client
+                .send(HashMap::new(), REGISTRATION_ID.to_string(), 42)
+                .await;
+            assert!(
+                matches!(result.as_ref().unwrap_err(), RouterError::NotFound),
+                "result = {result:?}"
+            );
+        }
+    }
+
     /// Unhandled errors (where a reason is returned) are wrapped and returned
     #[tokio::test]
     async fn other_adm_error() {
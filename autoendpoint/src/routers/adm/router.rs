@@ -3,10 +3,13 @@ use autopush_common::db::client::DbClient;
 use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::notification::Notification;
 use crate::extractors::router_data_input::RouterDataInput;
+use crate::reload::LiveSettings;
 use crate::routers::adm::client::AdmClient;
 use crate::routers::adm::error::AdmError;
 use crate::routers::adm::settings::AdmSettings;
-use crate::routers::common::{build_message_data, handle_error, incr_success_metrics};
+use crate::routers::common::{
+    build_message_data, dry_run_response, handle_error, incr_success_metrics,
+};
 use crate::routers::{Router, RouterError, RouterResponse};
 use async_trait::async_trait;
 use cadence::StatsdClient;
@@ -36,12 +39,17 @@ impl AdmRouter {
         http: reqwest::Client,
         metrics: Arc<StatsdClient>,
         db: Box<dyn DbClient>,
+        live_settings: Arc<LiveSettings>,
     ) -> Result<Self, AdmError> {
         let profiles = settings.profiles()?;
 
         let clients: HashMap<String, AdmClient> = profiles
             .into_iter()
-            .map(|(name, profile)| (name, AdmClient::new(&settings, profile, http.clone())))
+            .map(|(name, profile)| {
+                let client =
+                    AdmClient::new(&settings, profile, http.clone(), live_settings.clone());
+                (name, client)
+            })
             .collect();
         trace!("Initialized {} ADM clients", clients.len());
 
@@ -58,6 +66,17 @@ impl AdmRouter {
     pub fn active(&self) -> bool {
         !self.clients.is_empty()
     }
+
+    /// Check each profile can authenticate
+    pub async fn preflight(&self) -> ApiResult<()> {
+        for (profile, client) in &self.clients {
+            client.preflight().await.map_err(|e| {
+                ApiErrorKind::General(format!("ADM profile {profile} can't authenticate: {e}"))
+            })?;
+            info!("🔑 ADM profile {} authenticated", profile);
+        }
+        Ok(())
+    }
 }
 
 #[async_trait(?Send)]
@@ -112,6 +131,9 @@ impl Router for AdmRouter {
 
         // Send the notification to ADM
         let client = self.clients.get(profile).ok_or(AdmError::InvalidProfile)?;
+        if notification.dry_run {
+            return Ok(dry_run_response(&self.metrics, "adm", Some(profile)));
+        }
         trace!("Sending message to ADM: {:?}", message_data);
         let new_registration_id = match client
             .send(message_data, registration_id.to_string(), ttl)
@@ -125,7 +147,7 @@ impl Router for AdmRouter {
                     self.db.as_ref(),
                     "adm",
                     profile,
-                    notification.subscription.user.uaid,
+                    &notification.subscription.user,
                 )
                 .await)
             }
@@ -156,7 +178,7 @@ impl Router for AdmRouter {
 
         Ok(RouterResponse::success(
             self.endpoint_url
-                .join(&format!("/m/{}", notification.message_id))
+                .join(&format!("m/{}", notification.message_id))
                 .expect("Message ID is not URL-safe")
                 .to_string(),
             notification.headers.ttl as usize,
@@ -168,6 +190,7 @@ impl Router for AdmRouter {
 mod tests {
     use crate::error::ApiErrorKind;
     use crate::extractors::routers::RouterType;
+    use crate::reload::LiveSettings;
     use crate::routers::adm::client::tests::{
         mock_adm_endpoint_builder, mock_token_endpoint, CLIENT_ID, CLIENT_SECRET, REGISTRATION_ID,
     };
@@ -177,6 +200,7 @@ mod tests {
     use crate::routers::common::tests::{make_notification, CHANNEL_ID};
     use crate::routers::RouterError;
     use crate::routers::{Router, RouterResponse};
+    use crate::settings::Settings;
     use autopush_common::db::{client::DbClient, mock::MockDbClient, User};
     use cadence::StatsdClient;
     use mockall::predicate;
@@ -199,6 +223,7 @@ mod tests {
             reqwest::Client::new(),
             Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             db,
+            Arc::new(LiveSettings::new(Settings::default())),
         )
         .unwrap()
     }
@@ -310,10 +335,10 @@ mod tests {
     async fn no_adm_user() {
         let notification = make_notification(default_router_data(), None, RouterType::ADM);
         let mut db = MockDbClient::new();
-        db.expect_remove_user()
-            .with(predicate::eq(notification.subscription.user.uaid))
+        db.expect_remove_gone_user()
+            .with(predicate::eq(notification.subscription.user.clone()))
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_| Ok(true));
 
         let router = make_router(db.into_boxed_arc());
         let _token_mock = mock_token_endpoint();
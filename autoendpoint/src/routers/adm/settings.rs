@@ -1,8 +1,13 @@
 use std::collections::HashMap;
+use std::time::Duration;
+
 use url::Url;
 
+/// The default of the deprecated `timeout`
+const DEFAULT_TIMEOUT_SECS: usize = 3;
+
 /// Settings for `AdmRouter`
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct AdmSettings {
@@ -13,7 +18,9 @@ pub struct AdmSettings {
     pub max_data: usize,
     /// The base URL to use for ADM requests
     pub base_url: Url,
-    /// The number of seconds to wait for ADM requests to complete
+    /// Deprecated: the number of seconds to wait for ADM requests to
+    /// complete, used instead of `bridge_request_timeout_ms` when changed
+    /// from its default
     pub timeout: usize,
     /// The minimum TTL to use for ADM notifications
     pub min_ttl: usize,
@@ -34,7 +41,7 @@ impl Default for AdmSettings {
             profiles: "{}".to_string(),
             max_data: 6000,
             base_url: Url::parse("https://api.amazon.com").unwrap(),
-            timeout: 3,
+            timeout: DEFAULT_TIMEOUT_SECS,
             min_ttl: 60,
         }
     }
@@ -45,4 +52,10 @@ impl AdmSettings {
     pub fn profiles(&self) -> serde_json::Result<HashMap<String, AdmProfile>> {
         serde_json::from_str(&self.profiles)
     }
+
+    /// The time to wait for ADM requests to complete, when overriding the
+    /// bridge HTTP client's
+    pub fn request_timeout(&self) -> Option<Duration> {
+        (self.timeout != DEFAULT_TIMEOUT_SECS).then(|| Duration::from_secs(self.timeout as u64))
+    }
 }
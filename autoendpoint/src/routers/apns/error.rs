@@ -37,6 +37,12 @@ pub enum ApnsError {
     #[error("Invalid APS data")]
     InvalidApsData,
 
+    #[error("APNS topic {0:?} is not allowed")]
+    DisallowedTopic(String),
+
+    #[error("Invalid APNS push type {0:?}: expected alert or background")]
+    InvalidPushType(String),
+
     #[error("APNS recipient no longer available")]
     Unregistered,
 }
@@ -47,6 +53,8 @@ impl ApnsError {
         match self {
             ApnsError::InvalidReleaseChannel
             | ApnsError::InvalidApsData
+            | ApnsError::DisallowedTopic(_)
+            | ApnsError::InvalidPushType(_)
             | ApnsError::SizeLimit(_) => StatusCode::BAD_REQUEST,
 
             ApnsError::NoDeviceToken | ApnsError::NoReleaseChannel | ApnsError::Unregistered => {
@@ -75,6 +83,8 @@ impl ApnsError {
             | ApnsError::ApnsUpstream(_)
             | ApnsError::InvalidReleaseChannel
             | ApnsError::InvalidApsData
+            | ApnsError::DisallowedTopic(_)
+            | ApnsError::InvalidPushType(_)
             | ApnsError::Config(..)
             | ApnsError::SizeLimit(_) => None,
         }
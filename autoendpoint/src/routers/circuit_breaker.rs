@@ -0,0 +1,301 @@
+//! Per bridge circuit breakers
+//!
+//! When a bridge (e.g. FCM) is having an outage every notification routed to
+//! it would otherwise wait out the full request timeout. After
+//! `failure_threshold` consecutive failures the breaker opens, immediately
+//! rejecting requests for `reset` before allowing a single probe request
+//! through (half-open). A successful probe closes the breaker again, a failed
+//! one reopens it. A probe that's cancelled before completing (its request
+//! dropped) lets the next request probe instead.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cadence::{Gauged, StatsdClient};
+
+/// The state of a `CircuitBreaker`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakerState {
+    /// Requests are sent to the bridge
+    Closed,
+    /// Requests are rejected until the reset period has elapsed
+    Open,
+    /// A single probe request is allowed through
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// The value reported to the state gauge
+    fn gauge_value(self) -> u64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// A half-open probe request is in flight
+    probing: bool,
+}
+
+/// A circuit breaker for a single bridge
+pub struct CircuitBreaker {
+    bridge: &'static str,
+    /// Consecutive failures before opening, 0 disables the breaker
+    failure_threshold: u32,
+    reset: Duration,
+    inner: Mutex<BreakerInner>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        bridge: &'static str,
+        failure_threshold: u32,
+        reset: Duration,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        Self {
+            bridge,
+            failure_threshold,
+            reset,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+            metrics,
+        }
+    }
+
+    /// The current state of the breaker
+    pub fn state(&self) -> BreakerState {
+        self.inner
+            .lock()
+            .expect("CircuitBreaker lock poisoned")
+            .state
+    }
+
+    /// Whether a request may be sent to the bridge. Its outcome should be
+    /// recorded through the returned `Attempt`.
+    pub fn allow_request(&self) -> Option<Attempt<'_>> {
+        let attempt = |probe| {
+            Some(Attempt {
+                breaker: self,
+                probe,
+            })
+        };
+        if self.failure_threshold == 0 {
+            return attempt(false);
+        }
+        let mut inner = self.inner.lock().expect("CircuitBreaker lock poisoned");
+        match inner.state {
+            BreakerState::Closed => attempt(false),
+            BreakerState::Open => {
+                if inner
+                    .opened_at
+                    .map_or(true, |opened_at| opened_at.elapsed() >= self.reset)
+                {
+                    self.transition(&mut inner, BreakerState::HalfOpen);
+                    inner.probing = true;
+                    attempt(true)
+                } else {
+                    None
+                }
+            }
+            BreakerState::HalfOpen => {
+                if inner.probing {
+                    None
+                } else {
+                    inner.probing = true;
+                    attempt(true)
+                }
+            }
+        }
+    }
+
+    /// Record a successful request to the bridge
+    pub fn record_success(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("CircuitBreaker lock poisoned");
+        inner.consecutive_failures = 0;
+        inner.probing = false;
+        if inner.state != BreakerState::Closed {
+            self.transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    /// Record a failed request to the bridge
+    pub fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("CircuitBreaker lock poisoned");
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        inner.probing = false;
+        match inner.state {
+            BreakerState::HalfOpen => self.transition(&mut inner, BreakerState::Open),
+            BreakerState::Closed if inner.consecutive_failures >= self.failure_threshold => {
+                self.transition(&mut inner, BreakerState::Open)
+            }
+            _ => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut BreakerInner, state: BreakerState) {
+        info!(
+            "Bridge circuit breaker {:?} -> {:?}", inner.state, state;
+            "platform" => self.bridge,
+            "consecutive_failures" => inner.consecutive_failures,
+        );
+        inner.state = state;
+        if state == BreakerState::Open {
+            inner.opened_at = Some(Instant::now());
+        }
+        self.metrics
+            .gauge_with_tags("notification.bridge.circuit_breaker", state.gauge_value())
+            .with_tag("platform", self.bridge)
+            .send();
+    }
+}
+
+/// A request let through by a [CircuitBreaker]
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    /// Whether it's the half-open probe
+    probe: bool,
+}
+
+impl Attempt<'_> {
+    pub fn record_success(mut self) {
+        self.probe = false;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.probe = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        // A cancelled probe: let another request probe
+        if self.probe {
+            self.breaker
+                .inner
+                .lock()
+                .expect("CircuitBreaker lock poisoned")
+                .probing = false;
+        }
+    }
+}
+
+/// The circuit breakers for each bridge, so an outage of one bridge doesn't
+/// affect the others
+pub struct BridgeBreakers {
+    pub fcm: CircuitBreaker,
+    pub apns: CircuitBreaker,
+    pub adm: CircuitBreaker,
+}
+
+impl BridgeBreakers {
+    pub fn new(failure_threshold: u32, reset: Duration, metrics: &Arc<StatsdClient>) -> Self {
+        Self {
+            fcm: CircuitBreaker::new("fcm", failure_threshold, reset, metrics.clone()),
+            apns: CircuitBreaker::new("apns", failure_threshold, reset, metrics.clone()),
+            adm: CircuitBreaker::new("adm", failure_threshold, reset, metrics.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::{BreakerState, CircuitBreaker};
+
+    fn breaker(reset: Duration) -> CircuitBreaker {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        CircuitBreaker::new("test", 3, reset, metrics)
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        // A success resets the count
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow_request().is_some());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow_request().is_none());
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Only a single probe is let through
+        let probe = breaker.allow_request().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allow_request().is_none());
+
+        // A failed probe reopens the breaker
+        probe.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // A successful probe closes it
+        breaker.allow_request().unwrap().record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow_request().is_some());
+    }
+
+    /// A probe dropped before completing (its request cancelled) lets the
+    /// next request probe
+    #[test]
+    fn cancelled_probe() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        let probe = breaker.allow_request().unwrap();
+        assert!(breaker.allow_request().is_none());
+        drop(probe);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        let probe = breaker.allow_request().unwrap();
+        assert!(breaker.allow_request().is_none());
+        probe.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn disabled() {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let breaker = CircuitBreaker::new("test", 0, Duration::from_secs(60), metrics);
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow_request().is_some());
+    }
+}
@@ -1,17 +1,48 @@
 use crate::error::{ApiError, ApiResult};
 use crate::extractors::notification::Notification;
-use crate::routers::RouterError;
+use crate::routers::{RouterError, RouterResponse};
 use actix_web::http::StatusCode;
 use autopush_common::db::client::DbClient;
+use autopush_common::db::User;
 use autopush_common::util::InsertOpt;
 use cadence::{Counted, CountedExt, StatsdClient, Timed};
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use uuid::Uuid;
+
+/// The router data key of the app a subscription was registered for (one of
+/// the `registered_apps`)
+pub const APP_KEY: &str = "app";
+
+/// The router data key of a subscription's preferred locale, forwarded to
+/// its bridge
+pub const LOCALE_KEY: &str = "locale";
+
+/// The `app` tag of subscriptions registered without a (registered) app
+const UNKNOWN_APP: &str = "unknown";
+
+/// The app to tag the user's notification metrics with
+pub fn app_tag(user: &User) -> &str {
+    user.router_data
+        .as_ref()
+        .and_then(|router_data| router_data.get(APP_KEY))
+        .and_then(Value::as_str)
+        .unwrap_or(UNKNOWN_APP)
+}
 
 /// Convert a notification into a WebPush message
 pub fn build_message_data(notification: &Notification) -> ApiResult<HashMap<&'static str, String>> {
     let mut message_data = HashMap::new();
     message_data.insert("chid", notification.subscription.channel_id.to_string());
+    message_data.insert_opt(
+        LOCALE_KEY,
+        notification
+            .subscription
+            .user
+            .router_data
+            .as_ref()
+            .and_then(|router_data| router_data.get(LOCALE_KEY))
+            .and_then(Value::as_str),
+    );
 
     // Only add the other headers if there's data
     if let Some(data) = &notification.data {
@@ -43,8 +74,9 @@ pub async fn handle_error(
     db: &dyn DbClient,
     platform: &str,
     app_id: &str,
-    uaid: Uuid,
+    user: &User,
 ) -> ApiError {
+    let app = app_tag(user);
     match &error {
         RouterError::Authentication => {
             error!("Bridge authentication error");
@@ -52,6 +84,7 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "authentication",
                 error.status(),
                 error.errno(),
@@ -63,6 +96,7 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "gcm authentication",
                 error.status(),
                 error.errno(),
@@ -70,10 +104,15 @@ pub async fn handle_error(
         }
         RouterError::RequestTimeout => {
             warn!("Bridge timeout");
+            metrics
+                .incr_with_tags("notification.bridge.timeout")
+                .with_tag("platform", platform)
+                .send();
             incr_error_metric(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "timeout",
                 error.status(),
                 error.errno(),
@@ -85,6 +124,7 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "connection_unavailable",
                 error.status(),
                 error.errno(),
@@ -96,13 +136,17 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "recipient_gone",
                 error.status(),
                 error.errno(),
             );
 
-            if let Err(e) = db.remove_user(&uaid).await {
-                warn!("Error while removing user due to bridge not_found: {}", e);
+            // Kept while it has routes left to fall back to
+            if user.fallback_routes.is_empty() {
+                if let Err(e) = db.remove_gone_user(user).await {
+                    warn!("Error while removing user due to bridge not_found: {}", e);
+                }
             }
         }
         RouterError::Upstream { .. } => {
@@ -111,6 +155,7 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "server_error",
                 error.status(),
                 error.errno(),
@@ -122,6 +167,7 @@ pub async fn handle_error(
                 metrics,
                 platform,
                 app_id,
+                app,
                 "unknown",
                 error.status(),
                 error.errno(),
@@ -137,6 +183,7 @@ pub fn incr_error_metric(
     metrics: &StatsdClient,
     platform: &str,
     app_id: &str,
+    app: &str,
     reason: &str,
     status: StatusCode,
     errno: Option<usize>,
@@ -146,6 +193,7 @@ pub fn incr_error_metric(
         .incr_with_tags("notification.bridge.error")
         .with_tag("platform", platform)
         .with_tag("app_id", app_id)
+        .with_tag("app", app)
         .with_tag("reason", reason)
         .with_tag("error", &status.to_string())
         .with_tag("errno", &errno.unwrap_or(0).to_string())
@@ -163,6 +211,7 @@ pub fn incr_success_metrics(
         .incr_with_tags("notification.bridge.sent")
         .with_tag("platform", platform)
         .with_tag("app_id", app_id)
+        .with_tag("app", app_tag(&notification.subscription.user))
         .send();
     metrics
         .count_with_tags(
@@ -176,13 +225,39 @@ pub fn incr_success_metrics(
     metrics
         .time_with_tags(
             "notification.total_request_time",
-            (autopush_common::util::sec_since_epoch() - notification.timestamp) * 1000,
+            autopush_common::util::sec_since_epoch().saturating_sub(notification.timestamp) * 1000,
         )
         .with_tag("platform", platform)
         .with_tag("app_id", app_id)
         .send();
 }
 
+/// Build the response to a dry run notification, describing the bridge and
+/// routing key (e.g. the app ID) it would have been sent with
+pub fn dry_run_response(
+    metrics: &StatsdClient,
+    platform: &str,
+    routing_key: Option<&str>,
+) -> RouterResponse {
+    metrics
+        .incr_with_tags("dry_run.request")
+        .with_tag("platform", platform)
+        .send();
+    RouterResponse {
+        status: StatusCode::OK,
+        headers: HashMap::from([("Content-Type", "application/json".to_owned())]),
+        body: Some(
+            json!({
+                "dry_run": true,
+                "router_type": platform,
+                "routing_key": routing_key,
+            })
+            .to_string(),
+        ),
+        expired: false,
+    }
+}
+
 /// Common router test code
 #[cfg(test)]
 pub mod tests {
@@ -191,9 +266,12 @@ pub mod tests {
     use crate::extractors::routers::RouterType;
     use crate::extractors::subscription::Subscription;
     use autopush_common::db::User;
+    use cadence::{SpyMetricSink, StatsdClient};
     use std::collections::HashMap;
     use uuid::Uuid;
 
+    use super::{build_message_data, incr_success_metrics, APP_KEY, LOCALE_KEY};
+
     pub const CHANNEL_ID: &str = "deadbeef-13f9-4639-87f9-2ff731824f34";
 
     /// Get the test channel ID as a Uuid
@@ -221,6 +299,7 @@ pub mod tests {
             headers: NotificationHeaders {
                 ttl: 0,
                 topic: Some("test-topic".to_string()),
+                urgency: "normal".to_string(),
                 encoding: Some("test-encoding".to_string()),
                 encryption: Some("test-encryption".to_string()),
                 encryption_key: Some("test-encryption-key".to_string()),
@@ -229,6 +308,50 @@ pub mod tests {
             timestamp: 0,
             sort_key_timestamp: 0,
             data,
+            dry_run: false,
+            respond_async: false,
+            idempotency_key: None,
         }
     }
+
+    /// The subscription's locale is forwarded to its bridge, when registered
+    #[test]
+    fn message_data_locale() {
+        let router_data = HashMap::from([(LOCALE_KEY.to_owned(), serde_json::json!("pt-BR"))]);
+        let notification = make_notification(router_data, None, RouterType::FCM);
+        let message_data = build_message_data(&notification).unwrap();
+        assert_eq!(
+            message_data.get("locale").map(String::as_str),
+            Some("pt-BR")
+        );
+
+        let notification = make_notification(HashMap::new(), None, RouterType::FCM);
+        let message_data = build_message_data(&notification).unwrap();
+        assert!(!message_data.contains_key("locale"));
+    }
+
+    /// The sent counter's tagged with the registered app
+    #[test]
+    fn success_metrics_tag_app() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::from_sink("autoendpoint", sink);
+        let router_data = HashMap::from([(APP_KEY.to_owned(), serde_json::json!("fenix"))]);
+        let notification = make_notification(router_data, None, RouterType::FCM);
+        incr_success_metrics(&metrics, "fcmv1", "dev", &notification);
+        let notification = make_notification(HashMap::new(), None, RouterType::FCM);
+        incr_success_metrics(&metrics, "fcmv1", "dev", &notification);
+
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .filter(|m| m.starts_with("autoendpoint.notification.bridge.sent:"))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                "autoendpoint.notification.bridge.sent:1|c|#platform:fcmv1,app_id:dev,app:fenix",
+                "autoendpoint.notification.bridge.sent:1|c|#platform:fcmv1,app_id:dev,app:unknown",
+            ]
+        );
+    }
 }
@@ -0,0 +1,123 @@
+//! Per bridge concurrency limits
+//!
+//! A burst of notifications to a slow bridge (e.g. FCM) could otherwise open
+//! an unbounded number of simultaneous requests to it, exhausting our file
+//! descriptors. Each bridge allows at most `max_concurrency` requests in
+//! flight, requests unable to get a slot within `wait` are shed with a 503
+//! instead of queueing.
+use std::sync::Arc;
+use std::time::Duration;
+
+use cadence::{CountedExt, StatsdClient};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::routers::RouterError;
+
+/// Limits the number of concurrent requests to a single bridge
+pub struct ConcurrencyLimiter {
+    bridge: &'static str,
+    /// `None` when the limiter is disabled
+    semaphore: Option<Arc<Semaphore>>,
+    wait: Duration,
+    metrics: Arc<StatsdClient>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(
+        bridge: &'static str,
+        max_concurrency: usize,
+        wait: Duration,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        Self {
+            bridge,
+            semaphore: (max_concurrency > 0)
+                .then(|| Arc::new(Semaphore::new(max_concurrency.min(Semaphore::MAX_PERMITS)))),
+            wait,
+            metrics,
+        }
+    }
+
+    /// Wait for a slot to send a request to the bridge, which is held until
+    /// the returned permit is dropped (there's no permit when disabled)
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, RouterError> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+        match tokio::time::timeout(self.wait, semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            // Timed out (the semaphore is never closed)
+            _ => {
+                self.metrics
+                    .incr_with_tags("bridge.concurrency.rejected")
+                    .with_tag("platform", self.bridge)
+                    .send();
+                Err(RouterError::BridgeBusy)
+            }
+        }
+    }
+}
+
+/// The concurrency limiters for each bridge, so a slow bridge doesn't starve
+/// the others
+pub struct BridgeLimiters {
+    pub fcm: ConcurrencyLimiter,
+    pub apns: ConcurrencyLimiter,
+    pub adm: ConcurrencyLimiter,
+}
+
+impl BridgeLimiters {
+    pub fn new(max_concurrency: usize, wait: Duration, metrics: &Arc<StatsdClient>) -> Self {
+        Self {
+            fcm: ConcurrencyLimiter::new("fcm", max_concurrency, wait, metrics.clone()),
+            apns: ConcurrencyLimiter::new("apns", max_concurrency, wait, metrics.clone()),
+            adm: ConcurrencyLimiter::new("adm", max_concurrency, wait, metrics.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::{http::header, ResponseError};
+    use cadence::{NopMetricSink, StatsdClient};
+
+    use super::ConcurrencyLimiter;
+    use crate::error::ApiError;
+    use crate::routers::RouterError;
+
+    fn limiter(max_concurrency: usize) -> ConcurrencyLimiter {
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        ConcurrencyLimiter::new("test", max_concurrency, Duration::from_millis(10), metrics)
+    }
+
+    #[tokio::test]
+    async fn sheds_when_saturated() {
+        let limiter = limiter(2);
+        let first = limiter.acquire().await.unwrap();
+        let _second = limiter.acquire().await.unwrap();
+        assert!(first.is_some());
+
+        let error = limiter.acquire().await.unwrap_err();
+        assert!(matches!(error, RouterError::BridgeBusy));
+        let response = ApiError::from(error).error_response();
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        // Releasing a slot allows the next request through
+        drop(first);
+        assert!(limiter.acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let limiter = limiter(0);
+        let mut permits = Vec::new();
+        for _ in 0..10 {
+            permits.push(limiter.acquire().await.unwrap());
+        }
+        assert!(permits.iter().all(Option::is_none));
+    }
+}
@@ -1,3 +1,6 @@
+use crate::extractors::routers::RouterType;
+use crate::otel;
+use crate::reload::LiveSettings;
 use crate::routers::common::message_size_check;
 use crate::routers::fcm::error::FcmError;
 use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
@@ -6,20 +9,65 @@ use reqwest::StatusCode;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::Path;
-use std::time::Duration;
+use std::sync::Arc;
 use url::Url;
 use yup_oauth2::authenticator::DefaultAuthenticator;
 use yup_oauth2::{ServiceAccountAuthenticator, ServiceAccountKey};
 
 const OAUTH_SCOPES: &[&str] = &["https://www.googleapis.com/auth/firebase.messaging"];
 
+/// Legacy (server key) errors indicating the registration token is no longer
+/// valid
+const LEGACY_UNREGISTERED_ERRORS: &[&str] = &["NotRegistered", "InvalidRegistration"];
+
+/// Android message delivery priority
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FcmPriority {
+    Normal,
+    High,
+}
+
+impl FcmPriority {
+    /// Map a WebPush `Urgency` to a delivery priority. Only high urgency
+    /// messages may wake a sleeping device.
+    pub fn from_urgency(urgency: &str) -> Self {
+        match urgency {
+            "high" => FcmPriority::High,
+            _ => FcmPriority::Normal,
+        }
+    }
+
+    /// The HTTP v1 API's `AndroidMessagePriority` value
+    fn as_v1_str(self) -> &'static str {
+        match self {
+            FcmPriority::Normal => "NORMAL",
+            FcmPriority::High => "HIGH",
+        }
+    }
+
+    /// The legacy API's `priority` value
+    fn as_legacy_str(self) -> &'static str {
+        match self {
+            FcmPriority::Normal => "normal",
+            FcmPriority::High => "high",
+        }
+    }
+}
+
 /// Holds application-specific Firebase data and authentication. This client
 /// handles sending notifications to Firebase.
+///
+/// Credentials containing a service account key use the HTTP v1 API,
+/// otherwise the credential is presumed to be a legacy server key.
 pub struct FcmClient {
     endpoint: Url,
-    timeout: Duration,
+    legacy_endpoint: Url,
+    /// The current settings, read for each request's timeout (so reloads
+    /// apply)
+    live_settings: Arc<LiveSettings>,
     max_data: usize,
     authenticator: Option<DefaultAuthenticator>,
+    server_key: String,
     pub is_gcm: bool,
     http_client: reqwest::Client,
 }
@@ -30,6 +78,7 @@ impl FcmClient {
         settings: &FcmSettings,
         server_credential: FcmServerCredential,
         http: reqwest::Client,
+        live_settings: Arc<LiveSettings>,
     ) -> std::io::Result<Self> {
         // `map`ping off of `serde_json::from_str` gets hairy and weird, requiring
         // async blocks and a number of other specialty items. Doing a very stupid
@@ -74,62 +123,106 @@ impl FcmClient {
                     server_credential.project_id
                 ))
                 .expect("Project ID is not URL-safe"),
-            timeout: Duration::from_secs(settings.timeout as u64),
+            legacy_endpoint: settings
+                .base_url
+                .join("fcm/send")
+                .expect("Invalid FCM base URL"),
+            live_settings,
             max_data: settings.max_data,
             authenticator: auth,
+            server_key: server_credential.server_access_token,
             is_gcm: server_credential.is_gcm.unwrap_or_default(),
             http_client: http,
         })
     }
 
+    /// Apply the current FCM request timeout to a request
+    fn with_timeout(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.timeout(self.live_settings.get().bridge_timeout(RouterType::FCM))
+    }
+
+    /// Check the credential can authenticate, by fetching an OAuth token.
+    /// Returns whether it was checked: legacy server keys can't be without
+    /// sending a message.
+    pub async fn preflight(&self) -> Result<bool, RouterError> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(false);
+        };
+        let server_access_token = authenticator
+            .token(OAUTH_SCOPES)
+            .await
+            .map_err(FcmError::OAuthToken)?;
+        server_access_token.token().ok_or(FcmError::NoOAuthToken)?;
+        Ok(true)
+    }
+
     /// Send the message data to FCM
     pub async fn send(
         &self,
         data: HashMap<&'static str, String>,
         routing_token: String,
         ttl: usize,
+        priority: FcmPriority,
     ) -> Result<(), RouterError> {
         // Check the payload size. FCM only cares about the `data` field when
         // checking size.
         let data_json = serde_json::to_string(&data).unwrap();
         message_size_check(data_json.as_bytes(), self.max_data)?;
 
+        match &self.authenticator {
+            Some(authenticator) => {
+                self.send_v1(authenticator, data, routing_token, ttl, priority)
+                    .await
+            }
+            None => self.send_legacy(data, routing_token, ttl, priority).await,
+        }
+    }
+
+    /// Send the message data via the FCM HTTP v1 API
+    async fn send_v1(
+        &self,
+        authenticator: &DefaultAuthenticator,
+        data: HashMap<&'static str, String>,
+        routing_token: String,
+        ttl: usize,
+        priority: FcmPriority,
+    ) -> Result<(), RouterError> {
         // Build the FCM message
         let message = serde_json::json!({
             "message": {
                 "token": routing_token,
                 "android": {
                     "ttl": format!("{ttl}s"),
+                    "priority": priority.as_v1_str(),
                     "data": data
                 }
             }
         });
 
-        let server_access_token = self
-            .authenticator
-            .as_ref()
-            .unwrap()
+        let server_access_token = authenticator
             .token(OAUTH_SCOPES)
             .await
             .map_err(FcmError::OAuthToken)?;
         let token = server_access_token.token().ok_or(FcmError::NoOAuthToken)?;
 
         // Make the request
-        let response = self
-            .http_client
-            .post(self.endpoint.clone())
-            .header("Authorization", format!("Bearer {}", token))
-            .json(&message)
-            .timeout(self.timeout)
-            .send()
-            .await
-            .map_err(|e| {
-                if e.is_timeout() {
-                    RouterError::RequestTimeout
-                } else {
-                    RouterError::Connect(e)
-                }
-            })?;
+        let response = otel::in_span("fcm.send", async {
+            let request = self
+                .http_client
+                .post(self.endpoint.clone())
+                .header("Authorization", format!("Bearer {}", token))
+                .headers(otel::trace_headers())
+                .json(&message);
+            self.with_timeout(request).send().await
+        })
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                RouterError::RequestTimeout
+            } else {
+                RouterError::Connect(e)
+            }
+        })?;
 
         // Handle error
         let status = response.status();
@@ -152,6 +245,7 @@ impl FcmClient {
             return Err(match (status, data.error) {
                 (StatusCode::UNAUTHORIZED, _) => RouterError::Authentication,
                 (StatusCode::NOT_FOUND, _) => RouterError::NotFound,
+                (_, Some(error)) if error.is_unregistered() => RouterError::NotFound,
                 (_, Some(error)) => RouterError::Upstream {
                     status: error.status,
                     message: error.message,
@@ -165,6 +259,79 @@ impl FcmClient {
 
         Ok(())
     }
+
+    /// Send the message data via the legacy (server key) API
+    async fn send_legacy(
+        &self,
+        data: HashMap<&'static str, String>,
+        routing_token: String,
+        ttl: usize,
+        priority: FcmPriority,
+    ) -> Result<(), RouterError> {
+        let message = serde_json::json!({
+            "to": routing_token,
+            "time_to_live": ttl,
+            "priority": priority.as_legacy_str(),
+            "data": data
+        });
+
+        let response = otel::in_span("fcm.send", async {
+            let request = self
+                .http_client
+                .post(self.legacy_endpoint.clone())
+                .header("Authorization", format!("key={}", self.server_key))
+                .headers(otel::trace_headers())
+                .json(&message);
+            self.with_timeout(request).send().await
+        })
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                RouterError::RequestTimeout
+            } else {
+                RouterError::Connect(e)
+            }
+        })?;
+
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(RouterError::Authentication);
+        }
+        let raw_data = response
+            .bytes()
+            .await
+            .map_err(FcmError::DeserializeResponse)?;
+        if raw_data.is_empty() {
+            warn!("Empty FCM response [{status}]");
+            return Err(FcmError::EmptyResponse(status).into());
+        }
+        let data: LegacyFcmResponse = serde_json::from_slice(&raw_data).map_err(|e| {
+            let s = String::from_utf8(raw_data.to_vec()).unwrap_or_else(|e| e.to_string());
+            warn!("Invalid FCM response [{status}] \"{s}\"");
+            FcmError::InvalidResponse(e, s, status)
+        })?;
+
+        // we only ever send one.
+        match data
+            .results
+            .into_iter()
+            .next()
+            .and_then(|result| result.error)
+        {
+            None if status.is_success() => Ok(()),
+            None => Err(RouterError::Upstream {
+                status: status.to_string(),
+                message: "Unknown reason".to_string(),
+            }),
+            Some(error) if LEGACY_UNREGISTERED_ERRORS.contains(&error.as_str()) => {
+                Err(RouterError::NotFound)
+            }
+            Some(error) => Err(RouterError::Upstream {
+                status: error,
+                message: "Legacy FCM error".to_string(),
+            }),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -176,14 +343,46 @@ struct FcmResponse {
 struct FcmErrorResponse {
     status: String,
     message: String,
+    #[serde(default)]
+    details: Vec<FcmErrorDetail>,
+}
+
+impl FcmErrorResponse {
+    /// Whether FCM reports the registration token is no longer valid
+    fn is_unregistered(&self) -> bool {
+        self.details
+            .iter()
+            .any(|detail| detail.error_code.as_deref() == Some("UNREGISTERED"))
+    }
+}
+
+#[derive(Deserialize)]
+struct FcmErrorDetail {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LegacyFcmResponse {
+    #[serde(default)]
+    results: Vec<LegacyFcmResult>,
+}
+
+#[derive(Deserialize)]
+struct LegacyFcmResult {
+    error: Option<String>,
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::routers::fcm::client::FcmClient;
+    use crate::reload::LiveSettings;
+    use crate::routers::fcm::client::{FcmClient, FcmPriority};
     use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
     use crate::routers::RouterError;
+    use crate::settings::Settings;
     use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
     use url::Url;
 
     pub const PROJECT_ID: &str = "yup-test-243420";
@@ -236,6 +435,7 @@ pub mod tests {
             },
             credential,
             reqwest::Client::new(),
+            Arc::new(LiveSettings::new(Settings::default())),
         )
         .await
         .unwrap()
@@ -255,17 +455,41 @@ pub mod tests {
         let fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID)
             .match_header("Authorization", format!("Bearer {ACCESS_TOKEN}").as_str())
             .match_header("Content-Type", "application/json")
-            .match_body(r#"{"message":{"android":{"data":{"is_test":"true"},"ttl":"42s"},"token":"test-token"}}"#)
+            .match_body(r#"{"message":{"android":{"data":{"is_test":"true"},"priority":"NORMAL","ttl":"42s"},"token":"test-token"}}"#)
             .create();
 
         let mut data = HashMap::new();
         data.insert("is_test", "true".to_string());
 
-        let result = client.send(data, "test-token".to_string(), 42).await;
+        let result = client
+            .send(data, "test-token".to_string(), 42, FcmPriority::Normal)
+            .await;
         assert!(result.is_ok(), "result = {result:?}");
         fcm_mock.assert();
     }
 
+    /// The preflight check fetches an OAuth token
+    #[tokio::test]
+    async fn preflight() {
+        let client = make_client(FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(),
+        })
+        .await;
+        let _token_mock = mock_token_endpoint();
+        assert!(client.preflight().await.unwrap());
+
+        // Legacy server keys aren't checked
+        let client = make_client(FcmServerCredential {
+            project_id: GCM_PROJECT_ID.to_owned(),
+            is_gcm: Some(true),
+            server_access_token: "legacy-server-key".to_owned(),
+        })
+        .await;
+        assert!(!client.preflight().await.unwrap());
+    }
+
     /// Authorization errors are handled
     #[tokio::test]
     async fn unauthorized() {
@@ -282,7 +506,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -307,7 +536,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -316,6 +550,159 @@ pub mod tests {
         );
     }
 
+    /// UNREGISTERED errors are treated as the token no longer being valid
+    #[tokio::test]
+    async fn unregistered() {
+        let client = make_client(FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(),
+        })
+        .await;
+        let _token_mock = mock_token_endpoint();
+        let _fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID)
+            .with_status(400)
+            .with_body(r#"{"error":{"status":"INVALID_ARGUMENT","message":"test-message","details":[{"@type":"type.googleapis.com/google.firebase.fcm.v1.FcmError","errorCode":"UNREGISTERED"}]}}"#)
+            .create();
+
+        let result = client
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
+            .await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::NotFound),
+            "result = {result:?}"
+        );
+    }
+
+    /// Legacy server key credentials use the legacy API
+    #[tokio::test]
+    async fn sends_correct_legacy_request() {
+        let client = make_client(FcmServerCredential {
+            project_id: GCM_PROJECT_ID.to_owned(),
+            is_gcm: Some(true),
+            server_access_token: "test-server-key".to_owned(),
+        })
+        .await;
+        let fcm_mock = mockito::mock("POST", "/fcm/send")
+            .match_header("Authorization", "key=test-server-key")
+            .match_body(r#"{"data":{"is_test":"true"},"priority":"normal","time_to_live":42,"to":"test-token"}"#)
+            .with_body(r#"{"success":1,"failure":0,"results":[{"message_id":"1:abc"}]}"#)
+            .create();
+
+        let mut data = HashMap::new();
+        data.insert("is_test", "true".to_string());
+
+        let result = client
+            .send(data, "test-token".to_string(), 42, FcmPriority::Normal)
+            .await;
+        assert!(result.is_ok(), "result = {result:?}");
+        fcm_mock.assert();
+    }
+
+    /// Legacy NotRegistered errors are treated as the token no longer being valid
+    #[tokio::test]
+    async fn legacy_not_registered() {
+        let client = make_client(FcmServerCredential {
+            project_id: GCM_PROJECT_ID.to_owned(),
+            is_gcm: Some(true),
+            server_access_token: "test-server-key".to_owned(),
+        })
+        .await;
+        let _fcm_mock = mockito::mock("POST", "/fcm/send")
+            .with_body(r#"{"success":0,"failure":1,"results":[{"error":"NotRegistered"}]}"#)
+            .create();
+
+        let result = client
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
+            .await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::NotFound),
+            "result = {result:?}"
+        );
+    }
+
+    /// Send a notification to a server accepting the connection but never
+    /// responding, returning how long it took
+    async fn send_unanswered(settings: Settings) -> (Result<(), RouterError>, Duration) {
+        // Accept the connection but never respond
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _conn = listener.accept();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+        let credential = FcmServerCredential {
+            project_id: GCM_PROJECT_ID.to_owned(),
+            is_gcm: Some(true),
+            server_access_token: "test-server-key".to_owned(),
+        };
+        let client = FcmClient::new(
+            &FcmSettings {
+                base_url: Url::parse(&format!("http://{addr}")).unwrap(),
+                ..Default::default()
+            },
+            credential,
+            reqwest::Client::new(),
+            Arc::new(LiveSettings::new(settings)),
+        )
+        .await
+        .unwrap();
+
+        let start = Instant::now();
+        let result = client
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
+            .await;
+        (result, start.elapsed())
+    }
+
+    /// Requests outliving the timeout are aborted
+    #[tokio::test]
+    async fn request_timeout() {
+        let (result, elapsed) = send_unanswered(Settings {
+            fcm: FcmSettings {
+                timeout_ms: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::RequestTimeout),
+            "result = {result:?}"
+        );
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    /// Without an override, the shared timeout applies
+    #[tokio::test]
+    async fn shared_timeout() {
+        let (result, elapsed) = send_unanswered(Settings {
+            bridge_request_timeout_ms: 100,
+            ..Default::default()
+        })
+        .await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::RequestTimeout),
+            "result = {result:?}"
+        );
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
     /// Unhandled errors (where an error object is returned) are wrapped and returned
     #[tokio::test]
     async fn other_fcm_error() {
@@ -332,7 +719,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -361,7 +753,12 @@ pub mod tests {
             .create();
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(
+                HashMap::new(),
+                "test-token".to_string(),
+                42,
+                FcmPriority::Normal,
+            )
             .await;
         assert!(result.is_err());
         assert!(
@@ -373,4 +770,15 @@ pub mod tests {
             "result = {result:?}"
         );
     }
+
+    /// Only high urgency notifications are sent with high priority
+    #[test]
+    fn urgency_to_priority() {
+        assert_eq!(FcmPriority::from_urgency("very-low"), FcmPriority::Normal);
+        assert_eq!(FcmPriority::from_urgency("low"), FcmPriority::Normal);
+        assert_eq!(FcmPriority::from_urgency("normal"), FcmPriority::Normal);
+        assert_eq!(FcmPriority::from_urgency("high"), FcmPriority::High);
+        assert_eq!(FcmPriority::High.as_v1_str(), "HIGH");
+        assert_eq!(FcmPriority::High.as_legacy_str(), "high");
+    }
 }
@@ -1,10 +1,13 @@
 use autopush_common::db::client::DbClient;
 
-use crate::error::ApiResult;
+use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::notification::Notification;
 use crate::extractors::router_data_input::RouterDataInput;
-use crate::routers::common::{build_message_data, handle_error, incr_success_metrics};
-use crate::routers::fcm::client::FcmClient;
+use crate::reload::LiveSettings;
+use crate::routers::common::{
+    build_message_data, dry_run_response, handle_error, incr_success_metrics,
+};
+use crate::routers::fcm::client::{FcmClient, FcmPriority};
 use crate::routers::fcm::error::FcmError;
 use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
 use crate::routers::{Router, RouterError, RouterResponse};
@@ -37,11 +40,13 @@ impl FcmRouter {
         http: reqwest::Client,
         metrics: Arc<StatsdClient>,
         db: Box<dyn DbClient>,
+        live_settings: Arc<LiveSettings>,
     ) -> Result<Self, FcmError> {
         let server_credentials = settings.credentials()?;
-        let clients = Self::create_clients(&settings, server_credentials, http.clone())
-            .await
-            .map_err(FcmError::OAuthClientBuild)?;
+        let clients =
+            Self::create_clients(&settings, server_credentials, http.clone(), live_settings)
+                .await
+                .map_err(FcmError::OAuthClientBuild)?;
         Ok(Self {
             settings,
             endpoint_url,
@@ -56,13 +61,20 @@ impl FcmRouter {
         settings: &FcmSettings,
         server_credentials: HashMap<String, FcmServerCredential>,
         http: reqwest::Client,
+        live_settings: Arc<LiveSettings>,
     ) -> std::io::Result<HashMap<String, FcmClient>> {
         let mut clients = HashMap::new();
 
         for (profile, server_credential) in server_credentials {
             clients.insert(
                 profile,
-                FcmClient::new(settings, server_credential, http.clone()).await?,
+                FcmClient::new(
+                    settings,
+                    server_credential,
+                    http.clone(),
+                    live_settings.clone(),
+                )
+                .await?,
             );
         }
         trace!("Initialized {} FCM clients", clients.len());
@@ -74,6 +86,23 @@ impl FcmRouter {
         !self.clients.is_empty()
     }
 
+    /// Check each application's credential can authenticate
+    pub async fn preflight(&self) -> ApiResult<()> {
+        for (profile, client) in &self.clients {
+            match client.preflight().await {
+                Ok(true) => info!("🔑 FCM credential for {} authenticated", profile),
+                Ok(false) => info!("🔑 FCM legacy server key for {} not checked", profile),
+                Err(e) => {
+                    return Err(ApiErrorKind::General(format!(
+                        "FCM credential for {profile} can't authenticate: {e}"
+                    ))
+                    .into())
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Do the gauntlet check to get the routing credentials, these are the
     /// sender/project ID, and the subscription specific user routing token.
     /// FCM stores the values in the top hash as `token` & `app_id`, GCM stores them
@@ -165,16 +194,23 @@ impl Router for FcmRouter {
             .ok_or_else(|| FcmError::InvalidAppId(app_id.clone()))?;
 
         let message_data = build_message_data(notification)?;
+        let priority = FcmPriority::from_urgency(&notification.headers.urgency);
         let platform = "fcmv1";
+        if notification.dry_run {
+            return Ok(dry_run_response(&self.metrics, platform, Some(&app_id)));
+        }
         trace!("Sending message to {platform}: [{:?}]", &app_id);
-        if let Err(e) = client.send(message_data, routing_token, ttl).await {
+        if let Err(e) = client
+            .send(message_data, routing_token, ttl, priority)
+            .await
+        {
             return Err(handle_error(
                 e,
                 &self.metrics,
                 self.db.as_ref(),
                 platform,
                 &app_id,
-                notification.subscription.user.uaid,
+                &notification.subscription.user,
             )
             .await);
         };
@@ -184,7 +220,7 @@ impl Router for FcmRouter {
 
         Ok(RouterResponse::success(
             self.endpoint_url
-                .join(&format!("/m/{}", notification.message_id))
+                .join(&format!("m/{}", notification.message_id))
                 .expect("Message ID is not URL-safe")
                 .to_string(),
             notification.headers.ttl as usize,
@@ -196,6 +232,7 @@ impl Router for FcmRouter {
 mod tests {
     use crate::error::ApiErrorKind;
     use crate::extractors::routers::RouterType;
+    use crate::reload::LiveSettings;
     use crate::routers::common::tests::{make_notification, CHANNEL_ID};
     use crate::routers::fcm::client::tests::{
         make_service_key, mock_fcm_endpoint_builder, mock_token_endpoint, GCM_PROJECT_ID,
@@ -206,8 +243,10 @@ mod tests {
     use crate::routers::fcm::settings::FcmSettings;
     use crate::routers::RouterError;
     use crate::routers::{Router, RouterResponse};
+    use crate::settings::Settings;
     use autopush_common::db::client::DbClient;
     use autopush_common::db::mock::MockDbClient;
+    use autopush_common::db::FallbackRoute;
     use std::sync::Arc;
 
     use cadence::StatsdClient;
@@ -245,6 +284,7 @@ mod tests {
             reqwest::Client::new(),
             Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             db,
+            Arc::new(LiveSettings::new(Settings::default())),
         )
         .await
         .unwrap()
@@ -276,6 +316,7 @@ mod tests {
                             "data": {
                                 "chid": CHANNEL_ID
                             },
+                            "priority": "NORMAL",
                             "ttl": "60s"
                         },
                         "token": "test-token"
@@ -296,6 +337,29 @@ mod tests {
         fcm_mock.assert();
     }
 
+    /// A dry run notification is validated but not sent to FCM
+    #[tokio::test]
+    async fn dry_run() {
+        let db = MockDbClient::new().into_boxed_arc();
+        let router = make_router(make_service_key(), "whatever".to_string(), db).await;
+        let _token_mock = mock_token_endpoint();
+        let fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID).expect(0).create();
+        let mut notification = make_notification(default_router_data(), None, RouterType::FCM);
+        notification.dry_run = true;
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response.body.unwrap()).unwrap(),
+            serde_json::json!({
+                "dry_run": true,
+                "router_type": "fcmv1",
+                "routing_key": "dev"
+            })
+        );
+        fcm_mock.assert();
+    }
+
     /// A notification with data is sent to FCM
     #[tokio::test]
     async fn successful_routing_with_data() {
@@ -315,6 +379,7 @@ mod tests {
                                 "cryptokey": "test-crypto-key",
                                 "enckey": "test-encryption-key"
                             },
+                            "priority": "NORMAL",
                             "ttl": "60s"
                         },
                         "token": "test-token"
@@ -369,10 +434,10 @@ mod tests {
     async fn no_fcm_user() {
         let notification = make_notification(default_router_data(), None, RouterType::FCM);
         let mut db = MockDbClient::new();
-        db.expect_remove_user()
-            .with(predicate::eq(notification.subscription.user.uaid))
+        db.expect_remove_gone_user()
+            .with(predicate::eq(notification.subscription.user.clone()))
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_| Ok(true));
 
         let router = make_router(
             make_service_key(),
@@ -396,4 +461,64 @@ mod tests {
             "result = {result:?}"
         );
     }
+
+    /// An UNREGISTERED token is a permanent failure: the user is dropped and
+    /// a 410 is returned.
+    #[tokio::test]
+    async fn unregistered_fcm_user() {
+        let notification = make_notification(default_router_data(), None, RouterType::FCM);
+        let mut db = MockDbClient::new();
+        db.expect_remove_gone_user()
+            .with(predicate::eq(notification.subscription.user.clone()))
+            .times(1)
+            .return_once(|_| Ok(true));
+
+        let router = make_router(
+            make_service_key(),
+            "whatever".to_string(),
+            db.into_boxed_arc(),
+        )
+        .await;
+        let _token_mock = mock_token_endpoint();
+        let _fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID)
+            .with_status(400)
+            .with_body(r#"{"error":{"status":"INVALID_ARGUMENT","message":"test-message","details":[{"@type":"type.googleapis.com/google.firebase.fcm.v1.FcmError","errorCode":"UNREGISTERED"}]}}"#)
+            .create();
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err.kind, ApiErrorKind::Router(RouterError::NotFound)),
+            "err = {err:?}"
+        );
+        assert_eq!(err.kind.status(), actix_web::http::StatusCode::GONE);
+    }
+
+    /// Users with fallback routes are kept, for them to be tried
+    #[tokio::test]
+    async fn unregistered_fcm_user_with_fallback() {
+        let mut notification = make_notification(default_router_data(), None, RouterType::FCM);
+        notification.subscription.user.fallback_routes = vec![FallbackRoute {
+            router_type: "apns".to_owned(),
+            router_data: None,
+        }];
+        let mut db = MockDbClient::new();
+        db.expect_remove_gone_user().times(0);
+
+        let router = make_router(
+            make_service_key(),
+            "whatever".to_string(),
+            db.into_boxed_arc(),
+        )
+        .await;
+        let _token_mock = mock_token_endpoint();
+        let _fcm_mock = mock_fcm_endpoint_builder(PROJECT_ID)
+            .with_status(404)
+            .with_body(r#"{"error":{"status":"NOT_FOUND","message":"test-message"}}"#)
+            .create();
+
+        let err = router.route_notification(&notification).await.unwrap_err();
+        assert_eq!(err.kind.status(), actix_web::http::StatusCode::GONE);
+    }
 }
@@ -1,9 +1,13 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use url::Url;
 
+/// The default of the deprecated `timeout`
+const DEFAULT_TIMEOUT_SECS: usize = 3;
+
 /// Settings for `FcmRouter`
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 #[serde(default)]
 #[serde(deny_unknown_fields)]
 pub struct FcmSettings {
@@ -40,8 +44,13 @@ pub struct FcmSettings {
     pub max_data: usize,
     /// The base URL to use for FCM requests
     pub base_url: Url,
-    /// The number of seconds to wait for FCM requests to complete
+    /// Deprecated: the number of seconds to wait for FCM requests to
+    /// complete, used instead of `bridge_request_timeout_ms` when changed
+    /// from its default and `timeout_ms` is unset
     pub timeout: usize,
+    /// The number of milliseconds to wait for FCM requests to complete,
+    /// overrides `bridge_request_timeout_ms` when set
+    pub timeout_ms: Option<u64>,
 }
 
 /// Credential information for each application
@@ -60,7 +69,8 @@ impl Default for FcmSettings {
             server_credentials: "{}".to_string(),
             max_data: 4096,
             base_url: Url::parse("https://fcm.googleapis.com").unwrap(),
-            timeout: 3,
+            timeout: DEFAULT_TIMEOUT_SECS,
+            timeout_ms: None,
         }
     }
 }
@@ -70,4 +80,12 @@ impl FcmSettings {
     pub fn credentials(&self) -> serde_json::Result<HashMap<String, FcmServerCredential>> {
         serde_json::from_str(&self.server_credentials)
     }
+
+    /// The time to wait for FCM requests to complete, when overriding the
+    /// bridge HTTP client's
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis).or_else(|| {
+            (self.timeout != DEFAULT_TIMEOUT_SECS).then(|| Duration::from_secs(self.timeout as u64))
+        })
+    }
 }
@@ -0,0 +1,110 @@
+//! HTTP clients for the bridges
+//!
+//! The bridges sending notifications over plain HTTP (FCM and ADM) each get
+//! their own client (and so connection pool), configured the same way from
+//! the common `bridge_*` settings. APNS uses its own HTTP/2 client, as does
+//! FCM's OAuth token fetching, so neither goes through `bridge_proxy_url`.
+use std::time::Duration;
+
+use url::Url;
+
+use crate::settings::Settings;
+
+/// Builds the HTTP clients used by the bridges
+#[derive(Clone, Debug)]
+pub struct BridgeHttpClientBuilder {
+    connect_timeout: Duration,
+    timeout: Duration,
+    user_agent: String,
+    proxy_url: Option<Url>,
+    /// The maximum number of idle connections kept per host, `None` for
+    /// reqwest's default (no limit)
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Duration,
+}
+
+impl BridgeHttpClientBuilder {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            connect_timeout: Duration::from_millis(settings.connection_timeout_millis),
+            timeout: Duration::from_millis(settings.bridge_request_timeout_ms),
+            user_agent: settings.bridge_user_agent.clone(),
+            proxy_url: settings
+                .bridge_proxy_url
+                .as_deref()
+                .map(|url| Url::parse(url).expect("Invalid AUTOEND_BRIDGE_PROXY_URL")),
+            pool_max_idle_per_host: settings.bridge_pool_max_idle_per_host,
+            pool_idle_timeout: Duration::from_secs(settings.bridge_pool_idle_timeout_secs),
+        }
+    }
+
+    /// Build a client. The bridges apply their (reloadable) request timeouts
+    /// per request, overriding this one.
+    pub fn build(&self) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(proxy_url) = &self.proxy_url {
+            builder = builder.proxy(reqwest::Proxy::all(proxy_url.clone())?);
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::BridgeHttpClientBuilder;
+    use crate::settings::Settings;
+
+    #[test]
+    fn from_settings() {
+        let settings = Settings {
+            bridge_request_timeout_ms: 2500,
+            bridge_user_agent: "autoendpoint-test".to_owned(),
+            bridge_proxy_url: Some("http://egress.example.com:3128".to_owned()),
+            bridge_pool_max_idle_per_host: Some(8),
+            ..Default::default()
+        };
+        let builder = BridgeHttpClientBuilder::from_settings(&settings);
+        assert_eq!(builder.timeout, Duration::from_millis(2500));
+        assert_eq!(builder.user_agent, "autoendpoint-test");
+        assert_eq!(
+            builder.proxy_url.as_ref().map(|url| url.as_str()),
+            Some("http://egress.example.com:3128/")
+        );
+        assert_eq!(builder.pool_max_idle_per_host, Some(8));
+        assert!(builder.build().is_ok());
+
+        let builder = BridgeHttpClientBuilder::from_settings(&Settings::default());
+        assert!(builder.proxy_url.is_none());
+        assert!(builder.build().is_ok());
+    }
+
+    /// Requests carry the configured user agent
+    #[tokio::test]
+    async fn user_agent() {
+        let mock = mockito::mock("GET", "/bridge-user-agent")
+            .match_header("user-agent", "autoendpoint-test")
+            .create();
+        let settings = Settings {
+            bridge_user_agent: "autoendpoint-test".to_owned(),
+            ..Default::default()
+        };
+        let client = BridgeHttpClientBuilder::from_settings(&settings)
+            .build()
+            .unwrap();
+        client
+            .get(mockito::server_url() + "/bridge-user-agent")
+            .send()
+            .await
+            .unwrap();
+        mock.assert();
+    }
+}
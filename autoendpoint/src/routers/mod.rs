@@ -17,8 +17,13 @@ use thiserror::Error;
 
 pub mod adm;
 pub mod apns;
-mod common;
+pub mod circuit_breaker;
+pub mod common;
+pub mod concurrency;
 pub mod fcm;
+pub mod http_client;
+pub mod rate_limiter;
+pub mod retry;
 pub mod webpush;
 
 #[async_trait(?Send)]
@@ -36,11 +41,14 @@ pub trait Router {
 }
 
 /// The response returned when a router routes a notification
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct RouterResponse {
     pub status: StatusCode,
     pub headers: HashMap<&'static str, String>,
     pub body: Option<String>,
+    /// The notification was dropped undelivered, having a TTL of 0 and no
+    /// connected client
+    pub expired: bool,
 }
 
 impl RouterResponse {
@@ -55,6 +63,7 @@ impl RouterResponse {
                 map
             },
             body: None,
+            expired: false,
         }
     }
 }
@@ -88,6 +97,10 @@ pub enum RouterError {
     #[error("User was deleted during routing")]
     UserWasDeleted,
 
+    /// The subscription's `max_stored_messages_per_subscription` was reached
+    #[error("Too many messages are stored for this subscription")]
+    TooManyStoredMessages,
+
     #[error(
         "This message is intended for a constrained device and is limited in \
          size. Converted buffer is too long by {0} bytes"
@@ -103,6 +116,14 @@ pub enum RouterError {
     #[error("Bridge request timeout")]
     RequestTimeout,
 
+    /// The bridge's circuit breaker is open
+    #[error("Bridge is temporarily unavailable")]
+    BridgeUnavailable,
+
+    /// The bridge's concurrency limit was reached
+    #[error("Bridge is busy")]
+    BridgeBusy,
+
     #[error("Error while connecting to bridge service")]
     Connect(#[source] reqwest::Error),
 
@@ -121,12 +142,16 @@ impl RouterError {
             RouterError::Apns(e) => e.status(),
             RouterError::Fcm(e) => e.status(),
 
-            RouterError::SaveDb(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RouterError::SaveDb(_) | RouterError::BridgeUnavailable | RouterError::BridgeBusy => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
 
             RouterError::UserWasDeleted | RouterError::NotFound => StatusCode::GONE,
 
             RouterError::TooMuchData(_) => StatusCode::PAYLOAD_TOO_LARGE,
 
+            RouterError::TooManyStoredMessages => StatusCode::TOO_MANY_REQUESTS,
+
             RouterError::Authentication
             | RouterError::GCMAuthentication
             | RouterError::RequestTimeout
@@ -148,6 +173,8 @@ impl RouterError {
 
             RouterError::NotFound => Some(106),
 
+            RouterError::TooManyStoredMessages => Some(117),
+
             RouterError::SaveDb(_) => Some(201),
 
             RouterError::Authentication => Some(901),
@@ -158,7 +185,9 @@ impl RouterError {
 
             RouterError::GCMAuthentication => Some(904),
 
-            RouterError::Upstream { .. } => None,
+            RouterError::Upstream { .. }
+            | RouterError::BridgeUnavailable
+            | RouterError::BridgeBusy => None,
         }
     }
 
@@ -177,6 +206,8 @@ impl RouterError {
                 "notification.bridge.error.fcm.badappid"
             }
             RouterError::TooMuchData(_) => "notification.bridge.error.too_much_data",
+            RouterError::BridgeUnavailable => "notification.bridge.error.circuit_open",
+            RouterError::BridgeBusy => "notification.bridge.error.busy",
             _ => "",
         };
         if !err.is_empty() {
@@ -199,6 +230,9 @@ impl RouterError {
             | RouterError::NotFound
             | RouterError::RequestTimeout
             | RouterError::TooMuchData(_)
+            | RouterError::TooManyStoredMessages
+            | RouterError::BridgeUnavailable
+            | RouterError::BridgeBusy
             | RouterError::Upstream { .. } => false,
             _ => true,
         }
@@ -0,0 +1,199 @@
+//! Per subscription rate limiting
+//!
+//! A misbehaving app server sending to a single subscription could otherwise
+//! exhaust a bridge's (e.g. FCM's) quota for everyone. Each subscription gets
+//! a token bucket holding up to `burst` tokens, refilled at `per_sec` tokens
+//! a second. The buckets are kept in memory, the least recently used ones
+//! being evicted beyond `cache_size` subscriptions.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// A subscription's bucket
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// This bucket's position in `LimiterInner::lru`
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct LimiterInner {
+    /// Tokens added per second, 0 disables the limiter
+    per_sec: f64,
+    burst: f64,
+    buckets: HashMap<(Uuid, Uuid), Bucket>,
+    /// The subscriptions ordered by their last use, least recent first
+    lru: BTreeMap<u64, (Uuid, Uuid)>,
+    /// Incremented on every use
+    tick: u64,
+}
+
+/// A token bucket rate limiter keyed by subscription (UAID and channel ID)
+pub struct SubscriptionRateLimiter {
+    cache_size: usize,
+    inner: Mutex<LimiterInner>,
+}
+
+impl SubscriptionRateLimiter {
+    pub fn new(per_sec: f64, burst: u32, cache_size: usize) -> Self {
+        let limiter = Self {
+            cache_size: cache_size.max(1),
+            inner: Mutex::default(),
+        };
+        limiter.set_limits(per_sec, burst);
+        limiter
+    }
+
+    /// Change the limits (e.g. when reloading the settings). Buckets fuller
+    /// than the new `burst` are drained down to it on their next use.
+    pub fn set_limits(&self, per_sec: f64, burst: u32) {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("SubscriptionRateLimiter lock poisoned");
+        inner.per_sec = per_sec;
+        // A burst below 1 would never allow a request
+        inner.burst = f64::from(burst.max(1));
+    }
+
+    /// Take a token for a notification to the subscription. Returns how long
+    /// to wait before retrying if its bucket is empty.
+    pub fn check(&self, uaid: &Uuid, channel_id: &Uuid) -> Result<(), Duration> {
+        self.check_at(uaid, channel_id, Instant::now())
+    }
+
+    fn check_at(&self, uaid: &Uuid, channel_id: &Uuid, now: Instant) -> Result<(), Duration> {
+        let mut guard = self
+            .inner
+            .lock()
+            .expect("SubscriptionRateLimiter lock poisoned");
+        let inner = &mut *guard;
+        if inner.per_sec <= 0.0 {
+            return Ok(());
+        }
+        let (per_sec, burst) = (inner.per_sec, inner.burst);
+        let key = (*uaid, *channel_id);
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if !inner.buckets.contains_key(&key) && inner.buckets.len() >= self.cache_size {
+            if let Some((_, evicted)) = inner.lru.pop_first() {
+                inner.buckets.remove(&evicted);
+            }
+        }
+        let bucket = inner.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            last_used: tick,
+        });
+        inner.lru.remove(&bucket.last_used);
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_sec).min(burst);
+        bucket.updated = now;
+        bucket.last_used = tick;
+        inner.lru.insert(tick, key);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    /// The number of subscriptions currently tracked
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("SubscriptionRateLimiter lock poisoned")
+            .buckets
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::{http::header, ResponseError};
+    use uuid::Uuid;
+
+    use super::SubscriptionRateLimiter;
+    use crate::error::{ApiError, ApiErrorKind};
+
+    #[test]
+    fn limits_bursts() {
+        let limiter = SubscriptionRateLimiter::new(1.0, 5, 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(limiter.check_at(&uaid, &chid, now).is_ok());
+        }
+        let retry_after = limiter.check_at(&uaid, &chid, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // Other subscriptions aren't affected
+        assert!(limiter.check_at(&uaid, &Uuid::new_v4(), now).is_ok());
+
+        // The bucket refills over time
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(&uaid, &chid, later).is_ok());
+        assert!(limiter.check_at(&uaid, &chid, later).is_err());
+
+        let error: ApiError = ApiErrorKind::RateLimited(retry_after).into();
+        let response = error.error_response();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = SubscriptionRateLimiter::new(0.0, 1, 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        for _ in 0..10 {
+            assert!(limiter.check(&uaid, &chid).is_ok());
+        }
+    }
+
+    #[test]
+    fn set_limits() {
+        let limiter = SubscriptionRateLimiter::new(1.0, 5, 10);
+        let (uaid, chid) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(limiter.check_at(&uaid, &chid, now).is_ok());
+
+        // The existing bucket is capped to the lower burst
+        limiter.set_limits(2.0, 1);
+        assert!(limiter.check_at(&uaid, &chid, now).is_ok());
+        let retry_after = limiter.check_at(&uaid, &chid, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+
+        limiter.set_limits(0.0, 1);
+        assert!(limiter.check_at(&uaid, &chid, now).is_ok());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let limiter = SubscriptionRateLimiter::new(1.0, 1, 2);
+        let uaid = Uuid::new_v4();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+
+        assert!(limiter.check_at(&uaid, &first, now).is_ok());
+        assert!(limiter.check_at(&uaid, &second, now).is_ok());
+        // Use the first again, so the second is the least recently used
+        assert!(limiter.check_at(&uaid, &first, now).is_err());
+        assert!(limiter.check_at(&uaid, &third, now).is_ok());
+        assert_eq!(limiter.len(), 2);
+
+        // The second was evicted, so gets a fresh bucket (evicting the first)
+        assert!(limiter.check_at(&uaid, &second, now).is_ok());
+        assert!(limiter.check_at(&uaid, &third, now).is_err());
+        assert_eq!(limiter.len(), 2);
+    }
+}
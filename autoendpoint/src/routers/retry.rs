@@ -0,0 +1,220 @@
+//! Retrying transient bridge failures
+//!
+//! A bridge request failing with a transient error (a timeout, a connection
+//! error or a 5xx from the bridge) is retried up to `max_retries` times. The
+//! delay before each retry grows exponentially from `base_delay`, capped at
+//! `max_delay`. With jitter enabled the actual delay is a random one between
+//! 0 and the computed delay ("full jitter"), so the retries of a burst of
+//! notifications failing together don't all hit the recovering bridge at
+//! once.
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cadence::{CountedExt, Histogrammed, StatsdClient};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::routers::{RouterError, RouterResponse};
+
+/// Retries the requests to the bridges
+pub struct BridgeRetry {
+    /// Retries after the first attempt, 0 disables retrying
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    rng: Mutex<StdRng>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl BridgeRetry {
+    pub fn new(
+        max_retries: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+        jitter: bool,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+            max_delay,
+            jitter,
+            rng: Mutex::new(StdRng::from_entropy()),
+            metrics,
+        }
+    }
+
+    /// Use a jitter source seeded with `seed`, for deterministic delays
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            ..self
+        }
+    }
+
+    /// The delay before the retry following the `attempt`th (0 based) failed
+    /// attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        let millis = delay.as_millis() as u64;
+        let mut rng = self.rng.lock().expect("BridgeRetry lock poisoned");
+        Duration::from_millis(rng.gen_range(0..=millis))
+    }
+
+    /// Run `request` to the `bridge`, retrying its transient failures
+    pub async fn run<F, Fut>(&self, bridge: &str, request: F) -> ApiResult<RouterResponse>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ApiResult<RouterResponse>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = request().await;
+            match &result {
+                Err(e) if attempt < self.max_retries && is_transient(e) => {}
+                _ => return result,
+            }
+            let delay = self.delay(attempt);
+            self.metrics
+                .incr_with_tags("bridge.retry")
+                .with_tag("platform", bridge)
+                .send();
+            self.metrics
+                .histogram_with_tags("bridge.retry.delay", delay.as_millis() as u64)
+                .with_tag("platform", bridge)
+                .send();
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Whether the bridge request may succeed when retried. Requests rejected by
+/// the bridge's circuit breaker or concurrency limiter aren't retried:
+/// retrying would defeat them. Nor are authentication failures.
+fn is_transient(error: &ApiError) -> bool {
+    match &error.kind {
+        ApiErrorKind::Router(
+            RouterError::BridgeUnavailable
+            | RouterError::BridgeBusy
+            | RouterError::Authentication
+            | RouterError::GCMAuthentication
+            | RouterError::SaveDb(_),
+        ) => false,
+        ApiErrorKind::Router(e) => e.status().is_server_error(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::BridgeRetry;
+    use crate::error::ApiResult;
+    use crate::routers::{RouterError, RouterResponse};
+
+    fn bridge_retry(max_retries: u32, jitter: bool) -> BridgeRetry {
+        let (_, sink) = SpyMetricSink::new();
+        BridgeRetry::new(
+            max_retries,
+            Duration::from_millis(1),
+            Duration::from_millis(20),
+            jitter,
+            Arc::new(StatsdClient::from_sink("autoendpoint", sink)),
+        )
+    }
+
+    #[test]
+    fn exponential_delays() {
+        let retry = bridge_retry(10, false);
+        let delays: Vec<u64> = (0..7).map(|i| retry.delay(i).as_millis() as u64).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 20, 20]);
+        // Doesn't overflow
+        assert_eq!(retry.delay(u32::MAX), Duration::from_millis(20));
+    }
+
+    /// Jittered delays vary, within the cap, and are deterministic given the
+    /// seed
+    #[test]
+    fn jittered_delays() {
+        let delays = |seed| {
+            let retry = bridge_retry(10, true).with_seed(seed);
+            (0..50).map(|_| retry.delay(10)).collect::<Vec<_>>()
+        };
+        let jittered = delays(1);
+        assert!(jittered.iter().all(|d| *d <= Duration::from_millis(20)));
+        assert!(jittered.iter().any(|d| *d != jittered[0]));
+        assert_eq!(jittered, delays(1));
+    }
+
+    #[actix_rt::test]
+    async fn retries_transient_failures() {
+        let (rx, sink) = SpyMetricSink::new();
+        let retry = BridgeRetry::new(
+            2,
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+            true,
+            Arc::new(StatsdClient::from_sink("autoendpoint", sink)),
+        )
+        .with_seed(1);
+        let attempts = Cell::new(0);
+        let result = retry
+            .run("fcm", || async {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    Err(RouterError::RequestTimeout.into())
+                } else {
+                    Ok(RouterResponse::success("location".to_owned(), 60))
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(metrics.len(), 4);
+        assert_eq!(metrics[0], "autoendpoint.bridge.retry:1|c|#platform:fcm");
+        assert!(metrics[1].starts_with("autoendpoint.bridge.retry.delay:"));
+        assert!(metrics[1].ends_with("|h|#platform:fcm"));
+    }
+
+    /// The attempts made to send a request always failing with `error`
+    async fn attempts(max_retries: u32, error: fn() -> RouterError) -> u32 {
+        let attempts = Cell::new(0);
+        let result: ApiResult<RouterResponse> = bridge_retry(max_retries, true)
+            .run("apns", || async {
+                attempts.set(attempts.get() + 1);
+                Err(error().into())
+            })
+            .await;
+        assert!(result.is_err());
+        attempts.get()
+    }
+
+    #[actix_rt::test]
+    async fn gives_up() {
+        assert_eq!(attempts(2, || RouterError::RequestTimeout).await, 3);
+        // Disabled
+        assert_eq!(attempts(0, || RouterError::RequestTimeout).await, 1);
+        // Permanent failures and rejections aren't retried
+        assert_eq!(attempts(2, || RouterError::NotFound).await, 1);
+        assert_eq!(attempts(2, || RouterError::BridgeBusy).await, 1);
+    }
+}
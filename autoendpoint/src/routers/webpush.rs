@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 use cadence::{Counted, CountedExt, StatsdClient, Timed};
 use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::{hash_map::RandomState, HashMap};
 use std::sync::Arc;
@@ -9,10 +10,32 @@ use uuid::Uuid;
 
 use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::{notification::Notification, router_data_input::RouterDataInput};
-use crate::routers::{Router, RouterError, RouterResponse};
+use crate::routers::{common::dry_run_response, Router, RouterError, RouterResponse};
+use crate::stats::Stats;
 
 use autopush_common::db::{client::DbClient, User};
 
+/// The most of a user's stored messages read when counting those of a
+/// subscription
+const STORED_MESSAGES_FETCH_LIMIT: usize = 1000;
+
+/// The most stored messages evicted while storing a notification. Each store
+/// adds one, so a subscription over `max_stored_messages` (e.g. after it's
+/// lowered) is trimmed back gradually.
+const MAX_EVICTIONS_PER_STORE: usize = 10;
+
+/// What to do with a notification to store for a subscription with
+/// `max_stored_messages_per_subscription` messages already stored
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoredMessageOverflow {
+    /// Delete the subscription's oldest stored message(s) to make room
+    #[default]
+    Evict,
+    /// Refuse to store the notification
+    Reject,
+}
+
 /// The router for desktop user agents.
 ///
 /// These agents are connected via an Autopush connection server. The correct
@@ -23,6 +46,14 @@ pub struct WebPushRouter {
     pub metrics: Arc<StatsdClient>,
     pub http: reqwest::Client,
     pub endpoint_url: Url,
+    /// The maximum number of messages stored per subscription, `None` for
+    /// no limit
+    pub max_stored_messages: Option<u32>,
+    pub stored_message_overflow: StoredMessageOverflow,
+    /// Whether notifications are stored for disconnected users. If not,
+    /// they're dropped as if they had a TTL of 0.
+    pub storage_enabled: bool,
+    pub stats: Arc<Stats>,
 }
 
 #[async_trait(?Send)]
@@ -44,6 +75,13 @@ impl Router for WebPushRouter {
             notification.subscription.user.uaid
         );
         trace!("✉ Notification = {:?}", notification);
+        if notification.dry_run {
+            return Ok(dry_run_response(
+                &self.metrics,
+                "webpush",
+                user.node_id.as_deref(),
+            ));
+        }
 
         // Check if there is a node connected to the client
         if let Some(node_id) = &user.node_id {
@@ -91,12 +129,27 @@ impl Router for WebPushRouter {
                 // TODO: include `internal` if meta is set.
                 .with_tag("topic", &topic)
                 .send();
-            return Ok(self.make_delivered_response(notification));
+            let mut response = self.make_delivered_response(notification);
+            response.expired = true;
+            return Ok(response);
+        }
+
+        if !self.storage_enabled {
+            trace!("✉ Message storage is disabled, dropping the notification");
+            self.metrics.incr("notification.not_stored").ok();
+            let mut response =
+                self.make_response(notification, "NotStored", StatusCode::NO_CONTENT);
+            response.headers.insert("X-Not-Stored", "true".to_owned());
+            response.expired = true;
+            return Ok(response);
         }
 
         // Save notification, node is not present or busy
         trace!("✉ Node is not present or busy, storing notification");
+        self.check_room_to_store(notification).await?;
         self.store_notification(notification).await?;
+        self.evict_overflow(notification).await;
+        self.stats.record_stored();
 
         // Retrieve the user data again, they may have reconnected or the node
         // is no longer busy.
@@ -133,7 +186,8 @@ impl Router for WebPushRouter {
                     self.metrics
                         .time_with_tags(
                             "notification.total_request_time",
-                            (notification.timestamp - autopush_common::util::sec_since_epoch())
+                            autopush_common::util::sec_since_epoch()
+                                .saturating_sub(notification.timestamp)
                                 * 1000,
                         )
                         .with_tag("platform", "websocket")
@@ -180,6 +234,93 @@ impl WebPushRouter {
         self.http.put(&url).send().await
     }
 
+    /// Enforce `max_stored_messages` before storing a notification when
+    /// overflowing ones are rejected. Concurrent stores may each see room
+    /// for one more, briefly exceeding the limit.
+    async fn check_room_to_store(&self, notification: &Notification) -> ApiResult<()> {
+        let Some(max) = self.max_stored_messages else {
+            return Ok(());
+        };
+        if self.stored_message_overflow != StoredMessageOverflow::Reject {
+            return Ok(());
+        }
+        let stored = self.stored_messages(notification).await?;
+        // A topic message replaces the stored message with the same topic
+        if let Some(topic) = &notification.headers.topic {
+            if stored
+                .iter()
+                .any(|message| message.topic.as_ref() == Some(topic))
+            {
+                return Ok(());
+            }
+        }
+        if stored.len() < max as usize {
+            return Ok(());
+        }
+        trace!("✉ Subscription has too many stored messages, rejecting");
+        self.metrics.incr("stored_message.rejected").ok();
+        Err(ApiErrorKind::Router(RouterError::TooManyStoredMessages).into())
+    }
+
+    /// Enforce `max_stored_messages` after storing a notification when
+    /// overflowing ones evict the subscription's oldest stored messages.
+    /// Trimming after the store (rather than making room before it) has
+    /// concurrent stores converge on the limit, evicting at most
+    /// [MAX_EVICTIONS_PER_STORE] messages each.
+    async fn evict_overflow(&self, notification: &Notification) {
+        let Some(max) = self.max_stored_messages else {
+            return;
+        };
+        if self.stored_message_overflow != StoredMessageOverflow::Evict {
+            return;
+        }
+        let uaid = &notification.subscription.user.uaid;
+        // The notification's stored, so failing to evict is only logged
+        let stored = match self.stored_messages(notification).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                debug!("✉ Could not count the stored messages: {}", e);
+                return;
+            }
+        };
+        let overflow = stored
+            .len()
+            .saturating_sub(max as usize)
+            .min(MAX_EVICTIONS_PER_STORE);
+        for message in stored.iter().take(overflow) {
+            trace!("✉ Subscription has too many stored messages, evicting the oldest");
+            if let Err(e) = self.db.remove_message(uaid, &message.chidmessageid()).await {
+                debug!("✉ Could not evict a stored message: {}", e);
+                return;
+            }
+            self.metrics.incr("stored_message.evicted").ok();
+        }
+    }
+
+    /// The messages stored for the notification's subscription, oldest first
+    async fn stored_messages(
+        &self,
+        notification: &Notification,
+    ) -> ApiResult<Vec<autopush_common::notification::Notification>> {
+        let uaid = &notification.subscription.user.uaid;
+        let topic = self
+            .db
+            .fetch_topic_messages(uaid, STORED_MESSAGES_FETCH_LIMIT)
+            .await?;
+        let timestamp = self
+            .db
+            .fetch_timestamp_messages(uaid, None, STORED_MESSAGES_FETCH_LIMIT)
+            .await?;
+        let mut messages: Vec<_> = topic
+            .messages
+            .into_iter()
+            .chain(timestamp.messages)
+            .filter(|message| message.channel_id == notification.subscription.channel_id)
+            .collect();
+        messages.sort_by_key(|message| (message.timestamp, message.sortkey_timestamp));
+        Ok(messages)
+    }
+
     /// Store a notification in the database
     async fn store_notification(&self, notification: &Notification) -> ApiResult<()> {
         self.db
@@ -239,7 +380,7 @@ impl WebPushRouter {
                 map.insert(
                     "Location",
                     self.endpoint_url
-                        .join(&format!("/m/{}", notification.message_id))
+                        .join(&format!("m/{}", notification.message_id))
                         .expect("Message ID is not URL-safe")
                         .to_string(),
                 );
@@ -247,6 +388,236 @@ impl WebPushRouter {
                 map
             },
             body: None,
+            expired: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use cadence::{NopMetricSink, SpyMetricSink, StatsdClient};
+    use mockall::predicate;
+    use url::Url;
+    use uuid::Uuid;
+
+    use super::{StoredMessageOverflow, WebPushRouter, MAX_EVICTIONS_PER_STORE};
+    use crate::error::ApiErrorKind;
+    use crate::extractors::notification::Notification;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::{channel_id, make_notification};
+    use crate::routers::{Router, RouterError};
+    use crate::stats::Stats;
+    use autopush_common::db::client::{DbClient, FetchMessageResponse};
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::db::User;
+    use autopush_common::notification::Notification as StoredNotification;
+
+    const MAX_STORED_MESSAGES: u32 = 2;
+
+    fn make_router(db: Box<dyn DbClient>, overflow: StoredMessageOverflow) -> WebPushRouter {
+        WebPushRouter {
+            db,
+            metrics: Arc::new(StatsdClient::from_sink("autopush", NopMetricSink)),
+            http: reqwest::Client::new(),
+            endpoint_url: Url::parse("http://localhost:8080/").unwrap(),
+            max_stored_messages: Some(MAX_STORED_MESSAGES),
+            stored_message_overflow: overflow,
+            storage_enabled: true,
+            stats: Arc::new(Stats::default()),
         }
     }
+
+    /// A notification to a disconnected user, which is stored
+    fn stored_notification() -> Notification {
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        notification.headers.topic = None;
+        notification
+    }
+
+    /// `count` messages stored for the test subscription, oldest first (and
+    /// one for another subscription of the user)
+    fn stored_messages(count: u64) -> Vec<StoredNotification> {
+        let mut messages: Vec<_> = (0..count)
+            .map(|i| StoredNotification {
+                channel_id: channel_id(),
+                version: format!("message-{i}"),
+                ttl: 60,
+                timestamp: 1000 + i,
+                sortkey_timestamp: Some(1_000_000 + i),
+                ..Default::default()
+            })
+            .collect();
+        messages.push(StoredNotification {
+            channel_id: Uuid::new_v4(),
+            timestamp: 1,
+            sortkey_timestamp: Some(1),
+            ..Default::default()
+        });
+        // Not necessarily returned in order
+        messages.reverse();
+        messages
+    }
+
+    fn mock_stored_messages(db: &mut MockDbClient, count: u64) {
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(FetchMessageResponse::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: stored_messages(count),
+                })
+            });
+    }
+
+    fn mock_store(db: &mut MockDbClient) {
+        db.expect_save_message().times(1).return_once(|_, _| Ok(()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(|_| Ok(Some(User::default())));
+    }
+
+    /// Up to the limit (including the stored notification) nothing is
+    /// evicted
+    #[tokio::test]
+    async fn below_stored_message_limit() {
+        let mut db = MockDbClient::new();
+        mock_stored_messages(&mut db, MAX_STORED_MESSAGES.into());
+        db.expect_remove_message().never();
+        mock_store(&mut db);
+        let router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Evict);
+
+        let response = router
+            .route_notification(&stored_notification())
+            .await
+            .unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    /// Over the limit the subscription's oldest stored message is evicted
+    #[tokio::test]
+    async fn evicts_at_stored_message_limit() {
+        let mut db = MockDbClient::new();
+        mock_stored_messages(&mut db, u64::from(MAX_STORED_MESSAGES) + 1);
+        let oldest = stored_messages(1)
+            .into_iter()
+            .find(|message| message.channel_id == channel_id())
+            .unwrap()
+            .chidmessageid();
+        db.expect_remove_message()
+            .with(predicate::always(), predicate::eq(oldest))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock_store(&mut db);
+        let router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Evict);
+
+        let response = router
+            .route_notification(&stored_notification())
+            .await
+            .unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    /// Far over the limit only a few messages are evicted per store
+    #[tokio::test]
+    async fn caps_evictions_per_store() {
+        let mut db = MockDbClient::new();
+        mock_stored_messages(&mut db, u64::from(MAX_STORED_MESSAGES) + 100);
+        db.expect_remove_message()
+            .times(MAX_EVICTIONS_PER_STORE)
+            .returning(|_, _| Ok(()));
+        mock_store(&mut db);
+        let router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Evict);
+
+        let response = router
+            .route_notification(&stored_notification())
+            .await
+            .unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    /// At the limit the notification is rejected
+    #[tokio::test]
+    async fn rejects_at_stored_message_limit() {
+        let mut db = MockDbClient::new();
+        mock_stored_messages(&mut db, MAX_STORED_MESSAGES.into());
+        db.expect_remove_message().never();
+        db.expect_save_message().never();
+        let router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Reject);
+
+        let result = router.route_notification(&stored_notification()).await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::TooManyStoredMessages)
+            ),
+            "result = {result:?}"
+        );
+        assert_eq!(result.unwrap_err().kind.status(), 429);
+    }
+
+    /// A topic message replacing a stored one doesn't count toward the limit
+    #[tokio::test]
+    async fn topic_collapses_at_stored_message_limit() {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![StoredNotification {
+                        channel_id: channel_id(),
+                        topic: Some("test-topic".to_owned()),
+                        timestamp: 2000,
+                        ..Default::default()
+                    }],
+                })
+            });
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: stored_messages(1),
+                })
+            });
+        db.expect_remove_message().never();
+        mock_store(&mut db);
+        let router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Reject);
+
+        let mut notification = stored_notification();
+        notification.headers.topic = Some("test-topic".to_owned());
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    /// With storage disabled a notification to a disconnected user is
+    /// dropped, not stored
+    #[tokio::test]
+    async fn not_stored_when_storage_disabled() {
+        let mut db = MockDbClient::new();
+        db.expect_save_message().never();
+        db.expect_get_user().never();
+        let mut router = make_router(db.into_boxed_arc(), StoredMessageOverflow::Evict);
+        router.storage_enabled = false;
+        let (rx, sink) = SpyMetricSink::new();
+        router.metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+
+        let response = router
+            .route_notification(&stored_notification())
+            .await
+            .unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(response.headers.get("X-Not-Stored").unwrap(), "true");
+        assert!(response.expired);
+        assert!(rx
+            .try_iter()
+            .any(|m| m == b"autoendpoint.notification.not_stored:1|c"));
+    }
 }
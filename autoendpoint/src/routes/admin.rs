@@ -0,0 +1,370 @@
+//! Routes for operators, only registered when the admin routes are enabled
+//! (see `admin_auth_mode`). The debugging ones are only compiled with the
+//! `debug` feature.
+use actix_web::{
+    web::{Bytes, Data, Path},
+    HttpRequest, HttpResponse,
+};
+#[cfg(feature = "debug")]
+use autopush_common::util::b64_encode_url;
+use autopush_common::{db::client::DbClient, notification::Notification};
+use fernet::MultiFernet;
+use uuid::Uuid;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::extractors::{authorization_check::check_admin_auth, subscription::repad_base64};
+use crate::server::AppState;
+
+/// The number of a user's stored messages read at a time when deleting those
+/// of a subscription
+const STORED_MESSAGES_PAGE_SIZE: usize = 1000;
+
+/// Handle the `GET /v1/admin/subscription/{token}` route.
+///
+/// Returns which VAPID key (as the base64url SHA-256 hash of the public key
+/// held in `/v2/` endpoint tokens) a subscription expects along with its
+/// user's connection and delivery times. Key material and the
+/// subscription's auth secret are never returned. Only compiled with the
+/// `debug` feature.
+#[cfg(feature = "debug")]
+pub async fn subscription_info_route(
+    token: Path<String>,
+    app_state: Data<AppState>,
+    request: HttpRequest,
+    body: Bytes,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&request, &body, &app_state.settings)?;
+
+    let token = decrypt_token(&app_state.fernet, &token)?;
+    let (api_version, vapid_key_hash) = match token.len() {
+        32 => ("v1", None),
+        _ => ("v2", Some(b64_encode_url(&token[32..].to_vec()))),
+    };
+    let (uaid, channel_id) = subscription_ids(&token);
+
+    let user = app_state
+        .db
+        .get_user(&uaid)
+        .await?
+        .ok_or(ApiErrorKind::NoSubscription)?;
+    let channel_registered = app_state
+        .db
+        .get_channels(&uaid)
+        .await?
+        .contains(&channel_id);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "uaid": uaid,
+        "channel_id": channel_id,
+        "channel_registered": channel_registered,
+        "api_version": api_version,
+        "vapid_key_hash": vapid_key_hash,
+        "router_type": user.router_type,
+        "connected_at": user.connected_at,
+        "last_notification_timestamp": user.current_timestamp,
+        "last_connected_at": user.last_connected_at,
+        "last_notified_at": user.last_notified_at,
+    })))
+}
+
+/// Handle the `DELETE /v1/admin/subscription/{token}` route.
+///
+/// Revokes a subscription without the client's involvement (e.g. for abuse
+/// or a compromised key), deleting it along with its stored messages.
+pub async fn delete_subscription_route(
+    token: Path<String>,
+    app_state: Data<AppState>,
+    request: HttpRequest,
+    body: Bytes,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&request, &body, &app_state.settings)?;
+
+    let (uaid, channel_id) = subscription_ids(&decrypt_token(&app_state.fernet, &token)?);
+    let removed = delete_subscription(app_state.db.as_ref(), &uaid, &channel_id)
+        .await?
+        .ok_or(ApiErrorKind::NotFound)?;
+    info!(
+        "Admin deleted a subscription";
+        "admin_action" => "delete_subscription",
+        "uaid" => %uaid,
+        "channel_id" => %channel_id,
+        "messages_deleted" => removed,
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Delete a subscription and its stored messages, returning the number of
+/// messages deleted. `None` when there was no such subscription.
+async fn delete_subscription(
+    db: &dyn DbClient,
+    uaid: &Uuid,
+    channel_id: &Uuid,
+) -> ApiResult<Option<usize>> {
+    if !db.remove_channel(uaid, channel_id).await? {
+        return Ok(None);
+    }
+    let mut removed = 0;
+    // Topic messages have no cursor: once deleted they drop out of the next
+    // page
+    loop {
+        let page = db
+            .fetch_topic_messages(uaid, STORED_MESSAGES_PAGE_SIZE)
+            .await?;
+        let full = page.messages.len() >= STORED_MESSAGES_PAGE_SIZE;
+        let sort_keys = sort_keys_of(page.messages, channel_id);
+        let page_removed = if sort_keys.is_empty() {
+            0
+        } else {
+            db.remove_messages(uaid, &sort_keys).await?
+        };
+        removed += page_removed;
+        if !full {
+            break;
+        }
+        if page_removed == 0 {
+            warn!(
+                "Stopped deleting a subscription's topic messages at a full page of others";
+                "uaid" => %uaid,
+                "channel_id" => %channel_id,
+            );
+            break;
+        }
+    }
+    let mut timestamp = None;
+    loop {
+        let page = db
+            .fetch_timestamp_messages(uaid, timestamp, STORED_MESSAGES_PAGE_SIZE)
+            .await?;
+        let full = page.messages.len() >= STORED_MESSAGES_PAGE_SIZE;
+        let sort_keys = sort_keys_of(page.messages, channel_id);
+        if !sort_keys.is_empty() {
+            removed += db.remove_messages(uaid, &sort_keys).await?;
+        }
+        if !full || page.timestamp.is_none() || page.timestamp == timestamp {
+            break;
+        }
+        timestamp = page.timestamp;
+    }
+    Ok(Some(removed))
+}
+
+/// The sort keys of the channel's messages
+fn sort_keys_of(messages: Vec<Notification>, channel_id: &Uuid) -> Vec<String> {
+    messages
+        .into_iter()
+        .filter(|message| message.channel_id == *channel_id)
+        .map(|message| message.chidmessageid())
+        .collect()
+}
+
+/// Decrypt an endpoint token (of a `/v1/` or `/v2/` endpoint)
+fn decrypt_token(fernet: &MultiFernet, token: &str) -> ApiResult<Vec<u8>> {
+    let token = fernet
+        .decrypt(&repad_base64(token))
+        .map_err(|_| ApiErrorKind::InvalidToken)?;
+    match token.len() {
+        32 | 64 => Ok(token),
+        _ => Err(ApiErrorKind::InvalidToken.into()),
+    }
+}
+
+/// The UAID and channel ID of a decrypted endpoint token
+fn subscription_ids(token: &[u8]) -> (Uuid, Uuid) {
+    // Note: It is safe to unwrap the Uuid result because an error is only
+    // returned if the slice length is not 16.
+    let uaid = Uuid::from_slice(&token[..16]).unwrap();
+    let channel_id = Uuid::from_slice(&token[16..32]).unwrap();
+    (uaid, channel_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App};
+    use autopush_common::db::{client::FetchMessageResponse, mock::MockDbClient};
+    use autopush_common::notification::Notification;
+    use mockall::predicate;
+    use uuid::Uuid;
+
+    use super::{delete_subscription, delete_subscription_route, STORED_MESSAGES_PAGE_SIZE};
+    use crate::server::AppState;
+    use crate::settings::Settings;
+
+    #[tokio::test]
+    async fn deletes_subscription() {
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let message = |channel_id, topic: Option<&str>| Notification {
+            channel_id,
+            topic: topic.map(str::to_owned),
+            sortkey_timestamp: Some(1234),
+            ..Default::default()
+        };
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![message(channel_id, Some("topic"))],
+                })
+            });
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    // Another subscription's message is kept
+                    messages: vec![message(channel_id, None), message(Uuid::new_v4(), None)],
+                })
+            });
+        // Each page's deleted separately
+        for expected in [
+            format!("01:{}:topic", channel_id.as_hyphenated()),
+            format!("02:1234:{}", channel_id.as_hyphenated()),
+        ] {
+            db.expect_remove_messages()
+                .withf(move |_, sort_keys| sort_keys == [expected.clone()])
+                .times(1)
+                .return_once(|_, sort_keys| Ok(sort_keys.len()));
+        }
+
+        let db = db.into_boxed_arc();
+        let removed = delete_subscription(db.as_ref(), &uaid, &channel_id).await;
+        assert_eq!(removed.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn delete_missing_subscription() {
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(false));
+        db.expect_remove_messages().never();
+
+        let db = db.into_boxed_arc();
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let removed = delete_subscription(db.as_ref(), &uaid, &channel_id).await;
+        assert_eq!(removed.unwrap(), None);
+    }
+
+    /// Pages through more timestamp messages than fit in one fetch
+    #[tokio::test]
+    async fn deletes_past_a_page() {
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let message = move |sortkey_timestamp| Notification {
+            channel_id,
+            sortkey_timestamp: Some(sortkey_timestamp),
+            ..Default::default()
+        };
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(FetchMessageResponse::default()));
+        db.expect_fetch_timestamp_messages()
+            .with(
+                predicate::always(),
+                predicate::eq(None),
+                predicate::always(),
+            )
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(STORED_MESSAGES_PAGE_SIZE as u64),
+                    messages: (1..=STORED_MESSAGES_PAGE_SIZE as u64)
+                        .map(message)
+                        .collect(),
+                })
+            });
+        db.expect_fetch_timestamp_messages()
+            .with(
+                predicate::always(),
+                predicate::eq(Some(STORED_MESSAGES_PAGE_SIZE as u64)),
+                predicate::always(),
+            )
+            .times(1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(STORED_MESSAGES_PAGE_SIZE as u64 + 1),
+                    messages: vec![message(STORED_MESSAGES_PAGE_SIZE as u64 + 1)],
+                })
+            });
+        db.expect_remove_messages()
+            .times(2)
+            .returning(|_, sort_keys| Ok(sort_keys.len()));
+
+        let db = db.into_boxed_arc();
+        let removed = delete_subscription(db.as_ref(), &uaid, &channel_id).await;
+        assert_eq!(removed.unwrap(), Some(STORED_MESSAGES_PAGE_SIZE + 1));
+    }
+
+    /// Calls `DELETE /v1/admin/subscription/{token}` for a subscription, with
+    /// the `authorization` header
+    async fn delete_route(db: MockDbClient, authorization: &str) -> StatusCode {
+        let settings = Settings {
+            admin_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        };
+        let app_state = AppState::for_test(settings, db.into_boxed_arc()).await;
+        let mut ids = Uuid::new_v4().as_bytes().to_vec();
+        ids.extend(Uuid::new_v4().as_bytes());
+        let token = app_state.fernet.encrypt(&ids);
+        let app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
+            "/v1/admin/subscription/{token}",
+            web::delete().to(delete_subscription_route),
+        ))
+        .await;
+        let req = test::TestRequest::delete()
+            .uri(&format!(
+                "/v1/admin/subscription/{}",
+                token.trim_end_matches('=')
+            ))
+            .insert_header(("Authorization", authorization))
+            .to_request();
+        test::call_service(&app, req).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn delete_route_requires_admin_token() {
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel().never();
+        assert_eq!(
+            delete_route(db, "bearer wrong").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn delete_route_missing_subscription() {
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(false));
+        assert_eq!(
+            delete_route(db, "bearer s3cret").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn delete_route_deletes() {
+        let mut db = MockDbClient::new();
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(|_, _| Ok(FetchMessageResponse::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .return_once(|_, _, _| Ok(FetchMessageResponse::default()));
+        assert_eq!(
+            delete_route(db, "bearer s3cret").await,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
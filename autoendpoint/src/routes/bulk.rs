@@ -0,0 +1,422 @@
+//! Sending several notifications in one request
+//!
+//! `POST /bulk` takes a JSON array of (at most `max_bulk_items`)
+//! notifications, each as it'd be sent to its endpoint: the endpoint's
+//! token, the request's headers (including any VAPID `Authorization`) and
+//! its base64url encoded body. Each is validated and sent independently,
+//! the response listing their outcomes in order: `{"code": 201, "location":
+//! ...}` when sent, otherwise the error its endpoint would have returned.
+//! The notifications must all be for the same user, otherwise the request
+//! is rejected without sending any. Dry runs, `Prefer: respond-async` and
+//! `Idempotency-Key` aren't supported.
+use std::collections::HashMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Instant;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::web::{Data, Json};
+use actix_web::HttpResponse;
+use autopush_common::util::b64_decode_url;
+use cadence::{Histogrammed, StatsdClient};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::extractors::{
+    notification::Notification,
+    notification_headers::NotificationHeaders,
+    routers::{RouterType, Routers},
+    subscription::Subscription,
+    token_info::{ApiVersion, TokenInfo},
+};
+use crate::headers::util::get_owned_header;
+use crate::routers::RouterResponse;
+use crate::routes::webpush::{check_rate_limit, emit_receipt, record_notified, route};
+use crate::server::AppState;
+use crate::settings::Settings;
+
+/// The most bytes an item takes besides its body: its token and headers
+/// (e.g. a VAPID `Authorization`)
+const MAX_ITEM_OVERHEAD_BYTES: usize = 8 * 1024;
+
+/// A notification of a bulk request
+#[derive(Debug, Deserialize)]
+pub struct BulkItem {
+    /// The token of the endpoint's URL, prefixed by its API version for
+    /// `v2` endpoints (e.g. `v2/gAAAA...`)
+    endpoint_token: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// The base64url encoded body
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl BulkItem {
+    /// The item's headers, as they'd be sent to its endpoint
+    fn header_map(&self) -> ApiResult<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let header = HeaderName::from_str(name)
+                .ok()
+                .zip(HeaderValue::from_str(value).ok());
+            let Some((name, value)) = header else {
+                return Err(invalid(format!("Invalid header {name:?}")));
+            };
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+
+    fn token_info(&self, headers: &HeaderMap) -> ApiResult<TokenInfo> {
+        let (api_version, token) = match self.endpoint_token.split_once('/') {
+            Some((api_version, token)) => (api_version.parse()?, token),
+            None => (ApiVersion::Version1, self.endpoint_token.as_str()),
+        };
+        Ok(TokenInfo {
+            api_version,
+            token: token.to_owned(),
+            crypto_key_header: get_owned_header(headers, "crypto-key"),
+            auth_header: get_owned_header(headers, "authorization"),
+        })
+    }
+}
+
+/// The outcome of sending an item
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BulkItemResult {
+    Sent {
+        code: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        location: Option<String>,
+    },
+    Failed(ApiError),
+}
+
+impl From<ApiResult<RouterResponse>> for BulkItemResult {
+    fn from(result: ApiResult<RouterResponse>) -> Self {
+        match result {
+            Ok(response) => BulkItemResult::Sent {
+                code: response.status.as_u16(),
+                location: response.headers.get("Location").cloned(),
+            },
+            Err(e) => BulkItemResult::Failed(e),
+        }
+    }
+}
+
+fn invalid(reason: String) -> ApiError {
+    ApiErrorKind::InvalidBulkRequest(reason).into()
+}
+
+/// The largest bulk request body accepted
+pub fn max_request_bytes(settings: &Settings) -> usize {
+    // Bodies grow by a third when base64 encoded
+    let max_item_bytes = settings.max_data_bytes * 4 / 3 + MAX_ITEM_OVERHEAD_BYTES;
+    settings.max_bulk_items.max(1) * max_item_bytes
+}
+
+/// Handle the `POST /bulk` route
+pub async fn bulk_route(
+    items: Json<Vec<BulkItem>>,
+    routers: Routers,
+    app_state: Data<AppState>,
+) -> ApiResult<HttpResponse> {
+    let results = send_all(
+        items.into_inner(),
+        app_state.settings.max_bulk_items,
+        &app_state.metrics,
+        |item| prepare(item, &app_state),
+        |(token, notification)| send(token, notification, &routers, &app_state),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
+/// Validate the `items` (via `prepare`, returning each one's UAID) then
+/// send them (via `send`), both concurrently and no failure affecting the
+/// others. Rejected when the valid items aren't all for the same UAID.
+async fn send_all<P, PFut, T, S, SFut>(
+    items: Vec<BulkItem>,
+    max_items: usize,
+    metrics: &StatsdClient,
+    prepare: P,
+    send: S,
+) -> ApiResult<Vec<BulkItemResult>>
+where
+    P: Fn(BulkItem) -> PFut,
+    PFut: Future<Output = ApiResult<(Uuid, T)>>,
+    S: Fn(T) -> SFut,
+    SFut: Future<Output = ApiResult<RouterResponse>>,
+{
+    if items.len() > max_items {
+        return Err(invalid(format!(
+            "Must have no more than {max_items} notifications"
+        )));
+    }
+    metrics.histogram("bulk.items", items.len() as u64).ok();
+    let prepared = join_all(items.into_iter().map(prepare)).await;
+    let mut uaids = prepared.iter().flatten().map(|(uaid, _)| uaid);
+    if let Some(first) = uaids.next() {
+        if uaids.any(|uaid| uaid != first) {
+            return Err(invalid(
+                "The notifications must all be for the same user".to_owned(),
+            ));
+        }
+    }
+    let send = &send;
+    let results = join_all(
+        prepared
+            .into_iter()
+            .map(|item| async move { send(item?.1).await }),
+    )
+    .await;
+    Ok(results.into_iter().map(BulkItemResult::from).collect())
+}
+
+/// Validate an item as its endpoint would, returning its UAID with its
+/// token and notification
+async fn prepare(
+    item: BulkItem,
+    app_state: &Data<AppState>,
+) -> ApiResult<(Uuid, (String, Notification))> {
+    let headers = item.header_map()?;
+    NotificationHeaders::check_crypto_key_limits(
+        &headers,
+        app_state.settings.max_crypto_key_bytes,
+        app_state.settings.max_crypto_key_params,
+    )?;
+    let token_info = item.token_info(&headers)?;
+    let token = token_info.token.clone();
+    let subscription = Subscription::validate(token_info, app_state).await?;
+    let data = item
+        .body
+        .as_deref()
+        .map(b64_decode_url)
+        .transpose()
+        .map_err(|_| invalid("The body must be base64url encoded".to_owned()))?
+        .unwrap_or_default();
+    let notification = Notification::build(&headers, subscription, data.into(), app_state)?;
+    Ok((notification.subscription.user.uaid, (token, notification)))
+}
+
+/// Send a validated item, as its endpoint would
+async fn send(
+    token: String,
+    notification: Notification,
+    routers: &Routers,
+    app_state: &Data<AppState>,
+) -> ApiResult<RouterResponse> {
+    let router_type = RouterType::from_str(&notification.subscription.user.router_type)
+        .map_err(|_| ApiErrorKind::InvalidRouterType)?;
+    check_rate_limit(app_state, &notification, router_type)?;
+    let started = Instant::now();
+    let result = route(&notification, routers, router_type).await;
+    emit_receipt(
+        app_state.receipts.as_deref(),
+        &token,
+        &notification,
+        router_type,
+        started,
+        &result,
+    );
+    let response = result?;
+    record_notified(app_state.db.as_ref(), &notification);
+    #[cfg(feature = "debug")]
+    if let Some(url) = &app_state.settings.debug_mirror_url {
+        let summary =
+            crate::debug_mirror::NotificationSummary::new(&token, &notification, router_type);
+        crate::debug_mirror::mirror(&app_state.http, url, summary);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    use actix_web::{http::StatusCode, web::Data};
+    use autopush_common::db::{mock::MockDbClient, User};
+    use cadence::{SpyMetricSink, StatsdClient};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{prepare, send_all, BulkItem};
+    use crate::error::{ApiErrorKind, ApiResult};
+    use crate::extractors::{notification::Notification, token_info::ApiVersion};
+    use crate::routers::RouterResponse;
+    use crate::server::AppState;
+    use crate::settings::Settings;
+
+    fn items(tokens: &[&str]) -> Vec<BulkItem> {
+        tokens
+            .iter()
+            .map(|token| {
+                serde_json::from_value(json!({
+                    "endpoint_token": token,
+                    "headers": {"TTL": "60"},
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    /// Stands in for [prepare]: the `invalid` token fails, the others are
+    /// for the same user
+    async fn prepare_token(item: BulkItem) -> ApiResult<(Uuid, String)> {
+        match item.endpoint_token.as_str() {
+            "invalid" => Err(ApiErrorKind::InvalidToken.into()),
+            _ => Ok((Uuid::nil(), item.endpoint_token)),
+        }
+    }
+
+    /// Each item's sent independently, failing ones not affecting the rest
+    #[actix_rt::test]
+    async fn mixed_batch() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::from_sink("autoendpoint", sink);
+        let results = send_all(
+            items(&["sent", "gone", "invalid", "sent"]),
+            10,
+            &metrics,
+            prepare_token,
+            |token| async move {
+                match token.as_str() {
+                    "sent" => Ok(RouterResponse {
+                        status: StatusCode::CREATED,
+                        headers: HashMap::from([("Location", "location".to_owned())]),
+                        body: None,
+                        expired: false,
+                    }),
+                    _ => Err(ApiErrorKind::NoSubscription.into()),
+                }
+            },
+        )
+        .await
+        .unwrap();
+
+        let results = serde_json::to_value(results).unwrap();
+        assert_eq!(results[0], json!({"code": 201, "location": "location"}));
+        assert_eq!(results[1]["code"], 410);
+        assert_eq!(results[1]["errno"], 106);
+        assert_eq!(results[2]["code"], 404);
+        assert_eq!(results[2]["errno"], 102);
+        assert_eq!(results[3]["code"], 201);
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(metrics, ["autoendpoint.bulk.items:4|h"]);
+    }
+
+    /// Batches of more than `max_bulk_items` are rejected, sending none
+    #[actix_rt::test]
+    async fn over_limit() {
+        let metrics = StatsdClient::from_sink("autoendpoint", cadence::NopMetricSink);
+        let sent = Cell::new(0);
+        let result = send_all(items(&["a", "b", "c"]), 2, &metrics, prepare_token, |_| {
+            sent.set(sent.get() + 1);
+            async { Ok(RouterResponse::success("location".to_owned(), 60)) }
+        })
+        .await;
+        let err = result.unwrap_err();
+        assert_eq!(err.kind.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(err.kind, ApiErrorKind::InvalidBulkRequest(_)));
+        assert_eq!(sent.get(), 0);
+    }
+
+    /// Items are validated as their endpoints would, and those of different
+    /// users are rejected, sending none
+    #[actix_rt::test]
+    async fn single_user() {
+        let users = [Uuid::new_v4(), Uuid::new_v4()];
+        let channel_id = Uuid::new_v4();
+        let mut db = MockDbClient::new();
+        db.expect_rotating_message_table().returning(|| None);
+        db.expect_get_user().returning(move |uaid| {
+            Ok(users.contains(uaid).then(|| User {
+                uaid: *uaid,
+                ..Default::default()
+            }))
+        });
+        db.expect_get_channels()
+            .returning(move |_| Ok([channel_id].into_iter().collect()));
+        let app_state =
+            Data::new(AppState::for_test(Settings::default(), db.into_boxed_arc()).await);
+        let token = |uaid: &Uuid, channel_id: &Uuid| {
+            let mut ids = uaid.as_bytes().to_vec();
+            ids.extend(channel_id.as_bytes());
+            app_state
+                .fernet
+                .encrypt(&ids)
+                .trim_end_matches('=')
+                .to_owned()
+        };
+        let send = |(token, _): (String, Notification)| async move {
+            ApiResult::Ok(RouterResponse::success(format!("location/{token}"), 60))
+        };
+
+        let tokens = [
+            token(&users[0], &channel_id),
+            token(&users[0], &Uuid::new_v4()),
+            token(&users[0], &channel_id),
+        ];
+        let batch = items(&tokens.iter().map(String::as_str).collect::<Vec<_>>());
+        let results = send_all(
+            batch,
+            10,
+            &app_state.metrics,
+            |item| prepare(item, &app_state),
+            send,
+        )
+        .await
+        .unwrap();
+        let results = serde_json::to_value(results).unwrap();
+        assert_eq!(results[0]["code"], 200);
+        assert_eq!(results[0]["location"], format!("location/{}", tokens[0]));
+        // An unknown channel
+        assert_eq!(results[1]["code"], 410);
+        assert_eq!(results[2]["code"], 200);
+
+        let tokens = [token(&users[0], &channel_id), token(&users[1], &channel_id)];
+        let batch = items(&tokens.iter().map(String::as_str).collect::<Vec<_>>());
+        let sent = Cell::new(0);
+        let err = send_all(
+            batch,
+            10,
+            &app_state.metrics,
+            |item| prepare(item, &app_state),
+            |item| {
+                sent.set(sent.get() + 1);
+                send(item)
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err.kind, ApiErrorKind::InvalidBulkRequest(_)));
+        assert_eq!(sent.get(), 0);
+    }
+
+    #[test]
+    fn token_info() {
+        let item = items(&["v2/token"]).remove(0);
+        let headers = item.header_map().unwrap();
+        assert_eq!(headers.get("ttl").unwrap(), "60");
+        let token_info = item.token_info(&headers).unwrap();
+        assert_eq!(token_info.api_version, ApiVersion::Version2);
+        assert_eq!(token_info.token, "token");
+
+        let item = items(&["token"]).remove(0);
+        let token_info = item.token_info(&headers).unwrap();
+        assert_eq!(token_info.api_version, ApiVersion::Version1);
+        assert_eq!(token_info.token, "token");
+
+        let item = items(&["v3/token"]).remove(0);
+        assert!(item.token_info(&headers).is_err());
+    }
+}
@@ -1,26 +1,45 @@
 //! Health and Dockerflow routes
+use std::future::Future;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use actix_web::{
     web::{Data, Json},
     HttpResponse,
 };
+use futures::future::join_all;
 use reqwest::StatusCode;
 use serde_json::json;
+use url::Url;
 
 use autopush_common::db::error::DbResult;
 
 use crate::error::{ApiErrorKind, ApiResult};
 use crate::server::AppState;
 
-/// Handle the `/health` and `/__heartbeat__` routes
-pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
-    let router_health = interpret_table_health(state.db.router_table_exists().await);
-    let message_health = interpret_table_health(state.db.message_table_exists().await);
+/// How long each `/health` database check may take before it's reported as
+/// failed, so a hung database doesn't hang the probe
+const DB_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
-    Json(json!({
+/// How long each optional `/__heartbeat__` bridge reachability check may
+/// take
+const BRIDGE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The APNS production endpoint, checked for reachability
+const APNS_URL: &str = "https://api.push.apple.com";
+
+/// Handle the `/health` route
+pub async fn health_route(state: Data<AppState>) -> HttpResponse {
+    let (db_health, router_health, message_health) = futures::join!(
+        check_db(&state),
+        check_table(state.db.router_table_exists(), DB_HEALTH_CHECK_TIMEOUT),
+        check_table(state.db.message_table_exists(), DB_HEALTH_CHECK_TIMEOUT),
+    );
+
+    let mut body = json!({
         "status": "OK",
         "version": env!("CARGO_PKG_VERSION"),
+        "db": "connected",
         "router_table": router_health,
         "message_table": message_health,
         "routers": {
@@ -28,7 +47,160 @@ pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
             "apns": state.apns_router.active(),
             "fcm": state.fcm_router.active(),
         }
-    }))
+    });
+    match db_health {
+        Ok(()) => HttpResponse::Ok().json(body),
+        Err(cause) => {
+            warn!("⚠️ Health check failed: {}", &cause);
+            body["status"] = json!("ERROR");
+            body["db"] = json!(cause);
+            HttpResponse::ServiceUnavailable().json(body)
+        }
+    }
+}
+
+/// Handle the `/__heartbeat__` route
+///
+/// Runs the checks concurrently, reporting each one's status and latency. A
+/// failing database check is an `error` (503), while failing bridge checks
+/// (when `heartbeat_check_bridges`) only `warn`, so load balancers keep
+/// routing through transient bridge problems.
+pub async fn heartbeat_route(state: Data<AppState>) -> HttpResponse {
+    let mut bridge_checks = Vec::new();
+    if state.settings.heartbeat_check_bridges {
+        let bridges = [
+            (
+                "fcm",
+                state.fcm_router.active(),
+                Some(&state.settings.fcm.base_url),
+            ),
+            ("apns", state.apns_router.active(), None),
+            (
+                "adm",
+                state.adm_router.active(),
+                Some(&state.settings.adm.base_url),
+            ),
+        ];
+        for (name, active, base_url) in bridges {
+            if !active {
+                continue;
+            }
+            let url = base_url
+                .cloned()
+                .unwrap_or_else(|| Url::parse(APNS_URL).expect("Invalid APNS_URL"));
+            bridge_checks.push(timed_check(
+                name,
+                false,
+                check_bridge(&state.bridge_http, url),
+            ));
+        }
+    }
+    let (db_check, bridge_checks) = futures::join!(
+        timed_check("database", true, check_db(&state)),
+        join_all(bridge_checks)
+    );
+    let mut checks = vec![db_check];
+    checks.extend(bridge_checks);
+    let (status, body) = heartbeat_report(&checks);
+    if status != StatusCode::OK {
+        warn!("⚠️ Heartbeat failed: {}", body);
+    }
+    HttpResponse::build(status).json(body)
+}
+
+/// The outcome of a single `/__heartbeat__` check
+struct Check {
+    name: &'static str,
+    /// A failure of a required check is an error, otherwise a warning
+    required: bool,
+    latency: Duration,
+    result: Result<(), String>,
+}
+
+async fn timed_check(
+    name: &'static str,
+    required: bool,
+    check: impl Future<Output = Result<(), String>>,
+) -> Check {
+    let start = Instant::now();
+    let result = check.await;
+    Check {
+        name,
+        required,
+        latency: start.elapsed(),
+        result,
+    }
+}
+
+/// Summarize the checks as the heartbeat's status code and JSON body
+fn heartbeat_report(checks: &[Check]) -> (StatusCode, serde_json::Value) {
+    let mut status = "ok";
+    let mut results = serde_json::Map::new();
+    for check in checks {
+        let mut result = json!({
+            "status": "ok",
+            "latency_ms": check.latency.as_millis() as u64,
+        });
+        if let Err(cause) = &check.result {
+            result["status"] = json!("error");
+            result["cause"] = json!(cause);
+            if check.required {
+                status = "error";
+            } else if status == "ok" {
+                status = "warn";
+            }
+        }
+        results.insert(check.name.to_owned(), result);
+    }
+    let code = if status == "error" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        code,
+        json!({
+            "status": status,
+            "version": env!("CARGO_PKG_VERSION"),
+            "checks": results,
+        }),
+    )
+}
+
+/// Check the database, within `DB_HEALTH_CHECK_TIMEOUT`
+async fn check_db(state: &AppState) -> Result<(), String> {
+    match tokio::time::timeout(DB_HEALTH_CHECK_TIMEOUT, state.db.health_check()).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err("Database health check failed".to_owned()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!(
+            "Database health check timed out after {:?}",
+            DB_HEALTH_CHECK_TIMEOUT
+        )),
+    }
+}
+
+/// Check a bridge is reachable. Any response (even an error status) will do.
+async fn check_bridge(http: &reqwest::Client, url: Url) -> Result<(), String> {
+    match http.head(url).timeout(BRIDGE_CHECK_TIMEOUT).send().await {
+        Err(e) if e.is_connect() || e.is_timeout() => Err(e.to_string()),
+        // Other errors (e.g. protocol errors) still reached the bridge
+        _ => Ok(()),
+    }
+}
+
+/// Check a table exists (via `exists`), within `timeout`
+async fn check_table(
+    exists: impl Future<Output = DbResult<bool>>,
+    timeout: Duration,
+) -> serde_json::Value {
+    match tokio::time::timeout(timeout, exists).await {
+        Ok(health) => interpret_table_health(health),
+        Err(_) => json!({
+            "status": "NOT OK",
+            "cause": format!("Timed out after {:?}", timeout)
+        }),
+    }
 }
 
 /// Convert the result of a DB health check to JSON
@@ -64,11 +236,31 @@ pub async fn lb_heartbeat_route() -> HttpResponse {
 
 /// Handle the `/__version__` route
 pub async fn version_route() -> HttpResponse {
-    // Return the contents of the version.json file created by circleci
-    // and stored in the docker root
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .body(include_str!("../../../version.json"))
+    HttpResponse::Ok().json(version_info())
+}
+
+/// The contents of the version.json file created by circleci (and stored in
+/// the docker root), its missing (or placeholder "TBD") fields filled in with
+/// the build information captured at compile time
+fn version_info() -> serde_json::Value {
+    let mut info: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(include_str!("../../../version.json")).unwrap_or_default();
+    let build_info = [
+        ("commit", env!("AUTOEND_GIT_COMMIT")),
+        ("version", env!("CARGO_PKG_VERSION")),
+        ("source", env!("CARGO_PKG_REPOSITORY")),
+        ("build_timestamp", env!("AUTOEND_BUILD_TIMESTAMP")),
+    ];
+    for (key, value) in build_info {
+        let field = info.entry(key).or_insert(serde_json::Value::Null);
+        if field
+            .as_str()
+            .map_or(true, |field| field.is_empty() || field == "TBD")
+        {
+            *field = value.into();
+        }
+    }
+    serde_json::Value::Object(info)
 }
 
 /// Handle the `/v1/err` route
@@ -85,3 +277,69 @@ pub async fn log_check() -> ApiResult<String> {
 
     Err(ApiErrorKind::LogCheck.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{check_table, heartbeat_report, version_info, Check};
+
+    fn check(name: &'static str, required: bool, result: Result<(), String>) -> Check {
+        Check {
+            name,
+            required,
+            latency: Duration::from_millis(5),
+            result,
+        }
+    }
+
+    #[test]
+    fn heartbeat_statuses() {
+        let (code, body) =
+            heartbeat_report(&[check("database", true, Ok(())), check("fcm", false, Ok(()))]);
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["checks"]["database"]["latency_ms"], 5);
+
+        // Failing bridges only warn
+        let (code, body) = heartbeat_report(&[
+            check("database", true, Ok(())),
+            check("fcm", false, Err("unreachable".to_owned())),
+        ]);
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["status"], "warn");
+        assert_eq!(body["checks"]["fcm"]["status"], "error");
+        assert_eq!(body["checks"]["fcm"]["cause"], "unreachable");
+
+        let (code, body) = heartbeat_report(&[
+            check("database", true, Err("timed out".to_owned())),
+            check("fcm", false, Err("unreachable".to_owned())),
+        ]);
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "error");
+    }
+
+    #[actix_rt::test]
+    async fn table_check_times_out() {
+        let health = check_table(async { Ok(true) }, Duration::from_millis(10)).await;
+        assert_eq!(health["status"], "OK");
+
+        let health = check_table(std::future::pending(), Duration::from_millis(10)).await;
+        assert_eq!(health["status"], "NOT OK");
+        assert_eq!(health["cause"], "Timed out after 10ms");
+    }
+
+    #[test]
+    fn version_info_is_complete() {
+        let info = version_info();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            info["source"],
+            "https://github.com/mozilla-services/autopush-rs"
+        );
+        assert!(info["build_timestamp"].as_str().unwrap().ends_with('Z'));
+        assert!(info.get("commit").is_some());
+    }
+}
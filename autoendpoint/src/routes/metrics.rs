@@ -0,0 +1,27 @@
+//! Prometheus metrics route
+use actix_web::{
+    web::{Bytes, Data},
+    HttpRequest, HttpResponse,
+};
+
+use crate::error::ApiResult;
+use crate::extractors::authorization_check::check_admin_auth;
+use crate::server::AppState;
+
+/// Handle the `/metrics` route. Only registered when `prometheus_enabled`
+/// is set, authenticated like the admin routes (see `admin_auth_mode`).
+pub async fn prometheus_route(
+    state: Data<AppState>,
+    request: HttpRequest,
+    body: Bytes,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(&request, &body, &state.settings)?;
+    let body = state
+        .prometheus
+        .as_ref()
+        .map(|registry| registry.render())
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
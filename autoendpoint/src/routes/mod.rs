@@ -1,3 +1,7 @@
+pub mod admin;
+pub mod bulk;
 pub mod health;
+pub mod metrics;
 pub mod registration;
+pub mod stats;
 pub mod webpush;
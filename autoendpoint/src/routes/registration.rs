@@ -1,19 +1,25 @@
 use actix_web::web::{Data, Json};
 use actix_web::{HttpRequest, HttpResponse};
 use cadence::{CountedExt, StatsdClient};
+use std::collections::HashMap;
+use std::str::FromStr;
 use uuid::Uuid;
 
 use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::{
-    authorization_check::AuthorizationCheck, new_channel_data::NewChannelData,
+    authorization_check::AuthorizationCheck,
+    new_channel_data::NewChannelData,
     registration_path_args::RegistrationPathArgs,
     registration_path_args_with_uaid::RegistrationPathArgsWithUaid,
-    router_data_input::RouterDataInput, routers::Routers,
+    router_data_input::{is_valid_token, RouterDataInput},
+    routers::{RouterType, Routers},
 };
 use crate::headers::util::get_header;
+use crate::routers::common::{APP_KEY, LOCALE_KEY};
 use crate::server::AppState;
+use crate::settings::Settings;
 
-use autopush_common::db::User;
+use autopush_common::db::{FallbackRoute, User};
 use autopush_common::endpoint::make_endpoint;
 
 /// Handle the `POST /v1/{router_type}/{app_id}/registration` route
@@ -31,13 +37,18 @@ pub async fn register_uaid_route(
     );
     trace!("🌍 token = {}", router_data_input.token);
     let router = routers.get(path_args.router_type);
-    let router_data = router.register(&router_data_input, &path_args.app_id)?;
+    let mut router_data = router.register(&router_data_input, &path_args.app_id)?;
+    tag_app(&mut router_data, &router_data_input, &app_state.settings);
+    store_locale(&mut router_data, &router_data_input);
+    let fallback_routes =
+        register_fallback_routes(&router_data_input, &routers, &app_state.settings)?;
     incr_metric("ua.command.register", &app_state.metrics, &request);
 
     // Register user and channel in database
     let user = User {
         router_type: path_args.router_type.to_string(),
         router_data: Some(router_data),
+        fallback_routes,
         current_month: app_state.db.rotating_message_table().map(str::to_owned),
         ..Default::default()
     };
@@ -104,13 +115,18 @@ pub async fn update_token_route(
     );
     trace!("token = {}", router_data_input.token);
     let router = routers.get(path_args.router_type);
-    let router_data = router.register(&router_data_input, &path_args.app_id)?;
+    let mut router_data = router.register(&router_data_input, &path_args.app_id)?;
+    tag_app(&mut router_data, &router_data_input, &app_state.settings);
+    store_locale(&mut router_data, &router_data_input);
+    let fallback_routes =
+        register_fallback_routes(&router_data_input, &routers, &app_state.settings)?;
 
     // Update the user in the database
     let user = User {
         uaid: path_args.uaid,
         router_type: path_args.router_type.to_string(),
         router_data: Some(router_data),
+        fallback_routes,
         ..Default::default()
     };
     trace!("🌍 Updating user with UAID {}", user.uaid);
@@ -218,3 +234,116 @@ fn incr_metric(name: &str, metrics: &StatsdClient, request: &HttpRequest) {
         .with_tag("host", get_header(request, "Host").unwrap_or("unknown"))
         .send()
 }
+
+/// Store the registered app the subscription's for in its router data,
+/// ignoring unregistered ones (which would make unbounded metric tags)
+fn tag_app(
+    router_data: &mut HashMap<String, serde_json::Value>,
+    router_data_input: &RouterDataInput,
+    settings: &Settings,
+) {
+    let Some(app) = &router_data_input.app else {
+        return;
+    };
+    if settings.registered_apps.contains(app) {
+        router_data.insert(APP_KEY.to_owned(), serde_json::json!(app));
+    } else {
+        debug!("🌍 Ignoring the unregistered app {}", app);
+    }
+}
+
+/// Store the subscription's (validated) locale in its router data, for its
+/// bridge
+fn store_locale(
+    router_data: &mut HashMap<String, serde_json::Value>,
+    router_data_input: &RouterDataInput,
+) {
+    if let Some(locale) = &router_data_input.locale {
+        router_data.insert(LOCALE_KEY.to_owned(), serde_json::json!(locale));
+    }
+}
+
+/// Register the subscription's fallback routes with their routers, tagged
+/// with the same app and locale
+fn register_fallback_routes(
+    router_data_input: &RouterDataInput,
+    routers: &Routers,
+    settings: &Settings,
+) -> ApiResult<Vec<FallbackRoute>> {
+    router_data_input
+        .fallback_routes
+        .iter()
+        .map(|fallback| {
+            // WebPush isn't a bridge
+            let router_type = RouterType::from_str(&fallback.router_type)
+                .ok()
+                .filter(|router_type| *router_type != RouterType::WebPush)
+                .ok_or(ApiErrorKind::InvalidRouterType)?;
+            if !is_valid_token(router_type, &fallback.token) {
+                return Err(ApiErrorKind::InvalidRouterToken.into());
+            }
+            let mut router_data = routers
+                .get(router_type)
+                .register(&fallback.router_data_input(), &fallback.app_id)?;
+            tag_app(&mut router_data, router_data_input, settings);
+            store_locale(&mut router_data, router_data_input);
+            Ok(FallbackRoute {
+                router_type: router_type.to_string(),
+                router_data: Some(router_data),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web, App};
+    use autopush_common::db::mock::MockDbClient;
+    use serde_json::json;
+
+    use super::register_uaid_route;
+    use crate::routers::common::LOCALE_KEY;
+    use crate::server::AppState;
+    use crate::settings::Settings;
+
+    /// A valid locale's stored with the subscription, an invalid one's
+    /// rejected before registering anything
+    #[actix_rt::test]
+    async fn registers_locale() {
+        let mut db = MockDbClient::new();
+        db.expect_rotating_message_table().returning(|| None);
+        db.expect_add_user()
+            .withf(|user| {
+                user.router_data
+                    .as_ref()
+                    .and_then(|router_data| router_data.get(LOCALE_KEY))
+                    == Some(&json!("pt-BR"))
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        db.expect_add_channel().times(1).returning(|_, _| Ok(()));
+        let app_state = AppState::for_test(Settings::default(), db.into_boxed_arc()).await;
+        let app = test::init_service(App::new().app_data(web::Data::new(app_state)).route(
+            "/v1/{router_type}/{app_id}/registration",
+            web::post().to(register_uaid_route),
+        ))
+        .await;
+        let register = |locale: &str| {
+            test::TestRequest::post()
+                .uri("/v1/webpush/app/registration")
+                .set_json(json!({"token": "some-token", "locale": locale}))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, register("pt-BR")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, register("pt_BR")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["message"],
+            "Invalid locale \"pt_BR\", expected a language tag (e.g. en-US)"
+        );
+    }
+}
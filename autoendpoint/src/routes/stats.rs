@@ -0,0 +1,140 @@
+//! Runtime counters route
+use std::sync::atomic::Ordering;
+
+use actix_web::{
+    web::{Bytes, Data},
+    HttpRequest, HttpResponse,
+};
+
+use crate::error::ApiResult;
+use crate::extractors::authorization_check::check_admin_auth;
+use crate::server::AppState;
+use crate::settings::Settings;
+use crate::stats::Stats;
+
+/// Handle the `GET /stats` route, a JSON snapshot of the in-process
+/// counters (see [crate::stats]). Only registered when the admin routes are
+/// enabled (see `admin_auth_mode`).
+pub async fn stats_route(
+    app_state: Data<AppState>,
+    request: HttpRequest,
+    body: Bytes,
+) -> ApiResult<HttpResponse> {
+    stats_response(
+        &app_state.stats,
+        app_state.in_flight.load(Ordering::Relaxed),
+        &request,
+        &body,
+        &app_state.settings,
+    )
+}
+
+fn stats_response(
+    stats: &Stats,
+    in_flight_requests: usize,
+    request: &HttpRequest,
+    body: &[u8],
+    settings: &Settings,
+) -> ApiResult<HttpResponse> {
+    check_admin_auth(request, body, settings)?;
+    Ok(HttpResponse::Ok().json(stats.snapshot(in_flight_requests)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::to_bytes,
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpRequest,
+    };
+    use autopush_common::db::{mock::MockDbClient, User};
+    use uuid::Uuid;
+
+    use super::{stats_response, stats_route};
+    use crate::routes::webpush::webpush_route;
+    use crate::server::AppState;
+    use crate::settings::Settings;
+    use crate::stats::Stats;
+
+    fn settings() -> Settings {
+        Settings {
+            admin_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn request(auth_header: &str) -> HttpRequest {
+        TestRequest::get()
+            .uri("/stats")
+            .insert_header(("Authorization", auth_header))
+            .to_http_request()
+    }
+
+    /// Each notification posted is reflected in the snapshot
+    #[actix_rt::test]
+    async fn counts_notifications() {
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut db = MockDbClient::new();
+        db.expect_rotating_message_table().returning(|| None);
+        db.expect_get_user().returning(move |_| {
+            Ok(Some(User {
+                uaid,
+                ..Default::default()
+            }))
+        });
+        db.expect_get_channels()
+            .returning(move |_| Ok([channel_id].into_iter().collect()));
+        db.expect_save_message().times(1).returning(|_, _| Ok(()));
+        db.expect_update_last_notified_at().returning(|_, _| Ok(()));
+        let app_state = AppState::for_test(settings(), db.into_boxed_arc()).await;
+        let mut ids = uaid.as_bytes().to_vec();
+        ids.extend(channel_id.as_bytes());
+        let token = app_state.fernet.encrypt(&ids);
+        let token = token.trim_end_matches('=').to_owned();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(app_state))
+                .route(
+                    "/wpush/{api_version}/{token}",
+                    web::post().to(webpush_route),
+                )
+                .route("/stats", web::get().to(stats_route)),
+        )
+        .await;
+
+        let snapshot = || async {
+            let req = TestRequest::get()
+                .uri("/stats")
+                .insert_header(("Authorization", "bearer s3cret"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let before = snapshot().await;
+        assert_eq!(before["notifications"], 0);
+        assert_eq!(before["messages_stored_total"], 0);
+
+        let req = TestRequest::post()
+            .uri(&format!("/wpush/v1/{token}"))
+            .insert_header(("TTL", "60"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let after = snapshot().await;
+        assert_eq!(after["notifications"], 1);
+        assert_eq!(after["bridges"]["webpush"]["success"], 1);
+        assert_eq!(after["messages_stored_total"], 1);
+    }
+
+    #[test]
+    fn requires_admin_token() {
+        let stats = Stats::default();
+        let err =
+            stats_response(&stats, 0, &request("bearer wrong"), b"", &settings()).unwrap_err();
+        assert_eq!(err.kind.status(), StatusCode::UNAUTHORIZED);
+    }
+}
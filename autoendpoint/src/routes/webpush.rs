@@ -1,18 +1,45 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::delivery_queue::DeliveryQueue;
+use crate::delivery_status::{DeliveryStatus, DeliveryStatuses};
 use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::message_id::MessageId;
 use crate::extractors::notification::Notification;
 use crate::extractors::routers::{RouterType, Routers};
+use crate::idempotency::Reserved;
+use crate::otel;
+use crate::outbox::Outbox;
+use crate::receipts::{Receipt, ReceiptSink};
+use crate::routers::{RouterError, RouterResponse};
 use crate::server::AppState;
-use actix_web::web::Data;
-use actix_web::HttpResponse;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path};
+use actix_web::{HttpRequest, HttpResponse};
+use autopush_common::db::{client::DbClient, User};
+use autopush_common::logging::REQUEST_ID;
+use autopush_common::notification::Notification as StoredNotification;
+use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+use cadence::{CountedExt, StatsdClient};
+use opentelemetry::trace::FutureExt as _;
+use serde::Serialize;
+use url::Url;
+use uuid::Uuid;
+
+/// A user's `last_notified_at` is only rewritten once it's this stale,
+/// coalescing the writes of frequently notified users
+const LAST_NOTIFIED_RESOLUTION_MS: u64 = 60 * 60 * 1000;
 
 /// Handle the `POST /wpush/{api_version}/{token}` and `POST /wpush/{token}` routes
 pub async fn webpush_route(
     notification: Notification,
     routers: Routers,
-    _app_state: Data<AppState>,
+    app_state: Data<AppState>,
+    request: HttpRequest,
 ) -> ApiResult<HttpResponse> {
     // TODO:
     sentry::configure_scope(|scope| {
@@ -21,11 +48,448 @@ pub async fn webpush_route(
             notification.subscription.user.uaid.to_string().into(),
         );
     });
-    let router = routers.get(
-        RouterType::from_str(&notification.subscription.user.router_type)
-            .map_err(|_| ApiErrorKind::InvalidRouterType)?,
+    let router_type = RouterType::from_str(&notification.subscription.user.router_type)
+        .map_err(|_| ApiErrorKind::InvalidRouterType)?;
+    let subscription = &notification.subscription;
+    let (uaid, channel_id) = (subscription.user.uaid, subscription.channel_id);
+    // Held until the response is known, so repeats meanwhile aren't sent too
+    let reservation = match &notification.idempotency_key {
+        Some(key) => match app_state.idempotency_cache.reserve(&uaid, &channel_id, key) {
+            Reserved::New(reservation) => Some(reservation),
+            Reserved::Done(response) => {
+                app_state
+                    .metrics
+                    .incr_with_tags("notification.deduplicated")
+                    .with_tag("platform", &router_type.to_string())
+                    .send();
+                return Ok(response.into());
+            }
+            Reserved::InProgress => return Err(ApiErrorKind::IdempotencyConflict.into()),
+        },
+        None => None,
+    };
+    check_rate_limit(&app_state, &notification, router_type)?;
+    let token = request.match_info().get("token").unwrap_or_default();
+    // Dry runs aren't accepted, so aren't mirrored
+    #[cfg(feature = "debug")]
+    let summary = (!notification.dry_run)
+        .then(|| crate::debug_mirror::NotificationSummary::new(token, &notification, router_type));
+    let response = if notification.respond_async {
+        let db = app_state.db.clone();
+        let outbox =
+            (app_state.settings.async_outbox_enabled && !notification.dry_run).then(|| {
+                Outbox::new(
+                    db.clone(),
+                    Duration::from_secs(app_state.settings.async_outbox_lease_secs),
+                    app_state.metrics.clone(),
+                )
+            });
+        if let Some(outbox) = &outbox {
+            outbox.add(&notification).await?;
+        }
+        let (receipts, token) = (app_state.receipts.clone(), token.to_owned());
+        let queue = request
+            .app_data::<Rc<DeliveryQueue>>()
+            .expect("No delivery queue found");
+        let urgency = notification.headers.urgency.clone();
+        accept_async(
+            queue,
+            app_state.delivery_statuses.clone(),
+            &app_state.settings.endpoint_url(),
+            &urgency,
+            async move {
+                let started = Instant::now();
+                let result = route(&notification, &routers, router_type).await;
+                emit_receipt(
+                    receipts.as_deref(),
+                    &token,
+                    &notification,
+                    router_type,
+                    started,
+                    &result,
+                );
+                if let Some(outbox) = outbox {
+                    outbox.settle(&notification, &result).await;
+                }
+                let response = result?;
+                record_notified(db.as_ref(), &notification);
+                Ok(response)
+            },
+        )
+        .await?
+    } else {
+        let started = Instant::now();
+        let result = route(&notification, &routers, router_type).await;
+        emit_receipt(
+            app_state.receipts.as_deref(),
+            token,
+            &notification,
+            router_type,
+            started,
+            &result,
+        );
+        let response = result?;
+        record_notified(app_state.db.as_ref(), &notification);
+        response
+    };
+    #[cfg(feature = "debug")]
+    if let (Some(url), Some(summary)) = (&app_state.settings.debug_mirror_url, summary) {
+        crate::debug_mirror::mirror(&app_state.http, url, summary);
+    }
+    if let Some(reservation) = reservation {
+        reservation.complete(&response);
+    }
+    Ok(response.into())
+}
+
+/// Queue the receipt of routing the notification (to `token`) since
+/// `started`, unless it's a dry run or there's no `receipt_sink_url`
+pub(crate) fn emit_receipt(
+    receipts: Option<&ReceiptSink>,
+    token: &str,
+    notification: &Notification,
+    router_type: RouterType,
+    started: Instant,
+    result: &ApiResult<RouterResponse>,
+) {
+    if let Some(receipts) = receipts.filter(|_| !notification.dry_run) {
+        receipts.emit(Receipt::new(token, router_type, started, result));
+    }
+}
+
+/// Reject the notification when its subscription's exceeded its rate limit
+pub(crate) fn check_rate_limit(
+    app_state: &AppState,
+    notification: &Notification,
+    router_type: RouterType,
+) -> ApiResult<()> {
+    let subscription = &notification.subscription;
+    if let Err(retry_after) = app_state
+        .rate_limiter
+        .check(&subscription.user.uaid, &subscription.channel_id)
+    {
+        app_state
+            .metrics
+            .incr_with_tags("notification.rate_limited")
+            .with_tag("platform", &router_type.to_string())
+            .send();
+        return Err(ApiErrorKind::RateLimited(retry_after).into());
+    }
+    Ok(())
+}
+
+/// Route the notification to its router, falling back to the subscription's
+/// `fallback_routes` in order while they're permanently gone
+pub(crate) async fn route(
+    notification: &Notification,
+    routers: &Routers,
+    router_type: RouterType,
+) -> ApiResult<RouterResponse> {
+    routers.stats().record_notification();
+    if notification.subscription.user.fallback_routes.is_empty() {
+        return route_via(notification, routers, router_type).await;
+    }
+    route_with_fallback(
+        notification,
+        router_type,
+        routers.metrics(),
+        routers.db(),
+        |router_type| routers.bridge_allowed(router_type),
+        move |notification, router_type| async move {
+            route_via(&notification, routers, router_type).await
+        },
+    )
+    .await
+}
+
+/// Deliver the notification (via `deliver`) to its primary route, then each
+/// of its `allowed` fallback routes until one isn't permanently gone (410).
+/// Transient failures don't fall through. The fallback route delivering it
+/// becomes the user's primary route (in `db`), dropping the gone ones.
+async fn route_with_fallback<F, Fut>(
+    notification: &Notification,
+    router_type: RouterType,
+    metrics: &StatsdClient,
+    db: &dyn DbClient,
+    allowed: impl Fn(RouterType) -> bool,
+    deliver: F,
+) -> ApiResult<RouterResponse>
+where
+    F: Fn(Notification, RouterType) -> Fut,
+    Fut: Future<Output = ApiResult<RouterResponse>>,
+{
+    let mut result = deliver(notification.clone(), router_type).await;
+    let user = &notification.subscription.user;
+    // Not gone, just not routed by this server
+    let mut disallowed = Vec::new();
+    for (i, fallback) in user.fallback_routes.iter().enumerate() {
+        if !matches!(&result, Err(e) if e.kind.status() == StatusCode::GONE) {
+            break;
+        }
+        let Ok(fallback_type) = RouterType::from_str(&fallback.router_type) else {
+            warn!(
+                "Skipping an invalid fallback route: {}",
+                fallback.router_type
+            );
+            continue;
+        };
+        if !allowed(fallback_type) {
+            debug!("Skipping a disallowed fallback route: {}", fallback_type);
+            disallowed.push(fallback.clone());
+            continue;
+        }
+        metrics
+            .incr_with_tags("delivery.fallback")
+            .with_tag("platform", &fallback_type.to_string())
+            .send();
+        // The routers see the fallback as the primary route, with only the
+        // routes after it left
+        let mut fallback_notification = notification.clone();
+        fallback_notification.subscription.user = User {
+            router_type: fallback.router_type.clone(),
+            router_data: fallback.router_data.clone(),
+            fallback_routes: user.fallback_routes[i + 1..].to_vec(),
+            ..user.clone()
+        };
+        result = deliver(fallback_notification.clone(), fallback_type).await;
+        if result.is_ok() && !notification.dry_run {
+            let mut promoted = fallback_notification.subscription.user;
+            promoted.fallback_routes.splice(0..0, disallowed.drain(..));
+            promote_fallback(db, &promoted, &user.router_type).await;
+        }
+    }
+    result
+}
+
+/// Make the user's delivering fallback route (now `user.router_type`) its
+/// primary route, replacing `previous_router_type`. This is best effort: the
+/// next notification falls back again otherwise.
+async fn promote_fallback(db: &dyn DbClient, user: &User, previous_router_type: &str) {
+    match db.update_user_router(user, previous_router_type).await {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!("Not promoting the fallback route of a changed user"; "uaid" => %user.uaid)
+        }
+        Err(e) => warn!("Could not promote the fallback route: {}", e; "uaid" => %user.uaid),
+    }
+}
+
+/// Route the notification to the router, retrying transient bridge failures
+/// and counting the outcome
+async fn route_via(
+    notification: &Notification,
+    routers: &Routers,
+    router_type: RouterType,
+) -> ApiResult<RouterResponse> {
+    let result = match routers.retry(router_type) {
+        Some(retry) => {
+            retry
+                .run(&router_type.to_string(), || {
+                    route_guarded(notification, routers, router_type)
+                })
+                .await
+        }
+        None => route_guarded(notification, routers, router_type).await,
+    };
+    routers.stats().record_delivery(router_type, &result);
+    result
+}
+
+/// Route the notification to the router, guarded by the bridge's concurrency
+/// limiter and circuit breaker
+async fn route_guarded(
+    notification: &Notification,
+    routers: &Routers,
+    router_type: RouterType,
+) -> ApiResult<RouterResponse> {
+    let router = routers.get(router_type);
+    // Dry runs never reach the bridge, so don't take its capacity (or probe)
+    if notification.dry_run {
+        return router.route_notification(notification).await;
+    }
+    let Some(breaker) = routers.breaker(router_type) else {
+        return router.route_notification(notification).await;
+    };
+    // Held until the bridge request completes. Acquired before consulting
+    // the breaker, so a shed request never takes its half-open probe.
+    let _permit = match routers.limiter(router_type) {
+        Some(limiter) => limiter.acquire().await?,
+        None => None,
+    };
+    let Some(attempt) = breaker.allow_request() else {
+        return Err(RouterError::BridgeUnavailable.into());
+    };
+    let result = router.route_notification(notification).await;
+    match &result {
+        // Only bridge side failures count against the breaker
+        Err(e) if e.kind.status().is_server_error() => attempt.record_failure(),
+        _ => attempt.record_success(),
+    }
+    result
+}
+
+/// Record that the notification's user was just notified, in the
+/// background, unless it's a dry run: this is best effort and never delays
+/// (or fails) the delivery
+pub(crate) fn record_notified(db: &dyn DbClient, notification: &Notification) {
+    let now = ms_since_epoch();
+    let user = &notification.subscription.user;
+    if notification.dry_run
+        || user
+            .last_notified_at
+            .is_some_and(|last| now.saturating_sub(last) < LAST_NOTIFIED_RESOLUTION_MS)
+    {
+        return;
+    }
+    let (db, uaid) = (db.box_clone(), user.uaid);
+    actix_rt::spawn(async move {
+        if let Err(e) = db.update_last_notified_at(&uaid, now).await {
+            warn!("Could not record the user's last notification: {}", e; "uaid" => %uaid);
+        }
+    });
+}
+
+/// Accept a notification (`Prefer: respond-async`), queueing its `delivery`
+/// (by its `urgency`) to run in the background. The 202 response's
+/// `Location` is the delivery's status. When the queue is full, the
+/// delivery is run and its response returned instead.
+async fn accept_async(
+    queue: &Rc<DeliveryQueue>,
+    statuses: Arc<DeliveryStatuses>,
+    endpoint_url: &Url,
+    urgency: &str,
+    delivery: impl Future<Output = ApiResult<RouterResponse>> + 'static,
+) -> ApiResult<RouterResponse> {
+    if !queue.has_room() {
+        return delivery.await;
+    }
+    let id = statuses.insert_pending();
+    let location = endpoint_url
+        .join(&format!("v1/delivery/{id}"))
+        .expect("Delivery ID is not URL-safe");
+    // Keep the request's id on the delivery's log records, and its trace
+    let request_id = REQUEST_ID.try_with(Clone::clone).unwrap_or_default();
+    let cx = otel::child_context("notification.deliver");
+    let task = async move {
+        let status = match delivery.await {
+            Ok(response) => DeliveryStatus::Delivered {
+                code: response.status.as_u16(),
+            },
+            Err(e) => {
+                warn!("Asynchronous notification delivery failed: {}", e; "delivery_id" => %id);
+                DeliveryStatus::Failed {
+                    code: e.kind.status().as_u16(),
+                    errno: e.kind.errno(),
+                }
+            }
+        };
+        statuses.complete(&id, status);
+    };
+    queue.push(
+        urgency,
+        Box::pin(REQUEST_ID.scope(request_id, task.with_context(cx))),
     );
-    Ok(router.route_notification(&notification).await?.into())
+    Ok(RouterResponse {
+        status: StatusCode::ACCEPTED,
+        headers: HashMap::from([("Location", location.to_string())]),
+        body: None,
+        expired: false,
+    })
+}
+
+/// Handle the `GET /v1/delivery/{id}` route, the status of a notification
+/// accepted with `Prefer: respond-async`
+pub async fn delivery_status_route(id: Path<Uuid>, app_state: Data<AppState>) -> HttpResponse {
+    match app_state.delivery_statuses.get(&id) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// The delivery state of a notification, as reported by its `Location`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum MessageState {
+    /// Stored, awaiting the client
+    Pending,
+    /// Delivered to the client (or its bridge)
+    Delivered,
+    /// Its TTL ran out (whether or not it was delivered first)
+    Expired,
+    /// Replaced by a newer notification with the same topic before being
+    /// delivered
+    Replaced,
+    /// No longer stored, but not known to have been delivered: its
+    /// subscription is gone, or its TTL isn't known (older message ids)
+    Unknown,
+}
+
+/// Handle the `GET /m/{message_id}` route, the delivery state of the
+/// notification whose `201 Created` response's `Location` this is
+pub async fn notification_status_route(
+    message_id: MessageId,
+    id: Path<String>,
+    app_state: Data<AppState>,
+) -> ApiResult<HttpResponse> {
+    let state = message_state(app_state.db.as_ref(), &id, &message_id).await?;
+    Ok(HttpResponse::Ok().json(state))
+}
+
+/// The delivery state of the notification with the (encrypted) message `id`
+async fn message_state(
+    db: &dyn DbClient,
+    id: &str,
+    message_id: &MessageId,
+) -> ApiResult<MessageState> {
+    // Receipts expire along with their notification
+    let Some(expiry) = message_id.expiry() else {
+        return legacy_message_state(db, id, message_id).await;
+    };
+    if expiry <= sec_since_epoch() {
+        return Ok(MessageState::Expired);
+    }
+    let uaid = message_id.uaid();
+    if let Some(message) = db.get_message(&uaid, &message_id.sort_key()).await? {
+        return Ok(stored_message_state(&message, id));
+    }
+    // Removed once delivered, unless it was dropped with its subscription
+    let subscribed = db.get_user(&uaid).await?.is_some()
+        && db
+            .get_channels(&uaid)
+            .await?
+            .contains(&message_id.channel_id());
+    Ok(if subscribed {
+        MessageState::Delivered
+    } else {
+        MessageState::Unknown
+    })
+}
+
+/// The delivery state of a notification whose message id has no expiry:
+/// once no longer stored, it may have been delivered or expired
+async fn legacy_message_state(
+    db: &dyn DbClient,
+    id: &str,
+    message_id: &MessageId,
+) -> ApiResult<MessageState> {
+    let stored = db
+        .get_message(&message_id.uaid(), &message_id.sort_key())
+        .await?;
+    Ok(match stored {
+        Some(message) => stored_message_state(&message, id),
+        None => MessageState::Unknown,
+    })
+}
+
+/// The delivery state of the notification with the message `id`, given the
+/// one stored under its sort key
+fn stored_message_state(stored: &StoredNotification, id: &str) -> MessageState {
+    // Topic messages are replaced by newer ones with the same topic
+    if stored.version == id {
+        MessageState::Pending
+    } else {
+        MessageState::Replaced
+    }
 }
 
 /// Handle the `DELETE /m/{message_id}` route
@@ -43,3 +507,431 @@ pub async fn delete_notification_route(
 
     Ok(HttpResponse::NoContent().finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::http::StatusCode;
+    use autopush_common::db::{
+        client::DbClient, error::DbError, mock::MockDbClient, FallbackRoute, User,
+    };
+    use autopush_common::notification::Notification as StoredNotification;
+    use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+    use cadence::{SpyMetricSink, StatsdClient};
+    use fernet::{Fernet, MultiFernet};
+    use mockall::predicate;
+    use url::Url;
+    use uuid::Uuid;
+
+    use super::{
+        accept_async, message_state, record_notified, route_with_fallback, MessageState,
+        LAST_NOTIFIED_RESOLUTION_MS,
+    };
+    use crate::delivery_queue::{DeliveryQueue, QueueDepths};
+    use crate::delivery_status::{DeliveryStatus, DeliveryStatuses};
+    use crate::error::{ApiErrorKind, ApiResult};
+    use crate::extractors::{
+        message_id::MessageId, notification::Notification,
+        notification_headers::NotificationHeaders, routers::RouterType, subscription::Subscription,
+    };
+    use crate::routers::{RouterError, RouterResponse};
+
+    fn delivery_id(response: &RouterResponse) -> uuid::Uuid {
+        let id = response.headers["Location"]
+            .strip_prefix("https://push.example.com/v1/delivery/")
+            .unwrap();
+        id.parse().unwrap()
+    }
+
+    /// `Prefer: respond-async` notifications are accepted immediately, their
+    /// outcome being recorded once delivered
+    #[actix_rt::test]
+    async fn accepts_async() {
+        let statuses = Arc::new(DeliveryStatuses::new(Duration::from_secs(60), 10));
+        let endpoint_url = Url::parse("https://push.example.com/").unwrap();
+        let (_, sink) = SpyMetricSink::new();
+        let depths = QueueDepths::new(Arc::new(StatsdClient::from_sink("autoendpoint", sink)));
+        let queue = DeliveryQueue::new(2, 1, depths);
+
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let response = accept_async(
+            &queue,
+            statuses.clone(),
+            &endpoint_url,
+            "normal",
+            async move {
+                rx.await.ok();
+                Ok(RouterResponse::success("location".to_owned(), 60))
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        let id = delivery_id(&response);
+        assert_eq!(statuses.get(&id), Some(DeliveryStatus::Pending));
+
+        tx.send(()).unwrap();
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            statuses.get(&id),
+            Some(DeliveryStatus::Delivered { code: 200 })
+        );
+
+        let response = accept_async(&queue, statuses.clone(), &endpoint_url, "high", async {
+            Err(ApiErrorKind::Router(RouterError::BridgeUnavailable).into())
+        })
+        .await
+        .unwrap();
+        let id = delivery_id(&response);
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            statuses.get(&id),
+            Some(DeliveryStatus::Failed {
+                code: 503,
+                errno: None
+            })
+        );
+
+        // Delivered before responding when the queue is full
+        let depths = QueueDepths::new(Arc::new(StatsdClient::from_sink(
+            "autoendpoint",
+            cadence::NopMetricSink,
+        )));
+        let full = DeliveryQueue::new(0, 0, depths);
+        let response = accept_async(&full, statuses, &endpoint_url, "normal", async {
+            Ok(RouterResponse::success("location".to_owned(), 60))
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    /// The user's notification time advances, but only once it's stale, and
+    /// not for dry runs
+    #[actix_rt::test]
+    async fn records_notified() {
+        let mut notification = fallback_notification();
+        notification.subscription.user.last_notified_at =
+            Some(ms_since_epoch() - LAST_NOTIFIED_RESOLUTION_MS - 1);
+        let last_notified_at = notification.subscription.user.last_notified_at.unwrap();
+        let uaid = notification.subscription.user.uaid;
+        let mut db = MockDbClient::new();
+        db.expect_update_last_notified_at()
+            .withf(move |id, timestamp| *id == uaid && *timestamp > last_notified_at)
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let db = db.into_boxed_arc();
+        record_notified(db.as_ref(), &notification);
+
+        let mut dry_run = notification.clone();
+        dry_run.dry_run = true;
+        record_notified(db.as_ref(), &dry_run);
+
+        // Recently notified: no write
+        notification.subscription.user.last_notified_at = Some(ms_since_epoch());
+        record_notified(db.as_ref(), &notification);
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    /// A notification is pending while stored, then delivered, until it
+    /// expires
+    #[actix_rt::test]
+    async fn reports_message_state() {
+        let fernet = MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()]);
+        let (uaid, channel_id, timestamp) = (Uuid::new_v4(), Uuid::new_v4(), ms_since_epoch());
+        let message_id = MessageId::WithoutTopic {
+            uaid,
+            channel_id,
+            timestamp,
+            expiry: Some(sec_since_epoch() + 60),
+        };
+        let id = message_id.encrypt(&fernet);
+        let sort_key = message_id.sort_key();
+
+        let mut db = MockDbClient::new();
+        let stored_version = |version: &str| {
+            Some(StoredNotification {
+                version: version.to_owned(),
+                ..Default::default()
+            })
+        };
+        let mut stored = vec![
+            None,
+            None,
+            None,
+            stored_version("newer"),
+            stored_version(&id),
+        ];
+        db.expect_get_message()
+            .withf(move |_, key| key == sort_key)
+            .times(5)
+            .returning(move |_, _| Ok(stored.pop().unwrap()));
+        db.expect_get_user().times(2).returning(move |_| {
+            Ok(Some(User {
+                uaid,
+                ..Default::default()
+            }))
+        });
+        let mut channels = vec![HashSet::new(), HashSet::from([channel_id])];
+        db.expect_get_channels()
+            .times(2)
+            .returning(move |_| Ok(channels.pop().unwrap()));
+        let db = db.into_boxed_arc();
+
+        let decrypted = MessageId::decrypt(&fernet, &id).unwrap();
+        assert_eq!(decrypted, message_id);
+        let state = message_state(db.as_ref(), &id, &decrypted).await.unwrap();
+        assert_eq!(state, MessageState::Pending);
+        let state = message_state(db.as_ref(), &id, &decrypted).await.unwrap();
+        assert_eq!(state, MessageState::Replaced);
+        let state = message_state(db.as_ref(), &id, &decrypted).await.unwrap();
+        assert_eq!(state, MessageState::Delivered);
+        // Dropped along with its subscription
+        let state = message_state(db.as_ref(), &id, &decrypted).await.unwrap();
+        assert_eq!(state, MessageState::Unknown);
+
+        // Without an expiry, it may have expired instead
+        let legacy = MessageId::WithoutTopic {
+            uaid,
+            channel_id,
+            timestamp,
+            expiry: None,
+        };
+        let state = message_state(db.as_ref(), &id, &legacy).await.unwrap();
+        assert_eq!(state, MessageState::Unknown);
+
+        // Expired receipts aren't looked up
+        let expired = MessageId::WithoutTopic {
+            uaid,
+            channel_id,
+            timestamp,
+            expiry: Some(sec_since_epoch() - 1),
+        };
+        let state = message_state(db.as_ref(), &id, &expired).await.unwrap();
+        assert_eq!(state, MessageState::Expired);
+    }
+
+    /// A notification to an FCM subscription falling back to APNS, then ADM
+    fn fallback_notification() -> Notification {
+        let fallback = |router_type: &str| FallbackRoute {
+            router_type: router_type.to_owned(),
+            router_data: None,
+        };
+        Notification {
+            message_id: "message-id".to_owned(),
+            subscription: Subscription {
+                user: User {
+                    router_type: "fcm".to_owned(),
+                    fallback_routes: vec![fallback("apns"), fallback("adm")],
+                    ..Default::default()
+                },
+                channel_id: Uuid::new_v4(),
+                vapid: None,
+            },
+            headers: NotificationHeaders {
+                ttl: 60,
+                topic: None,
+                urgency: "normal".to_owned(),
+                encoding: None,
+                encryption: None,
+                encryption_key: None,
+                crypto_key: None,
+            },
+            timestamp: sec_since_epoch(),
+            sort_key_timestamp: ms_since_epoch(),
+            data: None,
+            dry_run: false,
+            respond_async: false,
+            idempotency_key: None,
+        }
+    }
+
+    /// Deliver the fallback notification via the `allowed` routes, each
+    /// responding with its `outcomes` status: sent (200), gone (410) or timed
+    /// out (502). Returns the result, the routes attempted (with the number
+    /// of fallback routes each saw) and the metrics.
+    async fn deliver_with_fallback(
+        outcomes: &[(RouterType, StatusCode)],
+        db: &dyn DbClient,
+        allowed: impl Fn(RouterType) -> bool,
+    ) -> (ApiResult<RouterResponse>, Vec<(String, usize)>, Vec<String>) {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = StatsdClient::from_sink("autoendpoint", sink);
+        let attempted = RefCell::new(Vec::new());
+        let result = route_with_fallback(
+            &fallback_notification(),
+            RouterType::FCM,
+            &metrics,
+            db,
+            allowed,
+            |notification, router_type| {
+                let user = notification.subscription.user;
+                attempted
+                    .borrow_mut()
+                    .push((user.router_type, user.fallback_routes.len()));
+                let (_, status) = outcomes
+                    .iter()
+                    .find(|(outcome_type, _)| *outcome_type == router_type)
+                    .unwrap();
+                let result = match *status {
+                    StatusCode::OK => Ok(RouterResponse::success("location".to_owned(), 60)),
+                    StatusCode::GONE => Err(RouterError::NotFound.into()),
+                    _ => Err(RouterError::RequestTimeout.into()),
+                };
+                async move { result }
+            },
+        )
+        .await;
+        let metrics = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        (result, attempted.into_inner(), metrics)
+    }
+
+    /// The user's router types: its primary route's, then its fallback
+    /// routes'
+    fn routes(user: &User) -> Vec<&str> {
+        std::iter::once(&user.router_type)
+            .chain(user.fallback_routes.iter().map(|route| &route.router_type))
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn primary_route_succeeds() {
+        let db = MockDbClient::new().into_boxed_arc();
+        let (result, attempted, metrics) =
+            deliver_with_fallback(&[(RouterType::FCM, StatusCode::OK)], db.as_ref(), |_| true)
+                .await;
+        assert_eq!(result.unwrap().status, StatusCode::OK);
+        assert_eq!(attempted, [("fcm".to_owned(), 2)]);
+        assert!(metrics.is_empty());
+    }
+
+    /// A permanently gone primary route falls back to the next, which then
+    /// only has the remaining route to fall back to. Delivering, it replaces
+    /// the primary route.
+    #[actix_rt::test]
+    async fn falls_back_on_gone_route() {
+        let mut db = MockDbClient::new();
+        db.expect_update_user_router()
+            .with(
+                predicate::function(|user: &User| routes(user) == ["apns", "adm"]),
+                predicate::eq("fcm"),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let db = db.into_boxed_arc();
+        let (result, attempted, metrics) = deliver_with_fallback(
+            &[
+                (RouterType::FCM, StatusCode::GONE),
+                (RouterType::APNS, StatusCode::OK),
+            ],
+            db.as_ref(),
+            |_| true,
+        )
+        .await;
+        assert_eq!(result.unwrap().status, StatusCode::OK);
+        assert_eq!(attempted, [("fcm".to_owned(), 2), ("apns".to_owned(), 1)]);
+        assert_eq!(
+            metrics,
+            ["autoendpoint.delivery.fallback:1|c|#platform:apns"]
+        );
+    }
+
+    /// Transient failures are returned as is, without falling back
+    #[actix_rt::test]
+    async fn no_fallback_on_transient_failure() {
+        let db = MockDbClient::new().into_boxed_arc();
+        let (result, attempted, metrics) = deliver_with_fallback(
+            &[(RouterType::FCM, StatusCode::BAD_GATEWAY)],
+            db.as_ref(),
+            |_| true,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(attempted, [("fcm".to_owned(), 2)]);
+        assert!(metrics.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn all_routes_gone() {
+        let db = MockDbClient::new().into_boxed_arc();
+        let (result, attempted, metrics) = deliver_with_fallback(
+            &[
+                (RouterType::FCM, StatusCode::GONE),
+                (RouterType::APNS, StatusCode::GONE),
+                (RouterType::ADM, StatusCode::GONE),
+            ],
+            db.as_ref(),
+            |_| true,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind.status(), StatusCode::GONE);
+        // Only the last route's failure leaves no fallback, removing the user
+        assert_eq!(
+            attempted,
+            [
+                ("fcm".to_owned(), 2),
+                ("apns".to_owned(), 1),
+                ("adm".to_owned(), 0)
+            ]
+        );
+        assert_eq!(metrics.len(), 2);
+    }
+
+    /// Fallback routes the server doesn't allow are skipped, but kept when
+    /// a later one's promoted
+    #[actix_rt::test]
+    async fn skips_disallowed_fallback() {
+        let mut db = MockDbClient::new();
+        db.expect_update_user_router()
+            .with(
+                predicate::function(|user: &User| routes(user) == ["adm", "apns"]),
+                predicate::eq("fcm"),
+            )
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let db = db.into_boxed_arc();
+        let (result, attempted, metrics) = deliver_with_fallback(
+            &[
+                (RouterType::FCM, StatusCode::GONE),
+                (RouterType::ADM, StatusCode::OK),
+            ],
+            db.as_ref(),
+            |router_type| router_type != RouterType::APNS,
+        )
+        .await;
+        assert_eq!(result.unwrap().status, StatusCode::OK);
+        assert_eq!(attempted, [("fcm".to_owned(), 2), ("adm".to_owned(), 0)]);
+        assert_eq!(
+            metrics,
+            ["autoendpoint.delivery.fallback:1|c|#platform:adm"]
+        );
+    }
+
+    /// Failing to promote the fallback route doesn't fail its delivery
+    #[actix_rt::test]
+    async fn promotion_failure() {
+        let mut db = MockDbClient::new();
+        db.expect_update_user_router()
+            .times(1)
+            .return_once(|_, _| Err(DbError::ConnectionError("unavailable".to_owned())));
+        let db = db.into_boxed_arc();
+        let (result, _, _) = deliver_with_fallback(
+            &[
+                (RouterType::FCM, StatusCode::GONE),
+                (RouterType::APNS, StatusCode::OK),
+            ],
+            db.as_ref(),
+            |_| true,
+        )
+        .await;
+        assert_eq!(result.unwrap().status, StatusCode::OK);
+    }
+}
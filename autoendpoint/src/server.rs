@@ -1,105 +1,301 @@
 //! Main application server
 #![forbid(unsafe_code)]
-use std::sync::Arc;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_web::{
-    dev, http::StatusCode, middleware::ErrorHandlers, web, web::Data, App, HttpServer,
+    dev::{self, Service},
+    http::StatusCode,
+    middleware::{Condition, ErrorHandlers},
+    web,
+    web::Data,
+    App, HttpServer,
 };
 #[cfg(feature = "bigtable")]
 use autopush_common::db::bigtable::BigTableClientImpl;
 #[cfg(feature = "dual")]
 use autopush_common::db::dual::DualClientImpl;
-use cadence::StatsdClient;
-use fernet::MultiFernet;
-use serde_json::json;
+use cadence::{CountedExt, Gauged, StatsdClient};
+use fernet::{Fernet, MultiFernet};
+use futures::FutureExt;
+use socket2::{Domain, Protocol, Socket, Type};
+use url::Url;
 
 #[cfg(feature = "dynamodb")]
 use autopush_common::db::dynamodb::DdbClientImpl;
 
 use autopush_common::{
-    db::{client::DbClient, DbSettings, StorageType},
-    middleware::sentry::SentryWrapper,
+    db::{client::DbClient, replica::ReplicaClientImpl, DbSettings, StorageType},
+    middleware::{request_id::RequestIdWrapper, sentry::SentryWrapper},
 };
 
+use crate::delivery_queue::{DeliveryQueue, QueueDepths};
+use crate::delivery_status::DeliveryStatuses;
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
-use crate::metrics;
-use crate::routers::{adm::router::AdmRouter, apns::router::ApnsRouter, fcm::router::FcmRouter};
+use crate::extractors::routers::Routers;
+use crate::idempotency::IdempotencyCache;
+use crate::maintenance::Maintenance;
+use crate::message_gc::MessageGc;
+use crate::metrics::{self, PrometheusRegistry};
+use crate::otel::{TracedDbClient, TracingWrapper};
+use crate::outbox::Outbox;
+use crate::receipts::ReceiptSink;
+use crate::reload::{reload_on_sighup, LiveSettings};
+use crate::route_metrics::RouteMetrics;
+use crate::routers::{
+    adm::router::AdmRouter, apns::router::ApnsRouter, circuit_breaker::BridgeBreakers,
+    concurrency::BridgeLimiters, fcm::router::FcmRouter, http_client::BridgeHttpClientBuilder,
+    rate_limiter::SubscriptionRateLimiter, retry::BridgeRetry,
+};
 use crate::routes::{
-    health::{health_route, lb_heartbeat_route, log_check, status_route, version_route},
+    admin::delete_subscription_route,
+    bulk::{bulk_route, max_request_bytes},
+    health::{
+        health_route, heartbeat_route, lb_heartbeat_route, log_check, status_route, version_route,
+    },
+    metrics::prometheus_route,
     registration::{
         get_channels_route, new_channel_route, register_uaid_route, unregister_channel_route,
         unregister_user_route, update_token_route,
     },
-    webpush::{delete_notification_route, webpush_route},
+    stats::stats_route,
+    webpush::{
+        delete_notification_route, delivery_status_route, notification_status_route, webpush_route,
+    },
 };
 use crate::settings::Settings;
+use crate::stale_subscriptions::StaleSubscriptions;
+use crate::stats::Stats;
+use crate::subscription_cache::{CachedDbClient, SubscriptionCache};
+use crate::timeout::RequestTimeout;
 
 #[derive(Clone)]
 pub struct AppState {
     /// Server Data
     pub metrics: Arc<StatsdClient>,
     pub settings: Settings,
+    /// `settings`, including those reloaded on SIGHUP. The reloadable
+    /// settings must be read from here.
+    pub live_settings: Arc<LiveSettings>,
     pub fernet: MultiFernet,
+    /// The individual crypto keys making up `fernet`, newest first
+    pub fernet_keys: Vec<Fernet>,
+    /// The origins accepted as a VAPID `aud` claim
+    pub vapid_audiences: Vec<Url>,
     pub db: Box<dyn DbClient>,
     pub http: reqwest::Client,
+    /// Configured like the bridges' clients, for checking their reachability
+    pub bridge_http: reqwest::Client,
     pub fcm_router: Arc<FcmRouter>,
     pub apns_router: Arc<ApnsRouter>,
     pub adm_router: Arc<AdmRouter>,
+    pub bridge_breakers: Arc<BridgeBreakers>,
+    pub bridge_limiters: Arc<BridgeLimiters>,
+    pub bridge_retry: Arc<BridgeRetry>,
+    pub rate_limiter: Arc<SubscriptionRateLimiter>,
+    /// Status of notifications accepted with `Prefer: respond-async`
+    pub delivery_statuses: Arc<DeliveryStatuses>,
+    pub idempotency_cache: Arc<IdempotencyCache>,
+    /// The queue of delivery receipts, when `receipt_sink_url` is set
+    pub receipts: Option<Arc<ReceiptSink>>,
+    /// Counters for the `/stats` snapshot
+    pub stats: Arc<Stats>,
+    /// The number of requests being handled
+    pub in_flight: Arc<AtomicUsize>,
+    /// Mirror of the statsd metrics, when `prometheus_enabled`
+    pub prometheus: Option<Arc<PrometheusRegistry>>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// The state for route tests: `settings` with no bridges configured and
+    /// `db` as the storage
+    pub async fn for_test(settings: Settings, db: Box<dyn DbClient>) -> Self {
+        let metrics = Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink));
+        let endpoint_url = settings.endpoint_url();
+        let http = reqwest::Client::new();
+        let live_settings = Arc::new(LiveSettings::new(settings.clone()));
+        let fcm_router = FcmRouter::new(
+            settings.fcm.clone(),
+            endpoint_url.clone(),
+            http.clone(),
+            metrics.clone(),
+            db.clone(),
+            live_settings.clone(),
+        )
+        .await
+        .unwrap();
+        let apns_router = ApnsRouter::new(
+            settings.apns.clone(),
+            endpoint_url.clone(),
+            metrics.clone(),
+            db.clone(),
+            live_settings.clone(),
+        )
+        .await
+        .unwrap();
+        let adm_router = AdmRouter::new(
+            settings.adm.clone(),
+            endpoint_url,
+            http.clone(),
+            metrics.clone(),
+            db.clone(),
+            live_settings.clone(),
+        )
+        .unwrap();
+        Self {
+            bridge_breakers: Arc::new(BridgeBreakers::new(
+                settings.bridge_circuit_failure_threshold,
+                Duration::from_secs(settings.bridge_circuit_reset_secs),
+                &metrics,
+            )),
+            bridge_limiters: Arc::new(BridgeLimiters::new(
+                settings.bridge_max_concurrency,
+                Duration::from_millis(settings.bridge_concurrency_wait_ms),
+                &metrics,
+            )),
+            bridge_retry: Arc::new(BridgeRetry::new(
+                settings.bridge_max_retries,
+                Duration::from_millis(settings.bridge_retry_base_delay_ms),
+                Duration::from_millis(settings.bridge_retry_max_delay_ms),
+                settings.bridge_retry_jitter,
+                metrics.clone(),
+            )),
+            rate_limiter: Arc::new(SubscriptionRateLimiter::new(
+                settings.subscription_rate_limit_per_sec,
+                settings.subscription_rate_limit_burst,
+                settings.rate_limit_cache_size,
+            )),
+            delivery_statuses: Arc::new(DeliveryStatuses::new(
+                Duration::from_secs(settings.respond_async_status_ttl_secs),
+                settings.respond_async_status_cache_size,
+            )),
+            idempotency_cache: Arc::new(IdempotencyCache::new(
+                Duration::from_secs(settings.idempotency_ttl_secs),
+                settings.idempotency_cache_size,
+            )),
+            live_settings,
+            fernet: settings.make_fernet(),
+            fernet_keys: settings.make_fernets(),
+            vapid_audiences: settings.vapid_audiences(),
+            prometheus: settings
+                .prometheus_enabled
+                .then(|| Arc::new(PrometheusRegistry::default())),
+            fcm_router: Arc::new(fcm_router),
+            apns_router: Arc::new(apns_router),
+            adm_router: Arc::new(adm_router),
+            db,
+            bridge_http: http.clone(),
+            http,
+            receipts: None,
+            stats: Arc::new(Stats::default()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            metrics,
+            settings,
+        }
+    }
 }
 
 pub struct Server;
 
+/// Tracks a request as in-flight for as long as it's alive
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl Server {
-    pub async fn with_settings(settings: Settings) -> ApiResult<dev::Server> {
-        let metrics = Arc::new(metrics::metrics_from_settings(&settings)?);
-        let bind_address = format!("{}:{}", settings.host, settings.port);
+    /// Start the server. The settings are reloaded from `config_filename`
+    /// (and the environment) on SIGHUP.
+    pub async fn with_settings(
+        settings: Settings,
+        config_filename: Option<String>,
+    ) -> ApiResult<dev::Server> {
+        let prometheus = settings
+            .prometheus_enabled
+            .then(|| Arc::new(PrometheusRegistry::default()));
+        let live_settings = Arc::new(LiveSettings::new(settings.clone()));
+        let metrics = Arc::new(metrics::metrics_from_settings(
+            live_settings.clone(),
+            prometheus.clone(),
+        )?);
+        let bind_address = settings
+            .bind_address()
+            .map_err(|e| ApiErrorKind::General(e.to_string()))?;
+        let shutdown_timeout = settings.shutdown_timeout_secs;
+        let tls_config = settings
+            .build_rustls_config()
+            .map_err(|e| ApiErrorKind::General(e.to_string()))?;
+        #[cfg(not(feature = "debug"))]
+        if settings.debug_mirror_url.is_some() {
+            warn!("Ignoring debug_mirror_url, it requires a build with the debug feature");
+        }
+        if settings.bridge_proxy_url.is_some() {
+            warn!("APNS and FCM OAuth token requests don't go through bridge_proxy_url");
+        }
         let fernet = settings.make_fernet();
+        let fernet_keys = settings.make_fernets();
+        let vapid_audiences = settings.vapid_audiences();
         let endpoint_url = settings.endpoint_url();
         let db_settings = DbSettings {
             dsn: settings.db_dsn.clone(),
-            db_settings: if settings.db_settings.is_empty() {
-                warn!("❗ Using obsolete message_table and router_table args");
-                // backfill from the older arguments.
-                json!({"message_table": settings.message_table_name, "router_table":settings.router_table_name}).to_string()
-            } else {
-                settings.db_settings.clone()
-            },
-        };
-        let db: Box<dyn DbClient> = match StorageType::from_dsn(&db_settings.dsn) {
-            #[cfg(feature = "dynamodb")]
-            StorageType::DynamoDb => {
-                debug!("Using Dynamodb");
-                Box::new(DdbClientImpl::new(metrics.clone(), &db_settings)?)
-            }
-            #[cfg(feature = "bigtable")]
-            StorageType::BigTable => {
-                debug!("Using BigTable");
-                Box::new(BigTableClientImpl::new(metrics.clone(), &db_settings)?)
-            }
-            #[cfg(all(feature = "bigtable", feature = "dual"))]
-            StorageType::Dual => Box::new(DualClientImpl::new(metrics.clone(), &db_settings)?),
-            _ => {
-                debug!("No idea what {:?} is", &db_settings.dsn);
-                return Err(ApiErrorKind::General(
-                    "Invalid or Unsupported DSN specified".to_owned(),
-                )
-                .into());
-            }
+            db_settings: settings.storage_settings(),
+            table_prefix: settings.database_table_prefix.clone(),
         };
+        let mut db = build_db(&metrics, &db_settings)?;
+        if let Some(read_dsn) = &settings.db_read_dsn {
+            debug!("Using a read replica");
+            let replica_settings = DbSettings {
+                dsn: Some(read_dsn.clone()),
+                db_settings: db_settings.db_settings.clone(),
+                table_prefix: db_settings.table_prefix.clone(),
+            };
+            let replica = build_db(&metrics, &replica_settings)?;
+            db = Box::new(ReplicaClientImpl::new(db, replica));
+        }
+        let tracing = settings.otel_endpoint.is_some();
+        if tracing {
+            db = Box::new(TracedDbClient::new(db));
+        }
+        if settings.subscription_cache_ttl_secs > 0 {
+            let cache = SubscriptionCache::new(
+                Duration::from_secs(settings.subscription_cache_ttl_secs),
+                settings.subscription_cache_size,
+            );
+            db = Box::new(CachedDbClient::new(db, Arc::new(cache), metrics.clone()));
+        }
         let http = reqwest::ClientBuilder::new()
             .connect_timeout(Duration::from_millis(settings.connection_timeout_millis))
             .timeout(Duration::from_millis(settings.request_timeout_millis))
             .build()
             .expect("Could not generate request client");
+        let bridge_http = BridgeHttpClientBuilder::from_settings(&settings);
         let fcm_router = Arc::new(
             FcmRouter::new(
                 settings.fcm.clone(),
                 endpoint_url.clone(),
-                http.clone(),
+                bridge_http
+                    .build()
+                    .expect("Could not generate FCM request client"),
                 metrics.clone(),
                 db.clone(),
+                live_settings.clone(),
             )
             .await?,
         );
@@ -109,97 +305,727 @@ impl Server {
                 endpoint_url.clone(),
                 metrics.clone(),
                 db.clone(),
+                live_settings.clone(),
             )
             .await?,
         );
         let adm_router = Arc::new(AdmRouter::new(
             settings.adm.clone(),
             endpoint_url,
-            http.clone(),
+            bridge_http
+                .build()
+                .expect("Could not generate ADM request client"),
             metrics.clone(),
             db.clone(),
+            live_settings.clone(),
         )?);
+        if settings.bridge_credential_preflight {
+            fcm_router.preflight().await?;
+            adm_router.preflight().await?;
+            apns_router.preflight();
+        }
+        let bridge_http = bridge_http
+            .build()
+            .expect("Could not generate bridge request client");
+        let bridge_breakers = Arc::new(BridgeBreakers::new(
+            settings.bridge_circuit_failure_threshold,
+            Duration::from_secs(settings.bridge_circuit_reset_secs),
+            &metrics,
+        ));
+        let bridge_limiters = Arc::new(BridgeLimiters::new(
+            settings.bridge_max_concurrency,
+            Duration::from_millis(settings.bridge_concurrency_wait_ms),
+            &metrics,
+        ));
+        let bridge_retry = Arc::new(BridgeRetry::new(
+            settings.bridge_max_retries,
+            Duration::from_millis(settings.bridge_retry_base_delay_ms),
+            Duration::from_millis(settings.bridge_retry_max_delay_ms),
+            settings.bridge_retry_jitter,
+            metrics.clone(),
+        ));
+        let rate_limiter = Arc::new(SubscriptionRateLimiter::new(
+            settings.subscription_rate_limit_per_sec,
+            settings.subscription_rate_limit_burst,
+            settings.rate_limit_cache_size,
+        ));
+        let delivery_statuses = Arc::new(DeliveryStatuses::new(
+            Duration::from_secs(settings.respond_async_status_ttl_secs),
+            settings.respond_async_status_cache_size,
+        ));
+        let idempotency_cache = Arc::new(IdempotencyCache::new(
+            Duration::from_secs(settings.idempotency_ttl_secs),
+            settings.idempotency_cache_size,
+        ));
+        if settings.storage_enabled && settings.message_gc_enabled {
+            actix_rt::spawn(
+                MessageGc::new(
+                    db.clone(),
+                    metrics.clone(),
+                    Duration::from_secs(settings.message_gc_interval_secs),
+                    settings.message_gc_batch_size,
+                )
+                .run(),
+            );
+        }
+        if settings.stale_subscription_days > 0 {
+            actix_rt::spawn(
+                StaleSubscriptions::new(
+                    db.clone(),
+                    metrics.clone(),
+                    settings.stale_subscription_days,
+                )
+                .run(),
+            );
+        }
+        let receipts = settings.receipt_sink_url.clone().map(|url| {
+            Arc::new(ReceiptSink::start(
+                http.clone(),
+                url,
+                settings.receipt_buffer_size,
+                metrics.clone(),
+            ))
+        });
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        actix_rt::spawn(reload_on_sighup(
+            config_filename,
+            live_settings.clone(),
+            rate_limiter.clone(),
+        ));
         let app_state = AppState {
             metrics: metrics.clone(),
             settings,
+            live_settings,
             fernet,
+            fernet_keys,
+            vapid_audiences,
             db,
             http,
+            bridge_http,
             fcm_router,
             apns_router,
             adm_router,
+            bridge_breakers,
+            bridge_limiters,
+            bridge_retry,
+            rate_limiter,
+            delivery_statuses,
+            idempotency_cache,
+            receipts,
+            stats: Arc::new(Stats::default()),
+            in_flight: in_flight.clone(),
+            prometheus,
         };
+        if app_state.settings.async_outbox_enabled {
+            let lease = Duration::from_secs(app_state.settings.async_outbox_lease_secs);
+            actix_rt::spawn(
+                Outbox::new(app_state.db.clone(), lease, metrics.clone()).recover_periodically(
+                    app_state.fernet.clone(),
+                    Routers::new(&app_state),
+                    lease,
+                ),
+            );
+        }
+        let trusted_proxies = app_state.settings.trusted_proxies();
+        let listener = bind_listener(
+            bind_address,
+            app_state.settings.bind_dual_stack(),
+            app_state.settings.tcp_backlog,
+            app_state.settings.tcp_nodelay,
+        )?;
+        let h2c_enabled = app_state.settings.h2c_enabled();
+        let server_in_flight = in_flight.clone();
+        let shutdown_metrics = metrics.clone();
+        let shutdown_db = app_state.db.clone();
+        let queue_depths = QueueDepths::new(metrics.clone());
 
         let server = HttpServer::new(move || {
-            // These have a bad habit of being reset. Specify them explicitly.
-            let cors = Cors::default()
-                .allow_any_origin()
-                .allow_any_header()
-                .allowed_methods(vec![
-                    actix_web::http::Method::DELETE,
-                    actix_web::http::Method::GET,
-                    actix_web::http::Method::POST,
-                    actix_web::http::Method::PUT,
-                ])
-                .max_age(3600);
+            let cors = build_cors(&app_state.settings);
             App::new()
                 // Actix 4 recommends wrapping structures wtih web::Data (internally an Arc)
                 .app_data(Data::new(app_state.clone()))
+                // Per server thread, as the deliveries aren't `Send`
+                .app_data(DeliveryQueue::new(
+                    app_state.settings.async_delivery_workers,
+                    app_state.settings.async_delivery_max_queued,
+                    queue_depths.clone(),
+                ))
                 // Extractor configuration
                 .app_data(web::PayloadConfig::new(app_state.settings.max_data_bytes))
-                .app_data(web::JsonConfig::default().limit(app_state.settings.max_data_bytes))
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(app_state.settings.max_data_bytes)
+                        .error_handler(ApiError::from_json_error),
+                )
+                .app_data(
+                    web::PathConfig::default()
+                        .error_handler(|_, _| ApiError::from(ApiErrorKind::NotFound).into()),
+                )
                 // Middleware
+                // Innermost, so the others see its 504s
+                .wrap(RequestTimeout::new(
+                    Duration::from_secs(app_state.settings.handler_deadline_secs),
+                    metrics.clone(),
+                ))
                 .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
+                .wrap(RouteMetrics::new(metrics.clone()))
                 // Our modified Sentry wrapper which does some blocking of non-reportable errors.
                 .wrap(SentryWrapper::<ApiError>::new(
                     metrics.clone(),
                     "api_error".to_owned(),
                 ))
                 .wrap(cors)
-                // Endpoints
+                .wrap(Condition::new(tracing, TracingWrapper))
+                .wrap_fn({
+                    let in_flight = server_in_flight.clone();
+                    move |req, srv| {
+                        let guard = InFlight::new(&in_flight);
+                        srv.call(req).map(move |res| {
+                            drop(guard);
+                            res
+                        })
+                    }
+                })
+                // Outermost, so the access log covers the whole request
+                .wrap(RequestIdWrapper::new(trusted_proxies.clone()))
+                // Routes, under the `url_path_prefix` (the root when it's empty)
                 .service(
-                    web::resource(["/wpush/{api_version}/{token}", "/wpush/{token}"])
-                        .route(web::post().to(webpush_route)),
+                    web::scope(&app_state.settings.url_path_prefix)
+                        .configure(|cfg| configure_routes(cfg, &app_state, &metrics)),
                 )
-                .service(
-                    web::resource("/m/{message_id}")
-                        .route(web::delete().to(delete_notification_route)),
-                )
-                .service(
-                    web::resource("/v1/{router_type}/{app_id}/registration")
-                        .route(web::post().to(register_uaid_route)),
-                )
-                .service(
-                    web::resource("/v1/{router_type}/{app_id}/registration/{uaid}")
-                        .route(web::put().to(update_token_route))
-                        .route(web::get().to(get_channels_route))
-                        .route(web::delete().to(unregister_user_route)),
-                )
-                .service(
-                    web::resource("/v1/{router_type}/{app_id}/registration/{uaid}/subscription")
-                        .route(web::post().to(new_channel_route)),
-                )
-                .service(
-                    web::resource(
-                        "/v1/{router_type}/{app_id}/registration/{uaid}/subscription/{chid}",
-                    )
-                    .route(web::delete().to(unregister_channel_route)),
-                )
-                // Health checks
-                .service(web::resource("/status").route(web::get().to(status_route)))
-                .service(web::resource("/health").route(web::get().to(health_route)))
-                // legacy
-                .service(web::resource("/v1/err").route(web::get().to(log_check)))
-                // standardized
-                .service(web::resource("/__error__").route(web::get().to(log_check)))
-                // Dockerflow
-                .service(web::resource("/__heartbeat__").route(web::get().to(health_route)))
-                .service(web::resource("/__lbheartbeat__").route(web::get().to(lb_heartbeat_route)))
-                .service(web::resource("/__version__").route(web::get().to(version_route)))
         })
-        .bind(bind_address)?
+        // Signals are handled by `shutdown_on_signal` instead
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout);
+        let server = match tls_config {
+            Some(tls_config) => server.listen_rustls(listener, tls_config)?,
+            None if h2c_enabled => server.listen_auto_h2c(listener)?,
+            None => server.listen(listener)?,
+        }
         .run();
+        actix_rt::spawn(Self::shutdown_on_signal(
+            server.handle(),
+            shutdown_metrics,
+            shutdown_db,
+            in_flight,
+            Duration::from_secs(shutdown_timeout),
+        ));
 
         Ok(server)
     }
+
+    /// Wait for SIGTERM/SIGINT, then stop accepting new connections and give
+    /// the in-flight requests up to `timeout` to complete.
+    ///
+    /// actix force closes any remaining connections once its own
+    /// `shutdown_timeout` (the same duration) elapses. The `db`'s background
+    /// tasks are stopped once the server has.
+    async fn shutdown_on_signal(
+        handle: dev::ServerHandle,
+        metrics: Arc<StatsdClient>,
+        db: Box<dyn DbClient>,
+        in_flight: Arc<AtomicUsize>,
+        timeout: Duration,
+    ) {
+        let mut sigterm =
+            match actix_rt::signal::unix::signal(actix_rt::signal::unix::SignalKind::terminate()) {
+                Ok(sigterm) => sigterm,
+                Err(e) => {
+                    error!("Could not install SIGTERM handler: {:?}", e);
+                    return;
+                }
+            };
+        tokio::select! {
+            _ = sigterm.recv() => info!("SIGTERM received, shutting down"),
+            _ = actix_rt::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+        }
+
+        let draining = in_flight.load(Ordering::SeqCst);
+        info!("Draining {} in-flight requests", draining);
+        metrics.incr("shutdown.begin").ok();
+        metrics.gauge("shutdown.draining", draining as u64).ok();
+
+        let stop = handle.stop(true);
+        futures::pin_mut!(stop);
+        if tokio::time::timeout(timeout, &mut stop).await.is_err() {
+            let abandoned = in_flight.load(Ordering::SeqCst);
+            warn!(
+                "Shutdown timed out after {:?}, abandoning {} in-flight requests",
+                timeout, abandoned
+            );
+            metrics.gauge("shutdown.draining", abandoned as u64).ok();
+            stop.await;
+        }
+        db.shutdown();
+    }
+}
+
+/// Build the client for the storage at `db_settings.dsn`
+fn build_db(metrics: &Arc<StatsdClient>, db_settings: &DbSettings) -> ApiResult<Box<dyn DbClient>> {
+    let db: Box<dyn DbClient> = match StorageType::from_dsn(&db_settings.dsn) {
+        #[cfg(feature = "dynamodb")]
+        StorageType::DynamoDb => {
+            debug!("Using Dynamodb");
+            Box::new(DdbClientImpl::new(metrics.clone(), db_settings)?)
+        }
+        #[cfg(feature = "bigtable")]
+        StorageType::BigTable => {
+            debug!("Using BigTable");
+            Box::new(BigTableClientImpl::new(metrics.clone(), db_settings)?)
+        }
+        #[cfg(all(feature = "bigtable", feature = "dual"))]
+        StorageType::Dual => Box::new(DualClientImpl::new(metrics.clone(), db_settings)?),
+        _ => {
+            debug!("No idea what {:?} is", &db_settings.dsn);
+            return Err(
+                ApiErrorKind::General("Invalid or Unsupported DSN specified".to_owned()).into(),
+            );
+        }
+    };
+    Ok(db)
+}
+
+/// Bind the server's socket. IPv6 sockets only accept IPv6 connections,
+/// unless bound to the wildcard address (`::`) with `dual_stack` (also
+/// accepting IPv4 connections, as IPv4-mapped addresses). Listens with a
+/// `backlog` of pending connections, `nodelay` setting `TCP_NODELAY` (which
+/// accepted connections inherit).
+fn bind_listener(
+    address: SocketAddr,
+    dual_stack: bool,
+    backlog: u32,
+    nodelay: bool,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!(dual_stack && address.ip().is_unspecified()))?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nodelay(nodelay)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// The request headers allowed by CORS: every header the routes read
+const CORS_ALLOWED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-encoding",
+    "content-type",
+    "crypto-key",
+    "encryption",
+    "encryption-key",
+    "idempotency-key",
+    "prefer",
+    "topic",
+    "ttl",
+    "urgency",
+    "x-dry-run",
+    "x-request-id",
+];
+
+/// Build the CORS middleware from the settings. CORS is disabled entirely
+/// (no CORS headers are sent) when `cors_allowed_origins` is empty.
+fn build_cors(settings: &Settings) -> Condition<Cors> {
+    // These have a bad habit of being reset. Specify them explicitly.
+    let mut cors = Cors::default()
+        .allowed_headers(CORS_ALLOWED_HEADERS.iter().copied())
+        .allowed_methods(
+            settings
+                .cors_allowed_methods
+                .iter()
+                .map(|method| method.as_str()),
+        )
+        .max_age(3600);
+    if settings
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &settings.cors_allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    Condition::new(!settings.cors_allowed_origins.is_empty(), cors)
+}
+
+/// Register the routes on `cfg` (the scope of the `url_path_prefix`)
+fn configure_routes(
+    cfg: &mut web::ServiceConfig,
+    app_state: &AppState,
+    metrics: &Arc<StatsdClient>,
+) {
+    // Endpoints
+    cfg.service(
+        web::resource(["/wpush/{api_version}/{token}", "/wpush/{token}"])
+            .wrap(Maintenance::new(
+                app_state.live_settings.clone(),
+                metrics.clone(),
+            ))
+            .route(web::post().to(webpush_route)),
+    )
+    .service(
+        web::resource("/bulk")
+            .wrap(Maintenance::new(
+                app_state.live_settings.clone(),
+                metrics.clone(),
+            ))
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(max_request_bytes(&app_state.settings))
+                    .error_handler(ApiError::from_json_error),
+            )
+            .route(web::post().to(bulk_route)),
+    )
+    .service(
+        web::resource("/m/{message_id}")
+            .route(web::get().to(notification_status_route))
+            .route(web::delete().to(delete_notification_route)),
+    )
+    .service(web::resource("/v1/delivery/{id}").route(web::get().to(delivery_status_route)))
+    .service(
+        web::resource("/v1/{router_type}/{app_id}/registration")
+            .route(web::post().to(register_uaid_route)),
+    )
+    .service(
+        web::resource("/v1/{router_type}/{app_id}/registration/{uaid}")
+            .route(web::put().to(update_token_route))
+            .route(web::get().to(get_channels_route))
+            .route(web::delete().to(unregister_user_route)),
+    )
+    .service(
+        web::resource("/v1/{router_type}/{app_id}/registration/{uaid}/subscription")
+            .route(web::post().to(new_channel_route)),
+    )
+    .service(
+        web::resource("/v1/{router_type}/{app_id}/registration/{uaid}/subscription/{chid}")
+            .route(web::delete().to(unregister_channel_route)),
+    )
+    // Health checks
+    .service(web::resource("/status").route(web::get().to(status_route)))
+    .service(web::resource("/health").route(web::get().to(health_route)))
+    // legacy
+    .service(web::resource("/v1/err").route(web::get().to(log_check)))
+    // standardized
+    .service(web::resource("/__error__").route(web::get().to(log_check)))
+    // Dockerflow
+    .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat_route)))
+    .service(web::resource("/__lbheartbeat__").route(web::get().to(lb_heartbeat_route)))
+    .service(web::resource("/__version__").route(web::get().to(version_route)));
+    if app_state.settings.admin_enabled() {
+        let subscription = web::resource("/v1/admin/subscription/{token}")
+            .route(web::delete().to(delete_subscription_route));
+        #[cfg(feature = "debug")]
+        let subscription =
+            subscription.route(web::get().to(crate::routes::admin::subscription_info_route));
+        cfg.service(subscription)
+            .service(web::resource("/stats").route(web::get().to(stats_route)));
+    }
+    if app_state.prometheus.is_some() {
+        cfg.service(web::resource("/metrics").route(web::get().to(prometheus_route)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::{
+        dev::ServerHandle,
+        http::header,
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse, HttpServer,
+    };
+    use autopush_common::db::{mock::MockDbClient, User};
+    use socket2::SockRef;
+    use uuid::Uuid;
+
+    use super::{bind_listener, build_cors, configure_routes, AppState, CORS_ALLOWED_HEADERS};
+    use crate::settings::{tests::self_signed_cert, Settings};
+
+    #[test]
+    fn binds_ipv6() {
+        let listener = bind_listener("[::1]:0".parse().unwrap(), false, 1024, true).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.ip().is_loopback() && address.is_ipv6());
+        assert!(SockRef::from(&listener).only_v6().unwrap());
+    }
+
+    #[test]
+    fn binds_dual_stack() {
+        let listener = bind_listener("[::]:0".parse().unwrap(), true, 1024, true).unwrap();
+        assert!(!SockRef::from(&listener).only_v6().unwrap());
+        // Accepts IPv4 connections
+        let port = listener.local_addr().unwrap().port();
+        std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        let listener = bind_listener("[::]:0".parse().unwrap(), false, 1024, true).unwrap();
+        assert!(SockRef::from(&listener).only_v6().unwrap());
+        // Only applies to the wildcard address
+        let listener = bind_listener("[::1]:0".parse().unwrap(), true, 1024, true).unwrap();
+        assert!(SockRef::from(&listener).only_v6().unwrap());
+    }
+
+    #[test]
+    fn applies_socket_options() {
+        for nodelay in [true, false] {
+            let listener =
+                bind_listener("127.0.0.1:0".parse().unwrap(), false, 1, nodelay).unwrap();
+            assert_eq!(SockRef::from(&listener).nodelay().unwrap(), nodelay);
+            // Accepted connections inherit it
+            let port = listener.local_addr().unwrap().port();
+            let _client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            listener.set_nonblocking(false).unwrap();
+            let (accepted, _) = listener.accept().unwrap();
+            assert_eq!(accepted.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    /// The state for `settings`, with a subscription whose notifications
+    /// (`stored` of them) are stored. Returns its endpoint's path too.
+    async fn subscribed_state(settings: Settings, stored: usize) -> (AppState, String) {
+        let (uaid, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut db = MockDbClient::new();
+        db.expect_rotating_message_table().returning(|| None);
+        db.expect_get_user().returning(move |_| {
+            Ok(Some(User {
+                uaid,
+                ..Default::default()
+            }))
+        });
+        db.expect_get_channels()
+            .returning(move |_| Ok([channel_id].into_iter().collect()));
+        db.expect_save_message()
+            .times(stored)
+            .returning(|_, _| Ok(()));
+        db.expect_update_last_notified_at().returning(|_, _| Ok(()));
+        let app_state = AppState::for_test(settings, db.into_boxed_arc()).await;
+        let mut ids = uaid.as_bytes().to_vec();
+        ids.extend(channel_id.as_bytes());
+        let token = app_state.fernet.encrypt(&ids);
+        let path = format!("/wpush/v1/{}", token.trim_end_matches('='));
+        (app_state, path)
+    }
+
+    /// An `aes128gcm` notification body: 4096 byte records, no key id and
+    /// some (opaque) ciphertext
+    fn aes128gcm_body() -> Vec<u8> {
+        let mut body = vec![0; 16];
+        body.extend(4096u32.to_be_bytes());
+        body.push(0);
+        body.extend([0; 32]);
+        body
+    }
+
+    /// Serve the routes on a local port, over TLS or h2c as the settings say
+    fn serve(app_state: AppState) -> (SocketAddr, ServerHandle) {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), false, 1024, true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let tls_config = app_state.settings.build_rustls_config().unwrap();
+        let h2c_enabled = app_state.settings.h2c_enabled();
+        let server = HttpServer::new(move || {
+            let metrics = app_state.metrics.clone();
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .configure(|cfg| configure_routes(cfg, &app_state, &metrics))
+        })
+        .workers(1)
+        .disable_signals();
+        let server = match tls_config {
+            Some(tls_config) => server.listen_rustls(listener, tls_config),
+            None if h2c_enabled => server.listen_auto_h2c(listener),
+            None => server.listen(listener),
+        }
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+        (address, handle)
+    }
+
+    /// Notifications are accepted over cleartext HTTP/2 (with prior
+    /// knowledge) as well as HTTP/1.1
+    #[actix_rt::test]
+    async fn serves_h2c() {
+        let settings = Settings {
+            http2_cleartext: true,
+            ..Default::default()
+        };
+        let (app_state, path) = subscribed_state(settings, 2).await;
+        let (address, handle) = serve(app_state);
+
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let response = client
+            .post(format!("http://{address}{path}"))
+            .header("TTL", "60")
+            .header("Content-Encoding", "aes128gcm")
+            .body(aes128gcm_body())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{address}{path}"))
+            .header("TTL", "60")
+            .header("Content-Encoding", "aes128gcm")
+            .body(aes128gcm_body())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        handle.stop(false).await;
+    }
+
+    /// Over TLS, HTTP/2 is negotiated via ALPN
+    #[actix_rt::test]
+    async fn serves_h2_over_tls() {
+        let (cert_file, key_file) = self_signed_cert();
+        let settings = Settings {
+            scheme: "https".to_owned(),
+            tls_cert_path: Some(cert_file.path().to_string_lossy().into_owned()),
+            tls_key_path: Some(key_file.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let (app_state, path) = subscribed_state(settings, 1).await;
+        let (address, handle) = serve(app_state);
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let response = client
+            .post(format!("https://{address}{path}"))
+            .header("TTL", "60")
+            .header("Content-Encoding", "aes128gcm")
+            .body(aes128gcm_body())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+        handle.stop(false).await;
+    }
+
+    #[actix_rt::test]
+    async fn cors() {
+        let settings = Settings {
+            cors_allowed_origins: vec!["https://dash.example.com".to_owned()],
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .wrap(build_cors(&settings))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Preflight
+        let req = TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .insert_header((header::ORIGIN, "https://dash.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                CORS_ALLOWED_HEADERS.join(","),
+            ))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://dash.example.com"
+        );
+        let allowed_headers = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_lowercase();
+        for name in CORS_ALLOWED_HEADERS {
+            assert!(allowed_headers.contains(name), "{name}");
+        }
+
+        // Disallowed origin
+        let req = TestRequest::post()
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn cors_disabled() {
+        let settings = Settings {
+            cors_allowed_origins: vec![],
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .wrap(build_cors(&settings))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .insert_header((header::ORIGIN, "https://dash.example.com"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    /// Routes are served under the prefix only, an empty prefix serving them
+    /// at the root
+    #[actix_rt::test]
+    async fn url_path_prefix() {
+        for (prefix, served, unserved) in [
+            ("/push", "/push/__lbheartbeat__", "/__lbheartbeat__"),
+            ("", "/__lbheartbeat__", "/push/__lbheartbeat__"),
+        ] {
+            let settings = Settings {
+                url_path_prefix: prefix.to_owned(),
+                ..Default::default()
+            };
+            let app_state =
+                AppState::for_test(settings, MockDbClient::new().into_boxed_arc()).await;
+            let metrics = app_state.metrics.clone();
+            let app = init_service(
+                App::new().service(
+                    web::scope(&app_state.settings.url_path_prefix)
+                        .configure(|cfg| configure_routes(cfg, &app_state, &metrics)),
+                ),
+            )
+            .await;
+
+            let req = TestRequest::get().uri(served).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{prefix}");
+            let req = TestRequest::get().uri(unserved).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{prefix}");
+        }
+    }
 }
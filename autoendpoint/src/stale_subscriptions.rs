@@ -0,0 +1,90 @@
+//! Reporting the number of stale subscriptions
+//!
+//! When `stale_subscription_days` is set, a background task periodically
+//! counts the users neither connected nor notified within that many days,
+//! reporting them as the `subscription.stale` gauge. Only the instance
+//! holding its lease (see [crate::lease]) does.
+use std::sync::Arc;
+use std::time::Duration;
+
+use autopush_common::db::{client::DbClient, error::DbResult};
+use autopush_common::util::ms_since_epoch;
+use cadence::{Gauged, StatsdClient};
+
+use crate::lease::Lease;
+
+/// Counting requires a full table scan, so it's kept infrequent
+const REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+pub struct StaleSubscriptions {
+    db: Box<dyn DbClient>,
+    metrics: Arc<StatsdClient>,
+    stale_days: u64,
+    lease: Lease,
+}
+
+impl StaleSubscriptions {
+    pub fn new(db: Box<dyn DbClient>, metrics: Arc<StatsdClient>, stale_days: u64) -> Self {
+        Self {
+            lease: Lease::for_interval(db.clone(), "stale_subscriptions", REPORT_INTERVAL),
+            db,
+            metrics,
+            stale_days,
+        }
+    }
+
+    /// Report the stale subscriptions forever, while holding the lease
+    pub async fn run(self) {
+        loop {
+            match self.lease.acquire().await {
+                Ok(true) => match self.report().await {
+                    Ok(stale) => info!("Counted {} stale user(s)", stale; "stale" => stale),
+                    Err(e) => warn!("Counting the stale users failed: {}", e),
+                },
+                Ok(false) => debug!("Another instance holds the stale subscription lease"),
+                Err(e) => warn!("Acquiring the stale subscription lease failed: {}", e),
+            }
+            actix_rt::time::sleep(REPORT_INTERVAL).await;
+        }
+    }
+
+    /// Count and report the stale users, returning their number
+    async fn report(&self) -> DbResult<u64> {
+        let cutoff = ms_since_epoch().saturating_sub(self.stale_days.saturating_mul(MS_PER_DAY));
+        let stale = self.db.count_stale_users(cutoff).await?;
+        self.metrics.gauge("subscription.stale", stale).ok();
+        Ok(stale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::util::ms_since_epoch;
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::{StaleSubscriptions, MS_PER_DAY};
+
+    #[tokio::test]
+    async fn reports_stale() {
+        let mut db = MockDbClient::new();
+        let expected = ms_since_epoch() - 30 * MS_PER_DAY;
+        db.expect_count_stale_users()
+            .withf(move |cutoff| (expected..expected + 1000).contains(cutoff))
+            .times(1)
+            .return_once(|_| Ok(7));
+        let (rx, sink) = SpyMetricSink::new();
+        let stale = StaleSubscriptions::new(
+            db.into_boxed_arc(),
+            Arc::new(StatsdClient::from_sink("autoendpoint", sink)),
+            30,
+        );
+        assert_eq!(stale.report().await.unwrap(), 7);
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(metric, "autoendpoint.subscription.stale:7|g");
+    }
+}
@@ -0,0 +1,126 @@
+//! In-process counters for the `GET /stats` snapshot
+//!
+//! A quick look at the server's activity for on-call, e.g. with `curl`,
+//! independent of statsd. The counters are atomics updated on the hot path,
+//! counting since the process started.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::error::ApiResult;
+use crate::extractors::routers::RouterType;
+use crate::routers::RouterResponse;
+
+#[derive(Debug, Default)]
+struct BridgeCounters {
+    success: AtomicU64,
+    failure: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    notifications: AtomicU64,
+    /// Indexed like [RouterType::ALL]
+    bridges: [BridgeCounters; RouterType::ALL.len()],
+    messages_stored_total: AtomicU64,
+}
+
+impl Stats {
+    /// Count a notification being routed
+    pub fn record_notification(&self) {
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of routing a notification via `router_type`
+    pub fn record_delivery(&self, router_type: RouterType, result: &ApiResult<RouterResponse>) {
+        let bridge = &self.bridges[Self::bridge_index(router_type)];
+        let counter = match result {
+            Ok(_) => &bridge.success,
+            Err(_) => &bridge.failure,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a notification stored for an offline client
+    pub fn record_stored(&self) {
+        self.messages_stored_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, in_flight_requests: usize) -> StatsSnapshot {
+        let bridges = RouterType::ALL
+            .iter()
+            .map(|router_type| {
+                let bridge = &self.bridges[Self::bridge_index(*router_type)];
+                let counts = BridgeSnapshot {
+                    success: bridge.success.load(Ordering::Relaxed),
+                    failure: bridge.failure.load(Ordering::Relaxed),
+                };
+                (router_type.to_string(), counts)
+            })
+            .collect();
+        StatsSnapshot {
+            notifications: self.notifications.load(Ordering::Relaxed),
+            bridges,
+            in_flight_requests,
+            messages_stored_total: self.messages_stored_total.load(Ordering::Relaxed),
+        }
+    }
+
+    fn bridge_index(router_type: RouterType) -> usize {
+        RouterType::ALL
+            .iter()
+            .position(|known| *known == router_type)
+            .expect("RouterType::ALL is missing a router type")
+    }
+}
+
+/// The counters at a point in time
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub notifications: u64,
+    /// Routing outcomes by bridge (including `webpush`)
+    pub bridges: BTreeMap<String, BridgeSnapshot>,
+    /// The HTTP requests being handled
+    pub in_flight_requests: usize,
+    /// Notifications stored for offline clients since the server started, not
+    /// the messages currently stored: those since delivered aren't subtracted
+    pub messages_stored_total: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BridgeSnapshot {
+    pub success: u64,
+    pub failure: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use crate::error::ApiErrorKind;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::RouterResponse;
+
+    #[test]
+    fn snapshot() {
+        let stats = Stats::default();
+        stats.record_notification();
+        stats.record_notification();
+        stats.record_delivery(
+            RouterType::FCM,
+            &Ok(RouterResponse::success("location".to_owned(), 60)),
+        );
+        stats.record_delivery(RouterType::FCM, &Err(ApiErrorKind::NoSubscription.into()));
+        stats.record_delivery(RouterType::APNS, &Err(ApiErrorKind::NoSubscription.into()));
+        stats.record_stored();
+
+        let snapshot = serde_json::to_value(stats.snapshot(3)).unwrap();
+        assert_eq!(snapshot["notifications"], 2);
+        assert_eq!(snapshot["bridges"]["fcm"]["success"], 1);
+        assert_eq!(snapshot["bridges"]["fcm"]["failure"], 1);
+        assert_eq!(snapshot["bridges"]["apns"]["failure"], 1);
+        assert_eq!(snapshot["bridges"]["webpush"]["success"], 0);
+        assert_eq!(snapshot["in_flight_requests"], 3);
+        assert_eq!(snapshot["messages_stored_total"], 1);
+    }
+}
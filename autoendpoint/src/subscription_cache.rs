@@ -0,0 +1,510 @@
+//! Caching the user records of bridged subscriptions
+//!
+//! Every notification reads its subscription's user record, even for hot
+//! endpoints. When `subscription_cache_ttl_secs` is set, the records of
+//! bridged (e.g. FCM) subscriptions, holding their routing info (the router
+//! type and its registration token), are kept in memory for that long. At
+//! most `subscription_cache_size` are kept, the least recently used being
+//! evicted first. WebPush users aren't cached: their `node_id` changes with
+//! every connection.
+//!
+//! Any change to a user made through [CachedDbClient] (including its removal
+//! after a bridge reports it gone) immediately invalidates its record.
+//! Changes made by other servers are only seen once the record expires, so
+//! before removing a user a bridge reported gone its record is re-read: the
+//! token that failed may be one another server has since replaced.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use autopush_common::db::{
+    client::{DbClient, FetchMessageResponse},
+    error::DbResult,
+    User,
+};
+use autopush_common::notification::Notification;
+use cadence::{CountedExt, StatsdClient};
+use uuid::Uuid;
+
+use crate::extractors::routers::RouterType;
+
+#[derive(Debug)]
+struct Entry {
+    user: User,
+    stored: Instant,
+    /// This entry's position in `CacheInner::lru`
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<Uuid, Entry>,
+    /// The UAIDs ordered by their last use, least recent first
+    lru: BTreeMap<u64, Uuid>,
+    /// Incremented on every use
+    tick: u64,
+}
+
+impl CacheInner {
+    fn remove(&mut self, uaid: &Uuid) {
+        if let Some(entry) = self.entries.remove(uaid) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+}
+
+/// An LRU cache of bridged users' records, keyed by UAID
+pub struct SubscriptionCache {
+    /// How long records are cached, 0 disables the cache
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl SubscriptionCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Whether the user's record may be cached
+    fn cacheable(user: &User) -> bool {
+        matches!(user.router_type.parse::<RouterType>(), Ok(router_type) if router_type != RouterType::WebPush)
+    }
+
+    /// Get the user's record, if cached within the TTL
+    pub fn get(&self, uaid: &Uuid) -> Option<User> {
+        self.get_at(uaid, Instant::now())
+    }
+
+    fn get_at(&self, uaid: &Uuid, now: Instant) -> Option<User> {
+        let mut guard = self.inner.lock().expect("SubscriptionCache lock poisoned");
+        let inner = &mut *guard;
+        let stored = inner.entries.get(uaid)?.stored;
+        if now.saturating_duration_since(stored) >= self.ttl {
+            inner.remove(uaid);
+            return None;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(uaid)?;
+        inner.lru.remove(&entry.last_used);
+        entry.last_used = tick;
+        inner.lru.insert(tick, *uaid);
+        Some(entry.user.clone())
+    }
+
+    /// Cache the user's record (when it's of a bridged subscription)
+    pub fn insert(&self, user: &User) {
+        self.insert_at(user, Instant::now())
+    }
+
+    fn insert_at(&self, user: &User, now: Instant) {
+        if !self.enabled() || !Self::cacheable(user) {
+            return;
+        }
+        let mut guard = self.inner.lock().expect("SubscriptionCache lock poisoned");
+        let inner = &mut *guard;
+        inner.remove(&user.uaid);
+        while inner.entries.len() >= self.capacity {
+            let Some((_, evicted)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&evicted);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(
+            user.uaid,
+            Entry {
+                user: user.clone(),
+                stored: now,
+                last_used: tick,
+            },
+        );
+        inner.lru.insert(tick, user.uaid);
+    }
+
+    /// Forget the user's record
+    pub fn invalidate(&self, uaid: &Uuid) {
+        self.inner
+            .lock()
+            .expect("SubscriptionCache lock poisoned")
+            .remove(uaid);
+    }
+
+    /// The number of records currently cached
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("SubscriptionCache lock poisoned")
+            .entries
+            .len()
+    }
+}
+
+/// Wraps a client, serving user lookups from a [SubscriptionCache] and
+/// invalidating it on every change to a user
+#[derive(Clone)]
+pub struct CachedDbClient {
+    db: Box<dyn DbClient>,
+    cache: Arc<SubscriptionCache>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl CachedDbClient {
+    pub fn new(
+        db: Box<dyn DbClient>,
+        cache: Arc<SubscriptionCache>,
+        metrics: Arc<StatsdClient>,
+    ) -> Self {
+        Self { db, cache, metrics }
+    }
+}
+
+#[async_trait]
+impl DbClient for CachedDbClient {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.cache.invalidate(&user.uaid);
+        self.db.add_user(user).await
+    }
+
+    async fn update_user(&self, user: &User) -> DbResult<bool> {
+        self.cache.invalidate(&user.uaid);
+        self.db.update_user(user).await
+    }
+
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        self.cache.invalidate(&user.uaid);
+        self.db.update_user_router(user, previous_router_type).await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        if let Some(user) = self.cache.get(uaid) {
+            self.metrics.incr("subscription_cache.hit").ok();
+            return Ok(Some(user));
+        }
+        let user = self.db.get_user(uaid).await?;
+        if let Some(user) = user
+            .as_ref()
+            .filter(|user| SubscriptionCache::cacheable(user))
+        {
+            self.metrics.incr("subscription_cache.miss").ok();
+            self.cache.insert(user);
+        }
+        Ok(user)
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        self.cache.invalidate(uaid);
+        self.db.remove_user(uaid).await
+    }
+
+    async fn remove_gone_user(&self, user: &User) -> DbResult<bool> {
+        self.cache.invalidate(&user.uaid);
+        let Some(current) = self.db.get_user(&user.uaid).await? else {
+            return Ok(false);
+        };
+        // `user` may be a fallback route standing in as the primary one
+        let still_routed = (current.router_type == user.router_type
+            && current.router_data == user.router_data)
+            || current.fallback_routes.iter().any(|route| {
+                route.router_type == user.router_type && route.router_data == user.router_data
+            });
+        if !still_routed {
+            debug!("Keeping a gone user whose routing info has since changed");
+            self.metrics.incr("subscription_cache.stale").ok();
+            return Ok(false);
+        }
+        self.db.remove_user(&user.uaid).await?;
+        Ok(true)
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        self.db.add_channel(uaid, channel_id).await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        self.db.get_channels(uaid).await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        self.cache.invalidate(uaid);
+        self.db.remove_channel(uaid, channel_id).await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+    ) -> DbResult<bool> {
+        self.cache.invalidate(uaid);
+        self.db.remove_node_id(uaid, node_id, connected_at).await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.db.save_message(uaid, message).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.db.save_messages(uaid, messages).await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.db.fetch_topic_messages(uaid, limit).await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.db
+            .fetch_timestamp_messages(uaid, timestamp, limit)
+            .await
+    }
+
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        // Doesn't affect routing
+        self.db.update_last_notified_at(uaid, timestamp).await
+    }
+
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        self.db.count_stale_users(cutoff).await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.db.increment_storage(uaid, timestamp).await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        self.db.get_message(uaid, sort_key).await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        self.db.remove_message(uaid, sort_key).await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        self.db.remove_messages(uaid, sort_keys).await
+    }
+
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        self.db.remove_expired_messages(limit).await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        self.db.router_table_exists().await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        self.db.message_table_exists().await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        self.db.health_check().await
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.db.rotating_message_table()
+    }
+
+    fn shutdown(&self) {
+        self.db.shutdown()
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use autopush_common::db::{client::DbClient, mock::MockDbClient, User};
+    use cadence::{SpyMetricSink, StatsdClient};
+    use uuid::Uuid;
+
+    use super::{CachedDbClient, SubscriptionCache};
+
+    fn fcm_user() -> User {
+        User {
+            router_type: "fcm".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// A client caching for 60 seconds, and the metrics it emits
+    fn cached(db: MockDbClient) -> (CachedDbClient, impl Fn() -> Vec<String>) {
+        let (rx, sink) = SpyMetricSink::new();
+        let client = CachedDbClient::new(
+            db.into_boxed_arc(),
+            Arc::new(SubscriptionCache::new(Duration::from_secs(60), 10)),
+            Arc::new(StatsdClient::from_sink("autoendpoint", sink)),
+        );
+        let metrics = move || {
+            rx.try_iter()
+                .map(|m| String::from_utf8(m).unwrap())
+                .collect()
+        };
+        (client, metrics)
+    }
+
+    #[actix_rt::test]
+    async fn hit_and_miss() {
+        let user = fcm_user();
+        let uaid = user.uaid;
+        let mut db = MockDbClient::new();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let (client, metrics) = cached(db);
+
+        for _ in 0..2 {
+            let user = client.get_user(&uaid).await.unwrap().unwrap();
+            assert_eq!(user.uaid, uaid);
+        }
+        assert_eq!(
+            metrics(),
+            [
+                "autoendpoint.subscription_cache.miss:1|c",
+                "autoendpoint.subscription_cache.hit:1|c",
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn webpush_users_not_cached() {
+        let user = User::default();
+        let uaid = user.uaid;
+        let mut db = MockDbClient::new();
+        db.expect_get_user()
+            .times(2)
+            .returning(move |_| Ok(Some(user.clone())));
+        let (client, metrics) = cached(db);
+
+        for _ in 0..2 {
+            client.get_user(&uaid).await.unwrap();
+        }
+        assert!(metrics().is_empty());
+    }
+
+    #[test]
+    fn expires() {
+        let cache = SubscriptionCache::new(Duration::from_secs(60), 10);
+        let user = fcm_user();
+        let now = Instant::now();
+        cache.insert_at(&user, now);
+        assert!(cache
+            .get_at(&user.uaid, now + Duration::from_secs(59))
+            .is_some());
+        assert!(cache
+            .get_at(&user.uaid, now + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = SubscriptionCache::new(Duration::from_secs(60), 2);
+        let (first, second, third) = (fcm_user(), fcm_user(), fcm_user());
+        let now = Instant::now();
+        cache.insert_at(&first, now);
+        cache.insert_at(&second, now);
+        // Using the first makes the second the least recently used
+        assert!(cache.get_at(&first.uaid, now).is_some());
+        cache.insert_at(&third, now);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at(&second.uaid, now).is_none());
+        assert!(cache.get_at(&first.uaid, now).is_some());
+    }
+
+    /// Removing the user (as when a bridge reports it gone) or one of its
+    /// channels invalidates it at once
+    #[actix_rt::test]
+    async fn invalidated_on_delete() {
+        let user = fcm_user();
+        let uaid = user.uaid;
+        let mut db = MockDbClient::new();
+        db.expect_get_user()
+            .times(3)
+            .returning(move |_| Ok(Some(user.clone())));
+        db.expect_remove_user().times(1).return_once(|_| Ok(()));
+        db.expect_remove_channel()
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let (client, _) = cached(db);
+
+        client.get_user(&uaid).await.unwrap();
+        client.remove_user(&uaid).await.unwrap();
+        client.get_user(&uaid).await.unwrap();
+        client.remove_channel(&uaid, &Uuid::new_v4()).await.unwrap();
+        client.get_user(&uaid).await.unwrap();
+    }
+
+    /// A user a bridge reported gone is only removed if the failed token is
+    /// still its current one, not when it was read from a stale record
+    #[actix_rt::test]
+    async fn gone_user_reread() {
+        let user = fcm_user();
+        let uaid = user.uaid;
+        let mut updated = user.clone();
+        updated.router_data = Some(
+            [("token".to_owned(), serde_json::json!("new-token"))]
+                .into_iter()
+                .collect(),
+        );
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        let cached_user = user.clone();
+        db.expect_get_user()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_| Ok(Some(cached_user)));
+        db.expect_get_user()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_| Ok(Some(updated)));
+        let current_user = user.clone();
+        db.expect_get_user()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_| Ok(Some(current_user)));
+        db.expect_remove_user()
+            .with(mockall::predicate::eq(uaid))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let (client, metrics) = cached(db);
+
+        let stale = client.get_user(&uaid).await.unwrap().unwrap();
+        // Another server updated the token
+        assert!(!client.remove_gone_user(&stale).await.unwrap());
+        assert!(client.remove_gone_user(&user).await.unwrap());
+        assert!(metrics().contains(&"autoendpoint.subscription_cache.stale:1|c".to_owned()));
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = SubscriptionCache::new(Duration::ZERO, 10);
+        let user = fcm_user();
+        cache.insert(&user);
+        assert!(cache.get(&user.uaid).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+//! Bounding the total time spent handling a request
+//!
+//! Requests still being handled after `handler_deadline_secs` are answered
+//! with a 504. Their handler's future is dropped, cancelling any storage or
+//! bridge requests it was awaiting.
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use cadence::{CountedExt, StatsdClient};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt as _;
+use futures_util::future::{ok, Ready};
+
+use crate::error::{ApiError, ApiErrorKind};
+
+pub struct RequestTimeout {
+    /// `None` for no timeout
+    timeout: Option<Duration>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl RequestTimeout {
+    pub fn new(timeout: Duration, metrics: Arc<StatsdClient>) -> Self {
+        Self {
+            timeout: (!timeout.is_zero()).then_some(timeout),
+            metrics,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTimeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTimeoutMiddleware {
+            service: Rc::new(service),
+            timeout: self.timeout,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct RequestTimeoutMiddleware<S> {
+    service: Rc<S>,
+    timeout: Option<Duration>,
+    metrics: Arc<StatsdClient>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let Some(timeout) = self.timeout else {
+            return self.service.call(sreq).boxed_local();
+        };
+        // Named by the route rather than the path, which includes tokens. The
+        // request isn't kept for the 504: routing requires its sole ownership.
+        let route = sreq
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let metrics = self.metrics.clone();
+        let handling = self.service.call(sreq);
+        async move {
+            match actix_rt::time::timeout(timeout, handling).await {
+                Ok(res) => res,
+                Err(_) => {
+                    warn!("Request timed out after {:?}", timeout; "route" => %route);
+                    metrics
+                        .incr_with_tags("request.timeout")
+                        .with_tag("route", &route)
+                        .send();
+                    Err(ApiError::from(ApiErrorKind::RequestTimeout).into())
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    use cadence::{SpyMetricSink, StatsdClient};
+
+    use super::RequestTimeout;
+
+    /// Set once a slow handler runs to completion
+    struct Completed(AtomicBool);
+
+    async fn slow(completed: web::Data<Completed>) -> HttpResponse {
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        completed.0.store(true, Ordering::SeqCst);
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn times_out() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("autoendpoint", sink));
+        let completed = web::Data::new(Completed(AtomicBool::new(false)));
+        let app = test::init_service(
+            App::new()
+                .app_data(completed.clone())
+                .wrap(RequestTimeout::new(Duration::from_millis(50), metrics))
+                .route("/wpush/{token}", web::post().to(slow))
+                .route("/quick", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/wpush/abc").to_request();
+        let err = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        let metric = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            metric,
+            "autoendpoint.request.timeout:1|c|#route:/wpush/{token}"
+        );
+        // The handler was cancelled
+        actix_rt::time::sleep(Duration::from_millis(300)).await;
+        assert!(!completed.0.load(Ordering::SeqCst));
+
+        let req = test::TestRequest::get().uri("/quick").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
@@ -1,3 +1,4 @@
+use grpcio::RpcStatusCode;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -23,4 +24,73 @@ pub enum BigTableError {
     /// General Pool builder errors.
     #[error("Pool Error {0}")]
     Pool(String),
+
+    /// Timed out waiting on the Pool (e.g. all connections are checked out)
+    #[error("Pool Timeout {0}")]
+    PoolTimeout(String),
+
+    /// A BigTable request failed
+    #[error("BigTable {0} request failed: {1}")]
+    Rpc(&'static str, #[source] grpcio::Error),
+}
+
+/// The gRPC statuses of failed requests that are safe to retry. Every
+/// request retried by the BigTable client is idempotent: reads, deletes and
+/// writes of cells with explicit timestamps.
+const TRANSIENT_STATUS_CODES: [RpcStatusCode; 3] = [
+    // The connection was reset or the service is briefly unavailable
+    RpcStatusCode::UNAVAILABLE,
+    // The request was aborted due to a concurrency conflict
+    RpcStatusCode::ABORTED,
+    // The request timed out (e.g. waiting on a contended row)
+    RpcStatusCode::DEADLINE_EXCEEDED,
+];
+
+impl BigTableError {
+    /// Whether this is a transient failure that's safe to retry. Logical
+    /// errors (e.g. "not found", invalid arguments) are never retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BigTableError::Rpc(_, grpcio::Error::RpcFailure(status)) => {
+                TRANSIENT_STATUS_CODES.contains(&status.code())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grpcio::{RpcStatus, RpcStatusCode};
+
+    use super::BigTableError;
+
+    fn rpc_error(code: RpcStatusCode) -> BigTableError {
+        BigTableError::Rpc("test", grpcio::Error::RpcFailure(RpcStatus::new(code)))
+    }
+
+    #[test]
+    fn transient_errors() {
+        for code in [
+            RpcStatusCode::UNAVAILABLE,
+            RpcStatusCode::ABORTED,
+            RpcStatusCode::DEADLINE_EXCEEDED,
+        ] {
+            assert!(rpc_error(code).is_transient(), "{:?}", code);
+        }
+        for code in [
+            RpcStatusCode::NOT_FOUND,
+            RpcStatusCode::INVALID_ARGUMENT,
+            RpcStatusCode::ALREADY_EXISTS,
+            RpcStatusCode::FAILED_PRECONDITION,
+            RpcStatusCode::PERMISSION_DENIED,
+            RpcStatusCode::UNKNOWN,
+            RpcStatusCode::INTERNAL,
+        ] {
+            assert!(!rpc_error(code).is_transient(), "{:?}", code);
+        }
+        assert!(!BigTableError::Write("oops".to_owned()).is_transient());
+        assert!(!BigTableError::PoolTimeout("busy".to_owned()).is_transient());
+        assert!(!BigTableError::Rpc("test", grpcio::Error::RemoteStopped).is_transient());
+    }
 }
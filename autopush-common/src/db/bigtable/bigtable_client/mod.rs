@@ -3,16 +3,18 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use again::RetryPolicy;
 use async_trait::async_trait;
-use cadence::StatsdClient;
+use cadence::{CountedExt, StatsdClient, Timed};
+use futures::{Future, StreamExt};
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin::DropRowRangeRequest;
 use google_cloud_rust_raw::bigtable::admin::v2::bigtable_table_admin_grpc::BigtableTableAdminClient;
 use google_cloud_rust_raw::bigtable::v2::bigtable::ReadRowsRequest;
 use google_cloud_rust_raw::bigtable::v2::bigtable_grpc::BigtableClient;
 use google_cloud_rust_raw::bigtable::v2::{bigtable, data};
-use grpcio::Channel;
+use grpcio::{CallOption, Channel};
 use protobuf::RepeatedField;
 use serde_json::{from_str, json};
 use uuid::Uuid;
@@ -23,6 +25,7 @@ use crate::db::{
     DbSettings, Notification, User,
 };
 use crate::notification::STANDARD_NOTIFICATION_PREFIX;
+use crate::util::ms_since_epoch;
 
 use self::row::Row;
 use super::pool::BigTablePool;
@@ -43,6 +46,13 @@ const ROUTER_FAMILY: &str = "router";
 const MESSAGE_FAMILY: &str = "message"; // The default family for messages
 const MESSAGE_TOPIC_FAMILY: &str = "message_topic";
 
+/// The maximum number of rows read or deleted per request by
+/// `remove_messages`
+const MAX_BATCH_DELETE_ROWS: usize = 500;
+
+/// The number of users read per request by `count_stale_users`
+const STALE_USER_SCAN_BATCH: usize = 1000;
+
 /// Semi convenience wrapper to ensure that the UAID is formatted and displayed consistently.
 // TODO:Should we create something similar for ChannelID?
 struct Uaid(Uuid);
@@ -64,7 +74,7 @@ impl From<Uaid> for String {
 pub struct BigTableClientImpl {
     pub(crate) settings: BigTableDbSettings,
     /// Metrics client
-    _metrics: Arc<StatsdClient>,
+    metrics: Arc<StatsdClient>,
     /// Connection Channel (used for alternate calls)
     pool: BigTablePool,
 }
@@ -98,6 +108,32 @@ fn as_key(uaid: &Uuid, channel_id: Option<&Uuid>, chidmessageid: Option<&str>) -
     parts.join("#")
 }
 
+/// The row key of a message, from its sort key (`chidmessageid`)
+fn message_row_key(uaid: &Uuid, chidmessageid: &str) -> DbResult<RowKey> {
+    // parse the sort_key to get the message's CHID
+    let parts: Vec<&str> = chidmessageid.split(':').collect();
+    if parts.len() < 3 {
+        return Err(DbError::General(format!(
+            "Invalid sort_key detected: {}",
+            chidmessageid
+        )));
+    }
+    let family = match parts[0] {
+        "01" => MESSAGE_TOPIC_FAMILY,
+        "02" => MESSAGE_FAMILY,
+        _ => "",
+    };
+    if family.is_empty() {
+        return Err(DbError::General(format!(
+            "Invalid sort_key detected: {}",
+            chidmessageid
+        )));
+    }
+    let chid = Uuid::parse_str(parts[1])
+        .map_err(|_| error::BigTableError::Admin("Invalid SortKey component".to_string()))?;
+    Ok(as_key(uaid, Some(&chid), Some(chidmessageid)))
+}
+
 /// Connect to a BigTable storage model.
 ///
 /// BigTable is available via the Google Console, and is a schema less storage system.
@@ -126,12 +162,12 @@ impl BigTableClientImpl {
     pub fn new(metrics: Arc<StatsdClient>, settings: &DbSettings) -> DbResult<Self> {
         // let env = Arc::new(EnvBuilder::new().build());
         debug!("🏊 BT Pool new");
-        let db_settings = BigTableDbSettings::try_from(settings.db_settings.as_ref())?;
+        let db_settings = BigTableDbSettings::try_from(settings.prefixed_db_settings().as_ref())?;
         debug!("🉑 {:#?}", db_settings);
         let pool = BigTablePool::new(settings, &metrics)?;
         Ok(Self {
             settings: db_settings,
-            _metrics: metrics,
+            metrics,
             pool,
         })
     }
@@ -168,17 +204,69 @@ impl BigTableClientImpl {
         limit: Option<usize>,
     ) -> Result<BTreeMap<RowKey, row::Row>, error::BigTableError> {
         let bigtable = self.pool.get().await?;
-        let resp = bigtable
-            .conn
-            .read_rows(&req)
-            .map_err(|e| error::BigTableError::Read(e.to_string()))?;
-        merge::RowMerger::process_chunks(resp, timestamp_filter, limit).await
+        let (db, req) = (&*bigtable, &req);
+        self.retry("read_rows", || async move {
+            let resp = db
+                .conn
+                .read_rows_opt(req, db.call_option())
+                .map_err(|e| error::BigTableError::Rpc("read_rows", e))?;
+            merge::RowMerger::process_chunks(resp, timestamp_filter, limit).await
+        })
+        .await
+    }
+
+    /// Perform a BigTable request, retrying it with exponential backoff (up
+    /// to `database_max_retries` times) while it fails with a transient
+    /// error. The final failure is returned as is.
+    async fn retry<T, F, Fut>(
+        &self,
+        op: &'static str,
+        request: F,
+    ) -> Result<T, error::BigTableError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, error::BigTableError>>,
+    {
+        let metrics = self.metrics.clone();
+        RetryPolicy::exponential(Duration::from_millis(50))
+            .with_jitter(true)
+            .with_max_retries(self.settings.database_max_retries)
+            .retry_if(request, move |e: &error::BigTableError| {
+                let transient = e.is_transient();
+                if transient {
+                    debug!("🉑 retryable {} {:?}", op, e);
+                    metrics
+                        .incr_with_tags("database.retry")
+                        .with_tag("error", op)
+                        .send();
+                }
+                transient
+            })
+            .await
     }
 
     /// write a given row.
     ///
     /// there's also `.mutate_rows` which I presume allows multiple.
     async fn write_row(&self, row: row::Row) -> Result<(), error::BigTableError> {
+        // Do the actual commit.
+        // fails with `cannot execute `LocalPool` executor from within another executor: EnterError`
+        self.mutate_row(self.write_row_request(row, false)?).await
+    }
+
+    /// Replace a given row: drop all of its existing cells and write the
+    /// row's. Both are committed in a single request, so atomically.
+    async fn replace_row(&self, row: row::Row) -> Result<(), error::BigTableError> {
+        self.mutate_row(self.write_row_request(row, true)?).await
+    }
+
+    /// The request writing a given row, first dropping all of its existing
+    /// cells when `replace`
+    fn write_row_request(
+        &self,
+        row: row::Row,
+        replace: bool,
+    ) -> Result<bigtable::MutateRowRequest, error::BigTableError> {
         let mut req = bigtable::MutateRowRequest::default();
 
         // compile the mutations.
@@ -188,6 +276,12 @@ impl BigTableClientImpl {
         let mut mutations = protobuf::RepeatedField::default();
         req.set_table_name(self.settings.table_name.clone());
         req.set_row_key(row.row_key.into_bytes());
+        if replace {
+            // Mutations are applied in order
+            let mut mutation = data::Mutation::default();
+            mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+            mutations.push(mutation);
+        }
         for (_family, cells) in row.cells {
             for cell in cells {
                 let mut mutation = data::Mutation::default();
@@ -208,17 +302,25 @@ impl BigTableClientImpl {
             }
         }
         req.set_mutations(mutations);
+        Ok(req)
+    }
 
-        // Do the actual commit.
-        // fails with `cannot execute `LocalPool` executor from within another executor: EnterError`
+    /// Commit the mutations of a single row
+    async fn mutate_row(
+        &self,
+        req: bigtable::MutateRowRequest,
+    ) -> Result<(), error::BigTableError> {
         let bigtable = self.pool.get().await?;
-        let _resp = bigtable
-            .conn
-            .mutate_row_async(&req)
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?
-            .await
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?;
-        Ok(())
+        let (db, req) = (&*bigtable, &req);
+        self.retry("mutate_row", || async move {
+            db.conn
+                .mutate_row_async_opt(req, db.call_option())
+                .map_err(|e| error::BigTableError::Rpc("mutate_row", e))?
+                .await
+                .map_err(|e| error::BigTableError::Rpc("mutate_row", e))?;
+            Ok(())
+        })
+        .await
     }
 
     /// Delete all cell data from the specified columns with the optional time range.
@@ -250,14 +352,7 @@ impl BigTableClientImpl {
 
         req.set_mutations(mutations);
 
-        let bigtable = self.pool.get().await?;
-        let _resp = bigtable
-            .conn
-            .mutate_row_async(&req)
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?
-            .await
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?;
-        Ok(())
+        self.mutate_row(req).await
     }
 
     /// Delete all the cells for the given row. NOTE: This will drop the row.
@@ -271,14 +366,52 @@ impl BigTableClientImpl {
         mutations.push(mutation);
         req.set_mutations(mutations);
 
+        self.mutate_row(req).await
+    }
+
+    /// Delete all the cells for each of the given rows in a single request.
+    /// NOTE: This will drop the rows.
+    async fn delete_row_batch(&self, row_keys: &[RowKey]) -> Result<(), error::BigTableError> {
+        let mut req = bigtable::MutateRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        let mut entries = protobuf::RepeatedField::default();
+        for row_key in row_keys {
+            let mut entry = bigtable::MutateRowsRequest_Entry::default();
+            entry.set_row_key(row_key.to_owned().into_bytes());
+            let mut mutations = protobuf::RepeatedField::default();
+            let mut mutation = data::Mutation::default();
+            mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+            mutations.push(mutation);
+            entry.set_mutations(mutations);
+            entries.push(entry);
+        }
+        req.set_entries(entries);
+
         let bigtable = self.pool.get().await?;
-        let _resp = bigtable
-            .conn
-            .mutate_row_async(&req)
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?
-            .await
-            .map_err(|e| error::BigTableError::Write(e.to_string()))?;
-        Ok(())
+        let (db, req) = (&*bigtable, &req);
+        self.retry("mutate_rows", || async move {
+            let mut stream = db
+                .conn
+                .mutate_rows_opt(req, db.call_option())
+                .map_err(|e| error::BigTableError::Rpc("mutate_rows", e))?;
+            // Each row's mutations are applied (or fail) separately
+            while let Some(resp) = stream.next().await {
+                let resp = resp.map_err(|e| error::BigTableError::Rpc("mutate_rows", e))?;
+                if let Some(failed) = resp
+                    .get_entries()
+                    .iter()
+                    .find(|entry| entry.get_status().get_code() != 0)
+                {
+                    return Err(error::BigTableError::Write(format!(
+                        "Could not delete row {}: {}",
+                        failed.get_index(),
+                        failed.get_status().get_message()
+                    )));
+                }
+            }
+            Ok(())
+        })
+        .await
     }
 
     /// This uses the admin interface to drop row ranges.
@@ -291,17 +424,22 @@ impl BigTableClientImpl {
         req.set_name(self.settings.table_name.clone());
         req.set_row_key_prefix(row_key.as_bytes().to_vec());
         req.set_delete_all_data_from_table(true);
-        admin
-            .drop_row_range_async(&req)
-            .map_err(|e| {
-                error!("{:?}", e);
-                error::BigTableError::Admin("Could not delete data from table (0)".to_owned())
-            })?
-            .await
-            .map_err(|e| {
-                error!("post await: {:?}", e);
-                error::BigTableError::Admin("Could not delete data from table (1)".to_owned())
-            })?;
+        let (admin, req) = (&admin, &req);
+        let timeout = self.settings.statement_timeout();
+        self.retry("drop_row_range", || async move {
+            admin
+                .drop_row_range_async_opt(req, call_option(timeout))
+                .map_err(|e| {
+                    error!("{:?}", e);
+                    error::BigTableError::Rpc("drop_row_range", e)
+                })?
+                .await
+                .map_err(|e| {
+                    error!("post await: {:?}", e);
+                    error::BigTableError::Rpc("drop_row_range", e)
+                })
+        })
+        .await?;
 
         Ok(true)
     }
@@ -357,6 +495,9 @@ impl BigTableClientImpl {
                 if let Some(cell) = row.take_cell("timestamp") {
                     notif.timestamp = to_u64(cell.value, "timestamp")?;
                 }
+                if let Some(cell) = row.take_cell("nack_count") {
+                    notif.nack_count = to_u64(cell.value, "nack_count")? as u32;
+                }
                 if let Some(cell) = row.take_cell("headers") {
                     notif.headers = Some(
                         serde_json::from_str::<HashMap<String, String>>(&to_string(
@@ -381,18 +522,34 @@ impl BigTableClientImpl {
     }
 }
 
+/// The options of a request, cancelling it after `timeout` (if any)
+fn call_option(timeout: Option<Duration>) -> CallOption {
+    match timeout {
+        Some(timeout) => CallOption::default().timeout(timeout),
+        None => CallOption::default(),
+    }
+}
+
 #[derive(Clone)]
 pub struct BigtableDb {
     pub(super) conn: BigtableClient,
+    /// The `database_statement_timeout_ms` applied to each request
+    pub(super) statement_timeout: Option<Duration>,
 }
 
 impl BigtableDb {
-    pub fn new(channel: Channel) -> Self {
+    pub fn new(channel: Channel, statement_timeout: Option<Duration>) -> Self {
         Self {
             conn: BigtableClient::new(channel),
+            statement_timeout,
         }
     }
 
+    /// The options of a request on this connection
+    pub(super) fn call_option(&self) -> CallOption {
+        call_option(self.statement_timeout)
+    }
+
     /// Perform a simple connectivity check.
     pub fn health_check(&mut self, table_name: &str) -> DbResult<bool> {
         let mut req = bigtable::ReadRowsRequest::default();
@@ -405,7 +562,7 @@ impl BigtableDb {
 
         let _ = self
             .conn
-            .read_rows(&req)
+            .read_rows_opt(&req, self.call_option())
             .map_err(|e| DbError::General(format!("BigTable connectivity error: {:?}", e)))?;
 
         Ok(true)
@@ -444,6 +601,13 @@ impl DbClient for BigTableClientImpl {
                 ..Default::default()
             });
         };
+        // Always written, so re-registering without any clears them
+        cells.push(cell::Cell {
+            family: ROUTER_FAMILY.to_owned(),
+            qualifier: "fallback_routes".to_owned(),
+            value: json!(user.fallback_routes).to_string().into_bytes(),
+            ..Default::default()
+        });
         if let Some(last_connect) = user.last_connect {
             cells.push(cell::Cell {
                 family: ROUTER_FAMILY.to_owned(),
@@ -460,6 +624,22 @@ impl DbClient for BigTableClientImpl {
                 ..Default::default()
             });
         };
+        if let Some(last_connected_at) = user.last_connected_at {
+            cells.push(cell::Cell {
+                family: ROUTER_FAMILY.to_owned(),
+                qualifier: "last_connected_at".to_owned(),
+                value: last_connected_at.to_be_bytes().to_vec(),
+                ..Default::default()
+            });
+        };
+        if let Some(last_notified_at) = user.last_notified_at {
+            cells.push(cell::Cell {
+                family: ROUTER_FAMILY.to_owned(),
+                qualifier: "last_notified_at".to_owned(),
+                value: last_notified_at.to_be_bytes().to_vec(),
+                ..Default::default()
+            });
+        };
         if let Some(node_id) = &user.node_id {
             cells.push(cell::Cell {
                 family: ROUTER_FAMILY.to_owned(),
@@ -498,6 +678,10 @@ impl DbClient for BigTableClientImpl {
         Ok(true)
     }
 
+    async fn update_user_router(&self, user: &User, _previous_router_type: &str) -> DbResult<bool> {
+        self.update_user(user).await
+    }
+
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         let key = uaid.as_simple().to_string();
         let mut result = User {
@@ -603,11 +787,123 @@ impl DbClient for BigTableClientImpl {
                 }
             }
 
+            if let Some(cell) = record.take_cell("last_connected_at") {
+                result.last_connected_at = Some(to_u64(cell.value, "last_connected_at")?);
+            }
+            if let Some(cell) = record.take_cell("last_notified_at") {
+                result.last_notified_at = Some(to_u64(cell.value, "last_notified_at")?);
+            }
+            if let Some(cell) = record.take_cell("fallback_routes") {
+                result.fallback_routes =
+                    from_str(&String::from_utf8_lossy(&cell.value)).map_err(|e| {
+                        DbError::Serialization(format!(
+                            "Could not deserialize fallback_routes: {:?}",
+                            e
+                        ))
+                    })?;
+            }
+
             return Ok(Some(result));
         }
         Ok(None)
     }
 
+    /// Only written to users with a `router_type`, so removed users aren't
+    /// recreated
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        let mut req = bigtable::CheckAndMutateRowRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_row_key(as_key(uaid, None, None).into_bytes());
+        req.set_predicate_filter({
+            let mut column_filter = data::RowFilter::default();
+            column_filter.set_column_qualifier_regex_filter("router_type".as_bytes().to_vec());
+            column_filter
+        });
+        let mut set_cell = data::Mutation_SetCell::default();
+        set_cell.set_family_name(ROUTER_FAMILY.to_owned());
+        set_cell.set_column_qualifier("last_notified_at".as_bytes().to_vec());
+        set_cell.set_value(timestamp.to_be_bytes().to_vec());
+        // Milli bounded, as in `write_row`
+        set_cell.set_timestamp_micros((ms_since_epoch() * 1000) as i64);
+        let mut mutation = data::Mutation::default();
+        mutation.set_set_cell(set_cell);
+        req.set_true_mutations(RepeatedField::from_vec(vec![mutation]));
+
+        let bigtable = self.pool.get().await?;
+        let (db, req) = (&*bigtable, &req);
+        self.retry("check_and_mutate_row", || async move {
+            db.conn
+                .check_and_mutate_row_async_opt(req, db.call_option())
+                .map_err(|e| error::BigTableError::Rpc("check_and_mutate_row", e))?
+                .await
+                .map_err(|e| error::BigTableError::Rpc("check_and_mutate_row", e))?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Scans the router rows (of the `uaid` row keys) in batches
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        let filter = {
+            let mut regex_filter = data::RowFilter::default();
+            regex_filter.set_row_key_regex_filter("^[^#]+$".as_bytes().to_vec());
+            let mut column_filter = data::RowFilter::default();
+            column_filter.set_column_qualifier_regex_filter(
+                "^(connected_at|last_connected_at|last_notified_at)$"
+                    .as_bytes()
+                    .to_vec(),
+            );
+            let mut latest_filter = data::RowFilter::default();
+            latest_filter.set_cells_per_column_limit_filter(1);
+
+            let mut chain = data::RowFilter_Chain::default();
+            let mut repeat_field = RepeatedField::default();
+            repeat_field.push(regex_filter);
+            repeat_field.push(column_filter);
+            repeat_field.push(latest_filter);
+            chain.set_filters(repeat_field);
+
+            let mut filter = data::RowFilter::default();
+            filter.set_chain(chain);
+            filter
+        };
+
+        let mut stale = 0;
+        let mut start_key: Option<RowKey> = None;
+        loop {
+            let mut req = ReadRowsRequest::default();
+            req.set_table_name(self.settings.table_name.clone());
+            req.set_filter(filter.clone());
+            req.set_rows_limit(STALE_USER_SCAN_BATCH as i64);
+            if let Some(start_key) = start_key.take() {
+                let mut range = data::RowRange::default();
+                range.set_start_key_open(start_key.into_bytes());
+                let mut row_set = data::RowSet::default();
+                row_set.set_row_ranges(RepeatedField::from_vec(vec![range]));
+                req.set_rows(row_set);
+            }
+
+            let rows = self.read_rows(req, None, None).await?;
+            let scanned = rows.len();
+            start_key = rows.keys().next_back().cloned();
+            for (_key, mut row) in rows {
+                let mut last_active = 0;
+                for column in ["connected_at", "last_connected_at", "last_notified_at"] {
+                    if let Some(cell) = row.take_cell(column) {
+                        last_active = last_active.max(to_u64(cell.value, column)?);
+                    }
+                }
+                if last_active < cutoff {
+                    stale += 1;
+                }
+            }
+            if scanned < STALE_USER_SCAN_BATCH {
+                return Ok(stale);
+            }
+        }
+    }
+
     async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
         self.delete_rows(&as_key(uaid, None, None)).await?;
         Ok(())
@@ -743,6 +1039,10 @@ impl DbClient for BigTableClientImpl {
 
         let mut cells: Vec<cell::Cell> = Vec::new();
 
+        // Topic messages share a row key per (channel, topic). Cells are
+        // versioned by their expiry, so any prior message is dropped to
+        // ensure the newest message fully replaces it.
+        let replace = message.topic.is_some();
         let family = if message.topic.is_some() {
             // Set the correct flag so we know how to read this row later.
             cells.push(cell::Cell {
@@ -834,7 +1134,20 @@ impl DbClient for BigTableClientImpl {
                 ..Default::default()
             });
         }
+        if message.nack_count > 0 {
+            cells.push(cell::Cell {
+                family: family.to_owned(),
+                qualifier: "nack_count".to_owned(),
+                value: u64::from(message.nack_count).to_be_bytes().to_vec(),
+                timestamp: ttl,
+                ..Default::default()
+            });
+        }
         row.add_cells(family, cells);
+        if replace {
+            trace!("🉑 Replacing any existing topic message {}", &row.row_key);
+            return self.replace_row(row).await.map_err(|e| e.into());
+        }
         trace!("🉑 Adding row");
         self.write_row(row).await.map_err(|e| e.into())
     }
@@ -888,6 +1201,20 @@ impl DbClient for BigTableClientImpl {
         self.write_row(row).await.map_err(|e| e.into())
     }
 
+    /// Read a single notification from storage, if still stored.
+    async fn get_message(
+        &self,
+        uaid: &Uuid,
+        chidmessageid: &str,
+    ) -> DbResult<Option<Notification>> {
+        let row_key = message_row_key(uaid, chidmessageid)?;
+        let Some(row) = self.read_row(&row_key, None).await? else {
+            return Ok(None);
+        };
+        let rows = BTreeMap::from([(row_key, row)]);
+        Ok(self.rows_to_notifications(rows, None)?.messages.pop())
+    }
+
     /// Delete the notification from storage.
     async fn remove_message(&self, uaid: &Uuid, chidmessageid: &str) -> DbResult<()> {
         trace!(
@@ -895,30 +1222,118 @@ impl DbClient for BigTableClientImpl {
             uaid.to_string(),
             chidmessageid
         );
-        // parse the sort_key to get the message's CHID
-        let parts: Vec<&str> = chidmessageid.split(':').collect();
-        if parts.len() < 3 {
-            return Err(DbError::General(format!(
-                "Invalid sort_key detected: {}",
-                chidmessageid
-            )));
+        let row_key = message_row_key(uaid, chidmessageid)?;
+        debug!("🉑🔥 Deleting message {}", &row_key);
+        self.delete_row(&row_key).await.map_err(|e| e.into())
+    }
+
+    /// Delete the notifications from storage, in batches: the rows that
+    /// still exist are read, then deleted in a single request.
+    ///
+    /// Bigtable only applies mutations atomically per row, so a failed batch
+    /// may have deleted some of its messages.
+    async fn remove_messages(&self, uaid: &Uuid, chidmessageids: &[String]) -> DbResult<usize> {
+        let start = Instant::now();
+        let row_keys = chidmessageids
+            .iter()
+            .map(|chidmessageid| message_row_key(uaid, chidmessageid))
+            .collect::<DbResult<Vec<_>>>()?;
+
+        let mut removed = 0;
+        for chunk in row_keys.chunks(MAX_BATCH_DELETE_ROWS) {
+            let mut keys = RepeatedField::default();
+            for row_key in chunk {
+                keys.push(row_key.as_bytes().to_vec());
+            }
+            let mut row_set = data::RowSet::default();
+            row_set.set_row_keys(keys);
+            // Only the row keys are needed
+            let filter = {
+                let mut limit_filter = data::RowFilter::default();
+                limit_filter.set_cells_per_row_limit_filter(1);
+                let mut strip_filter = data::RowFilter::default();
+                strip_filter.set_strip_value_transformer(true);
+
+                let mut chain = data::RowFilter_Chain::default();
+                let mut repeat_field = RepeatedField::default();
+                repeat_field.push(limit_filter);
+                repeat_field.push(strip_filter);
+                chain.set_filters(repeat_field);
+
+                let mut filter = data::RowFilter::default();
+                filter.set_chain(chain);
+                filter
+            };
+            let mut req = ReadRowsRequest::default();
+            req.set_table_name(self.settings.table_name.clone());
+            req.set_rows(row_set);
+            req.set_filter(filter);
+
+            let existing: Vec<RowKey> =
+                self.read_rows(req, None, None).await?.into_keys().collect();
+            if existing.is_empty() {
+                continue;
+            }
+            debug!("🉑🔥 Deleting {} messages", existing.len());
+            self.delete_row_batch(&existing).await?;
+            removed += existing.len();
         }
-        let family = match parts[0] {
-            "01" => MESSAGE_TOPIC_FAMILY,
-            "02" => MESSAGE_FAMILY,
-            _ => "",
+        self.metrics
+            .time_with_tags("store.batch_delete", start.elapsed())
+            .send();
+        Ok(removed)
+    }
+
+    /// Delete messages whose cells have expired.
+    ///
+    /// Message cells are timestamped with their expiry, so the family GC
+    /// policies eventually drop them, but Bigtable runs GC lazily (it may
+    /// take up to a week). Look for message rows with a `ttl` cell
+    /// timestamped before now and drop those rows explicitly.
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        let now_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| DbError::General(e.to_string()))?
+            .as_millis() as i64
+            * 1000;
+        let filter = {
+            // Message rows (both standard and topic) have a sort key suffix
+            let mut regex_filter = data::RowFilter::default();
+            regex_filter.set_row_key_regex_filter("^[^#]+#[^#]+#0[12]:.+".as_bytes().to_vec());
+            let mut column_filter = data::RowFilter::default();
+            column_filter.set_column_qualifier_regex_filter("ttl".as_bytes().to_vec());
+            let mut range = data::TimestampRange::default();
+            range.set_end_timestamp_micros(now_micros);
+            let mut time_filter = data::RowFilter::default();
+            time_filter.set_timestamp_range_filter(range);
+            let mut strip_filter = data::RowFilter::default();
+            strip_filter.set_strip_value_transformer(true);
+
+            let mut chain = data::RowFilter_Chain::default();
+            let mut repeat_field = RepeatedField::default();
+            repeat_field.push(regex_filter);
+            repeat_field.push(column_filter);
+            repeat_field.push(time_filter);
+            repeat_field.push(strip_filter);
+            chain.set_filters(repeat_field);
+
+            let mut filter = data::RowFilter::default();
+            filter.set_chain(chain);
+            filter
         };
-        if family.is_empty() {
-            return Err(DbError::General(format!(
-                "Invalid sort_key detected: {}",
-                chidmessageid
-            )));
+        let mut req = ReadRowsRequest::default();
+        req.set_table_name(self.settings.table_name.clone());
+        req.set_filter(filter);
+        if limit > 0 {
+            req.set_rows_limit(limit as i64);
         }
-        let chid = Uuid::parse_str(parts[1])
-            .map_err(|_| error::BigTableError::Admin("Invalid SortKey component".to_string()))?;
-        let row_key = as_key(uaid, Some(&chid), Some(chidmessageid));
-        debug!("🉑🔥 Deleting message {}", &row_key);
-        self.delete_row(&row_key).await.map_err(|e| e.into())
+
+        let rows = self.read_rows(req, None, None).await?;
+        for row_key in rows.keys() {
+            trace!("🉑🔥 Deleting expired message {}", row_key);
+            self.delete_row(row_key).await?;
+        }
+        Ok(rows.len())
     }
 
     /// Return `limit` pending messages from storage. `limit=0` for all messages.
@@ -1036,6 +1451,10 @@ impl DbClient for BigTableClientImpl {
         None
     }
 
+    fn shutdown(&self) {
+        self.pool.close()
+    }
+
     fn box_clone(&self) -> Box<dyn DbClient> {
         Box::new(self.clone())
     }
@@ -1054,7 +1473,7 @@ mod tests {
     use super::*;
     use cadence::StatsdClient;
 
-    use crate::db::DbSettings;
+    use crate::db::{DbSettings, FallbackRoute};
 
     const TEST_USER: &str = "DEADBEEF-0000-0000-0000-0123456789AB";
     const TEST_CHID: &str = "DECAFBAD-0000-0000-0000-0123456789AB";
@@ -1080,6 +1499,7 @@ mod tests {
             dsn: Some(env_dsn),
             db_settings: json!({"table_name":"projects/test/instances/test/tables/autopush"})
                 .to_string(),
+            ..Default::default()
         };
 
         let metrics = Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build());
@@ -1096,6 +1516,40 @@ mod tests {
         assert_eq!(k, "deadbeef0000000000000123456789ab#decafbad0000000000000123456789ab#01:decafbad-0000-0000-0000-0123456789ab:Inbox");
     }
 
+    /// A replaced row's existing cells are dropped in the same request that
+    /// writes its new ones
+    #[test]
+    fn replace_row_request() {
+        let client = new_client().unwrap();
+        let row = || {
+            let mut row = Row {
+                row_key: "row".to_owned(),
+                ..Default::default()
+            };
+            row.add_cells(
+                MESSAGE_FAMILY,
+                vec![cell::Cell {
+                    family: MESSAGE_FAMILY.to_owned(),
+                    qualifier: "ttl".to_owned(),
+                    value: vec![1],
+                    timestamp: SystemTime::now(),
+                    ..Default::default()
+                }],
+            );
+            row
+        };
+
+        let req = client.write_row_request(row(), true).unwrap();
+        let mutations = req.get_mutations();
+        assert_eq!(mutations.len(), 2);
+        assert!(mutations[0].has_delete_from_row());
+        assert!(mutations[1].has_set_cell());
+
+        let req = client.write_row_request(row(), false).unwrap();
+        assert_eq!(req.get_mutations().len(), 1);
+        assert!(req.get_mutations()[0].has_set_cell());
+    }
+
     /// run a gauntlet of testing. These are a bit linear because they need
     /// to run in sequence.
     #[actix_rt::test]
@@ -1144,6 +1598,22 @@ mod tests {
             client.get_user(&uaid).await.unwrap().unwrap().connected_at
         );
 
+        // are the fallback routes stored, and cleared?
+        let fallback_routes = vec![FallbackRoute {
+            router_type: "apns".to_owned(),
+            router_data: None,
+        }];
+        let with_fallback = User {
+            fallback_routes: fallback_routes.clone(),
+            ..updated.clone()
+        };
+        client.update_user(&with_fallback).await.unwrap();
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.fallback_routes, fallback_routes);
+        client.update_user(&updated).await.unwrap();
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert!(fetched.fallback_routes.is_empty());
+
         let test_data = "An_encrypted_pile_of_crap".to_owned();
         let timestamp = now();
         let sort_key = now();
@@ -1185,6 +1655,33 @@ mod tests {
             .unwrap();
         assert_eq!(fetched.messages.len(), 0);
 
+        // Do topic messages replace prior ones with the same topic?
+        for version in ["topic1", "topic2", "topic3"] {
+            let topic_notification = crate::db::Notification {
+                channel_id: chid,
+                version: version.to_owned(),
+                ttl: 300,
+                timestamp,
+                topic: Some("Inbox".to_owned()),
+                data: Some(test_data.clone()),
+                ..Default::default()
+            };
+            assert!(client.save_message(&uaid, topic_notification).await.is_ok());
+        }
+        let fetched = client.fetch_topic_messages(&uaid, 999).await.unwrap();
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].version, "topic3".to_owned());
+        assert!(client
+            .remove_message(&uaid, &format!("01:{}:Inbox", chid.as_hyphenated()))
+            .await
+            .is_ok());
+        assert!(client
+            .fetch_topic_messages(&uaid, 999)
+            .await
+            .unwrap()
+            .messages
+            .is_empty());
+
         // can we clean up our toys?
         assert!(client
             .remove_message(&uaid, &format!("02:{}:{}", chid.as_simple(), sort_key))
@@ -1210,6 +1707,109 @@ mod tests {
         assert!(client.get_user(&uaid).await.unwrap().is_none());
     }
 
+    #[actix_rt::test]
+    async fn remove_expired_messages() {
+        let client = new_client().unwrap();
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::parse_str(TEST_CHID).unwrap();
+        let timestamp = now();
+
+        for (offset, version, ttl) in [(0, "expired", 0), (1, "live", 300)] {
+            let notification = crate::db::Notification {
+                channel_id: chid,
+                version: version.to_owned(),
+                ttl,
+                timestamp,
+                sortkey_timestamp: Some(timestamp + offset),
+                ..Default::default()
+            };
+            let row_key = as_key(&uaid, Some(&chid), Some(&notification.chidmessageid()));
+            client.save_message(&uaid, notification).await.unwrap();
+            assert!(client.read_row(&row_key, None).await.unwrap().is_some());
+        }
+        // Let the zero TTL message expire
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(client.remove_expired_messages(0).await.unwrap() >= 1);
+        let remaining = client
+            .fetch_timestamp_messages(&uaid, None, 999)
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].version, "live".to_owned());
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn remove_messages() {
+        let client = new_client().unwrap();
+        let uaid = Uuid::new_v4();
+        let chid = Uuid::parse_str(TEST_CHID).unwrap();
+
+        let mut sort_keys = vec![];
+        for topic in ["topic1", "topic2", "topic3"] {
+            let notification = crate::db::Notification {
+                channel_id: chid,
+                version: topic.to_owned(),
+                ttl: 300,
+                timestamp: now(),
+                topic: Some(topic.to_owned()),
+                ..Default::default()
+            };
+            sort_keys.push(notification.chidmessageid());
+            client.save_message(&uaid, notification).await.unwrap();
+        }
+        // Already gone
+        client.remove_message(&uaid, &sort_keys[0]).await.unwrap();
+        sort_keys.push(format!("01:{}:topic4", chid.as_hyphenated()));
+
+        assert_eq!(client.remove_messages(&uaid, &sort_keys).await.unwrap(), 2);
+        assert!(client
+            .fetch_topic_messages(&uaid, 999)
+            .await
+            .unwrap()
+            .messages
+            .is_empty());
+        assert_eq!(client.remove_messages(&uaid, &sort_keys).await.unwrap(), 0);
+
+        client.remove_user(&uaid).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn activity_timestamps() {
+        let client = new_client().unwrap();
+        let uaid = Uuid::new_v4();
+        let connected_at = ms_since_epoch();
+        let user = User {
+            uaid,
+            connected_at,
+            last_connected_at: Some(connected_at),
+            ..Default::default()
+        };
+        client.add_user(&user).await.unwrap();
+
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.last_connected_at, Some(connected_at));
+        assert_eq!(fetched.last_notified_at, None);
+
+        client
+            .update_last_notified_at(&uaid, connected_at + 10)
+            .await
+            .unwrap();
+        let fetched = client.get_user(&uaid).await.unwrap().unwrap();
+        assert_eq!(fetched.last_notified_at, Some(connected_at + 10));
+
+        // Doesn't recreate removed users
+        client.remove_user(&uaid).await.unwrap();
+        client
+            .update_last_notified_at(&uaid, connected_at + 20)
+            .await
+            .unwrap();
+        assert!(client.get_user(&uaid).await.unwrap().is_none());
+    }
+
     // #[actix_rt::test]
     // async fn sometest() {}
 
@@ -54,6 +54,15 @@ pub struct BigTableDbSettings {
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_u32_to_duration")]
     pub database_pool_connection_timeout: Duration,
+    /// Max time (in milliseconds) to wait to check out a connection from the
+    /// pool. Defaults to deadpool's default (wait indefinitely)
+    #[serde(default)]
+    pub database_pool_connection_timeout_ms: Option<u64>,
+    /// Max time (in milliseconds) a single request may take, after which
+    /// it's cancelled (and BigTable stops processing it). Defaults to no
+    /// limit
+    #[serde(default)]
+    pub database_statement_timeout_ms: Option<u64>,
     /// Max time (in seconds) a connection should live
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_u32_to_duration")]
@@ -62,6 +71,29 @@ pub struct BigTableDbSettings {
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_u32_to_duration")]
     pub database_pool_max_idle: Duration,
+    /// Max times to retry a request failing with a transient error
+    #[serde(default = "default_max_retries")]
+    pub database_max_retries: usize,
+    /// How often (in seconds) to emit the pool utilization gauges, 0 to
+    /// disable them
+    #[serde(default = "default_metrics_interval_secs")]
+    pub metrics_interval_secs: u64,
+}
+
+fn default_max_retries() -> usize {
+    3
+}
+
+fn default_metrics_interval_secs() -> u64 {
+    10
+}
+
+impl BigTableDbSettings {
+    /// The `database_statement_timeout_ms`, if any
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.database_statement_timeout_ms
+            .map(Duration::from_millis)
+    }
 }
 
 impl TryFrom<&str> for BigTableDbSettings {
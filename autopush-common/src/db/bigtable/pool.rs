@@ -1,9 +1,10 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{fmt, sync::Arc};
 
 use async_trait::async_trait;
-use cadence::StatsdClient;
-use deadpool::managed::{Manager, PoolConfig, Timeouts};
+use cadence::{Gauged, StatsdClient, Timed};
+use deadpool::managed::{Manager, PoolConfig, PoolError};
+use deadpool::Runtime;
 use grpcio::{Channel, ChannelBuilder, ChannelCredentials, EnvBuilder};
 
 use crate::db::bigtable::{bigtable_client::BigtableDb, BigTableDbSettings, BigTableError};
@@ -25,7 +26,7 @@ const DEFAULT_GRPC_PORT: u16 = 8086;
 pub struct BigTablePool {
     /// Pool of db connections
     pub pool: deadpool::managed::Pool<BigtableClientManager>,
-    _metrics: Arc<StatsdClient>,
+    metrics: Arc<StatsdClient>,
 }
 
 impl fmt::Debug for BigTablePool {
@@ -40,10 +41,53 @@ impl BigTablePool {
     pub async fn get(
         &self,
     ) -> Result<deadpool::managed::Object<BigtableClientManager>, error::BigTableError> {
-        self.pool
-            .get()
-            .await
-            .map_err(|e| error::BigTableError::Pool(e.to_string()))
+        let start = Instant::now();
+        let result = self.pool.get().await;
+        self.metrics
+            .time_with_tags("db.pool.wait_time", start.elapsed())
+            .send();
+        result.map_err(|e| match e {
+            PoolError::Timeout(_) => error::BigTableError::PoolTimeout(e.to_string()),
+            _ => error::BigTableError::Pool(e.to_string()),
+        })
+    }
+
+    /// Emit gauges of the pool's current utilization
+    pub fn report_metrics(&self) {
+        let status = self.pool.status();
+        let idle = status.available;
+        let _ = self
+            .metrics
+            .gauge("db.pool.active", status.size.saturating_sub(idle) as u64);
+        let _ = self.metrics.gauge("db.pool.idle", idle as u64);
+        let _ = self.metrics.gauge("db.pool.pending", status.waiting as u64);
+    }
+
+    /// Close the pool, ending its metrics reporter
+    pub fn close(&self) {
+        self.pool.close()
+    }
+
+    /// Periodically emit the pool's utilization gauges in the background,
+    /// until the pool's closed.
+    ///
+    /// Requires a running Tokio runtime, otherwise they're skipped.
+    fn spawn_metrics_reporter(&self, interval: Duration) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            warn!("🏊 No runtime available, not reporting pool metrics");
+            return;
+        };
+        let pool = self.clone();
+        handle.spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if pool.pool.is_closed() {
+                    break;
+                }
+                pool.report_metrics();
+            }
+        });
     }
 
     /// Get the pools manager, because we would like to talk to them.
@@ -58,7 +102,7 @@ impl BigTablePool {
                 "No DSN specified in settings".to_owned(),
             ));
         };
-        let bt_settings = BigTableDbSettings::try_from(settings.db_settings.as_str())?;
+        let bt_settings = BigTableDbSettings::try_from(settings.prefixed_db_settings().as_str())?;
         debug!("🉑 DSN: {}", &endpoint);
         // Url::parsed() doesn't know how to handle `grpc:` schema, so it returns "null".
         let parsed = url::Url::parse(endpoint).map_err(|e| {
@@ -96,20 +140,30 @@ impl BigTablePool {
                 "🏊 Setting connection timeout to {} seconds",
                 &bt_settings.database_pool_connection_timeout.as_secs()
             );
-            config.timeouts = Timeouts {
-                create: Some(bt_settings.database_pool_connection_timeout),
-                ..Default::default()
-            };
+            config.timeouts.create = Some(bt_settings.database_pool_connection_timeout);
+        }
+        if let Some(timeout_ms) = bt_settings.database_pool_connection_timeout_ms {
+            debug!(
+                "🏊 Setting checkout timeout to {} milliseconds",
+                &timeout_ms
+            );
+            config.timeouts.wait = Some(Duration::from_millis(timeout_ms));
         }
         let pool = deadpool::managed::Pool::builder(manager)
             .config(config)
+            // Timeouts require a runtime
+            .runtime(Runtime::Tokio1)
             .build()
             .map_err(|e| DbError::BTError(BigTableError::Pool(e.to_string())))?;
 
-        Ok(Self {
+        let pool = Self {
             pool,
-            _metrics: metrics.clone(),
-        })
+            metrics: metrics.clone(),
+        };
+        if bt_settings.metrics_interval_secs > 0 {
+            pool.spawn_metrics_reporter(Duration::from_secs(bt_settings.metrics_interval_secs));
+        }
+        Ok(pool)
     }
 }
 
@@ -151,7 +205,10 @@ impl Manager for BigtableClientManager {
     /// `BigtableClient` is the most atomic we can go.
     async fn create(&self) -> Result<BigtableDb, DbError> {
         debug!("🏊 Create a new pool entry.");
-        Ok(BigtableDb::new(self.get_channel()?))
+        Ok(BigtableDb::new(
+            self.get_channel()?,
+            self.settings.statement_timeout(),
+        ))
     }
 
     /// Recycle if the connection has outlived it's lifespan.
@@ -221,3 +278,138 @@ impl BigtableClientManager {
         Ok(chan)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use cadence::{NopMetricSink, SpyMetricSink, StatsdClient};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::BigTablePool;
+    use crate::db::bigtable::{BigTableClientImpl, BigTableError};
+    use crate::db::{client::DbClient, error::DbError, DbSettings};
+
+    #[actix_rt::test]
+    async fn checkout_times_out() {
+        // Channels connect lazily, so no emulator is required here.
+        let settings = DbSettings {
+            dsn: Some("grpc://localhost:8086".to_owned()),
+            db_settings: json!({
+                "table_name": "projects/test/instances/test/tables/autopush",
+                "database_pool_max_size": 1,
+                "database_pool_connection_timeout_ms": 100,
+            })
+            .to_string(),
+            ..Default::default()
+        };
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let pool = BigTablePool::new(&settings, &metrics).unwrap();
+
+        let _held = pool.get().await.unwrap();
+        let start = Instant::now();
+        let err = pool.get().await.unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(matches!(err, BigTableError::PoolTimeout(_)));
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[actix_rt::test]
+    async fn reports_active_connections() {
+        let settings = DbSettings {
+            dsn: Some("grpc://localhost:8086".to_owned()),
+            db_settings: json!({
+                "table_name": "projects/test/instances/test/tables/autopush",
+                "metrics_interval_secs": 0,
+            })
+            .to_string(),
+            ..Default::default()
+        };
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::builder("", sink).build());
+        let pool = BigTablePool::new(&settings, &metrics).unwrap();
+
+        let first = pool.get().await.unwrap();
+        let _second = pool.get().await.unwrap();
+        pool.report_metrics();
+        drop(first);
+        pool.report_metrics();
+
+        let metrics: Vec<String> = rx
+            .try_iter()
+            .map(|metric| String::from_utf8(metric).unwrap())
+            .collect();
+        let gauges: Vec<&str> = metrics
+            .iter()
+            .map(String::as_str)
+            .filter(|m| m.starts_with("db.pool.active:"))
+            .collect();
+        assert_eq!(gauges, ["db.pool.active:2|g", "db.pool.active:1|g"]);
+        assert!(metrics.iter().any(|m| m.starts_with("db.pool.idle:1|g")));
+        assert!(metrics.iter().any(|m| m.starts_with("db.pool.wait_time:")));
+    }
+
+    #[actix_rt::test]
+    async fn reporter_ends_on_close() {
+        let settings = DbSettings {
+            dsn: Some("grpc://localhost:8086".to_owned()),
+            db_settings: json!({
+                "table_name": "projects/test/instances/test/tables/autopush",
+                "metrics_interval_secs": 0,
+            })
+            .to_string(),
+            ..Default::default()
+        };
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::builder("", sink).build());
+        let pool = BigTablePool::new(&settings, &metrics).unwrap();
+
+        pool.spawn_metrics_reporter(Duration::from_millis(10));
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_iter().count() > 0);
+        pool.close();
+        // Any report already underway
+        actix_rt::time::sleep(Duration::from_millis(20)).await;
+        rx.try_iter().for_each(drop);
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(rx.try_iter().count(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn statement_times_out() {
+        // Accepts connections (into its backlog) but never responds, so the
+        // requests would otherwise hang
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let settings = DbSettings {
+            dsn: Some(format!("grpc://localhost:{port}")),
+            db_settings: json!({
+                "table_name": "projects/test/instances/test/tables/autopush",
+                "database_statement_timeout_ms": 200,
+                "database_max_retries": 0,
+                "metrics_interval_secs": 0,
+            })
+            .to_string(),
+            ..Default::default()
+        };
+        let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
+        let client = BigTableClientImpl::new(metrics, &settings).unwrap();
+
+        let start = Instant::now();
+        let err = client.get_user(&Uuid::new_v4()).await.unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(
+            matches!(
+                &err,
+                DbError::BTError(BigTableError::Rpc(_, grpcio::Error::RpcFailure(status)))
+                    if status.code() == grpcio::RpcStatusCode::DEADLINE_EXCEEDED
+            ),
+            "{err:?}"
+        );
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+}
@@ -31,12 +31,36 @@ pub trait DbClient: Send + Sync {
     // TODO: make the bool a #[must_use]
     async fn update_user(&self, user: &User) -> DbResult<bool>;
 
+    /// Update a user whose router type was `previous_router_type` (e.g.
+    /// promoting one of its fallback routes), otherwise like `update_user`.
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool>;
+
     /// Read a user from the database
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>>;
 
+    /// Set the user's `last_notified_at` (in milliseconds), unless the user
+    /// no longer exists
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()>;
+
+    /// Count the users neither connected nor notified since `cutoff` (in
+    /// milliseconds). This scans every user, so should be called sparingly.
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64>;
+
     /// Delete a user from the router table
     async fn remove_user(&self, uaid: &Uuid) -> DbResult<()>;
 
+    /// Delete a user a bridge reported gone, unless its routing info has
+    /// changed since `user` was read (e.g. another server updated its
+    /// token). Returns whether it was deleted.
+    ///
+    /// `user` is expected to have just been read from this client, so by
+    /// default it's deleted outright. Clients serving records from a cache
+    /// must re-read it first.
+    async fn remove_gone_user(&self, user: &User) -> DbResult<bool> {
+        self.remove_user(&user.uaid).await?;
+        Ok(true)
+    }
+
     /// Add a channel to a user
     async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()>;
 
@@ -76,9 +100,24 @@ pub trait DbClient: Send + Sync {
     /// Update the last read timestamp for a user
     async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()>;
 
+    /// Fetch a stored notification, `None` when it's not (or no longer)
+    /// stored
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>>;
+
     /// Delete a notification
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()>;
 
+    /// Delete multiple notifications, returning the number deleted.
+    /// Notifications that no longer exist are skipped.
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize>;
+
+    /// Delete up to `limit` stored messages whose TTL has expired (`limit=0`
+    /// for all of them), returning the number deleted.
+    ///
+    /// Storage that expires messages natively (DynamoDB's TTL) deletes
+    /// nothing and returns 0.
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize>;
+
     /// Check if the router table exists
     async fn router_table_exists(&self) -> DbResult<bool>;
 
@@ -100,6 +139,10 @@ pub trait DbClient: Send + Sync {
     #[allow(clippy::needless_lifetimes)]
     fn rotating_message_table<'a>(&'a self) -> Option<&'a str>;
 
+    /// Stop this client's background tasks (e.g. reporting its pool's
+    /// metrics), once the server has shut down
+    fn shutdown(&self) {}
+
     fn box_clone(&self) -> Box<dyn DbClient>;
 }
 
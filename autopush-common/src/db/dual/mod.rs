@@ -41,9 +41,14 @@ pub struct DualDbSettings {
 
 impl DualClientImpl {
     pub fn new(metrics: Arc<StatsdClient>, settings: &DbSettings) -> DbResult<Self> {
-        let db_settings: DualDbSettings = from_str(&settings.db_settings).map_err(|e| {
+        let mut db_settings: DualDbSettings = from_str(&settings.db_settings).map_err(|e| {
             DbError::General(format!("Could not parse DualDBSettings string {:?}", e))
         })?;
+        for engine in [&mut db_settings.primary, &mut db_settings.secondary] {
+            if engine.table_prefix.is_empty() {
+                engine.table_prefix = settings.table_prefix.clone();
+            }
+        }
         if StorageType::from_dsn(&db_settings.primary.dsn) != StorageType::BigTable {
             return Err(DbError::General(
                 "Invalid primary DSN specified (must be BigTable type)".to_owned(),
@@ -80,6 +85,18 @@ impl DbClient for DualClientImpl {
         self.primary.update_user(user).await
     }
 
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        if self.write_to_secondary {
+            let _ = self
+                .secondary
+                .update_user_router(user, previous_router_type)
+                .await?;
+        }
+        self.primary
+            .update_user_router(user, previous_router_type)
+            .await
+    }
+
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         match self.primary.get_user(uaid).await {
             Ok(Some(user)) => Ok(Some(user)),
@@ -149,11 +166,28 @@ impl DbClient for DualClientImpl {
         self.primary.save_message(uaid, message).await
     }
 
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        match self.primary.get_message(uaid, sort_key).await? {
+            Some(message) => Ok(Some(message)),
+            None => self.secondary.get_message(uaid, sort_key).await,
+        }
+    }
+
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         let _ = self.secondary.remove_message(uaid, sort_key).await;
         self.primary.remove_message(uaid, sort_key).await
     }
 
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        let _ = self.secondary.remove_messages(uaid, sort_keys).await;
+        self.primary.remove_messages(uaid, sort_keys).await
+    }
+
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        let removed = self.primary.remove_expired_messages(limit).await?;
+        Ok(removed + self.secondary.remove_expired_messages(limit).await?)
+    }
+
     async fn fetch_topic_messages(
         &self,
         uaid: &Uuid,
@@ -192,6 +226,21 @@ impl DbClient for DualClientImpl {
         self.primary.save_messages(uaid, messages).await
     }
 
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        if self.write_to_secondary {
+            self.secondary
+                .update_last_notified_at(uaid, timestamp)
+                .await?;
+        }
+        self.primary.update_last_notified_at(uaid, timestamp).await
+    }
+
+    /// Users still in the secondary are also copied to the primary once
+    /// read, so only the primary is counted
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        self.primary.count_stale_users(cutoff).await
+    }
+
     async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
         if self.write_to_secondary {
             let _ = self.secondary.increment_storage(uaid, timestamp).await?;
@@ -215,6 +264,11 @@ impl DbClient for DualClientImpl {
         None
     }
 
+    fn shutdown(&self) {
+        self.primary.shutdown();
+        self.secondary.shutdown();
+    }
+
     fn box_clone(&self) -> Box<dyn DbClient> {
         Box::new(self.clone())
     }
@@ -262,6 +316,7 @@ mod test {
         let dual_settings = DbSettings {
             dsn: Some("dual".to_owned()),
             db_settings: arg_str,
+            ..Default::default()
         };
         let metrics = Arc::new(StatsdClient::builder("", NopMetricSink).build());
 
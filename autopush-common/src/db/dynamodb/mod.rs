@@ -1,14 +1,16 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::{Debug, Display};
+use std::future::Future;
 use std::result::Result as StdResult;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::db::client::DbClient;
 use crate::db::dynamodb::retry::{
-    retry_policy, retryable_batchwriteitem_error, retryable_delete_error,
-    retryable_describe_table_error, retryable_getitem_error, retryable_putitem_error,
-    retryable_updateitem_error,
+    retry_policy, retryable_batchgetitem_error, retryable_batchwriteitem_error,
+    retryable_delete_error, retryable_describe_table_error, retryable_getitem_error,
+    retryable_putitem_error, retryable_scan_error, retryable_updateitem_error,
 };
 use crate::db::error::{DbError, DbResult};
 use crate::db::{
@@ -18,13 +20,16 @@ use crate::notification::Notification;
 use crate::util::sec_since_epoch;
 
 use async_trait::async_trait;
-use cadence::{CountedExt, StatsdClient};
+use cadence::{Counted, CountedExt, StatsdClient, Timed};
 use chrono::Utc;
-use rusoto_core::credential::StaticProvider;
+use rusoto_core::credential::{DefaultCredentialsProvider, StaticProvider};
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture};
+use rusoto_core::signature::SignedRequest;
 use rusoto_core::{HttpClient, Region, RusotoError};
 use rusoto_dynamodb::{
-    AttributeValue, BatchWriteItemInput, DeleteItemInput, DescribeTableError, DescribeTableInput,
-    DynamoDb, DynamoDbClient, GetItemInput, ListTablesInput, PutItemInput, PutRequest, QueryInput,
+    AttributeValue, BatchGetItemInput, BatchWriteItemInput, DeleteItemInput, DeleteRequest,
+    DescribeTableError, DescribeTableInput, DynamoDb, DynamoDbClient, GetItemInput,
+    KeysAndAttributes, ListTablesInput, PutItemInput, PutRequest, QueryInput, ScanInput,
     UpdateItemError, UpdateItemInput, WriteRequest,
 };
 use serde::{Deserialize, Serialize};
@@ -34,12 +39,34 @@ use uuid::Uuid;
 pub mod macros;
 pub mod retry;
 
+/// The maximum number of items per BatchWriteItem request
+const MAX_BATCH_WRITE_ITEMS: usize = 25;
+
+/// The maximum number of times the items a batch request leaves unprocessed
+/// (e.g. when throttled) are resent
+const MAX_UNPROCESSED_RETRIES: u32 = 5;
+
+/// The items in the message table, keyed by their attribute names
+type Item = HashMap<String, AttributeValue>;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DynamoDbSettings {
     #[serde(default)]
     pub router_table: String,
     #[serde(default)]
     pub message_table: String,
+    /// Max time (in milliseconds) a single request may take, after which
+    /// it's cancelled. Defaults to no limit
+    #[serde(default)]
+    pub database_statement_timeout_ms: Option<u64>,
+}
+
+impl DynamoDbSettings {
+    /// The `database_statement_timeout_ms`, if any
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.database_statement_timeout_ms
+            .map(Duration::from_millis)
+    }
 }
 
 impl TryFrom<&str> for DynamoDbSettings {
@@ -59,9 +86,14 @@ pub struct DdbClientImpl {
 
 impl DdbClientImpl {
     pub fn new(metrics: Arc<StatsdClient>, db_settings: &DbSettings) -> DbResult<Self> {
+        let settings = DynamoDbSettings::try_from(db_settings.prefixed_db_settings().as_ref())?;
+        let dispatcher = TimeoutDispatcher {
+            client: HttpClient::new().expect("TLS initialization error"),
+            timeout: settings.statement_timeout(),
+        };
         let db_client = if let Ok(endpoint) = env::var("AWS_LOCAL_DYNAMODB") {
             DynamoDbClient::new_with(
-                HttpClient::new().expect("TLS initialization error"),
+                dispatcher,
                 StaticProvider::new_minimal("BogusKey".to_string(), "BogusKey".to_string()),
                 Region::Custom {
                     name: "us-east-1".to_string(),
@@ -69,10 +101,12 @@ impl DdbClientImpl {
                 },
             )
         } else {
-            DynamoDbClient::new(Region::default())
+            let credentials = DefaultCredentialsProvider::new().map_err(|e| {
+                DbError::General(format!("Could not load the AWS credentials: {:?}", e))
+            })?;
+            DynamoDbClient::new_with(dispatcher, credentials, Region::default())
         };
 
-        let settings = DynamoDbSettings::try_from(db_settings.db_settings.as_ref())?;
         Ok(Self {
             db_client,
             metrics,
@@ -106,6 +140,166 @@ impl DdbClientImpl {
 
         Ok(["CREATING", "UPDATING", "ACTIVE"].contains(&status.as_str()))
     }
+
+    /// Send the [update_user_input] request
+    async fn update_user_from(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        let input = update_user_input(&self.settings.router_table, user, previous_router_type)?;
+        let result = retry_policy()
+            .retry_if(
+                || self.db_client.update_item(input.clone()),
+                retryable_updateitem_error(self.metrics.clone()),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The keys (of the message table) of the messages that exist
+    async fn existing_messages(&self, keys: Vec<Item>) -> DbResult<Vec<Item>> {
+        let table = &self.settings.message_table;
+        let request = hashmap! {
+            table.clone() => KeysAndAttributes {
+                keys,
+                consistent_read: Some(true),
+                projection_expression: Some("uaid, chidmessageid".to_owned()),
+                ..Default::default()
+            }
+        };
+        let found = until_processed(request, |request_items| async move {
+            let input = BatchGetItemInput {
+                request_items,
+                ..Default::default()
+            };
+            let output = retry_policy()
+                .retry_if(
+                    || self.db_client.batch_get_item(input.clone()),
+                    retryable_batchgetitem_error(self.metrics.clone()),
+                )
+                .await?;
+            let items = output
+                .responses
+                .and_then(|mut responses| responses.remove(table))
+                .unwrap_or_default();
+            Ok((items, output.unprocessed_keys))
+        })
+        .await?;
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    /// Write (put or delete) the items in the message table
+    async fn write_messages(&self, items: Vec<WriteRequest>) -> DbResult<()> {
+        let request = hashmap! { self.settings.message_table.clone() => items };
+        until_processed(request, |request_items| async move {
+            let input = BatchWriteItemInput {
+                request_items,
+                ..Default::default()
+            };
+            let output = retry_policy()
+                .retry_if(
+                    || self.db_client.batch_write_item(input.clone()),
+                    retryable_batchwriteitem_error(self.metrics.clone()),
+                )
+                .await?;
+            Ok(((), output.unprocessed_items))
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Send the `request` via `batch`, then resend what it reports unprocessed
+/// (backing off between attempts) until nothing is. Returns the responses
+/// to each attempt.
+async fn until_processed<K, V, R, F, Fut>(
+    mut request: HashMap<K, V>,
+    mut batch: F,
+) -> DbResult<Vec<R>>
+where
+    F: FnMut(HashMap<K, V>) -> Fut,
+    Fut: Future<Output = DbResult<(R, Option<HashMap<K, V>>)>>,
+{
+    let mut responses = Vec::new();
+    let mut delay = Duration::from_millis(50);
+    for retries in 0.. {
+        let (response, unprocessed) = batch(request).await?;
+        responses.push(response);
+        match unprocessed {
+            Some(unprocessed) if !unprocessed.is_empty() => request = unprocessed,
+            _ => break,
+        }
+        if retries == MAX_UNPROCESSED_RETRIES {
+            return Err(DbError::General(
+                "Items left unprocessed by a DynamoDB batch request".to_owned(),
+            ));
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    Ok(responses)
+}
+
+/// Dispatches the requests via `client`, cancelling any taking longer than
+/// `timeout` (the `database_statement_timeout_ms`)
+struct TimeoutDispatcher {
+    client: HttpClient,
+    timeout: Option<Duration>,
+}
+
+impl DispatchSignedRequest for TimeoutDispatcher {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        self.client.dispatch(request, timeout.or(self.timeout))
+    }
+}
+
+/// The request updating the user (in `router_table`), unless it's missing,
+/// newly connected or its router type is no longer `previous_router_type`
+fn update_user_input(
+    router_table: &str,
+    user: &User,
+    previous_router_type: &str,
+) -> DbResult<UpdateItemInput> {
+    let mut user_map = serde_dynamodb::to_hashmap(&user)?;
+    user_map.remove("uaid");
+    let update_expression = format!(
+        "SET {}",
+        user_map
+            .keys()
+            .map(|key| format!("{0}=:{0}", key))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let mut values: HashMap<_, _> = user_map
+        .into_iter()
+        .map(|(key, value)| (format!(":{}", key), value))
+        .collect();
+    values.insert(
+        ":previous_router_type".to_owned(),
+        val!(S => previous_router_type),
+    );
+    Ok(UpdateItemInput {
+        table_name: router_table.to_owned(),
+        key: ddb_item! { uaid: s => user.uaid.simple().to_string() },
+        update_expression: Some(update_expression),
+        expression_attribute_values: Some(values),
+        condition_expression: Some(
+            "attribute_exists(uaid) and (
+                attribute_not_exists(router_type) or
+                (router_type = :previous_router_type)
+            ) and (
+                attribute_not_exists(node_id) or
+                (connected_at < :connected_at)
+            )"
+            .to_string(),
+        ),
+        ..Default::default()
+    })
 }
 
 /// Like Result::ok, convert from Result<T, E> to Option<T> but applying a
@@ -157,49 +351,11 @@ impl DbClient for DdbClientImpl {
     }
 
     async fn update_user(&self, user: &User) -> DbResult<bool> {
-        let mut user_map = serde_dynamodb::to_hashmap(&user)?;
-        user_map.remove("uaid");
-        let input = UpdateItemInput {
-            table_name: self.settings.router_table.clone(),
-            key: ddb_item! { uaid: s => user.uaid.simple().to_string() },
-            update_expression: Some(format!(
-                "SET {}",
-                user_map
-                    .keys()
-                    .map(|key| format!("{0}=:{0}", key))
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            )),
-            expression_attribute_values: Some(
-                user_map
-                    .into_iter()
-                    .map(|(key, value)| (format!(":{}", key), value))
-                    .collect(),
-            ),
-            condition_expression: Some(
-                "attribute_exists(uaid) and (
-                    attribute_not_exists(router_type) or
-                    (router_type = :router_type)
-                ) and (
-                    attribute_not_exists(node_id) or
-                    (connected_at < :connected_at)
-                )"
-                .to_string(),
-            ),
-            ..Default::default()
-        };
+        self.update_user_from(user, &user.router_type).await
+    }
 
-        let result = retry_policy()
-            .retry_if(
-                || self.db_client.update_item(input.clone()),
-                retryable_updateitem_error(self.metrics.clone()),
-            )
-            .await;
-        match result {
-            Ok(_) => Ok(true),
-            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
-            Err(e) => Err(e.into()),
-        }
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        self.update_user_from(user, previous_router_type).await
     }
 
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
@@ -337,6 +493,64 @@ impl DbClient for DdbClientImpl {
             .unwrap_or(false))
     }
 
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        let input = UpdateItemInput {
+            key: ddb_item! { uaid: s => uaid.simple().to_string() },
+            update_expression: Some("SET last_notified_at = :timestamp".to_string()),
+            condition_expression: Some("attribute_exists(uaid)".to_string()),
+            expression_attribute_values: Some(hashmap! {
+                ":timestamp".to_string() => val!(N => timestamp.to_string())
+            }),
+            table_name: self.settings.router_table.clone(),
+            ..Default::default()
+        };
+
+        let result = retry_policy()
+            .retry_if(
+                || self.db_client.update_item(input.clone()),
+                retryable_updateitem_error(self.metrics.clone()),
+            )
+            .await;
+        match result {
+            Ok(_) | Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        let mut input = ScanInput {
+            table_name: self.settings.router_table.clone(),
+            filter_expression: Some(
+                "connected_at < :cutoff and (
+                    attribute_not_exists(last_connected_at) or last_connected_at < :cutoff
+                ) and (
+                    attribute_not_exists(last_notified_at) or last_notified_at < :cutoff
+                )"
+                .to_string(),
+            ),
+            expression_attribute_values: Some(hashmap! {
+                ":cutoff".to_string() => val!(N => cutoff.to_string())
+            }),
+            select: Some("COUNT".to_string()),
+            ..Default::default()
+        };
+
+        let mut stale = 0;
+        loop {
+            let output = retry_policy()
+                .retry_if(
+                    || self.db_client.scan(input.clone()),
+                    retryable_scan_error(self.metrics.clone()),
+                )
+                .await?;
+            stale += output.count.unwrap_or_default() as u64;
+            match output.last_evaluated_key {
+                Some(key) if !key.is_empty() => input.exclusive_start_key = Some(key),
+                _ => return Ok(stale),
+            }
+        }
+    }
+
     async fn remove_node_id(
         &self,
         uaid: &Uuid,
@@ -544,18 +758,35 @@ impl DbClient for DdbClientImpl {
                     })
             })
             .collect();
-        let batch_input = BatchWriteItemInput {
-            request_items: hashmap! { self.settings.message_table.clone() => put_items },
+        self.write_messages(put_items).await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        let input = GetItemInput {
+            table_name: self.settings.message_table.clone(),
+            consistent_read: Some(true),
+            key: ddb_item! {
+               uaid: s => uaid.simple().to_string(),
+               chidmessageid: s => sort_key.to_owned()
+            },
             ..Default::default()
         };
 
-        retry_policy()
+        let Some(item) = retry_policy()
             .retry_if(
-                || self.db_client.batch_write_item(batch_input.clone()),
-                retryable_batchwriteitem_error(self.metrics.clone()),
+                || self.db_client.get_item(input.clone()),
+                retryable_getitem_error(self.metrics.clone()),
             )
-            .await?;
-        Ok(())
+            .await?
+            .item
+        else {
+            return Ok(None);
+        };
+        let record: NotificationRecord = serde_dynamodb::from_hashmap(item)?;
+        record
+            .into_notif()
+            .map(Some)
+            .map_err(|e| DbError::Serialization(e.to_string()))
     }
 
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
@@ -580,6 +811,51 @@ impl DbClient for DdbClientImpl {
         Ok(())
     }
 
+    /// Delete the messages in batches of up to 25 (BatchWriteItem's limit).
+    ///
+    /// BatchWriteItem doesn't report whether the deleted items existed, so
+    /// they're read first.
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        let start = Instant::now();
+        let mut removed = 0;
+        for chunk in sort_keys.chunks(MAX_BATCH_WRITE_ITEMS) {
+            let keys = chunk
+                .iter()
+                .map(|sort_key| {
+                    ddb_item! {
+                       uaid: s => uaid.simple().to_string(),
+                       chidmessageid: s => sort_key.to_owned()
+                    }
+                })
+                .collect();
+            let existing = self.existing_messages(keys).await?;
+            if existing.is_empty() {
+                continue;
+            }
+            removed += existing.len();
+            let delete_items = existing
+                .into_iter()
+                .map(|key| WriteRequest {
+                    put_request: None,
+                    delete_request: Some(DeleteRequest { key }),
+                })
+                .collect();
+            self.write_messages(delete_items).await?;
+        }
+        self.metrics
+            .count_with_tags("notification.message.deleted", removed as i64)
+            .send();
+        self.metrics
+            .time_with_tags("store.batch_delete", start.elapsed())
+            .send();
+        Ok(removed)
+    }
+
+    /// DynamoDB deletes expired messages itself via the table's TTL
+    async fn remove_expired_messages(&self, _limit: usize) -> DbResult<usize> {
+        Ok(0)
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         self.table_exists(self.settings.router_table.clone()).await
     }
@@ -628,3 +904,140 @@ pub(crate) fn has_connected_this_month(user: &User) -> bool {
         v.to_string().starts_with(&pat)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+
+    use rusoto_dynamodb::AttributeValue;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use std::time::{Duration, Instant};
+
+    use rusoto_core::request::DispatchSignedRequest;
+    use rusoto_core::signature::SignedRequest;
+    use rusoto_core::{HttpClient, Region};
+
+    use super::{
+        until_processed, update_user_input, DynamoDbSettings, TimeoutDispatcher,
+        MAX_UNPROCESSED_RETRIES,
+    };
+    use crate::db::{FallbackRoute, User};
+
+    /// The fallback routes are written with the rest of the user, and read
+    /// back as they were
+    #[test]
+    fn update_user_persists_fallback_routes() {
+        let user = User {
+            uaid: Uuid::new_v4(),
+            router_type: "apns".to_owned(),
+            fallback_routes: vec![FallbackRoute {
+                router_type: "adm".to_owned(),
+                router_data: Some(HashMap::from([("token".to_owned(), json!("adm-token"))])),
+            }],
+            ..Default::default()
+        };
+        let input = update_user_input("router", &user, "fcm").unwrap();
+        assert!(input
+            .update_expression
+            .unwrap()
+            .contains("fallback_routes=:fallback_routes"));
+        assert!(input
+            .condition_expression
+            .unwrap()
+            .contains("router_type = :previous_router_type"));
+
+        let mut values = input.expression_attribute_values.unwrap();
+        assert_eq!(
+            values.remove(":previous_router_type").unwrap().s.as_deref(),
+            Some("fcm")
+        );
+        let mut item: HashMap<String, AttributeValue> = values
+            .into_iter()
+            .map(|(key, value)| (key.trim_start_matches(':').to_owned(), value))
+            .collect();
+        item.extend(input.key);
+        let read: User = serde_dynamodb::from_hashmap(item).unwrap();
+        assert_eq!(read, user);
+    }
+
+    /// Users written before fallback routes existed have none
+    #[test]
+    fn user_without_fallback_routes() {
+        let user = User::default();
+        let mut item = serde_dynamodb::to_hashmap(&user).unwrap();
+        item.remove("fallback_routes");
+        let read: User = serde_dynamodb::from_hashmap(item).unwrap();
+        assert!(read.fallback_routes.is_empty());
+    }
+
+    #[test]
+    fn statement_timeout_setting() {
+        let settings = DynamoDbSettings::try_from(
+            r#"{"router_table": "router", "message_table": "message", "database_statement_timeout_ms": 200}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.statement_timeout(),
+            Some(Duration::from_millis(200))
+        );
+        let settings = DynamoDbSettings::try_from(r#"{"router_table": "router"}"#).unwrap();
+        assert_eq!(settings.statement_timeout(), None);
+    }
+
+    #[actix_rt::test]
+    async fn statement_times_out() {
+        // Accepts connections (into its backlog) but never responds, so the
+        // requests would otherwise hang
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let region = Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint: format!("http://{}", listener.local_addr().unwrap()),
+        };
+        let dispatcher = TimeoutDispatcher {
+            client: HttpClient::new().unwrap(),
+            timeout: Some(Duration::from_millis(200)),
+        };
+
+        let start = Instant::now();
+        let request = SignedRequest::new("POST", "dynamodb", &region, "/");
+        assert!(dispatcher.dispatch(request, None).await.is_err());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    /// What a batch request leaves unprocessed is resent, until nothing is
+    #[actix_rt::test]
+    async fn resends_unprocessed() {
+        let request = HashMap::from([("message", vec![1, 2, 3])]);
+        let sent = RefCell::new(Vec::new());
+        let responses = until_processed(request, |request| {
+            sent.borrow_mut().push(request["message"].clone());
+            // The first item's processed each time
+            let unprocessed: Vec<_> = request["message"][1..].to_vec();
+            let len = unprocessed.len();
+            let unprocessed = (len > 0).then(|| HashMap::from([("message", unprocessed)]));
+            async move { Ok((len, unprocessed)) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(responses, [2, 1, 0]);
+        assert_eq!(sent.into_inner(), [vec![1, 2, 3], vec![2, 3], vec![3]]);
+    }
+
+    /// Items persistently left unprocessed fail the request
+    #[actix_rt::test]
+    async fn unprocessed_retries_exhausted() {
+        let attempts = Cell::new(0);
+        let result = until_processed(HashMap::from([("message", 1)]), |request| {
+            attempts.set(attempts.get() + 1);
+            async move { Ok(((), Some(request))) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), MAX_UNPROCESSED_RETRIES + 1);
+    }
+}
@@ -2,8 +2,8 @@ use again::RetryPolicy;
 use cadence::{CountedExt, StatsdClient};
 use rusoto_core::RusotoError;
 use rusoto_dynamodb::{
-    BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError, PutItemError,
-    UpdateItemError,
+    BatchGetItemError, BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError,
+    PutItemError, ScanError, UpdateItemError,
 };
 use std::sync::Arc;
 
@@ -31,11 +31,17 @@ retryable_error!(retryable_getitem_error, GetItemError, "get_item");
 retryable_error!(retryable_updateitem_error, UpdateItemError, "update_item");
 retryable_error!(retryable_putitem_error, PutItemError, "put_item");
 retryable_error!(retryable_delete_error, DeleteItemError, "delete_item");
+retryable_error!(retryable_scan_error, ScanError, "scan");
 retryable_error!(
     retryable_batchwriteitem_error,
     BatchWriteItemError,
     "batch_write_item"
 );
+retryable_error!(
+    retryable_batchgetitem_error,
+    BatchGetItemError,
+    "batch_get_item"
+);
 
 // DescribeTableError does not have a ProvisionedThroughputExceeded variant
 pub fn retryable_describe_table_error(
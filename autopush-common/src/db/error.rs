@@ -1,7 +1,7 @@
 use rusoto_core::RusotoError;
 use rusoto_dynamodb::{
-    BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError, PutItemError,
-    QueryError, UpdateItemError,
+    BatchGetItemError, BatchWriteItemError, DeleteItemError, DescribeTableError, GetItemError,
+    PutItemError, QueryError, ScanError, UpdateItemError,
 };
 use thiserror::Error;
 
@@ -27,12 +27,18 @@ pub enum DbError {
     #[error("Database error while performing BatchWriteItem")]
     DdbBatchWriteItem(#[from] RusotoError<BatchWriteItemError>),
 
+    #[error("Database error while performing BatchGetItem")]
+    DdbBatchGetItem(#[from] RusotoError<BatchGetItemError>),
+
     #[error("Database error while performing DescribeTable")]
     DdbDescribeTable(#[from] RusotoError<DescribeTableError>),
 
     #[error("Database error while performing Query")]
     DdbQuery(#[from] RusotoError<QueryError>),
 
+    #[error("Database error while performing Scan")]
+    DdbScan(#[from] RusotoError<ScanError>),
+
     #[error("Error while performing DynamoDB (de)serialization: {0}")]
     DdbSerialization(#[from] serde_dynamodb::Error),
 
@@ -62,3 +68,15 @@ pub enum DbError {
     #[error("Unknown Database Error {0}")]
     General(String),
 }
+
+impl DbError {
+    /// Whether this is a transient error (e.g. the connection pool is
+    /// exhausted) which the caller should retry later
+    pub fn is_unavailable(&self) -> bool {
+        #[cfg(feature = "bigtable")]
+        if let DbError::BTError(BigTableError::PoolTimeout(_)) = self {
+            return true;
+        }
+        false
+    }
+}
@@ -24,6 +24,12 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).update_user(user).await
     }
 
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        Arc::as_ref(self)
+            .update_user_router(user, previous_router_type)
+            .await
+    }
+
     async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
         Arc::as_ref(self).get_user(uaid).await
     }
@@ -32,6 +38,10 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).remove_user(uaid).await
     }
 
+    async fn remove_gone_user(&self, user: &User) -> DbResult<bool> {
+        Arc::as_ref(self).remove_gone_user(user).await
+    }
+
     async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
         Arc::as_ref(self).add_channel(uaid, channel_id).await
     }
@@ -82,14 +92,36 @@ impl DbClient for Arc<MockDbClient> {
             .await
     }
 
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        Arc::as_ref(self)
+            .update_last_notified_at(uaid, timestamp)
+            .await
+    }
+
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        Arc::as_ref(self).count_stale_users(cutoff).await
+    }
+
     async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
         Arc::as_ref(self).increment_storage(uaid, timestamp).await
     }
 
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        Arc::as_ref(self).get_message(uaid, sort_key).await
+    }
+
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
         Arc::as_ref(self).remove_message(uaid, sort_key).await
     }
 
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        Arc::as_ref(self).remove_messages(uaid, sort_keys).await
+    }
+
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        Arc::as_ref(self).remove_expired_messages(limit).await
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         Arc::as_ref(self).router_table_exists().await
     }
@@ -29,6 +29,7 @@ pub mod dual;
 pub mod dynamodb;
 pub mod error;
 pub mod models;
+pub mod replica;
 mod util;
 
 // used by integration testing
@@ -115,6 +116,48 @@ pub struct DbSettings {
     /// [crate::db::dynamodb::DynamoDbSettings]
     /// and [crate::db::bigtable::BigTableDbSettings]
     pub db_settings: String,
+    /// Prefixed to the storage's table names, so separate deployments can
+    /// share a database. Letters, digits and `_` only.
+    #[serde(default)]
+    pub table_prefix: String,
+}
+
+impl DbSettings {
+    /// Whether `prefix` is a valid `table_prefix`
+    pub fn valid_table_prefix(prefix: &str) -> bool {
+        prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// The `db_settings` with their table names prefixed by `table_prefix`:
+    /// DynamoDB's `router_table` and `message_table` and the last segment of
+    /// Bigtable's `table_name` path. (Dual storage passes the prefix on to
+    /// its engines.)
+    pub fn prefixed_db_settings(&self) -> String {
+        use serde_json::Value;
+
+        if self.table_prefix.is_empty() {
+            return self.db_settings.clone();
+        }
+        let Ok(Value::Object(mut settings)) = serde_json::from_str(&self.db_settings) else {
+            // Left for the storage engine to reject
+            return self.db_settings.clone();
+        };
+        let prefix = &self.table_prefix;
+        for key in ["router_table", "message_table"] {
+            if let Some(Value::String(table)) = settings.get_mut(key) {
+                *table = format!("{}{}", prefix, table);
+            }
+        }
+        if let Some(Value::String(path)) = settings.get_mut("table_name") {
+            *path = match path.rsplit_once('/') {
+                Some((parent, table)) => format!("{}/{}{}", parent, prefix, table),
+                None => format!("{}{}", prefix, path),
+            };
+        }
+        Value::Object(settings).to_string()
+    }
 }
 //TODO: add `From<autopush::settings::Settings> for DbSettings`?
 //TODO: add `From<autoendpoint::settings::Settings> for DbSettings`?
@@ -174,6 +217,25 @@ pub struct User {
     //TODO: rename this to `last_notification_timestamp`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub current_timestamp: Option<u64>,
+    /// Time in milliseconds the user last connected via WebSocket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_connected_at: Option<u64>,
+    /// Time in milliseconds a notification was last delivered to the user
+    /// (updated at a coarse granularity, see
+    /// [crate::db::client::DbClient::update_last_notified_at])
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_notified_at: Option<u64>,
+    /// Bridge routes tried, in order, when the primary one (`router_type`
+    /// and `router_data`) is permanently gone
+    #[serde(default)]
+    pub fallback_routes: Vec<FallbackRoute>,
+}
+
+/// A bridge route a user's notifications fall back to
+#[derive(Deserialize, PartialEq, Debug, Clone, Serialize)]
+pub struct FallbackRoute {
+    pub router_type: String,
+    pub router_data: Option<HashMap<String, serde_json::Value>>,
 }
 
 impl Default for User {
@@ -190,6 +252,9 @@ impl Default for User {
             record_version: Some(USER_RECORD_VERSION),
             current_month: None,
             current_timestamp: None,
+            last_connected_at: None,
+            last_notified_at: None,
+            fallback_routes: Vec::new(),
         }
     }
 }
@@ -249,6 +314,9 @@ pub struct NotificationRecord {
     /// value before sending it to storage or a connection node.
     #[serde(skip_serializing_if = "Option::is_none")]
     updateid: Option<String>,
+    /// The number of times a UserAgent has Nack'd the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nack_count: Option<u32>,
 }
 
 impl NotificationRecord {
@@ -337,6 +405,7 @@ impl NotificationRecord {
             data: self.data,
             headers: self.headers.map(|m| m.into()),
             sortkey_timestamp: key.sortkey_timestamp,
+            nack_count: self.nack_count.unwrap_or_default(),
         })
     }
 
@@ -351,7 +420,49 @@ impl NotificationRecord {
             data: val.data,
             headers: val.headers.map(|h| h.into()),
             updateid: Some(val.version),
+            nack_count: (val.nack_count > 0).then_some(val.nack_count),
             ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DbSettings;
+
+    #[test]
+    fn test_prefixed_db_settings() {
+        let settings = DbSettings {
+            db_settings: r#"{"router_table":"router","message_table":"message"}"#.to_owned(),
+            ..Default::default()
+        };
+        // Exactly as configured without a prefix
+        assert_eq!(settings.prefixed_db_settings(), settings.db_settings);
+
+        let settings = DbSettings {
+            table_prefix: "tenantA_".to_owned(),
+            ..settings
+        };
+        let prefixed: serde_json::Value =
+            serde_json::from_str(&settings.prefixed_db_settings()).unwrap();
+        assert_eq!(prefixed["router_table"], "tenantA_router");
+        assert_eq!(prefixed["message_table"], "tenantA_message");
+
+        // Bigtable's table path
+        let settings = DbSettings {
+            db_settings: r#"{"table_name":"projects/p/instances/i/tables/autopush"}"#.to_owned(),
+            ..settings
+        };
+        let prefixed: serde_json::Value =
+            serde_json::from_str(&settings.prefixed_db_settings()).unwrap();
+        assert_eq!(
+            prefixed["table_name"],
+            "projects/p/instances/i/tables/tenantA_autopush"
+        );
+
+        assert!(DbSettings::valid_table_prefix("tenantA_"));
+        for invalid in ["tenant-a", "tenant.a", "tenant a"] {
+            assert!(!DbSettings::valid_table_prefix(invalid), "{invalid}");
+        }
+    }
+}
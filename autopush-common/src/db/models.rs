@@ -19,6 +19,8 @@ pub(crate) struct NotificationHeaders {
     encryption_key: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgency: Option<String>,
 }
 
 #[allow(clippy::implicit_hasher)]
@@ -29,6 +31,7 @@ impl From<NotificationHeaders> for HashMap<String, String> {
         map.insert_opt("encryption", val.encryption);
         map.insert_opt("encryption_key", val.encryption_key);
         map.insert_opt("encoding", val.encoding);
+        map.insert_opt("urgency", val.urgency);
         map
     }
 }
@@ -40,6 +43,7 @@ impl From<HashMap<String, String>> for NotificationHeaders {
             encryption: val.get("encryption").map(|v| v.to_string()),
             encryption_key: val.get("encryption_key").map(|v| v.to_string()),
             encoding: val.get("encoding").map(|v| v.to_string()),
+            urgency: val.get("urgency").map(|v| v.to_string()),
         }
     }
 }
@@ -59,7 +63,8 @@ pub(crate) struct RangeKey {
 #[cfg(test)]
 mod tests {
     use crate::db::NotificationRecord;
-    use crate::util::us_since_epoch;
+    use crate::notification::Notification;
+    use crate::util::{ms_since_epoch, sec_since_epoch, us_since_epoch};
     use uuid::Uuid;
 
     #[test]
@@ -90,4 +95,26 @@ mod tests {
             assert!(key.is_err());
         }
     }
+
+    /// The Nack count is stored with the message, and defaults to none
+    #[test]
+    fn test_nack_count_roundtrip() {
+        let uaid = Uuid::new_v4();
+        let notif = Notification {
+            channel_id: Uuid::new_v4(),
+            version: "version".to_owned(),
+            ttl: 60,
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(ms_since_epoch()),
+            nack_count: 2,
+            ..Default::default()
+        };
+        let record = NotificationRecord::from_notif(&uaid, notif);
+        assert_eq!(record.clone().into_notif().unwrap().nack_count, 2);
+
+        let mut item = serde_json::to_value(&record).unwrap();
+        item.as_object_mut().unwrap().remove("nack_count");
+        let record: NotificationRecord = serde_json::from_value(item).unwrap();
+        assert_eq!(record.into_notif().unwrap().nack_count, 0);
+    }
 }
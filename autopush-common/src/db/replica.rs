@@ -0,0 +1,190 @@
+//! Read replica data store.
+//!
+//! Wraps two clients of the same storage type: lookups (reading users,
+//! channels and messages) are sent to the read replica, while everything
+//! else (writes, deletes and table checks) goes to the primary.
+//!
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::db::{
+    client::{DbClient, FetchMessageResponse},
+    error::DbResult,
+    Notification, User,
+};
+
+#[derive(Clone)]
+pub struct ReplicaClientImpl {
+    primary: Box<dyn DbClient>,
+    replica: Box<dyn DbClient>,
+}
+
+impl ReplicaClientImpl {
+    pub fn new(primary: Box<dyn DbClient>, replica: Box<dyn DbClient>) -> Self {
+        Self { primary, replica }
+    }
+}
+
+#[async_trait]
+impl DbClient for ReplicaClientImpl {
+    async fn add_user(&self, user: &User) -> DbResult<()> {
+        self.primary.add_user(user).await
+    }
+
+    async fn update_user(&self, user: &User) -> DbResult<bool> {
+        self.primary.update_user(user).await
+    }
+
+    async fn update_user_router(&self, user: &User, previous_router_type: &str) -> DbResult<bool> {
+        self.primary
+            .update_user_router(user, previous_router_type)
+            .await
+    }
+
+    async fn get_user(&self, uaid: &Uuid) -> DbResult<Option<User>> {
+        self.replica.get_user(uaid).await
+    }
+
+    async fn remove_user(&self, uaid: &Uuid) -> DbResult<()> {
+        self.primary.remove_user(uaid).await
+    }
+
+    async fn add_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<()> {
+        self.primary.add_channel(uaid, channel_id).await
+    }
+
+    async fn get_channels(&self, uaid: &Uuid) -> DbResult<HashSet<Uuid>> {
+        self.replica.get_channels(uaid).await
+    }
+
+    async fn remove_channel(&self, uaid: &Uuid, channel_id: &Uuid) -> DbResult<bool> {
+        self.primary.remove_channel(uaid, channel_id).await
+    }
+
+    async fn remove_node_id(
+        &self,
+        uaid: &Uuid,
+        node_id: &str,
+        connected_at: u64,
+    ) -> DbResult<bool> {
+        self.primary
+            .remove_node_id(uaid, node_id, connected_at)
+            .await
+    }
+
+    async fn save_message(&self, uaid: &Uuid, message: Notification) -> DbResult<()> {
+        self.primary.save_message(uaid, message).await
+    }
+
+    async fn save_messages(&self, uaid: &Uuid, messages: Vec<Notification>) -> DbResult<()> {
+        self.primary.save_messages(uaid, messages).await
+    }
+
+    async fn fetch_topic_messages(
+        &self,
+        uaid: &Uuid,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.replica.fetch_topic_messages(uaid, limit).await
+    }
+
+    async fn fetch_timestamp_messages(
+        &self,
+        uaid: &Uuid,
+        timestamp: Option<u64>,
+        limit: usize,
+    ) -> DbResult<FetchMessageResponse> {
+        self.replica
+            .fetch_timestamp_messages(uaid, timestamp, limit)
+            .await
+    }
+
+    async fn update_last_notified_at(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.primary.update_last_notified_at(uaid, timestamp).await
+    }
+
+    async fn count_stale_users(&self, cutoff: u64) -> DbResult<u64> {
+        self.primary.count_stale_users(cutoff).await
+    }
+
+    async fn increment_storage(&self, uaid: &Uuid, timestamp: u64) -> DbResult<()> {
+        self.primary.increment_storage(uaid, timestamp).await
+    }
+
+    async fn get_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<Option<Notification>> {
+        self.primary.get_message(uaid, sort_key).await
+    }
+
+    async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()> {
+        self.primary.remove_message(uaid, sort_key).await
+    }
+
+    async fn remove_messages(&self, uaid: &Uuid, sort_keys: &[String]) -> DbResult<usize> {
+        self.primary.remove_messages(uaid, sort_keys).await
+    }
+
+    async fn remove_expired_messages(&self, limit: usize) -> DbResult<usize> {
+        self.primary.remove_expired_messages(limit).await
+    }
+
+    async fn router_table_exists(&self) -> DbResult<bool> {
+        self.primary.router_table_exists().await
+    }
+
+    async fn message_table_exists(&self) -> DbResult<bool> {
+        self.primary.message_table_exists().await
+    }
+
+    async fn health_check(&self) -> DbResult<bool> {
+        Ok(self.primary.health_check().await? && self.replica.health_check().await?)
+    }
+
+    fn rotating_message_table(&self) -> Option<&str> {
+        self.primary.rotating_message_table()
+    }
+
+    fn shutdown(&self) {
+        self.primary.shutdown();
+        self.replica.shutdown();
+    }
+
+    fn box_clone(&self) -> Box<dyn DbClient> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate;
+    use uuid::Uuid;
+
+    use super::ReplicaClientImpl;
+    use crate::db::{client::DbClient, mock::MockDbClient, User};
+
+    #[actix_rt::test]
+    async fn routes_reads_to_replica() {
+        let uaid = Uuid::new_v4();
+        let mut primary = MockDbClient::new();
+        primary.expect_add_user().times(1).return_once(|_| Ok(()));
+        primary.expect_get_user().never();
+        let mut replica = MockDbClient::new();
+        replica
+            .expect_get_user()
+            .with(predicate::eq(uaid))
+            .times(1)
+            .return_once(|_| Ok(None));
+        replica.expect_add_user().never();
+
+        let client = ReplicaClientImpl::new(primary.into_boxed_arc(), replica.into_boxed_arc());
+        client
+            .add_user(&User {
+                uaid,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(client.get_user(&uaid).await.unwrap().is_none());
+    }
+}
@@ -1,4 +1,9 @@
-use std::{io, sync::OnceLock, time::Duration};
+use std::{
+    env, io,
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::Duration,
+};
 
 use gethostname::gethostname;
 use slog::{self, Drain};
@@ -8,7 +13,60 @@ use crate::errors::Result;
 
 static EC2_INSTANCE_ID: OnceLock<Option<String>> = OnceLock::new();
 
-pub fn init_logging(json: bool, name: &str, version: &str) -> Result<()> {
+/// The filter of the logger set up by `init_logging`, for `set_log_level`
+static LOG_FILTER: Mutex<Option<Arc<dyn SetLogLevel>>> = Mutex::new(None);
+
+tokio::task_local! {
+    /// The correlation id of the request being handled by the current task
+    /// (see [crate::middleware::request_id::RequestIdWrapper])
+    pub static REQUEST_ID: String;
+}
+
+/// Adds the current task's `REQUEST_ID` (when set) to each record.
+///
+/// This must wrap the `slog_async` drain: the task local is only available
+/// on the thread handling the request, not on `slog_async`'s thread.
+struct RequestIdDrain<D>(D);
+
+impl<D: Drain> Drain for RequestIdDrain<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> std::result::Result<Self::Ok, Self::Err> {
+        let Ok(request_id) = REQUEST_ID.try_with(|id| id.clone()) else {
+            return self.0.log(record, values);
+        };
+        let location = slog::RecordLocation {
+            file: record.file(),
+            line: record.line(),
+            column: record.column(),
+            function: record.function(),
+            module: record.module(),
+        };
+        let record_static = slog::RecordStatic {
+            location: &location,
+            tag: record.tag(),
+            level: record.level(),
+        };
+        let kv = (
+            slog::SingleKV::from(("request_id", request_id.as_str())),
+            record.kv(),
+        );
+        self.0.log(
+            &slog::Record::new(&record_static, record.msg(), slog::BorrowedKV(&kv)),
+            values,
+        )
+    }
+}
+
+/// Initialize logging, filtered by `log_level` (an env_logger style filter,
+/// e.g. "info" or "autoendpoint=debug,hyper=warn"). `RUST_LOG` takes
+/// precedence when set, as does its default ("error") without a `log_level`.
+pub fn init_logging(json: bool, name: &str, version: &str, log_level: Option<&str>) -> Result<()> {
     let logger = if json {
         let ec2_instance_id = EC2_INSTANCE_ID.get_or_init(|| get_ec2_instance_id().ok());
         let hostname = ec2_instance_id
@@ -20,15 +78,11 @@ pub fn init_logging(json: bool, name: &str, version: &str) -> Result<()> {
             .hostname(hostname)
             .build()
             .fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        reloadable_logger(drain, log_level)
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        reloadable_logger(drain, log_level)
     };
     // XXX: cancel slog_scope's NoGlobalLoggerSet for now, it's difficult to
     // prevent it from potentially panicing during tests. reset_logging resets
@@ -39,6 +93,113 @@ pub fn init_logging(json: bool, name: &str, version: &str) -> Result<()> {
     Ok(())
 }
 
+/// Build the root logger, filtered by a `LogFilter` registered for
+/// `set_log_level`
+fn reloadable_logger<D>(drain: D, log_level: Option<&str>) -> slog::Logger
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+{
+    // The filter is shared with `set_log_level`, so the drain must be `Sync`
+    let filter = Arc::new(LogFilter::new(Mutex::new(drain).fuse(), log_level));
+    *LOG_FILTER.lock().expect("LOG_FILTER lock poisoned") = Some(filter.clone());
+    let drain = slog_async::Async::new(filter).build().fuse();
+    slog::Logger::root(RequestIdDrain(drain), slog_o!())
+}
+
+/// Change the filter of the logger set up by `init_logging` (e.g. when
+/// reloading the settings). As with `init_logging`, `RUST_LOG` takes
+/// precedence when set.
+pub fn set_log_level(log_level: Option<&str>) {
+    if let Some(filter) = LOG_FILTER
+        .lock()
+        .expect("LOG_FILTER lock poisoned")
+        .as_ref()
+    {
+        filter.set(log_level);
+    }
+}
+
+trait SetLogLevel: Send + Sync {
+    fn set(&self, log_level: Option<&str>);
+}
+
+/// An `env_filter` that can be swapped out while logging
+struct LogFilter<D: Drain> {
+    drain: Arc<D>,
+    filter: RwLock<slog_envlogger::EnvLogger<Arc<D>>>,
+}
+
+impl<D: Drain> LogFilter<D> {
+    fn new(drain: D, log_level: Option<&str>) -> Self {
+        let drain = Arc::new(drain);
+        Self {
+            filter: RwLock::new(env_filter(Arc::clone(&drain), log_level)),
+            drain,
+        }
+    }
+}
+
+impl<D: Drain + Send + Sync> SetLogLevel for LogFilter<D> {
+    fn set(&self, log_level: Option<&str>) {
+        let filter = env_filter(Arc::clone(&self.drain), log_level);
+        *self.filter.write().expect("LogFilter lock poisoned") = filter;
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for LogFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> std::result::Result<Self::Ok, Self::Err> {
+        self.filter
+            .read()
+            .expect("LogFilter lock poisoned")
+            .log(record, values)
+    }
+}
+
+/// Filter the drain per `RUST_LOG`, or `log_level` when `RUST_LOG` is unset
+fn env_filter<D: Drain>(drain: D, log_level: Option<&str>) -> slog_envlogger::EnvLogger<D> {
+    let rust_log = env::var("RUST_LOG").ok().filter(|spec| !spec.is_empty());
+    match (rust_log, log_level) {
+        (None, Some(log_level)) => slog_envlogger::LogBuilder::new(drain)
+            .parse(log_level)
+            .build(),
+        _ => slog_envlogger::new(drain),
+    }
+}
+
+/// Check an env_logger style filter (`[module=]level` directives separated
+/// by commas, optionally followed by `/` and a message filter).
+///
+/// `slog_envlogger` only prints a warning for, and ignores, invalid
+/// directives.
+pub fn validate_log_filter(spec: &str) -> std::result::Result<(), String> {
+    let mut parts = spec.splitn(3, '/');
+    let directives = parts.next().unwrap_or_default();
+    if parts.nth(1).is_some() {
+        return Err("only one '/' message filter is allowed".to_owned());
+    }
+    for directive in directives.split(',').map(str::trim) {
+        let mut parts = directive.split('=');
+        match (parts.next(), parts.next().map(str::trim), parts.next()) {
+            // A bare level or module name
+            (_, None, None) => {}
+            (Some(module), Some(level), None) if !module.is_empty() => {
+                if !level.is_empty() && slog::FilterLevel::from_str(level).is_err() {
+                    return Err(format!("unknown level {:?} in {:?}", level, directive));
+                }
+            }
+            _ => return Err(format!("invalid directive {:?}", directive)),
+        }
+    }
+    Ok(())
+}
+
 pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, o!());
     slog_scope::set_global_logger(logger).cancel_reset();
@@ -69,3 +230,48 @@ fn get_ec2_instance_id() -> reqwest::Result<String> {
         .error_for_status()?
         .text()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use slog::Drain;
+
+    use super::{LogFilter, SetLogLevel};
+
+    /// Records the messages logged
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Drain for Recorder {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record<'_>,
+            _: &slog::OwnedKVList,
+        ) -> std::result::Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn set_log_level() {
+        if std::env::var("RUST_LOG").map_or(false, |spec| !spec.is_empty()) {
+            // RUST_LOG takes precedence
+            return;
+        }
+        let filter = Arc::new(LogFilter::new(Recorder::default(), Some("info")));
+        let logger = slog::Logger::root(filter.clone().fuse(), slog::o!());
+        slog::debug!(logger, "hidden");
+        slog::info!(logger, "shown");
+
+        filter.set(Some("debug"));
+        slog::debug!(logger, "now shown");
+        filter.set(Some("warn"));
+        slog::info!(logger, "now hidden");
+        assert_eq!(*filter.drain.0.lock().unwrap(), ["shown", "now shown"]);
+    }
+}
@@ -1,27 +1,187 @@
 //! Metrics tie-ins
+use std::io;
 use std::net::UdpSocket;
+use std::panic::RefUnwindSafe;
 
 use cadence::{
-    BufferedUdpMetricSink, MetricError, NopMetricSink, QueuingMetricSink, StatsdClient,
+    BufferedUdpMetricSink, MetricError, MetricSink, NopMetricSink, QueuingMetricSink, StatsdClient,
     StatsdClientBuilder,
 };
 
+/// A boxed `MetricSink` usable by a `StatsdClient`
+pub type BoxedMetricSink = Box<dyn MetricSink + Send + Sync + RefUnwindSafe>;
+
 /// Create a cadence StatsdClientBuilder from the given options
+///
+/// The `host` may be a comma separated list of `host[:port]`s (defaulting
+/// to `port`), every metric being sent to all of them.
 pub fn builder(
     prefix: &str,
     host: &Option<String>,
     port: u16,
 ) -> Result<StatsdClientBuilder, MetricError> {
     let builder = if let Some(host) = host {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true)?;
-
-        let addr = (host.as_str(), port);
-        let udp_sink = BufferedUdpMetricSink::from(addr, socket)?;
-        let sink = QueuingMetricSink::from(udp_sink);
-        StatsdClient::builder(prefix, sink)
+        let mut targets = parse_targets(host, port)?;
+        if targets.len() == 1 {
+            let (host, port) = targets.remove(0);
+            StatsdClient::builder(prefix, udp_sink(&host, port)?)
+        } else {
+            StatsdClient::builder(prefix, FanOutMetricSink::new(&targets)?)
+        }
     } else {
         StatsdClient::builder(prefix, NopMetricSink)
     };
     Ok(builder.with_error_handler(|err| warn!("⚠️ Metric send error: {:?}", err)))
 }
+
+/// Create the statsd `MetricSink` for the given options, for wrapping by
+/// other sinks
+pub fn sink(host: &Option<String>, port: u16) -> Result<BoxedMetricSink, MetricError> {
+    Ok(if let Some(host) = host {
+        let mut targets = parse_targets(host, port)?;
+        if targets.len() == 1 {
+            let (host, port) = targets.remove(0);
+            Box::new(udp_sink(&host, port)?)
+        } else {
+            Box::new(FanOutMetricSink::new(&targets)?)
+        }
+    } else {
+        Box::new(NopMetricSink)
+    })
+}
+
+/// Parse a comma separated list of `host[:port]`s, using `port` for hosts
+/// without one. IPv6 addresses with a port must be bracketed
+/// (`[::1]:8125`).
+fn parse_targets(hosts: &str, port: u16) -> Result<Vec<(String, u16)>, MetricError> {
+    hosts
+        .split(',')
+        .map(|entry| {
+            let entry = entry.trim();
+            let invalid = || {
+                MetricError::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid statsd host {:?}", entry),
+                ))
+            };
+            let (host, port) = match entry.rsplit_once(':') {
+                // A bare IPv6 address
+                Some((host, _)) if host.contains(':') && !host.ends_with(']') => (entry, port),
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (entry, port),
+            };
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            Ok((host.to_owned(), port))
+        })
+        .collect()
+}
+
+fn udp_sink(host: &str, port: u16) -> Result<QueuingMetricSink, MetricError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+
+    let addr = (host, port);
+    let udp_sink = BufferedUdpMetricSink::from(addr, socket)?;
+    Ok(QueuingMetricSink::from(udp_sink))
+}
+
+/// Sends every metric to several statsd servers (e.g. while migrating
+/// between them)
+pub struct FanOutMetricSink {
+    sinks: Vec<QueuingMetricSink>,
+}
+
+impl FanOutMetricSink {
+    fn new(targets: &[(String, u16)]) -> Result<Self, MetricError> {
+        let sinks = targets
+            .iter()
+            .map(|(host, port)| udp_sink(host, *port))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { sinks })
+    }
+}
+
+impl MetricSink for FanOutMetricSink {
+    /// Emit the metric to every sink, even when some of them fail (returning
+    /// the last error)
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut result = Ok(metric.len());
+        for sink in &self.sinks {
+            if let Err(e) = sink.emit(metric) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in &self.sinks {
+            if let Err(e) = sink.flush() {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use cadence::CountedExt;
+
+    use super::{builder, parse_targets};
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            parse_targets("statsd.example.com", 8125).unwrap(),
+            [("statsd.example.com".to_owned(), 8125)]
+        );
+        assert_eq!(
+            parse_targets("legacy:9125, 10.0.0.1,[::1]:9126,::1", 8125).unwrap(),
+            [
+                ("legacy".to_owned(), 9125),
+                ("10.0.0.1".to_owned(), 8125),
+                ("::1".to_owned(), 9126),
+                ("::1".to_owned(), 8125),
+            ]
+        );
+        assert!(parse_targets("legacy:statsd", 8125).is_err());
+        assert!(parse_targets("legacy:99999", 8125).is_err());
+        assert!(parse_targets("legacy,,new", 8125).is_err());
+        assert!(parse_targets(":8125", 8125).is_err());
+        assert!(builder("", &Some("legacy:".to_owned()), 8125).is_err());
+    }
+
+    #[test]
+    fn fans_out() {
+        let servers: Vec<UdpSocket> = (0..2)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let hosts = servers
+            .iter()
+            .map(|server| server.local_addr().unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let client = builder("test", &Some(hosts), 8125).unwrap().build();
+        client.incr("fan_out").unwrap();
+        // Flushes the buffered sinks
+        drop(client);
+
+        for server in servers {
+            server
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = [0; 512];
+            let len = server.recv(&mut buf).unwrap();
+            let received = String::from_utf8_lossy(&buf[..len]);
+            assert_eq!(received.trim_end(), "test.fan_out:1|c");
+        }
+    }
+}
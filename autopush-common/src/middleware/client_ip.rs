@@ -0,0 +1,169 @@
+//! Resolving the client's IP address behind trusted proxies (e.g. load
+//! balancers)
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::{dev::ServiceRequest, HttpMessage, HttpRequest};
+use ipnet::IpNet;
+
+/// The header proxies append the address of their peer to
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// The resolved client IP address, stored in the request's extensions by
+/// [super::request_id::RequestIdWrapper]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Get the request's resolved client IP address
+    pub fn of(req: &HttpRequest) -> Option<IpAddr> {
+        req.extensions()
+            .get::<ClientIp>()
+            .map(|client_ip| client_ip.0)
+    }
+}
+
+/// The networks of the proxies trusted to report the client's address via
+/// `X-Forwarded-For`
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parse a list of CIDRs (e.g. "10.0.0.0/8") or bare IP addresses
+    pub fn parse<S: AsRef<str>>(proxies: &[S]) -> Result<Self, String> {
+        proxies
+            .iter()
+            .map(|proxy| {
+                let proxy = proxy.as_ref().trim();
+                IpNet::from_str(proxy)
+                    .or_else(|_| IpAddr::from_str(proxy).map(IpNet::from))
+                    .map_err(|_| format!("Invalid CIDR {:?}", proxy))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolve the client's address.
+    ///
+    /// When the socket peer is a trusted proxy, this is the rightmost
+    /// `X-Forwarded-For` entry that isn't also a trusted proxy (entries left
+    /// of it may be spoofed by the client). Otherwise it's always the peer.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: &[&str]) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.trusts(&client) {
+            return client;
+        }
+        let entries = forwarded_for
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .rev();
+        for entry in entries {
+            let Ok(ip) = IpAddr::from_str(entry) else {
+                // Unparseable, so stick with the last trusted proxy
+                break;
+            };
+            client = canonical(ip);
+            if !self.trusts(&client) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Resolve the client's address of a request, `None` when the socket
+    /// peer is unknown (e.g. in tests)
+    pub fn resolve(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        let forwarded_for: Vec<&str> = req
+            .headers()
+            .get_all(FORWARDED_FOR_HEADER)
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        Some(self.client_ip(peer, &forwarded_for))
+    }
+}
+
+/// An IPv4-mapped IPv6 address (`::ffff:a.b.c.d`, e.g. the peers of a dual
+/// stack listener) as the IPv4 address, so IPv4 networks match it
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::TrustedProxies;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_untrusted_peers() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8"]).unwrap();
+        // A spoofed X-Forwarded-For from a client connecting directly
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.7"), &["198.51.100.1"]),
+            ip("203.0.113.7")
+        );
+        // No proxies are trusted by default
+        assert_eq!(
+            TrustedProxies::default().client_ip(ip("10.0.0.1"), &["198.51.100.1"]),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parses_trusted_chains() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"]).unwrap();
+        // The client's own (spoofable) entries left of its address are skipped
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &["1.1.1.1, 203.0.113.7", "192.0.2.1"]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("2001:db8::1"), &["2001:db9::5, 2001:db8::2"]),
+            ip("2001:db9::5")
+        );
+        // Without an untrusted entry the leftmost is used
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &["10.1.1.1"]),
+            ip("10.1.1.1")
+        );
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &[]), ip("10.0.0.1"));
+        // Garbage stops the walk at the last trusted proxy
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &["203.0.113.7, unknown, 10.2.2.2"]),
+            ip("10.2.2.2")
+        );
+    }
+
+    /// IPv4-mapped peers (of a dual stack listener) match IPv4 networks
+    #[test]
+    fn ipv4_mapped_peers() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8"]).unwrap();
+        assert_eq!(
+            proxies.client_ip(ip("::ffff:10.0.0.1"), &["::ffff:203.0.113.7"]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("::ffff:203.0.113.7"), &["198.51.100.1"]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        assert!(TrustedProxies::parse(&["10.0.0.0/33"]).is_err());
+        assert!(TrustedProxies::parse(&["load-balancer"]).is_err());
+    }
+}
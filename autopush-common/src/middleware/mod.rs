@@ -1 +1,3 @@
+pub mod client_ip;
+pub mod request_id;
 pub mod sentry;
@@ -0,0 +1,158 @@
+use std::{rc::Rc, sync::Arc, time::Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use futures_util::future::{ok, Ready};
+use uuid::Uuid;
+
+use super::client_ip::{ClientIp, TrustedProxies};
+use crate::logging::REQUEST_ID;
+
+/// The header carrying a request's correlation id
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming `X-Request-Id` that's reused rather than replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Assigns each request a correlation id (reusing a valid incoming
+/// `X-Request-Id`), includes it in every log record emitted while handling
+/// the request, echoes it in the response's `X-Request-Id` header and logs
+/// an access line for the completed request.
+///
+/// The client's IP address (resolved through any `trusted_proxies`) is
+/// logged and stored as the request's [ClientIp].
+#[derive(Clone, Default)]
+pub struct RequestIdWrapper {
+    trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl RequestIdWrapper {
+    pub fn new(trusted_proxies: TrustedProxies) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdWrapper
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service: Rc::new(service),
+            trusted_proxies: self.trusted_proxies.clone(),
+        })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+    trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, sreq: ServiceRequest) -> Self::Future {
+        let request_id = sreq
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_owned)
+            .unwrap_or_else(|| Uuid::new_v4().as_simple().to_string());
+        let client_ip = self.trusted_proxies.resolve(&sreq);
+        if let Some(client_ip) = client_ip {
+            sreq.extensions_mut().insert(ClientIp(client_ip));
+        }
+        let method = sreq.method().to_string();
+        let path = sreq.path().to_owned();
+        let start = Instant::now();
+        let service = self.service.clone();
+
+        REQUEST_ID
+            .scope(request_id.clone(), async move {
+                let result = service.call(sreq).await;
+                let latency_ms = start.elapsed().as_millis() as u64;
+                let status = match &result {
+                    Ok(response) => response.status(),
+                    Err(error) => error.as_response_error().status_code(),
+                };
+                info!(
+                    "{} {} {}", method, path, status.as_u16();
+                    "method" => &method,
+                    "path" => &path,
+                    "status" => status.as_u16(),
+                    "latency_ms" => latency_ms,
+                    "client_ip" => client_ip.map(|ip| ip.to_string())
+                );
+                let mut response = result?;
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::{RequestIdWrapper, REQUEST_ID_HEADER};
+    use crate::logging::REQUEST_ID;
+
+    async fn echo_request_id() -> HttpResponse {
+        HttpResponse::Ok().body(REQUEST_ID.with(|id| id.clone()))
+    }
+
+    #[actix_rt::test]
+    async fn generates_and_reuses_request_ids() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestIdWrapper::default())
+                .route("/", web::get().to(echo_request_id)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        let generated = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        assert_eq!(generated.len(), 32);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, generated.as_bytes());
+
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "upstream-id"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "upstream-id"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(body, "upstream-id".as_bytes());
+    }
+}
@@ -10,12 +10,18 @@ use futures_util::future::{ok, Ready};
 use sentry::{protocol::Event, Hub};
 use serde_json::value::Value;
 
-use crate::{errors::ReportableError, tags::Tags};
+use crate::{errors::ReportableError, logging::REQUEST_ID, tags::Tags};
 
+/// Reports errors (and 5xx responses) to Sentry, or records them as metrics
+/// when they're not reportable.
+///
+/// Nothing is reported (and the per request Sentry setup is skipped) unless
+/// a Sentry client was initialized before the wrapper was created.
 #[derive(Clone)]
 pub struct SentryWrapper<E> {
     metrics: Arc<StatsdClient>,
     metric_label: String,
+    reporting: bool,
     phantom: PhantomData<E>,
 }
 
@@ -24,6 +30,9 @@ impl<E> SentryWrapper<E> {
         Self {
             metrics,
             metric_label,
+            reporting: Hub::main()
+                .client()
+                .map_or(false, |client| client.is_enabled()),
             phantom: PhantomData,
         }
     }
@@ -46,6 +55,7 @@ where
             service: Rc::new(RefCell::new(service)),
             metrics: self.metrics.clone(),
             metric_label: self.metric_label.clone(),
+            reporting: self.reporting,
             phantom: PhantomData,
         })
     }
@@ -56,6 +66,7 @@ pub struct SentryWrapperMiddleware<S, E> {
     service: Rc<RefCell<S>>,
     metrics: Arc<StatsdClient>,
     metric_label: String,
+    reporting: bool,
     phantom: PhantomData<E>,
 }
 
@@ -73,11 +84,18 @@ where
 
     fn call(&self, sreq: ServiceRequest) -> Self::Future {
         // Set up the hub to add request data to events
-        let hub = Hub::new_from_top(Hub::main());
-        let _ = hub.push_scope();
-        let sentry_request = sentry_request_from_http(&sreq);
-        hub.configure_scope(|scope| {
-            scope.add_event_processor(Box::new(move |event| process_event(event, &sentry_request)))
+        let hub = self.reporting.then(|| {
+            let hub = Hub::new_from_top(Hub::main());
+            let _ = hub.push_scope();
+            let sentry_request = sentry_request_from_http(&sreq);
+            let route = sreq.match_pattern();
+            let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+            hub.configure_scope(|scope| {
+                scope.add_event_processor(Box::new(move |event| {
+                    process_event(event, &sentry_request, &route, &request_id)
+                }))
+            });
+            hub
         });
 
         // get the tag information
@@ -113,6 +131,9 @@ where
                             return Err(error);
                         }
                     };
+                    let Some(hub) = hub else {
+                        return Err(error);
+                    };
                     debug!("Reporting error to Sentry (service error): {}", error);
                     let mut event = event_from_actix_error::<E>(&error);
                     event.extra.append(&mut tags.clone().extra_tree());
@@ -142,6 +163,9 @@ where
                         return Ok(response);
                     }
                 }
+                let Some(hub) = hub else {
+                    return Ok(response);
+                };
                 debug!("Reporting error to Sentry (response error): {}", error);
                 let mut event = event_from_actix_error::<E>(error);
                 event.extra.append(&mut tags.clone().extra_tree());
@@ -156,6 +180,19 @@ where
                 }
                 let event_id = hub.capture_event(event);
                 trace!("event_id = {}", event_id);
+            } else if response.status().is_server_error() {
+                if let Some(hub) = hub {
+                    debug!("Reporting {} response to Sentry", response.status());
+                    let mut event = Event {
+                        message: Some(format!("{} response", response.status())),
+                        level: sentry::protocol::Level::Error,
+                        ..Default::default()
+                    };
+                    event.extra.append(&mut tags.clone().extra_tree());
+                    event.tags.append(&mut tags.clone().tag_tree());
+                    let event_id = hub.capture_event(event);
+                    trace!("event_id = {}", event_id);
+                }
             }
             Ok(response)
         }
@@ -184,18 +221,27 @@ fn sentry_request_from_http(request: &ServiceRequest) -> sentry::protocol::Reque
     }
 }
 
-/// Add request data to a Sentry event
+/// Add request data (including the matched route and the request's
+/// correlation id) to a Sentry event
 #[allow(clippy::unnecessary_wraps)]
 fn process_event(
     mut event: Event<'static>,
     request: &sentry::protocol::Request,
+    route: &Option<String>,
+    request_id: &Option<String>,
 ) -> Option<Event<'static>> {
     if event.request.is_none() {
         event.request = Some(request.clone());
     }
-
-    // TODO: Use ServiceRequest::match_pattern for the event transaction.
-    //       Coming in Actix v3.
+    if event.transaction.is_none() {
+        event.transaction = route.clone();
+    }
+    if let Some(request_id) = request_id {
+        event
+            .tags
+            .entry("request_id".to_owned())
+            .or_insert_with(|| request_id.clone());
+    }
 
     Some(event)
 }
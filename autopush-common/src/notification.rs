@@ -27,6 +27,9 @@ pub struct Notification {
     pub sortkey_timestamp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<HashMap<String, String>>,
+    /// The number of times a UserAgent has Nack'd it
+    #[serde(default, skip_serializing)]
+    pub nack_count: u32,
 }
 
 pub const TOPIC_NOTIFICATION_PREFIX: &str = "01";
@@ -67,10 +70,93 @@ impl Notification {
     /// Convenience function to determine if the notification
     /// has aged out.
     pub fn expired(&self, at_sec: u64) -> bool {
-        at_sec >= self.timestamp + self.ttl
+        self.expired_with_tolerance(at_sec, 0)
+    }
+
+    /// Whether the notification has aged out at `at_sec`, allowing for the
+    /// clock it was stored by (its `timestamp`) having been up to
+    /// `tolerance_secs` behind.
+    ///
+    /// As long as the storing clock was no more than the tolerance behind
+    /// the one reading `at_sec`, a notification never expires before its TTL
+    /// has elapsed. A reading clock that's behind the storing one (e.g. it
+    /// has since stepped back) sees less time elapsed, never a negative
+    /// amount, so the notification is kept for up to that much longer than
+    /// its TTL. A TTL of 0 is never deliverable from storage.
+    pub fn expired_with_tolerance(&self, at_sec: u64, tolerance_secs: u64) -> bool {
+        self.ttl == 0
+            || at_sec.saturating_sub(self.timestamp) >= self.ttl.saturating_add(tolerance_secs)
     }
 }
 
 fn default_ttl() -> u64 {
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Notification;
+
+    #[test]
+    fn test_expired_boundary() {
+        let notif = Notification {
+            ttl: 60,
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        assert!(!notif.expired(1_059));
+        // A TTL equal to the elapsed time has aged out
+        assert!(notif.expired(1_060));
+        assert!(notif.expired(1_061));
+
+        // A TTL of 0 is never deliverable from storage
+        let notif = Notification {
+            ttl: 0,
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        assert!(notif.expired(1_000));
+    }
+
+    /// Stored by a clock that had stepped back by 20 seconds, the
+    /// notification's read within its TTL (by a correct clock)
+    #[test]
+    fn test_expired_backward_clock_step() {
+        let notif = Notification {
+            ttl: 60,
+            timestamp: 1_000 - 20,
+            ..Default::default()
+        };
+        let now = 1_000 + 50;
+        // Previously expired early
+        assert!(notif.expired(now));
+        assert!(!notif.expired_with_tolerance(now, 30));
+        // ...but still expires
+        assert!(notif.expired_with_tolerance(1_000 + 70, 30));
+
+        // Read by a clock behind the storing one
+        let notif = Notification {
+            ttl: 60,
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        assert!(!notif.expired_with_tolerance(990, 30));
+        assert!(!notif.expired(990));
+    }
+
+    /// Read by a clock that's since stepped back by 20 seconds, the
+    /// notification's kept for up to 20 seconds longer
+    #[test]
+    fn test_expired_backward_reader_clock_step() {
+        let notif = Notification {
+            ttl: 60,
+            timestamp: 1_000,
+            ..Default::default()
+        };
+        let reader_clock = |real_sec: u64| real_sec - 20;
+        assert!(!notif.expired_with_tolerance(reader_clock(1_050), 30));
+        // Past its TTL (and the tolerance) in real time
+        assert!(!notif.expired_with_tolerance(reader_clock(1_100), 30));
+        assert!(notif.expired_with_tolerance(reader_clock(1_110), 30));
+    }
+}
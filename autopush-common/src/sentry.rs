@@ -1,18 +1,37 @@
 use std::error::Error;
+use std::sync::Arc;
+
+use sentry::protocol::Event;
 
 use crate::errors::ReportableError;
 
+/// Request headers and event extras which may hold secrets (e.g. VAPID
+/// tokens) or message bodies, dropped from every event
+const SCRUBBED_FIELDS: [&str; 3] = ["authorization", "body", "data"];
+
 /// Return a `sentry::::ClientOptions` w/ the `debug-images` integration
-/// disabled
+/// disabled and sensitive data scrubbed from events
 pub fn client_options() -> sentry::ClientOptions {
     // debug-images conflicts w/ our debug = 1 rustc build option:
     // https://github.com/getsentry/sentry-rust/issues/574
     let mut opts = sentry::apply_defaults(sentry::ClientOptions::default());
     opts.integrations.retain(|i| i.name() != "debug-images");
     opts.default_integrations = false;
+    opts.before_send = Some(Arc::new(scrub_event));
     opts
 }
 
+/// Remove VAPID tokens and message bodies from an event before it's sent
+fn scrub_event(mut event: Event<'static>) -> Option<Event<'static>> {
+    let is_scrubbed = |key: &String| SCRUBBED_FIELDS.contains(&key.to_lowercase().as_str());
+    if let Some(request) = &mut event.request {
+        request.data = None;
+        request.headers.retain(|key, _| !is_scrubbed(key));
+    }
+    event.extra.retain(|key, _| !is_scrubbed(key));
+    Some(event)
+}
+
 /// Custom `sentry::event_from_error` for `ReportableError`
 ///
 /// `std::error::Error` doesn't support backtraces, thus `sentry::event_from_error`
@@ -76,3 +95,36 @@ where
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sentry::protocol::{Event, Request};
+
+    use super::scrub_event;
+
+    #[test]
+    fn scrubs_secrets() {
+        let mut event = Event {
+            request: Some(Request {
+                data: Some("encrypted message body".to_owned()),
+                headers: [
+                    ("Authorization", "vapid t=eyJ0eXAi,k=BDd3_hVL9fZi"),
+                    ("TTL", "60"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        event.extra.insert("body".to_owned(), "encrypted".into());
+        event.extra.insert("errno".to_owned(), 104.into());
+
+        let event = scrub_event(event).unwrap();
+        let request = event.request.unwrap();
+        assert_eq!(request.data, None);
+        assert_eq!(request.headers.keys().collect::<Vec<_>>(), vec!["TTL"]);
+        assert_eq!(event.extra.keys().collect::<Vec<_>>(), vec!["errno"]);
+    }
+}
@@ -43,6 +43,24 @@ pub fn b64_encode_std(input: &Vec<u8>) -> String {
     base64::engine::general_purpose::STANDARD_NO_PAD.encode(input)
 }
 
+/// Whether the `Authorization` header's bearer token matches the configured
+/// `admin_token` (compared in constant time). Never when no `admin_token` is
+/// configured.
+pub fn is_admin_token(auth_header: Option<&str>, admin_token: Option<&str>) -> bool {
+    let Some(admin_token) = admin_token.filter(|token| !token.is_empty()) else {
+        return false;
+    };
+    let Some(token) = auth_header
+        .and_then(|header| header.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token)
+    else {
+        return false;
+    };
+    token.len() == admin_token.len()
+        && openssl::memcmp::eq(token.as_bytes(), admin_token.as_bytes())
+}
+
 pub fn deserialize_u32_to_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -112,6 +112,7 @@ impl AutopushServer {
             !self.app_state.human_logs,
             env!("CARGO_PKG_NAME"),
             env!("CARGO_PKG_VERSION"),
+            None,
         )
         .expect("init_logging failed");
         let handles = Server::start(&self.app_state).expect("failed to start server");
@@ -1454,7 +1454,7 @@ class TestRustWebPush(unittest.TestCase):
         claims = {
             "aud": "http://localhost:{}".format(ENDPOINT_PORT),
             "exp": int(time.time()) + 86400,
-            "sub": "a@example.com",
+            "sub": "mailto:a@example.com",
         }
         vapid = _get_vapid(private_key, claims)
         pk_hex = vapid["crypto-key"]